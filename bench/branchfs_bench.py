@@ -48,7 +48,8 @@ class BranchFSBench:
         cmd = [self.branchfs] + list(args)
         return subprocess.run(cmd, check=check, capture_output=True, text=True, env=env)
 
-    def _start_daemon(self, base_dir: Path, storage: Path, mountpoint: Path, log_file: Path):
+    def _start_daemon(self, base_dir: Path, storage: Path, mountpoint: Path, log_file: Path,
+                      extra_args: tuple = ()):
         """Start branchfs daemon with logging enabled."""
         env = os.environ.copy()
         env["RUST_LOG"] = "debug"
@@ -56,7 +57,7 @@ class BranchFSBench:
         with open(log_file, "w") as f:
             proc = subprocess.Popen(
                 [self.branchfs, "mount", "--base", str(base_dir),
-                 "--storage", str(storage), str(mountpoint)],
+                 "--storage", str(storage), *extra_args, str(mountpoint)],
                 env=env,
                 stdout=f,
                 stderr=subprocess.STDOUT
@@ -342,6 +343,192 @@ class BranchFSBench:
                 self._stop_daemon(mountpoint, storage)
                 proc.wait(timeout=5)
 
+    # =========================================================================
+    # Benchmark 4b: Readahead (backing-store reads for a streaming read)
+    # =========================================================================
+    def bench_readahead(self, file_size_mb: int = 50, block_size_kb: int = 64,
+                        windows_kb: list[int] = [0, 512, 2048]):
+        """Count backing-store reads for a sequential read at varying readahead windows."""
+        print("\n=== Benchmark: Readahead ===")
+
+        with tempfile.TemporaryDirectory() as tmpdir:
+            tmpdir = Path(tmpdir)
+            base_dir = tmpdir / "base"
+            base_dir.mkdir()
+            (base_dir / "stream.bin").write_bytes(os.urandom(file_size_mb * 1024 * 1024))
+
+            for window_kb in windows_kb:
+                mountpoint = tmpdir / f"mnt_{window_kb}"
+                storage = tmpdir / f"storage_{window_kb}"
+                log_file = tmpdir / f"daemon_{window_kb}.log"
+                mountpoint.mkdir()
+                storage.mkdir()
+
+                proc = self._start_daemon(base_dir, storage, mountpoint, log_file,
+                                          ("--readahead", str(window_kb * 1024)))
+                try:
+                    start = time.perf_counter()
+                    with open(mountpoint / "stream.bin", "rb") as f:
+                        while f.read(block_size_kb * 1024):
+                            pass
+                    read_time = time.perf_counter() - start
+                    time.sleep(0.2)  # let release() log the stream stats
+
+                    backing_reads = 0
+                    with open(log_file) as f:
+                        for line in f:
+                            match = re.search(r"\[BENCH\] read ino \d+: (\d+) backing reads", line)
+                            if match:
+                                backing_reads += int(match.group(1))
+
+                    self.results.append(BenchmarkResult(
+                        name="readahead",
+                        params={"file_size_mb": file_size_mb, "block_size_kb": block_size_kb,
+                                "window_kb": window_kb, "backing_reads": backing_reads},
+                        latency_us=read_time * 1_000_000,
+                        throughput_mbps=file_size_mb / read_time
+                    ))
+                    print(f"  window {window_kb:5d} KB: {backing_reads:6d} backing reads, "
+                          f"{file_size_mb / read_time:.1f} MB/s")
+                finally:
+                    self._stop_daemon(mountpoint, storage)
+                    proc.wait(timeout=5)
+
+    # =========================================================================
+    # Benchmark 4c: Large Directory Listing
+    # =========================================================================
+    def _daemon_pid(self, storage: Path) -> Optional[int]:
+        """Find the forked daemon serving `storage` (it keeps the mount's cmdline)."""
+        for entry in Path("/proc").iterdir():
+            if not entry.name.isdigit():
+                continue
+            try:
+                cmdline = (entry / "cmdline").read_bytes().split(b"\0")
+            except OSError:
+                continue
+            if str(storage).encode() in cmdline and b"mount" in cmdline:
+                return int(entry.name)
+        return None
+
+    def _peak_rss_kb(self, pid: int) -> int:
+        with open(f"/proc/{pid}/status") as f:
+            for line in f:
+                if line.startswith("VmHWM:"):
+                    return int(line.split()[1])
+        return 0
+
+    def bench_readdir(self, num_files: int = 500_000, max_bytes_per_entry: int = 1024):
+        """Time `ls` of one huge base directory and check the daemon's memory growth."""
+        print("\n=== Benchmark: Large Directory Listing ===")
+
+        with tempfile.TemporaryDirectory() as tmpdir:
+            tmpdir = Path(tmpdir)
+            big_dir = tmpdir / "base" / "big"
+            mountpoint = tmpdir / "mnt"
+            storage = tmpdir / "storage"
+            log_file = tmpdir / "daemon.log"
+
+            big_dir.mkdir(parents=True)
+            mountpoint.mkdir()
+            storage.mkdir()
+            for i in range(num_files):
+                (big_dir / f"file_{i}").touch()
+
+            proc = self._start_daemon(tmpdir / "base", storage, mountpoint, log_file)
+
+            try:
+                pid = self._daemon_pid(storage)
+                rss_before = self._peak_rss_kb(pid) if pid else 0
+
+                start = time.perf_counter()
+                listing = subprocess.run(["ls", "-f", str(mountpoint / "big")],
+                                         capture_output=True, check=True).stdout
+                elapsed = time.perf_counter() - start
+                listed = listing.count(b"\n") - 2  # minus . and ..
+
+                growth_kb = (self._peak_rss_kb(pid) - rss_before) if pid else 0
+                per_entry = growth_kb * 1024 / num_files
+
+                self.results.append(BenchmarkResult(
+                    name="readdir",
+                    params={"num_files": num_files, "listed": listed,
+                            "peak_rss_growth_kb": growth_kb},
+                    latency_us=elapsed * 1_000_000
+                ))
+                print(f"  {listed} entries in {elapsed:.2f} s, daemon peak RSS "
+                      f"+{growth_kb / 1024:.1f} MB ({per_entry:.0f} bytes/entry)")
+
+                assert listed == num_files, f"listed {listed} of {num_files} entries"
+                # Inodes stay allocated per entry; anything beyond that
+                # means the listing itself is being buffered
+                assert per_entry <= max_bytes_per_entry, \
+                    f"daemon grew {per_entry:.0f} bytes per entry"
+            finally:
+                self._stop_daemon(mountpoint, storage)
+                proc.wait(timeout=5)
+
+    # =========================================================================
+    # Benchmark 4d: Commit After Opening Many Files
+    # =========================================================================
+    def bench_commit_opened(self, num_files: int = 5000, held_open: int = 100,
+                            iterations: int = 3):
+        """Measure commit time after a session opened many files, some still open."""
+        print("\n=== Benchmark: Commit After Opening Many Files (Internal) ===")
+
+        with tempfile.TemporaryDirectory() as tmpdir:
+            tmpdir = Path(tmpdir)
+            base_dir = tmpdir / "base"
+            self._create_base_files(base_dir, num_files, file_size=64)
+            rel_paths = [f"dir_{i // 100}/file_{i}.txt" for i in range(num_files)]
+
+            latencies = []
+            for i in range(iterations):
+                mountpoint = tmpdir / f"mnt_{i}"
+                storage = tmpdir / f"storage_{i}"
+                log_file = tmpdir / f"daemon_{i}.log"
+                mountpoint.mkdir()
+                storage.mkdir()
+
+                proc = self._start_daemon(base_dir, storage, mountpoint, log_file)
+                held = []
+                try:
+                    self._run("create", "bench", str(mountpoint), "--storage", str(storage))
+                    for rel in rel_paths:
+                        (mountpoint / rel).read_bytes()
+                    held = [open(mountpoint / rel, "rb") for rel in rel_paths[:held_open]]
+                    cached = held[0].read()
+                    (mountpoint / "modification.bin").write_bytes(os.urandom(1024))
+
+                    (mountpoint / ".branchfs_ctl").write_text("commit")
+                    time.sleep(0.2)
+
+                    # A file kept open across the commit must go back to the
+                    # daemon instead of serving its cached pages
+                    (base_dir / rel_paths[0]).write_bytes(os.urandom(len(cached)))
+                    try:
+                        after = os.pread(held[0].fileno(), len(cached), 0)
+                    except OSError:
+                        after = None
+                    assert after != cached, "open file served stale cached pages"
+
+                    latencies.extend(self._parse_bench_logs(log_file, "commit"))
+                finally:
+                    for f in held:
+                        f.close()
+                    self._stop_daemon(mountpoint, storage)
+                    proc.wait(timeout=5)
+
+            if latencies:
+                avg_lat = sum(latencies) / len(latencies)
+                self.results.append(BenchmarkResult(
+                    name="commit_opened",
+                    params={"opened_files": num_files, "held_open": held_open},
+                    latency_us=avg_lat,
+                    iterations=len(latencies)
+                ))
+                print(f"  {num_files} files opened, {held_open} still open: "
+                      f"{avg_lat:.1f} us ({avg_lat/1000:.2f} ms)")
+
     # =========================================================================
     # Benchmark 5: Nested Branch Depth
     # =========================================================================
@@ -472,7 +659,8 @@ def main():
     parser.add_argument("--quick", action="store_true",
                        help="Run quick benchmarks with smaller parameters")
     parser.add_argument("--bench", choices=["creation", "commit", "abort",
-                                            "throughput", "nested", "all"],
+                                            "throughput", "readahead", "readdir",
+                                            "commit-opened", "nested", "all"],
                        default="all", help="Which benchmark to run")
     parser.add_argument("--latex", default=None,
                        help="Generate LaTeX tables in specified directory")
@@ -516,6 +704,16 @@ def main():
         if args.bench in ["throughput", "all"]:
             bench.bench_throughput(file_size_mb=20 if args.quick else 50)
 
+        if args.bench in ["readahead", "all"]:
+            bench.bench_readahead(file_size_mb=20 if args.quick else 50)
+
+        if args.bench in ["readdir", "all"]:
+            bench.bench_readdir(num_files=50_000 if args.quick else 500_000)
+
+        if args.bench in ["commit-opened", "all"]:
+            bench.bench_commit_opened(num_files=1000 if args.quick else 5000,
+                                      iterations=min(iterations, 3))
+
         if args.bench in ["nested", "all"]:
             bench.bench_nested_depth(depths, iterations=30 if args.quick else 50)
 
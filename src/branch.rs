@@ -1,575 +1,4604 @@
 use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use fuser::Notifier;
 use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 
+use crate::dedup;
 use crate::error::{BranchError, Result};
+use crate::fs::{Durability, EPOCH_INO};
 use crate::inode::ROOT_INO;
+use crate::storage;
 
-pub struct Branch {
+/// Name of the manifest written at the root of a backup directory
+pub const BACKUP_MANIFEST: &str = "backup.json";
+
+/// Name of the branch tree a running daemon keeps at the storage root, so a
+/// daemon started after a crash can pick its branches up again
+pub const BRANCH_TREE_FILE: &str = "branches.json";
+
+/// Commit history kept at the storage root, one JSON `CommitLogEntry` per
+/// line, oldest first
+pub const COMMIT_LOG_FILE: &str = "commits.log";
+
+/// Branch tree recorded in a backup (and in `BRANCH_TREE_FILE`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub base_path: PathBuf,
+    pub branches: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupEntry {
     pub name: String,
     pub parent: Option<String>,
-    pub files_dir: PathBuf,
-    pub tombstones_file: PathBuf,
-    tombstones: RwLock<HashSet<String>>,
+    /// Creation time in seconds since the Unix epoch (absent in older backups)
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    /// Who created the branch, if recorded (absent in older backups)
+    #[serde(default)]
+    pub created_by: Option<String>,
 }
 
-impl Branch {
-    pub fn new(name: &str, parent: Option<&str>, storage_path: &Path) -> Result<Self> {
-        let branch_dir = storage_path.join("branches").join(name);
-        let files_dir = branch_dir.join("files");
-        let tombstones_file = branch_dir.join("tombstones");
+/// Summary of a branch as reported by `List`
+#[derive(Debug, Clone)]
+pub struct BranchInfo {
+    pub name: String,
+    pub parent: Option<String>,
+    pub created_at: SystemTime,
+    pub created_by: Option<String>,
+    /// Total size of the branch's delta files in bytes
+    pub size: u64,
+}
 
-        fs::create_dir_all(&files_dir)?;
-        if !tombstones_file.exists() {
-            File::create(&tombstones_file)?;
-        }
+/// Kinds of filesystem activity counted per branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchEvent {
+    Read,
+    Write,
+    /// A file copied up from the base or a parent into the branch's delta
+    Cow,
+    Create,
+    Unlink,
+}
 
-        let tombstones = Self::load_tombstones(&tombstones_file)?;
+/// Activity counters of a branch; updated from FUSE handlers with relaxed
+/// ordering since they are only read for reporting.
+#[derive(Debug, Default)]
+pub struct BranchCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    cow_copies: AtomicU64,
+    creates: AtomicU64,
+    unlinks: AtomicU64,
+}
 
-        Ok(Self {
-            name: name.to_string(),
-            parent: parent.map(|s| s.to_string()),
-            files_dir,
-            tombstones_file,
-            tombstones: RwLock::new(tombstones),
-        })
-    }
+/// Point-in-time copy of `BranchCounters`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BranchStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub cow_copies: u64,
+    pub creates: u64,
+    pub unlinks: u64,
+}
 
-    fn load_tombstones(path: &Path) -> Result<HashSet<String>> {
-        let mut set = HashSet::new();
-        if path.exists() {
-            let file = File::open(path)?;
-            for line in BufReader::new(file).lines() {
-                set.insert(line?);
-            }
-        }
-        Ok(set)
+impl BranchCounters {
+    pub fn record(&self, event: BranchEvent) {
+        let counter = match event {
+            BranchEvent::Read => &self.reads,
+            BranchEvent::Write => &self.writes,
+            BranchEvent::Cow => &self.cow_copies,
+            BranchEvent::Create => &self.creates,
+            BranchEvent::Unlink => &self.unlinks,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn is_deleted(&self, path: &str) -> bool {
-        self.tombstones.read().contains(path)
+    pub fn stats(&self) -> BranchStats {
+        BranchStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            cow_copies: self.cow_copies.load(Ordering::Relaxed),
+            creates: self.creates.load(Ordering::Relaxed),
+            unlinks: self.unlinks.load(Ordering::Relaxed),
+        }
     }
 
-    pub fn add_tombstone(&self, path: &str) -> Result<()> {
-        let mut tombstones = self.tombstones.write();
-        if tombstones.insert(path.to_string()) {
-            let mut file = fs::OpenOptions::new()
-                .append(true)
-                .open(&self.tombstones_file)?;
-            writeln!(file, "{}", path)?;
+    pub fn reset(&self) {
+        for counter in [
+            &self.reads,
+            &self.writes,
+            &self.cow_copies,
+            &self.creates,
+            &self.unlinks,
+        ] {
+            counter.store(0, Ordering::Relaxed);
         }
-        Ok(())
     }
+}
+
+/// Daemon-wide totals since it started, for `Request::Metrics`; relaxed
+/// ordering as for `BranchCounters`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commits: AtomicU64,
+    aborts: AtomicU64,
+    branches_created: AtomicU64,
+    bytes_written: AtomicU64,
+}
 
-    pub fn remove_tombstone(&self, path: &str) {
-        self.tombstones.write().remove(path);
+/// Point-in-time copy of `Metrics`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub commits: u64,
+    pub aborts: u64,
+    pub branches_created: u64,
+    pub bytes_written: u64,
+}
+
+impl Metrics {
+    /// Count `bytes` written through a mount's `write` op.
+    pub fn record_write(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
     }
 
-    pub fn get_tombstones(&self) -> HashSet<String> {
-        self.tombstones.read().clone()
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            commits: self.commits.load(Ordering::Relaxed),
+            aborts: self.aborts.load(Ordering::Relaxed),
+            branches_created: self.branches_created.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
     }
+}
 
-    /// Replace the in-memory tombstone set and rewrite the tombstones file.
-    pub fn set_tombstones(&self, new_tombstones: HashSet<String>) -> Result<()> {
-        let mut tombstones = self.tombstones.write();
-        *tombstones = new_tombstones;
-        // Rewrite the tombstones file
-        let mut file = File::create(&self.tombstones_file)?;
-        for t in tombstones.iter() {
-            writeln!(file, "{}", t)?;
+/// Invalidation health of one mount's notifier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifierHealth {
+    /// False from a hard invalidation failure until the notifier is repaired
+    pub healthy: bool,
+    /// Hard failures seen over the mount's lifetime
+    pub failures: u64,
+    pub last_error: Option<String>,
+    /// Times the notifier was re-acquired after a failure
+    pub repairs: u64,
+}
+
+impl Default for NotifierHealth {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            failures: 0,
+            last_error: None,
+            repairs: 0,
         }
-        Ok(())
     }
+}
 
-    pub fn delta_path(&self, rel_path: &str) -> PathBuf {
-        self.files_dir.join(rel_path.trim_start_matches('/'))
+/// The branch tree left in `storage_path` by the previous daemon, if it
+/// did not exit cleanly
+pub fn read_branch_tree(storage_path: &Path) -> Option<BackupManifest> {
+    let data = fs::read(storage_path.join(BRANCH_TREE_FILE)).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(manifest) => Some(manifest),
+        Err(e) => {
+            log::warn!("Ignoring unreadable branch tree: {}", e);
+            None
+        }
     }
+}
 
-    pub fn has_delta(&self, rel_path: &str) -> bool {
-        self.delta_path(rel_path).exists()
+/// A branch waiting to be registered: name, parent, creation time and
+/// creator
+type PendingBranch = (String, String, Option<u64>, Option<String>);
+
+/// Order `pending` so every parent comes before its children, given the
+/// branches already `known`.  Fails if a parent never shows up.
+fn parents_first(
+    mut pending: Vec<PendingBranch>,
+    mut known: HashSet<String>,
+) -> Result<Vec<PendingBranch>> {
+    let mut order = Vec::new();
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|(_, parent, _, _)| known.contains(parent));
+        if ready.is_empty() {
+            return Err(BranchError::Invalid(
+                "backup contains a parent cycle".to_string(),
+            ));
+        }
+        known.extend(ready.iter().map(|(name, _, _, _)| name.clone()));
+        order.extend(ready);
+        pending = waiting;
     }
+    Ok(order)
 }
 
-fn validate_branch_name(name: &str) -> Result<()> {
-    if name.is_empty() {
-        return Err(BranchError::Invalid("branch name cannot be empty".into()));
+/// A commit recorded in `COMMIT_LOG_FILE`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitLogEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub branch: String,
+    /// Branch the commit went into (`main` for the base)
+    pub into: String,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Files copied and deletions applied, sorted
+    pub paths: Vec<String>,
+}
+
+/// Entries of the commit log in `storage_path`, oldest first.  A missing
+/// log is empty; unreadable lines are skipped.
+pub fn read_commit_log(storage_path: &Path) -> Result<Vec<CommitLogEntry>> {
+    let file = match File::open(storage_path.join(COMMIT_LOG_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::warn!("Skipping unreadable commit log entry: {}", e),
+        }
     }
-    if name == "." || name == ".." {
-        return Err(BranchError::Invalid(format!(
-            "'{}' is not a valid branch name",
-            name
-        )));
+    Ok(entries)
+}
+
+/// Seconds since the Unix epoch, clamping times before it to 0
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Output format of a commit preview
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    /// Structured `CommitPreview`
+    #[default]
+    Json,
+    /// Unified diff of every affected text file
+    Patch,
+}
+
+impl std::str::FromStr for PreviewFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "patch" => Ok(Self::Patch),
+            _ => Err(format!(
+                "invalid preview format '{}' (expected json or patch)",
+                s
+            )),
+        }
     }
-    if name.contains('/') || name.contains('\0') {
-        return Err(BranchError::Invalid(
-            "branch name cannot contain '/' or null bytes".into(),
-        ));
+}
+
+/// How a commit writes a file whose target has other hard links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HardlinkMode {
+    /// Rewrite the shared inode in place, so every linked path gets the
+    /// new content
+    #[default]
+    Preserve,
+    /// Replace only the committed path with a new file; the other links
+    /// keep the old content
+    Break,
+}
+
+impl std::str::FromStr for HardlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(Self::Preserve),
+            "break" => Ok(Self::Break),
+            _ => Err(format!(
+                "invalid hardlink mode '{}' (expected preserve or break)",
+                s
+            )),
+        }
     }
-    if name.starts_with('@') {
-        return Err(BranchError::Invalid(
-            "branch name cannot start with '@' (reserved for virtual paths)".into(),
-        ));
+}
+
+/// Modification time a commit gives the files it writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitMtime {
+    /// The time of the commit
+    #[default]
+    Now,
+    /// The branch's copy's mtime, i.e. when the file was last written
+    Branch,
+    /// The mtime of the file being replaced, so tools keyed on mtime see
+    /// no change; new files get the time of the commit
+    Preserve,
+}
+
+impl std::str::FromStr for CommitMtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "now" => Ok(Self::Now),
+            "branch" => Ok(Self::Branch),
+            "preserve" => Ok(Self::Preserve),
+            _ => Err(format!(
+                "invalid commit mtime '{}' (expected now, branch or preserve)",
+                s
+            )),
+        }
     }
-    if name.len() > 255 {
-        return Err(BranchError::Invalid(
-            "branch name cannot exceed 255 characters".into(),
-        ));
+}
+
+/// Whether `path` is a regular file with more than one hard link.
+fn is_hardlinked(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_file() && m.nlink() > 1)
+}
+
+/// Whether anything but directories is below `dir`, stopping at the first
+/// such entry.
+fn has_files(dir: &Path) -> Result<bool> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || has_files(&entry.path())? {
+            return Ok(true);
+        }
     }
-    Ok(())
+    Ok(false)
 }
 
-pub struct BranchManager {
-    pub storage_path: PathBuf,
-    pub base_path: PathBuf,
-    pub workspace_path: PathBuf,
-    branches: RwLock<std::collections::HashMap<String, Branch>>,
-    pub epoch: AtomicU64,
-    /// Notifiers for invalidating kernel cache on commit/abort
-    /// Maps (branch_name, mountpoint) -> Notifier
-    notifiers: Mutex<std::collections::HashMap<(String, PathBuf), Arc<Notifier>>>,
-    /// Track opened file inodes per branch for cache invalidation
-    /// Maps branch_name -> Set of inodes
-    opened_inodes: Mutex<std::collections::HashMap<String, HashSet<u64>>>,
+/// Temporary sibling a commit writes `dest` to before renaming it over.
+fn commit_tmp(dest: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(dest.file_name().unwrap_or_default());
+    name.push(".branchfs-commit");
+    dest.with_file_name(name)
 }
 
-impl BranchManager {
-    pub fn new(storage_path: PathBuf, base_path: PathBuf, workspace_path: PathBuf) -> Result<Self> {
-        fs::create_dir_all(&storage_path)?;
+/// Prefix of the directory in the base a commit to main stages its files
+/// in, followed by `<pid>-<nonce>` so it never names an existing entry
+const COMMIT_STAGING: &str = ".branchfs-commit-";
 
-        // Always start fresh with just the "main" branch
-        let mut branches = std::collections::HashMap::new();
-        let main_branch = Branch::new("main", None, &storage_path)?;
-        branches.insert("main".to_string(), main_branch);
+/// A fresh staging directory name for a commit to main
+fn base_staging_name() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let nonce = nanos ^ NEXT.fetch_add(1, Ordering::Relaxed).rotate_right(17);
+    format!("{}{}-{:x}", COMMIT_STAGING, std::process::id(), nonce)
+}
 
-        Ok(Self {
-            storage_path,
-            base_path,
-            workspace_path,
-            branches: RwLock::new(branches),
-            epoch: AtomicU64::new(0),
-            notifiers: Mutex::new(std::collections::HashMap::new()),
-            opened_inodes: Mutex::new(std::collections::HashMap::new()),
-        })
+/// Whether `rel_path` is a staging directory of a commit to main, which
+/// mounts never show
+pub fn is_commit_staging(rel_path: &str) -> bool {
+    let Some(rest) = rel_path
+        .trim_start_matches('/')
+        .strip_prefix(COMMIT_STAGING)
+    else {
+        return false;
+    };
+    rest.split_once('-').is_some_and(|(pid, nonce)| {
+        !pid.is_empty()
+            && pid.bytes().all(|b| b.is_ascii_digit())
+            && !nonce.is_empty()
+            && nonce.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+/// Journal of the commit being applied, in the storage directory
+pub const COMMIT_JOURNAL_FILE: &str = "commit-journal.json";
+
+/// Staging directory of the commit in progress, in the storage directory.
+/// Written before the directory is created, so recovery only ever
+/// discards a directory a commit made.
+const COMMIT_STAGING_FILE: &str = "commit-staging";
+
+/// Test hook: abort the daemon at `point` of a commit (`staged` or
+/// `applying`) when `BRANCHFS_CRASH_COMMIT` names it
+#[cfg(feature = "testing")]
+fn crash_point(point: &str) {
+    if std::env::var("BRANCHFS_CRASH_COMMIT").is_ok_and(|p| p == point) {
+        log::warn!("BRANCHFS_CRASH_COMMIT: aborting the commit {}", point);
+        std::process::abort();
     }
+}
 
-    pub fn create_branch(&self, name: &str, parent: &str) -> Result<()> {
-        let start = Instant::now();
-        validate_branch_name(name)?;
+#[cfg(not(feature = "testing"))]
+fn crash_point(_point: &str) {}
 
-        let mut branches = self.branches.write();
+/// Serde for the paths of a `CommitJournal`, written as `storage::name_to_string`
+/// strings so names that are not UTF-8 survive JSON
+mod escaped {
+    use std::path::PathBuf;
 
-        if branches.contains_key(name) {
-            return Err(BranchError::AlreadyExists(name.to_string()));
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::storage;
+
+    pub trait Escape: Sized {
+        type Repr: Serialize + DeserializeOwned;
+        fn escape(&self) -> Self::Repr;
+        fn unescape(repr: Self::Repr) -> Self;
+    }
+
+    impl Escape for PathBuf {
+        type Repr = String;
+        fn escape(&self) -> String {
+            storage::name_to_string(self.as_os_str())
+        }
+        fn unescape(repr: String) -> Self {
+            storage::to_os_path(&repr)
         }
+    }
 
-        if !branches.contains_key(parent) {
-            return Err(BranchError::ParentNotFound(parent.to_string()));
+    /// Branch paths are escaped already
+    impl Escape for String {
+        type Repr = String;
+        fn escape(&self) -> String {
+            self.clone()
+        }
+        fn unescape(repr: String) -> Self {
+            repr
         }
+    }
 
-        let branch = Branch::new(name, Some(parent), &self.storage_path)?;
-        branches.insert(name.to_string(), branch);
+    impl<T: Escape> Escape for Option<T> {
+        type Repr = Option<T::Repr>;
+        fn escape(&self) -> Self::Repr {
+            self.as_ref().map(T::escape)
+        }
+        fn unescape(repr: Self::Repr) -> Self {
+            repr.map(T::unescape)
+        }
+    }
 
-        let elapsed = start.elapsed();
-        log::debug!(
-            "[BENCH] create_branch '{}': {:?} ({} us)",
-            name,
-            elapsed,
-            elapsed.as_micros()
-        );
+    impl<T: Escape> Escape for Vec<T> {
+        type Repr = Vec<T::Repr>;
+        fn escape(&self) -> Self::Repr {
+            self.iter().map(T::escape).collect()
+        }
+        fn unescape(repr: Self::Repr) -> Self {
+            repr.into_iter().map(T::unescape).collect()
+        }
+    }
 
-        Ok(())
+    impl<A: Escape, B: Escape> Escape for (A, B) {
+        type Repr = (A::Repr, B::Repr);
+        fn escape(&self) -> Self::Repr {
+            (self.0.escape(), self.1.escape())
+        }
+        fn unescape(repr: Self::Repr) -> Self {
+            (A::unescape(repr.0), B::unescape(repr.1))
+        }
     }
 
-    pub fn get_branch(&self, _name: &str) -> Option<std::sync::Arc<Branch>> {
-        // Note: This is a simplified version. In production, use Arc properly.
-        None
+    impl<A: Escape, B: Escape, C: Escape> Escape for (A, B, C) {
+        type Repr = (A::Repr, B::Repr, C::Repr);
+        fn escape(&self) -> Self::Repr {
+            (self.0.escape(), self.1.escape(), self.2.escape())
+        }
+        fn unescape(repr: Self::Repr) -> Self {
+            (
+                A::unescape(repr.0),
+                B::unescape(repr.1),
+                C::unescape(repr.2),
+            )
+        }
     }
 
-    pub fn with_branch<F, R>(&self, name: &str, f: F) -> Result<R>
-    where
-        F: FnOnce(&Branch) -> Result<R>,
-    {
-        let branches = self.branches.read();
-        let branch = branches
-            .get(name)
-            .ok_or_else(|| BranchError::NotFound(name.to_string()))?;
-        f(branch)
+    pub fn serialize<T: Escape, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        value.escape().serialize(s)
     }
 
-    pub fn get_epoch(&self) -> u64 {
-        self.epoch.load(Ordering::SeqCst)
+    pub fn deserialize<'de, T: Escape, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+        T::Repr::deserialize(d).map(T::unescape)
     }
+}
 
-    pub fn is_branch_valid(&self, name: &str) -> bool {
-        self.branches.read().contains_key(name)
+/// Modification time the committed copy of `src` at `dest` keeps, or
+/// `None` to leave it at the time of the copy.
+fn committed_mtime(src: &Path, dest: &Path, mtime: CommitMtime) -> Option<SystemTime> {
+    let regular = |path: &Path| fs::symlink_metadata(path).ok().filter(|m| m.is_file());
+    match mtime {
+        CommitMtime::Now => None,
+        CommitMtime::Branch => regular(src),
+        CommitMtime::Preserve => regular(dest),
     }
+    .and_then(|meta| meta.modified().ok())
+}
 
-    pub fn list_branches(&self) -> Vec<(String, Option<String>)> {
-        self.branches
-            .read()
-            .iter()
-            .map(|(name, branch)| (name.clone(), branch.parent.clone()))
-            .collect()
+fn set_mtime(path: &Path, time: SystemTime) -> std::io::Result<()> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    nix::sys::stat::utimensat(
+        None,
+        path,
+        &nix::sys::time::TimeSpec::new(0, libc::UTIME_OMIT),
+        &nix::sys::time::TimeSpec::from_duration(since_epoch),
+        nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+/// The files of a commit, copied into a staging directory on the target's
+/// filesystem and synced before anything in the target changes, then
+/// renamed into place.
+///
+/// Crash consistency: until `apply`, the target is untouched, and an
+/// interrupted staging only leaves the staging directory behind (removed
+/// by the next daemon).  During `apply` every file is replaced whole by a
+/// rename, so a crash leaves each path with either its old or its new
+/// content, never a mix, and the next daemon rolls the commit forward from
+/// its journal (see `CommitJournal`).  The exception is
+/// a hard-linked target under `HardlinkMode::Preserve`, which is rewritten
+/// in place so its other links see the change.
+#[derive(Serialize, Deserialize)]
+struct StagedCommit {
+    /// Base directory or parent branch delta the commit writes to
+    #[serde(with = "escaped")]
+    target: PathBuf,
+    #[serde(with = "escaped")]
+    staging: PathBuf,
+    hardlinks: HardlinkMode,
+    mtime: CommitMtime,
+    /// (path, staged copy, delta file); no staged copy for a file written
+    /// in place
+    #[serde(with = "escaped")]
+    files: Vec<(String, Option<PathBuf>, PathBuf)>,
+    /// (dev, ino) of a hard-linked delta file → its staged copy, so every
+    /// name of it is committed as a link to one copy
+    #[serde(skip)]
+    linked: std::collections::HashMap<(u64, u64), PathBuf>,
+    /// Directories whose entries changed, synced by `apply`
+    #[serde(skip)]
+    dirs: std::collections::BTreeSet<PathBuf>,
+    /// Target inodes `branchfs gc` shared with other branches: replaced
+    /// like unlinked files, never rewritten in place
+    #[serde(skip)]
+    shared: HashSet<(u64, u64)>,
+}
+
+impl StagedCommit {
+    /// Start a commit into `target`, staging in `staging`, which must be
+    /// on the same filesystem and not exist yet.
+    fn new(
+        target: &Path,
+        staging: PathBuf,
+        hardlinks: HardlinkMode,
+        mtime: CommitMtime,
+    ) -> Result<Self> {
+        fs::create_dir(&staging)?;
+        Ok(Self {
+            target: target.to_path_buf(),
+            staging,
+            hardlinks,
+            mtime,
+            files: Vec::new(),
+            linked: std::collections::HashMap::new(),
+            dirs: std::collections::BTreeSet::new(),
+            shared: HashSet::new(),
+        })
     }
 
-    /// Register a notifier for a mounted branch
-    pub fn register_notifier(
-        &self,
-        branch_name: &str,
-        mountpoint: PathBuf,
-        notifier: Arc<Notifier>,
-    ) {
-        self.notifiers
-            .lock()
-            .insert((branch_name.to_string(), mountpoint), notifier);
+    /// Commit into `target`'s delta, whose inodes shared by `branchfs gc`
+    /// must not be written through.
+    fn sharing(mut self, target: &Branch) -> Self {
+        self.shared = target.shared.clone();
+        self
     }
 
-    /// Unregister a notifier when unmounting
-    pub fn unregister_notifier(&self, branch_name: &str, mountpoint: &Path) {
-        self.notifiers
-            .lock()
-            .remove(&(branch_name.to_string(), mountpoint.to_path_buf()));
+    /// Copy the delta file `src` for `rel_path` into the staging directory
+    /// and sync it.
+    fn stage(&mut self, rel_path: &str, src: &Path) -> Result<()> {
+        let dest = storage::join(&self.target, rel_path);
+        // Links are recreated, and a link being replaced must not be
+        // written through
+        let neither_link = !storage::is_symlink(src) && !storage::is_symlink(&dest);
+        let shared =
+            fs::symlink_metadata(&dest).is_ok_and(|m| self.shared.contains(&(m.dev(), m.ino())));
+        if neither_link && is_hardlinked(&dest) && !shared {
+            match self.hardlinks {
+                HardlinkMode::Preserve => {
+                    self.files
+                        .push((rel_path.to_string(), None, src.to_path_buf()));
+                    return Ok(());
+                }
+                HardlinkMode::Break => {
+                    log::warn!("commit replaces {:?}, breaking its hard links", dest)
+                }
+            }
+        }
+
+        let staged = self.staging.join(self.files.len().to_string());
+        let meta = fs::symlink_metadata(src)?;
+        let key = (meta.is_file() && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()));
+        match key.and_then(|key| self.linked.get(&key)) {
+            Some(first) => fs::hard_link(first, &staged)?,
+            None => {
+                storage::copy_file(src, &staged)?;
+                if meta.is_file() {
+                    if let Some(time) = committed_mtime(src, &dest, self.mtime) {
+                        set_mtime(&staged, time)?;
+                    }
+                    File::open(&staged)?.sync_all()?;
+                }
+                if let Some(key) = key {
+                    self.linked.insert(key, staged.clone());
+                }
+            }
+        }
+        self.files
+            .push((rel_path.to_string(), Some(staged), src.to_path_buf()));
+        Ok(())
+    }
+
+    /// `stage` every (path, delta file) of `files`, discarding the staging
+    /// directory if one fails.
+    fn stage_all(mut self, files: &[(String, PathBuf)]) -> Result<Self> {
+        for (rel_path, src) in files {
+            if let Err(e) = self.stage(rel_path, src) {
+                self.discard();
+                return Err(e);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Remove `rel_path` from the target, whatever it is.
+    fn delete(&mut self, rel_path: &str) -> Result<()> {
+        let path = storage::join(&self.target, rel_path);
+        if storage::is_dir(&path) {
+            fs::remove_dir_all(&path)?;
+        } else if storage::exists(&path) {
+            fs::remove_file(&path)?;
+        } else {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.dirs.insert(parent.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Create the directories above `rel_path` missing from the target,
+    /// like the ones the branch has there, replacing whatever non-directory
+    /// is in the way (the branch replaced it with a directory).
+    fn make_parents(&mut self, rel_path: &str, src: &Path) -> Result<()> {
+        let depth = rel_path.trim_matches('/').matches('/').count();
+        let mut src_dir = src.to_path_buf();
+        let mut missing = Vec::new();
+        let mut dir = storage::join(&self.target, rel_path);
+        for _ in 0..depth {
+            dir.pop();
+            src_dir.pop();
+            if storage::is_dir(&dir) {
+                break;
+            }
+            missing.push((dir.clone(), src_dir.clone()));
+        }
+        for (dir, src_dir) in missing.into_iter().rev() {
+            if storage::exists(&dir) {
+                fs::remove_file(&dir)?;
+            }
+            if storage::is_dir(&src_dir) {
+                storage::create_dir_like(&src_dir, &dir)?;
+            } else {
+                fs::create_dir(&dir)?;
+            }
+            if let Some(parent) = dir.parent() {
+                self.dirs.insert(parent.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `deletions` from the target, then move every staged file
+    /// into place and sync the directories changed, removing the staging
+    /// directory.
+    fn apply<'a>(&mut self, deletions: impl IntoIterator<Item = &'a String>) -> Result<()> {
+        for rel_path in deletions {
+            self.delete(rel_path)?;
+        }
+        for (rel_path, staged, src) in std::mem::take(&mut self.files) {
+            let dest = storage::join(&self.target, &rel_path);
+            self.make_parents(&rel_path, &src)?;
+            let Some(staged) = staged else {
+                if !storage::exists(&src) {
+                    continue;
+                }
+                log::warn!(
+                    "commit rewrites {:?} in place; its other hard links change too",
+                    dest
+                );
+                let keep = committed_mtime(&src, &dest, self.mtime);
+                fs::copy(&src, &dest)?;
+                if let Some(time) = keep {
+                    set_mtime(&dest, time)?;
+                }
+                continue;
+            };
+            // Already moved by a run the daemon did not live through
+            if !storage::exists(&staged) {
+                continue;
+            }
+            if storage::is_dir(&dest) {
+                fs::remove_dir_all(&dest)?;
+            }
+            match fs::rename(&staged, &dest) {
+                // Another filesystem mounted inside the target
+                Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    let tmp = commit_tmp(&dest);
+                    storage::copy_file(&staged, &tmp)?;
+                    if let Ok(time) = fs::symlink_metadata(&staged).and_then(|m| m.modified()) {
+                        let _ = set_mtime(&tmp, time);
+                    }
+                    fs::rename(&tmp, &dest)?;
+                }
+                result => result?,
+            }
+            if let Some(parent) = dest.parent() {
+                self.dirs.insert(parent.to_path_buf());
+            }
+            crash_point("applying");
+        }
+        for dir in &self.dirs {
+            match File::open(dir) {
+                Ok(dir) => dir.sync_all()?,
+                // Removed by a later deletion
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.staging.exists() {
+            fs::remove_dir_all(&self.staging)?;
+        }
+        Ok(())
+    }
+
+    /// Drop what was staged, leaving the target as it was.
+    fn discard(&self) {
+        let _ = fs::remove_dir_all(&self.staging);
+    }
+
+    /// Whether `apply` has moved anything yet, and so is past the deletions
+    fn started(&self) -> bool {
+        self.files
+            .iter()
+            .any(|(_, staged, _)| staged.as_ref().is_some_and(|s| !storage::exists(s)))
+    }
+}
+
+/// A staged commit and what finishing it changes in the storage, recorded
+/// in `COMMIT_JOURNAL_FILE` once staging is complete and removed once the
+/// commit is done.  A daemon that finds it on startup died while applying
+/// the commit, and rolls it forward (see `recover_commit`).
+#[derive(Serialize, Deserialize)]
+struct CommitJournal {
+    branch: String,
+    into: String,
+    commit: StagedCommit,
+    /// Paths deleted from the target before the files are moved in
+    deletions: Vec<String>,
+    /// Tombstones files rewritten afterwards, with their new content
+    #[serde(with = "escaped")]
+    tombstones: Vec<(PathBuf, Vec<String>)>,
+    /// Delta files taken out of the branch (a commit of selected paths)
+    #[serde(with = "escaped")]
+    merged: Vec<PathBuf>,
+    /// Whether the branch goes (a full commit)
+    remove_branch: bool,
+}
+
+impl CommitJournal {
+    fn write(&self, storage_path: &Path) -> Result<()> {
+        let path = storage_path.join(COMMIT_JOURNAL_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(storage_path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Mark the commit done
+    fn remove(storage_path: &Path) {
+        let _ = fs::remove_file(storage_path.join(COMMIT_JOURNAL_FILE));
+        let _ = fs::remove_file(storage_path.join(COMMIT_STAGING_FILE));
+    }
+
+    /// Finish the storage side of a commit interrupted after its files
+    /// were in place: the tombstones, the merged delta files, the branch.
+    fn finish_storage(&self, storage_path: &Path) -> Result<()> {
+        for (file, tombstones) in &self.tombstones {
+            let mut out = File::create(file)?;
+            for t in tombstones {
+                writeln!(out, "{}", t)?;
+            }
+        }
+        let branch_dir = storage_path.join("branches").join(&self.branch);
+        let files_dir = branch_dir.join("files");
+        for src_path in &self.merged {
+            let _ = fs::remove_file(src_path);
+            let mut dir = src_path.parent();
+            while let Some(d) = dir.filter(|d| *d != files_dir) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+        if self.remove_branch && branch_dir.exists() {
+            fs::remove_dir_all(&branch_dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Deal with a commit the previous daemon did not finish, before its
+/// branches are recovered.  One whose files were all staged is rolled
+/// forward, so the target ends up fully committed; one that died while
+/// staging is rolled back by dropping the staged copies, leaving the
+/// target untouched.
+pub fn recover_commit(storage_path: &Path) {
+    let journal_path = storage_path.join(COMMIT_JOURNAL_FILE);
+    let journal = fs::read(&journal_path).ok().and_then(|data| {
+        match serde_json::from_slice::<CommitJournal>(&data) {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                log::warn!("Ignoring unreadable commit journal: {}", e);
+                None
+            }
+        }
+    });
+    if let Some(mut journal) = journal {
+        // Deleting again after files were moved in could remove them
+        let deletions = if journal.commit.started() {
+            Vec::new()
+        } else {
+            journal.deletions.clone()
+        };
+        let result = journal
+            .commit
+            .apply(&deletions)
+            .and_then(|()| journal.finish_storage(storage_path));
+        match result {
+            Ok(()) => {
+                log::info!(
+                    "Finished the commit of '{}' into '{}' the previous daemon was applying",
+                    journal.branch,
+                    journal.into
+                );
+                CommitJournal::remove(storage_path);
+            }
+            Err(e) => log::warn!(
+                "Failed to finish the interrupted commit of '{}' into '{}': {}",
+                journal.branch,
+                journal.into,
+                e
+            ),
+        }
+    }
+
+    // Copies staged by a commit that died before its journal was written.
+    // Only the directory the commit named is discarded: anything else in
+    // the base is the user's, and a journal left behind still needs its
+    // staged copies
+    if journal_path.exists() {
+        return;
+    }
+    let marker = storage_path.join(COMMIT_STAGING_FILE);
+    let Ok(named) = fs::read(&marker) else {
+        return;
+    };
+    let dir = PathBuf::from(std::ffi::OsStr::from_bytes(&named));
+    if dir.is_dir() {
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => log::info!("Discarded the interrupted commit staged in {:?}", dir),
+            Err(e) => {
+                log::warn!("Failed to remove commit staging {:?}: {}", dir, e);
+                return;
+            }
+        }
+    }
+    let _ = fs::remove_file(&marker);
+}
+
+/// A file a commit would write into its target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewFile {
+    pub path: String,
+    /// BLAKE3 hash of the content being replaced (absent for new files)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    /// BLAKE3 hash of the content written
+    pub after: String,
+}
+
+/// Every change committing `branch` would make to `target`: the base for
+/// a direct child of main, otherwise the parent branch as seen through it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitPreview {
+    pub branch: String,
+    pub target: String,
+    pub overwrite: Vec<PreviewFile>,
+    pub create: Vec<PreviewFile>,
+    /// Files and directories removed, including everything below a
+    /// deleted directory
+    pub delete: Vec<String>,
+    /// Overwritten files whose target has other hard links (see
+    /// `HardlinkMode`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hardlinked: Vec<String>,
+}
+
+/// Unified diff of one file between two versions (`None` = absent), in
+/// `git diff` style; binary content is reported as a single line.
+fn unified_diff(rel_path: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> String {
+    let old_label = match old {
+        Some(_) => format!("a{}", rel_path),
+        None => "/dev/null".to_string(),
+    };
+    let new_label = match new {
+        Some(_) => format!("b{}", rel_path),
+        None => "/dev/null".to_string(),
+    };
+
+    let as_text = |data: Option<&[u8]>| -> Option<String> {
+        match data {
+            Some(bytes) if bytes.contains(&0) => None,
+            Some(bytes) => String::from_utf8(bytes.to_vec()).ok(),
+            None => Some(String::new()),
+        }
+    };
+    match (as_text(old), as_text(new)) {
+        (Some(old_text), Some(new_text)) => similar::TextDiff::from_lines(&old_text, &new_text)
+            .unified_diff()
+            .header(&old_label, &new_label)
+            .to_string(),
+        _ => format!("Binary files {} and {} differ\n", old_label, new_label),
+    }
+}
+
+/// A tombstone in effect for a branch's view
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deletion {
+    pub path: String,
+    /// Branch in the chain that recorded the tombstone
+    pub branch: String,
+    /// Whether the tombstone still hides an entry of the parent chain or
+    /// base; false once that entry is gone anyway
+    pub shadows: bool,
+}
+
+/// Metadata of a single branch (`Request::Info`, and the reply to
+/// `Request::Create`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BranchDetails {
+    pub name: String,
+    pub parent: Option<String>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    /// Who created the branch (`branchfs create --created-by`), if known
+    pub created_by: Option<String>,
+    /// Directory holding the branch's delta files and tombstones
+    pub storage: PathBuf,
+    /// Directories a path is looked up in, first match wins: the delta of
+    /// the branch and of each ancestor up to main, then the base
+    pub resolution_order: Vec<PathBuf>,
+}
+
+/// Where a branch's view of a path lives on disk (`Request::Where`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Location {
+    /// The branch's own delta file for the path, whether or not it exists
+    pub delta: PathBuf,
+    pub delta_exists: bool,
+    /// Whether a tombstone the branch itself recorded, for the path or a
+    /// directory above it, hides the path
+    pub tombstoned: bool,
+    /// File reads are served from: the branch's delta, an ancestor's delta
+    /// or the base; `None` if the path does not exist in the branch
+    pub resolved: Option<PathBuf>,
+    /// Whether `resolved` is a redirected delta (`--write-mode redirect`)
+    pub redirected: bool,
+}
+
+/// Outcome of a permission check against a branch's view of a path
+/// (`Request::CheckAccess`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessCheck {
+    pub allowed: bool,
+    /// Permission bits of the resolved file
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// An entry of a branch's own delta (`Request::ListDeltas`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaEntry {
+    pub path: String,
+    /// Size of the delta file; absent for tombstones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Whether the branch deleted the path rather than wrote it
+    pub tombstone: bool,
+}
+
+/// How a branch changed a path relative to the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl std::fmt::Display for DiffStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DiffStatus::Added => "added",
+            DiffStatus::Modified => "modified",
+            DiffStatus::Deleted => "deleted",
+        })
+    }
+}
+
+/// A path a branch changed (`Request::Diff`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffEntry {
+    pub path: String,
+    pub status: DiffStatus,
+}
+
+/// Outcome of `BranchManager::merge` (`Request::Merge`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MergeReport {
+    /// Files copied and deletions applied to the target branch
+    pub merged: Vec<String>,
+    /// Paths both branches changed; the target branch kept its version
+    pub conflicts: Vec<String>,
+}
+
+/// Disagreement between the daemon's branch state and what is on disk,
+/// found by `BranchManager::verify` (`Request::Validate`)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct StorageIssue {
+    /// Branch the issue is about (a directory name for stray directories)
+    pub branch: String,
+    /// Short machine-readable kind, e.g. `missing_files_dir`
+    pub kind: String,
+    pub detail: String,
+}
+
+/// Advisory commit lock taken by an orchestrator through
+/// `Request::AcquireCommitLock`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitLock {
+    pub holder: String,
+    /// Unix seconds
+    pub acquired_at: u64,
+    /// Unix seconds after which the lock lapses; held until released if
+    /// absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl CommitLock {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+}
+
+/// Delta file created by a redirected write (`--write-mode redirect`).
+///
+/// The file starts out sparse at the inherited file's size.  Only the
+/// `written` ranges hold the branch's data; the rest of the first
+/// `source_len` bytes is still read from the file the branch inherits.
+struct Redirect {
+    branch: String,
+    rel_path: String,
+    source_len: u64,
+    /// Sorted, non-overlapping `[start, end)` ranges below `source_len`
+    written: Vec<(u64, u64)>,
+    /// The branch's `REDIRECTS_FILE`; `None` for a transaction's delta,
+    /// which does not outlive the daemon anyway
+    log: Option<PathBuf>,
+}
+
+/// Log of a branch's redirected deltas, one JSON `RedirectLogEntry` per
+/// line next to its `files`, so a daemon started after a crash still reads
+/// their unwritten ranges from the inherited file
+const REDIRECTS_FILE: &str = "redirects";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum RedirectLogEntry {
+    New { path: String, source_len: u64 },
+    Write { path: String, start: u64, end: u64 },
+    Truncate { path: String, size: u64 },
+    Drop { path: String },
+}
+
+impl Redirect {
+    /// Append `entry` to the redirect's log, if it has one, fsyncing it if
+    /// `sync`.
+    fn log(&self, entry: &RedirectLogEntry, sync: bool) -> Result<()> {
+        let Some(path) = &self.log else {
+            return Ok(());
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn mark_written(&mut self, start: u64, end: u64) {
+        let (mut start, mut end) = (start, end.min(self.source_len));
+        if start >= end {
+            return;
+        }
+        let mut written = Vec::with_capacity(self.written.len() + 1);
+        for &(s, e) in &self.written {
+            if e < start || s > end {
+                written.push((s, e));
+            } else {
+                start = start.min(s);
+                end = end.max(e);
+            }
+        }
+        written.push((start, end));
+        written.sort_unstable();
+        self.written = written;
+    }
+
+    /// Ranges of `[start, end)` that still come from the inherited file.
+    fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let end = end.min(self.source_len);
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for &(s, e) in &self.written {
+            if pos >= end {
+                break;
+            }
+            if s > pos {
+                gaps.push((pos, s.min(end)));
+            }
+            pos = pos.max(e);
+        }
+        if pos < end {
+            gaps.push((pos, end));
+        }
+        gaps
+    }
+
+    /// Bytes past `size` were cut off, so they no longer come from the
+    /// inherited file even if the file grows again.
+    fn truncate(&mut self, size: u64) {
+        self.source_len = self.source_len.min(size);
+        let source_len = self.source_len;
+        self.written.retain_mut(|(s, e)| {
+            *e = (*e).min(source_len);
+            *s < *e
+        });
+    }
+}
+
+/// Read up to `size` bytes of `path` at `offset`; short at end of file.
+fn read_at(path: &Path, offset: u64, size: usize) -> Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::with_capacity(size);
+    file.take(size as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Changes made to a branch inside `txn:begin` … `txn:commit`, staged in
+/// the branch's `txn` directory and a tombstone set of their own.  The
+/// branch's own view reads them first; commits and child branches see
+/// them only once published.
+struct Txn {
+    /// `txn:begin`s not yet matched by a `txn:commit`; only the outermost
+    /// commit publishes
+    depth: usize,
+    deleted: HashSet<String>,
+    /// Tombstones of the branch that paths recreated in the transaction lift
+    restored: HashSet<String>,
+}
+
+/// Staging directory of a transaction, beside the branch's `files`
+const TXN_DIR: &str = "txn";
+
+pub struct Branch {
+    pub name: String,
+    pub parent: Option<String>,
+    pub files_dir: PathBuf,
+    pub tombstones_file: PathBuf,
+    pub created_at: SystemTime,
+    pub created_by: Option<String>,
+    /// Activity since creation or since a child was last committed into it
+    pub counters: BranchCounters,
+    tombstones: RwLock<HashSet<String>>,
+    /// Tombstones appended but not yet fsynced (deferred durability)
+    tombstones_dirty: AtomicBool,
+    txn: RwLock<Option<Txn>>,
+    /// Delta inodes `branchfs gc` hard-linked to another branch's copy
+    shared: HashSet<(u64, u64)>,
+}
+
+impl Branch {
+    pub fn new(name: &str, parent: Option<&str>, storage_path: &Path) -> Result<Self> {
+        let branch_dir = storage_path.join("branches").join(name);
+        let files_dir = branch_dir.join("files");
+        let tombstones_file = branch_dir.join("tombstones");
+
+        fs::create_dir_all(&files_dir)?;
+        if !tombstones_file.exists() {
+            File::create(&tombstones_file)?;
+        }
+        // Transactions do not outlive the daemon
+        let txn_dir = branch_dir.join(TXN_DIR);
+        if txn_dir.exists() {
+            log::warn!("Discarding unfinished transaction of branch '{}'", name);
+            fs::remove_dir_all(&txn_dir)?;
+        }
+
+        let tombstones = Self::load_tombstones(&tombstones_file)?;
+        let shared = dedup::load_shared(&branch_dir.join(dedup::SHARED_FILE));
+
+        Ok(Self {
+            name: name.to_string(),
+            parent: parent.map(|s| s.to_string()),
+            files_dir,
+            tombstones_file,
+            created_at: SystemTime::now(),
+            created_by: None,
+            counters: BranchCounters::default(),
+            tombstones: RwLock::new(tombstones),
+            tombstones_dirty: AtomicBool::new(false),
+            txn: RwLock::new(None),
+            shared,
+        })
+    }
+
+    fn load_tombstones(path: &Path) -> Result<HashSet<String>> {
+        let mut set = HashSet::new();
+        if path.exists() {
+            let file = File::open(path)?;
+            for line in BufReader::new(file).lines() {
+                set.insert(line?);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Whether `path` or a directory above it is tombstoned: removing a
+    /// directory hides everything below it.
+    pub fn is_deleted(&self, path: &str) -> bool {
+        let tombstones = self.tombstones.read();
+        if tombstones.is_empty() {
+            return false;
+        }
+        let mut current = path;
+        loop {
+            if tombstones.contains(current) {
+                return true;
+            }
+            match current.rfind('/') {
+                Some(0) | None => return false,
+                Some(i) => current = &current[..i],
+            }
+        }
+    }
+
+    pub fn add_tombstone(&self, path: &str) -> Result<()> {
+        let mut tombstones = self.tombstones.write();
+        if tombstones.insert(path.to_string()) {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(&self.tombstones_file)?;
+            writeln!(file, "{}", path)?;
+        }
+        Ok(())
+    }
+
+    /// Add a tombstone and flush it according to `durable`.
+    pub fn add_tombstone_with(&self, path: &str, durable: Durability) -> Result<()> {
+        self.add_tombstone(path)?;
+        match durable {
+            Durability::Off => {}
+            Durability::Sync => self.sync_tombstones()?,
+            Durability::Deferred => self.tombstones_dirty.store(true, Ordering::SeqCst),
+        }
+        Ok(())
+    }
+
+    /// fsync the tombstones file.
+    pub fn sync_tombstones(&self) -> Result<()> {
+        self.tombstones_dirty.store(false, Ordering::SeqCst);
+        File::open(&self.tombstones_file)?.sync_data()?;
+        Ok(())
+    }
+
+    pub fn remove_tombstone(&self, path: &str) -> Result<()> {
+        self.remove_tombstones(std::iter::once(path))
+    }
+
+    /// Drop the tombstones of `paths`, rewriting the tombstones file if any
+    /// was there so they stay gone after a restart.
+    pub fn remove_tombstones<'a>(&self, paths: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        let mut tombstones = self.tombstones.write();
+        let mut removed = false;
+        for path in paths {
+            removed |= tombstones.remove(path);
+        }
+        if removed {
+            Self::write_tombstones(&self.tombstones_file, &tombstones)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_tombstones(&self) -> HashSet<String> {
+        self.tombstones.read().clone()
+    }
+
+    /// Replace the in-memory tombstone set and rewrite the tombstones file.
+    pub fn set_tombstones(&self, new_tombstones: HashSet<String>) -> Result<()> {
+        let mut tombstones = self.tombstones.write();
+        *tombstones = new_tombstones;
+        Self::write_tombstones(&self.tombstones_file, &tombstones)
+    }
+
+    /// Replace the tombstones file with `tombstones`, so a crash leaves
+    /// either the old or the new set.
+    fn write_tombstones(path: &Path, tombstones: &HashSet<String>) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for t in tombstones {
+            writeln!(file, "{}", t)?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn delta_path(&self, rel_path: &str) -> PathBuf {
+        storage::join(&self.files_dir, rel_path)
+    }
+
+    pub fn has_delta(&self, rel_path: &str) -> bool {
+        storage::exists(&self.delta_path(rel_path))
+    }
+
+    /// Whether the delta file at `path` is one `branchfs gc` hard-linked
+    /// to another branch's copy, which a change in place would reach too
+    pub fn is_shared(&self, path: &Path) -> bool {
+        !self.shared.is_empty()
+            && fs::symlink_metadata(path).is_ok_and(|m| {
+                m.is_file() && m.nlink() > 1 && self.shared.contains(&(m.dev(), m.ino()))
+            })
+    }
+
+    fn txn_dir(&self) -> PathBuf {
+        self.files_dir.with_file_name(TXN_DIR)
+    }
+
+    pub fn in_txn(&self) -> bool {
+        self.txn.read().is_some()
+    }
+
+    /// Where a write to `rel_path` goes: the open transaction's staging
+    /// directory, or the branch's delta.
+    pub fn write_path(&self, rel_path: &str) -> PathBuf {
+        if self.in_txn() {
+            storage::join(&self.txn_dir(), rel_path)
+        } else {
+            self.delta_path(rel_path)
+        }
+    }
+
+    /// What the open transaction makes of `rel_path`: `Some(Some(path))`
+    /// for a staged file, `Some(None)` if it deleted the path or a
+    /// directory above it, `None` if it leaves the path alone.
+    fn staged(&self, rel_path: &str) -> Option<Option<PathBuf>> {
+        let txn = self.txn.read();
+        let txn = txn.as_ref()?;
+        let path = storage::join(&self.txn_dir(), rel_path);
+        if storage::exists(&path) {
+            return Some(Some(path));
+        }
+        let mut current = rel_path;
+        loop {
+            if txn.deleted.contains(current) {
+                return Some(None);
+            }
+            match current.rfind('/') {
+                Some(0) | None => return None,
+                Some(i) => current = &current[..i],
+            }
+        }
+    }
+
+    /// Tombstone `path`, in the open transaction if there is one.
+    pub fn tombstone(&self, path: &str, durable: Durability) -> Result<()> {
+        if let Some(txn) = self.txn.write().as_mut() {
+            txn.deleted.insert(path.to_string());
+            return Ok(());
+        }
+        self.add_tombstone_with(path, durable)
+    }
+
+    /// Drop the tombstone of a recreated `path`, in the open transaction
+    /// if there is one.
+    pub fn untombstone(&self, path: &str) -> Result<()> {
+        if let Some(txn) = self.txn.write().as_mut() {
+            txn.deleted.remove(path);
+            txn.restored.insert(path.to_string());
+            return Ok(());
+        }
+        self.remove_tombstone(path)
+    }
+}
+
+/// Move everything in `src` into `dst`, merging with directories `dst`
+/// already has.
+fn merge_into(src: &Path, dst: &Path) -> Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.is_dir() && is_dir => {
+                merge_into(&entry.path(), &target)?;
+                fs::set_permissions(&target, entry.metadata()?.permissions())?;
+                continue;
+            }
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&target)?,
+            Ok(_) if is_dir => fs::remove_file(&target)?,
+            _ => {}
+        }
+        fs::rename(entry.path(), &target)?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is in `paths`, or is an ancestor or descendant of a path
+/// in it.
+fn overlaps(paths: &std::collections::BTreeSet<String>, path: &str) -> bool {
+    let mut ancestor = path;
+    loop {
+        if paths.contains(ancestor) {
+            return true;
+        }
+        match ancestor.rfind('/') {
+            Some(i) if i > 0 => ancestor = &ancestor[..i],
+            _ => break,
+        }
+    }
+    let prefix = format!("{}/", path);
+    paths
+        .range(prefix.clone()..)
+        .next()
+        .is_some_and(|p| p.starts_with(&prefix))
+}
+
+fn validate_branch_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(BranchError::Invalid("branch name cannot be empty".into()));
+    }
+    if name == "." || name == ".." {
+        return Err(BranchError::Invalid(format!(
+            "'{}' is not a valid branch name",
+            name
+        )));
+    }
+    if name.contains('/') || name.contains('\0') {
+        return Err(BranchError::Invalid(
+            "branch name cannot contain '/' or null bytes".into(),
+        ));
+    }
+    if name.starts_with('@') {
+        return Err(BranchError::Invalid(
+            "branch name cannot start with '@' (reserved for virtual paths)".into(),
+        ));
+    }
+    if name.len() > 255 {
+        return Err(BranchError::Invalid(
+            "branch name cannot exceed 255 characters".into(),
+        ));
+    }
+    Ok(())
+}
+
+pub struct BranchManager {
+    pub storage_path: PathBuf,
+    pub base_path: PathBuf,
+    pub workspace_path: PathBuf,
+    branches: RwLock<std::collections::HashMap<String, Branch>>,
+    pub epoch: AtomicU64,
+    /// Notifiers for invalidating kernel cache on commit/abort
+    /// Maps (branch_name, mountpoint) -> Notifier
+    notifiers: Mutex<std::collections::HashMap<(String, PathBuf), Arc<Notifier>>>,
+    /// Health of each mount's notifier, keyed by mountpoint
+    notifier_health: Mutex<std::collections::HashMap<PathBuf, NotifierHealth>>,
+    /// Track open file inodes per branch for cache invalidation.  Opens
+    /// drop the kernel's page cache (no `FOPEN_KEEP_CACHE`) and attributes
+    /// are never cached (zero TTL), so only files open across a change can
+    /// still serve stale data.
+    /// Maps branch_name -> inode -> open handles
+    opened_inodes: Mutex<std::collections::HashMap<String, std::collections::HashMap<u64, usize>>>,
+    /// Content hashes of resolved files, keyed by (dev, ino) and checked
+    /// against the file's ctime and size.  Unlike the mtime, the ctime
+    /// can't be set back from userspace (commits preserve mtimes).
+    hash_cache: Mutex<std::collections::HashMap<(u64, u64), CachedHash>>,
+    /// Delta files still partly served from the file they inherit, keyed
+    /// by delta path.  Always locked after `branches`, never before.
+    redirects: Mutex<std::collections::HashMap<PathBuf, Redirect>>,
+    /// Advisory lock handed out to orchestrators
+    commit_lock: Mutex<Option<CommitLock>>,
+    /// Held for the whole of a commit, so concurrent commits apply one at
+    /// a time
+    commits: Mutex<()>,
+    /// Files open for writing, so a commit does not copy a half-written
+    /// delta.  Maps writer id -> (branch_name, path); releases go by id so
+    /// they still find the entry after the branch is renamed
+    writers: Mutex<std::collections::HashMap<u64, (String, String)>>,
+    next_writer: AtomicU64,
+    /// Messages for the next commit of a branch (`Request::CommitMessage`),
+    /// keyed by branch name
+    commit_messages: Mutex<std::collections::HashMap<String, String>>,
+    /// Commits, aborts, creations and bytes written since the daemon started
+    pub metrics: Metrics,
+}
+
+/// A `BranchManager::hash_cache` entry: ctime (secs, nsecs), size, hash
+type CachedHash = ((i64, i64), u64, String);
+
+/// Entries kept in `BranchManager::hash_cache` before it is cleared
+const HASH_CACHE_LIMIT: usize = 4096;
+
+impl BranchManager {
+    pub fn new(storage_path: PathBuf, base_path: PathBuf, workspace_path: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&storage_path)?;
+
+        // Start with just the "main" branch; a crashed daemon's branches are
+        // added back by `recover_branches`
+        let mut branches = std::collections::HashMap::new();
+        let main_branch = Branch::new("main", None, &storage_path)?;
+        branches.insert("main".to_string(), main_branch);
+
+        let manager = Self {
+            storage_path,
+            base_path,
+            workspace_path,
+            branches: RwLock::new(branches),
+            epoch: AtomicU64::new(0),
+            notifiers: Mutex::new(std::collections::HashMap::new()),
+            notifier_health: Mutex::new(std::collections::HashMap::new()),
+            opened_inodes: Mutex::new(std::collections::HashMap::new()),
+            hash_cache: Mutex::new(std::collections::HashMap::new()),
+            redirects: Mutex::new(std::collections::HashMap::new()),
+            commit_lock: Mutex::new(None),
+            commits: Mutex::new(()),
+            writers: Mutex::new(std::collections::HashMap::new()),
+            next_writer: AtomicU64::new(1),
+            commit_messages: Mutex::new(std::collections::HashMap::new()),
+            metrics: Metrics::default(),
+        };
+        manager.save_branch_tree(&manager.branches.read());
+        Ok(manager)
+    }
+
+    pub fn create_branch(&self, name: &str, parent: &str, created_by: Option<&str>) -> Result<()> {
+        let start = Instant::now();
+        validate_branch_name(name)?;
+
+        let mut branches = self.branches.write();
+
+        if branches.contains_key(name) {
+            return Err(BranchError::AlreadyExists(name.to_string()));
+        }
+
+        if !branches.contains_key(parent) {
+            return Err(BranchError::ParentNotFound(parent.to_string()));
+        }
+
+        let mut branch = Branch::new(name, Some(parent), &self.storage_path)?;
+        branch.created_by = created_by.map(str::to_string);
+        branches.insert(name.to_string(), branch);
+        self.save_branch_tree(&branches);
+        self.metrics
+            .branches_created
+            .fetch_add(1, Ordering::Relaxed);
+
+        let elapsed = start.elapsed();
+        log::debug!(
+            "[BENCH] create_branch '{}': {:?} ({} us)",
+            name,
+            elapsed,
+            elapsed.as_micros()
+        );
+
+        Ok(())
+    }
+
+    pub fn get_branch(&self, _name: &str) -> Option<std::sync::Arc<Branch>> {
+        // Note: This is a simplified version. In production, use Arc properly.
+        None
+    }
+
+    pub fn with_branch<F, R>(&self, name: &str, f: F) -> Result<R>
+    where
+        F: FnOnce(&Branch) -> Result<R>,
+    {
+        let branches = self.branches.read();
+        let branch = branches
+            .get(name)
+            .ok_or_else(|| BranchError::NotFound(name.to_string()))?;
+        f(branch)
+    }
+
+    pub fn get_epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Advance the epoch without committing so every mount goes stale and
+    /// gets invalidated.  Only for tests of the ESTALE/invalidation paths.
+    #[cfg(feature = "testing")]
+    pub fn bump_epoch(&self) -> u64 {
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        self.invalidate_all_mounts();
+        epoch
+    }
+
+    pub fn is_branch_valid(&self, name: &str) -> bool {
+        self.branches.read().contains_key(name)
+    }
+
+    /// Name, parent, creation time and delta size of every branch, or only
+    /// of the children of `parent` and of the branches below
+    /// `descendants_of` when given.  Fails if either names no branch.
+    pub fn branch_info(
+        &self,
+        parent: Option<&str>,
+        descendants_of: Option<&str>,
+    ) -> Result<Vec<BranchInfo>> {
+        let branches = self.branches.read();
+        for name in parent.iter().chain(descendants_of.iter()) {
+            if !branches.contains_key(*name) {
+                return Err(BranchError::NotFound(name.to_string()));
+            }
+        }
+        let is_below = |branch: &Branch, ancestor: &str| {
+            let mut current = branch.parent.as_deref();
+            while let Some(name) = current {
+                if name == ancestor {
+                    return true;
+                }
+                current = branches.get(name).and_then(|b| b.parent.as_deref());
+            }
+            false
+        };
+        Ok(branches
+            .values()
+            .filter(|branch| parent.is_none_or(|p| branch.parent.as_deref() == Some(p)))
+            .filter(|branch| descendants_of.is_none_or(|a| is_below(branch, a)))
+            .map(|branch| {
+                let mut size = 0;
+                let _ = self.walk_files(&branch.files_dir, "", &mut |_, path| {
+                    size += fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                });
+                BranchInfo {
+                    name: branch.name.clone(),
+                    parent: branch.parent.clone(),
+                    created_at: branch.created_at,
+                    created_by: branch.created_by.clone(),
+                    size,
+                }
+            })
+            .collect())
+    }
+
+    /// fsync tombstones left pending by mounts using deferred durability.
+    pub fn flush_deferred_tombstones(&self) {
+        for branch in self.branches.read().values() {
+            if branch.tombstones_dirty.load(Ordering::SeqCst) {
+                if let Err(e) = branch.sync_tombstones() {
+                    log::warn!("Failed to sync tombstones of '{}': {}", branch.name, e);
+                }
+            }
+        }
+    }
+
+    /// fsync a branch's tombstones file, whatever the mount's durability.
+    pub fn sync_tombstones(&self, branch_name: &str) -> Result<()> {
+        self.with_branch(branch_name, |b| b.sync_tombstones())
+    }
+
+    /// Count an event on a branch; unknown branches are ignored.
+    pub fn record_event(&self, branch_name: &str, event: BranchEvent) {
+        if let Some(branch) = self.branches.read().get(branch_name) {
+            branch.counters.record(event);
+        }
+    }
+
+    pub fn branch_stats(&self, name: &str) -> Result<BranchStats> {
+        self.with_branch(name, |b| Ok(b.counters.stats()))
+    }
+
+    /// Creation time of a branch.
+    pub fn branch_created_at(&self, name: &str) -> Result<SystemTime> {
+        self.with_branch(name, |b| Ok(b.created_at))
+    }
+
+    pub fn branch_details(&self, name: &str) -> Result<BranchDetails> {
+        let branches = self.branches.read();
+        let b = branches
+            .get(name)
+            .ok_or_else(|| BranchError::NotFound(name.to_string()))?;
+
+        // Same walk as `resolve_in`
+        let mut resolution_order = Vec::new();
+        let mut current = Some(b);
+        while let Some(branch) = current {
+            resolution_order.push(branch.files_dir.clone());
+            current = branch.parent.as_deref().and_then(|p| branches.get(p));
+        }
+        resolution_order.push(self.base_path.clone());
+
+        Ok(BranchDetails {
+            name: b.name.clone(),
+            parent: b.parent.clone(),
+            created_at: unix_secs(b.created_at),
+            created_by: b.created_by.clone(),
+            storage: self.storage_path.join("branches").join(&b.name),
+            resolution_order,
+        })
+    }
+
+    pub fn list_branches(&self) -> Vec<(String, Option<String>)> {
+        self.branches
+            .read()
+            .iter()
+            .map(|(name, branch)| (name.clone(), branch.parent.clone()))
+            .collect()
+    }
+
+    /// Register a notifier for a mounted branch
+    pub fn register_notifier(
+        &self,
+        branch_name: &str,
+        mountpoint: PathBuf,
+        notifier: Arc<Notifier>,
+    ) {
+        self.notifier_health
+            .lock()
+            .entry(mountpoint.clone())
+            .or_default();
+        self.notifiers
+            .lock()
+            .insert((branch_name.to_string(), mountpoint), notifier);
+    }
+
+    /// Unregister a notifier when unmounting
+    pub fn unregister_notifier(&self, branch_name: &str, mountpoint: &Path) {
+        self.notifier_health.lock().remove(mountpoint);
+        self.notifiers
+            .lock()
+            .remove(&(branch_name.to_string(), mountpoint.to_path_buf()));
+    }
+
+    /// Health of the notifier registered for `mountpoint`
+    pub fn notifier_health(&self, mountpoint: &Path) -> Option<NotifierHealth> {
+        self.notifier_health.lock().get(mountpoint).cloned()
+    }
+
+    /// Mountpoints whose notifier has failed since it was last repaired
+    pub fn unhealthy_notifiers(&self) -> Vec<PathBuf> {
+        self.notifier_health
+            .lock()
+            .iter()
+            .filter(|(_, health)| !health.healthy)
+            .map(|(mountpoint, _)| mountpoint.clone())
+            .collect()
+    }
+
+    /// Replace a failed mount's notifier with a freshly acquired one.
+    pub fn repair_notifier(&self, branch_name: &str, mountpoint: PathBuf, notifier: Arc<Notifier>) {
+        if let Some(health) = self.notifier_health.lock().get_mut(&mountpoint) {
+            health.healthy = true;
+            health.repairs += 1;
+        }
+        self.notifiers
+            .lock()
+            .insert((branch_name.to_string(), mountpoint), notifier);
+    }
+
+    /// Mark a mount's notifier as failed; the daemon re-acquires it later.
+    fn record_notifier_error(&self, mountpoint: &Path, error: String) {
+        log::warn!("Notifier for {:?} failed: {}", mountpoint, error);
+        if let Some(health) = self.notifier_health.lock().get_mut(mountpoint) {
+            health.healthy = false;
+            health.failures += 1;
+            health.last_error = Some(error);
+        }
+    }
+
+    /// Test-only: record a notifier failure as if the kernel had rejected
+    /// an invalidation
+    #[cfg(feature = "testing")]
+    pub fn inject_notifier_failure(&self, mountpoint: &Path) {
+        self.record_notifier_error(mountpoint, "injected failure".to_string());
+    }
+
+    /// Invalidate one inode through a mount's notifier.  `ENOENT` only
+    /// means the kernel has nothing cached; anything else marks the
+    /// notifier unhealthy.
+    fn notify_inval_inode(
+        &self,
+        mountpoint: &Path,
+        notifier: &Notifier,
+        ino: u64,
+    ) -> std::io::Result<()> {
+        let result = notifier.inval_inode(ino, 0, -1);
+        if let Err(e) = &result {
+            if e.raw_os_error() != Some(libc::ENOENT) {
+                self.record_notifier_error(mountpoint, e.to_string());
+            }
+        }
+        result
+    }
+
+    /// Move a mount's notifier from one branch to another.
+    ///
+    /// Both map updates happen under a single lock so a concurrent
+    /// invalidation always finds a notifier for the mount.
+    pub fn swap_notifier(
+        &self,
+        old_branch: &str,
+        new_branch: &str,
+        mountpoint: PathBuf,
+        notifier: Arc<Notifier>,
+    ) {
+        let mut notifiers = self.notifiers.lock();
+        notifiers.remove(&(old_branch.to_string(), mountpoint.clone()));
+        notifiers.insert((new_branch.to_string(), mountpoint), notifier);
+    }
+
+    /// Register an opened file inode for cache invalidation tracking
+    pub fn register_opened_inode(&self, branch_name: &str, ino: u64) {
+        *self
+            .opened_inodes
+            .lock()
+            .entry(branch_name.to_string())
+            .or_default()
+            .entry(ino)
+            .or_default() += 1;
+    }
+
+    /// Drop a handle registered by `register_opened_inode`; the inode stops
+    /// being invalidated once its last handle is released.
+    pub fn release_opened_inode(&self, branch_name: &str, ino: u64) {
+        let mut opened = self.opened_inodes.lock();
+        let Some(inodes) = opened.get_mut(branch_name) else {
+            return;
+        };
+        if let Some(count) = inodes.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                inodes.remove(&ino);
+            }
+        }
+        if inodes.is_empty() {
+            opened.remove(branch_name);
+        }
+    }
+
+    /// Register a handle open for writing `path` of `branch_name`; the
+    /// returned id is passed to `release_writer`.
+    pub fn register_writer(&self, branch_name: &str, path: &str) -> u64 {
+        let id = self.next_writer.fetch_add(1, Ordering::SeqCst);
+        self.writers
+            .lock()
+            .insert(id, (branch_name.to_string(), path.to_string()));
+        id
+    }
+
+    /// Drop a handle registered by `register_writer`
+    pub fn release_writer(&self, id: u64) {
+        self.writers.lock().remove(&id);
+    }
+
+    /// Paths of a branch currently open for writing, sorted
+    pub fn open_writers(&self, branch_name: &str) -> Vec<String> {
+        let mut paths: Vec<String> = self
+            .writers
+            .lock()
+            .values()
+            .filter(|(branch, _)| branch == branch_name)
+            .map(|(_, path)| path.clone())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Number of open file inodes tracked for a branch
+    pub fn opened_inode_count(&self, branch_name: &str) -> usize {
+        self.opened_inodes
+            .lock()
+            .get(branch_name)
+            .map_or(0, |inodes| inodes.len())
+    }
+
+    /// Whether a notifier is registered for the given branch and mountpoint
+    pub fn has_notifier(&self, branch_name: &str, mountpoint: &Path) -> bool {
+        self.notifiers
+            .lock()
+            .contains_key(&(branch_name.to_string(), mountpoint.to_path_buf()))
+    }
+
+    /// Invalidate kernel cache for all mounts
+    fn invalidate_all_mounts(&self) {
+        self.invalidate_epoch_files();
+        self.invalidate_mounts(|_| true);
+    }
+
+    /// Drop cached attributes of every mount's epoch file after the epoch
+    /// moved.  Mounts without `--epoch-file` just report ENOENT.
+    fn invalidate_epoch_files(&self) {
+        for ((_, mountpoint), notifier) in self.notifiers.lock().iter() {
+            if let Err(e) = self.notify_inval_inode(mountpoint, notifier, EPOCH_INO) {
+                log::debug!("Failed to invalidate epoch file at {:?}: {}", mountpoint, e);
+            }
+        }
+    }
+
+    /// Invalidate kernel cache for specific branches
+    pub fn invalidate_branches(&self, branch_names: &[String]) {
+        self.invalidate_mounts(|branch| branch_names.iter().any(|b| b == branch));
+    }
+
+    /// Invalidate the root and the open file inodes of every mount whose
+    /// branch matches `wanted`.  Targets are collected first so the kernel
+    /// is notified without holding the locks opens register under.
+    fn invalidate_mounts(&self, wanted: impl Fn(&str) -> bool) {
+        let start = Instant::now();
+        let targets: Vec<(String, PathBuf, Arc<Notifier>, Vec<u64>)> = {
+            let notifiers = self.notifiers.lock();
+            let opened_inodes = self.opened_inodes.lock();
+            notifiers
+                .iter()
+                .filter(|((branch, _), _)| wanted(branch))
+                .map(|((branch, mountpoint), notifier)| {
+                    let inodes = opened_inodes
+                        .get(branch)
+                        .map(|inodes| inodes.keys().copied().collect())
+                        .unwrap_or_default();
+                    (branch.clone(), mountpoint.clone(), notifier.clone(), inodes)
+                })
+                .collect()
+        };
+
+        let mut invalidated = 0;
+        for (branch, mountpoint, notifier, inodes) in &targets {
+            // Invalidate root inode first (directory cache)
+            if let Err(e) = self.notify_inval_inode(mountpoint, notifier, ROOT_INO) {
+                log::debug!(
+                    "Failed to invalidate root inode for branch '{}' at {:?}: {}",
+                    branch,
+                    mountpoint,
+                    e
+                );
+            }
+
+            for &ino in inodes.iter().filter(|&&ino| ino != ROOT_INO) {
+                match self.notify_inval_inode(mountpoint, notifier, ino) {
+                    Ok(()) => invalidated += 1,
+                    Err(e) => log::debug!(
+                        "Failed to invalidate inode {} for branch '{}': {}",
+                        ino,
+                        branch,
+                        e
+                    ),
+                }
+            }
+
+            log::info!(
+                "Invalidated cache for branch '{}' at {:?}",
+                branch,
+                mountpoint
+            );
+        }
+
+        let elapsed = start.elapsed();
+        log::debug!(
+            "[BENCH] invalidate {} mounts, {} open inodes: {:?} ({} us)",
+            targets.len(),
+            invalidated,
+            elapsed,
+            elapsed.as_micros()
+        );
+    }
+
+    /// Return the names of branches whose parent is `parent_name`.
+    pub fn get_children(&self, parent_name: &str) -> Vec<String> {
+        self.branches
+            .read()
+            .values()
+            .filter(|b| b.parent.as_deref() == Some(parent_name))
+            .map(|b| b.name.clone())
+            .collect()
+    }
+
+    /// Rename a branch: moves its storage directory, re-keys it in the branch
+    /// map and re-parents its children.  Notifiers and opened inodes follow
+    /// the new name; updating mounts is left to the caller.
+    pub fn rename_branch(&self, old: &str, new: &str) -> Result<()> {
+        if old == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+        validate_branch_name(new)?;
+        // Redirects are keyed by delta path, which the rename changes
+        self.materialize_redirects()?;
+
+        let mut branches = self.branches.write();
+        if !branches.contains_key(old) {
+            return Err(BranchError::NotFound(old.to_string()));
+        }
+        if branches.contains_key(new) {
+            return Err(BranchError::AlreadyExists(new.to_string()));
+        }
+
+        let branches_dir = self.storage_path.join("branches");
+        fs::rename(branches_dir.join(old), branches_dir.join(new))?;
+
+        let mut branch = branches
+            .remove(old)
+            .ok_or_else(|| BranchError::NotFound(old.to_string()))?;
+        branch.name = new.to_string();
+        branch.files_dir = branches_dir.join(new).join("files");
+        branch.tombstones_file = branches_dir.join(new).join("tombstones");
+        branches.insert(new.to_string(), branch);
+
+        for child in branches.values_mut() {
+            if child.parent.as_deref() == Some(old) {
+                child.parent = Some(new.to_string());
+            }
+        }
+        self.save_branch_tree(&branches);
+        drop(branches);
+
+        {
+            let mut notifiers = self.notifiers.lock();
+            let keys: Vec<_> = notifiers
+                .keys()
+                .filter(|(branch, _)| branch == old)
+                .cloned()
+                .collect();
+            for key in keys {
+                if let Some(notifier) = notifiers.remove(&key) {
+                    notifiers.insert((new.to_string(), key.1), notifier);
+                }
+            }
+        }
+        {
+            let mut opened = self.opened_inodes.lock();
+            if let Some(inodes) = opened.remove(old) {
+                opened.insert(new.to_string(), inodes);
+            }
+        }
+        for (branch, _) in self.writers.lock().values_mut() {
+            if branch == old {
+                *branch = new.to_string();
+            }
+        }
+        {
+            let mut messages = self.commit_messages.lock();
+            if let Some(message) = messages.remove(old) {
+                messages.insert(new.to_string(), message);
+            }
+        }
+
+        log::info!("Renamed branch '{}' to '{}'", old, new);
+        self.invalidate_branches(&[new.to_string()]);
+        Ok(())
+    }
+
+    /// Return `(name, parent)` for the children of `parent_name`, or for its
+    /// whole subtree when `recursive` is set (parents always come before
+    /// their children).  Fails if `parent_name` does not exist.
+    pub fn child_branches(
+        &self,
+        parent_name: &str,
+        recursive: bool,
+    ) -> Result<Vec<(String, String)>> {
+        let branches = self.branches.read();
+        if !branches.contains_key(parent_name) {
+            return Err(BranchError::NotFound(parent_name.to_string()));
+        }
+
+        let mut result = Vec::new();
+        let mut queue = std::collections::VecDeque::from([parent_name.to_string()]);
+        while let Some(current) = queue.pop_front() {
+            let mut children: Vec<String> = branches
+                .values()
+                .filter(|b| b.parent.as_deref() == Some(current.as_str()))
+                .map(|b| b.name.clone())
+                .collect();
+            children.sort();
+            for child in children {
+                if recursive {
+                    queue.push_back(child.clone());
+                }
+                result.push((child, current.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Branches whose parent no longer exists, as `(name, missing parent)`
+    /// sorted by name.  Paths cannot be resolved through them.
+    pub fn orphans(&self) -> Vec<(String, String)> {
+        Self::orphans_in(&self.branches.read())
+    }
+
+    fn orphans_in(branches: &std::collections::HashMap<String, Branch>) -> Vec<(String, String)> {
+        let mut orphans: Vec<(String, String)> = branches
+            .values()
+            .filter_map(|b| {
+                b.parent
+                    .as_ref()
+                    .filter(|parent| !branches.contains_key(*parent))
+                    .map(|parent| (b.name.clone(), parent.clone()))
+            })
+            .collect();
+        orphans.sort();
+        orphans
+    }
+
+    /// Re-parent every orphan onto `main`.  Returns the orphans repaired,
+    /// as listed by `orphans` before the repair.
+    pub fn repair_orphans(&self) -> Vec<(String, String)> {
+        let mut branches = self.branches.write();
+        let orphans = Self::orphans_in(&branches);
+        for (name, parent) in &orphans {
+            if let Some(branch) = branches.get_mut(name) {
+                branch.parent = Some("main".to_string());
+                log::info!("Re-parented orphan '{}' (was '{}') onto main", name, parent);
+            }
+        }
+        if !orphans.is_empty() {
+            self.save_branch_tree(&branches);
+        }
+        drop(branches);
+
+        let names: Vec<String> = orphans.iter().map(|(name, _)| name.clone()).collect();
+        self.invalidate_branches(&names);
+        orphans
+    }
+
+    /// Compare the live branch state against storage: every branch needs
+    /// its `files` directory and a readable `tombstones` file matching the
+    /// in-memory deletions, a parent that exists, and an entry in
+    /// `BRANCH_TREE_FILE`; every directory under `branches` needs a branch.
+    /// Holds the commit lock so a commit in flight is not reported.
+    pub fn verify(&self) -> Vec<StorageIssue> {
+        let _commit = self.commits.lock();
+        let branches = self.branches.read();
+        let mut issues = Vec::new();
+        let mut issue = |branch: &str, kind: &str, detail: String| {
+            issues.push(StorageIssue {
+                branch: branch.to_string(),
+                kind: kind.to_string(),
+                detail,
+            })
+        };
+
+        for branch in branches.values() {
+            let name = branch.name.as_str();
+            if !branch.files_dir.is_dir() {
+                issue(
+                    name,
+                    "missing_files_dir",
+                    format!("{:?} is not a directory", branch.files_dir),
+                );
+            }
+            if let Some(parent) = branch
+                .parent
+                .as_ref()
+                .filter(|p| !branches.contains_key(*p))
+            {
+                issue(
+                    name,
+                    "missing_parent",
+                    format!("parent '{}' does not exist", parent),
+                );
+            }
+
+            let on_disk = match fs::read(&branch.tombstones_file) {
+                Ok(data) => match String::from_utf8(data) {
+                    Ok(text) => text.lines().map(str::to_string).collect::<HashSet<_>>(),
+                    Err(_) => {
+                        issue(
+                            name,
+                            "unreadable_tombstones",
+                            "tombstones file is not valid UTF-8".into(),
+                        );
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    issue(
+                        name,
+                        "unreadable_tombstones",
+                        format!("{:?}: {}", branch.tombstones_file, e),
+                    );
+                    continue;
+                }
+            };
+            let in_memory = branch.get_tombstones();
+            let mut stale: Vec<&String> = on_disk.difference(&in_memory).collect();
+            stale.sort();
+            for path in stale {
+                issue(
+                    name,
+                    "stale_tombstone",
+                    format!("{} is deleted on disk but not in memory", path),
+                );
+            }
+            let mut unsaved: Vec<&String> = in_memory.difference(&on_disk).collect();
+            unsaved.sort();
+            for path in unsaved {
+                issue(
+                    name,
+                    "unsaved_tombstone",
+                    format!("{} is deleted in memory but not on disk", path),
+                );
+            }
+        }
+
+        let tree_path = self.storage_path.join(BRANCH_TREE_FILE);
+        let tree = fs::read(&tree_path)
+            .map_err(BranchError::from)
+            .and_then(|data| Ok(serde_json::from_slice::<BackupManifest>(&data)?));
+        match tree {
+            Ok(tree) => {
+                let recorded: std::collections::HashMap<&str, &Option<String>> = tree
+                    .branches
+                    .iter()
+                    .map(|e| (e.name.as_str(), &e.parent))
+                    .collect();
+                for branch in branches.values() {
+                    match recorded.get(branch.name.as_str()) {
+                        None => issue(
+                            &branch.name,
+                            "tree_missing",
+                            format!("not recorded in {}", BRANCH_TREE_FILE),
+                        ),
+                        Some(parent) if **parent != branch.parent => issue(
+                            &branch.name,
+                            "tree_parent",
+                            format!(
+                                "{} records parent {:?}, daemon has {:?}",
+                                BRANCH_TREE_FILE, parent, branch.parent
+                            ),
+                        ),
+                        Some(_) => {}
+                    }
+                }
+                for name in recorded.keys().filter(|n| !branches.contains_key(**n)) {
+                    issue(
+                        name,
+                        "tree_extra",
+                        format!("recorded in {} but not a branch", BRANCH_TREE_FILE),
+                    );
+                }
+            }
+            Err(e) => issue("", "tree_unreadable", format!("{:?}: {}", tree_path, e)),
+        }
+
+        if let Ok(entries) = fs::read_dir(self.storage_path.join("branches")) {
+            for entry in entries.flatten() {
+                let dir = entry.file_name().to_string_lossy().to_string();
+                if !branches.contains_key(&dir) {
+                    issue(
+                        &dir,
+                        "stray_dir",
+                        format!("{:?} belongs to no branch", entry.path()),
+                    );
+                }
+            }
+        }
+
+        issues.sort();
+        issues
+    }
+
+    pub fn resolve_path(&self, branch_name: &str, rel_path: &str) -> Result<Option<PathBuf>> {
+        self.resolve_in(&self.branches.read(), branch_name, rel_path)
+    }
+
+    /// Create the directories above `rel_path` missing from `branch_name`'s
+    /// delta (or open transaction), each with the mode and owner of the directory it shadows, so
+    /// copying up a file deep in an inherited tree keeps the tree's
+    /// permissions.  Directories nothing shadows get the default mode.
+    pub fn materialize_parents(&self, branch_name: &str, rel_path: &str) -> Result<()> {
+        let branches = self.branches.read();
+        let branch = branches
+            .get(branch_name)
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+        fs::create_dir_all(branch.write_path("/"))?;
+        let mut end = 0;
+        while let Some(i) = rel_path[end + 1..].find('/') {
+            end += 1 + i;
+            let dir = &rel_path[..end];
+            let delta = branch.write_path(dir);
+            if storage::exists(&delta) {
+                continue;
+            }
+            let created = match self.resolve_in(&branches, branch_name, dir)? {
+                Some(src) if storage::is_dir(&src) => storage::create_dir_like(&src, &delta),
+                _ => fs::create_dir(&delta).map_err(BranchError::from),
+            };
+            match created {
+                Err(BranchError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                other => other?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a tombstone in `branch_name`'s chain hides `rel_path`, i.e.
+    /// whether a directory listing must leave out an entry the base or a
+    /// delta still holds.  Cheaper than `resolve_path` when nothing along
+    /// the chain is tombstoned, as no delta needs checking then.
+    pub fn is_hidden(&self, branch_name: &str, rel_path: &str) -> bool {
+        if is_commit_staging(rel_path) {
+            return true;
+        }
+        let branches = self.branches.read();
+        if let Some(staged) = branches.get(branch_name).and_then(|b| b.staged(rel_path)) {
+            return staged.is_none();
+        }
+        let mut chain = Vec::new();
+        let mut current = Some(branch_name);
+        while let Some(branch) = current.and_then(|name| branches.get(name)) {
+            chain.push(branch);
+            if branch.is_deleted(rel_path) {
+                return !chain.iter().any(|b| b.has_delta(rel_path));
+            }
+            current = branch.parent.as_deref();
+        }
+        false
+    }
+
+    /// `resolve_path` against an already locked branch map.
+    fn resolve_in(
+        &self,
+        branches: &std::collections::HashMap<String, Branch>,
+        branch_name: &str,
+        rel_path: &str,
+    ) -> Result<Option<PathBuf>> {
+        if let Some(staged) = branches.get(branch_name).and_then(|b| b.staged(rel_path)) {
+            return Ok(staged);
+        }
+        self.resolve_committed_in(branches, branch_name, rel_path)
+    }
+
+    /// The copy of `rel_path` staged by `branch_name`'s open transaction.
+    pub fn staged_path(&self, branch_name: &str, rel_path: &str) -> Option<PathBuf> {
+        self.branches.read().get(branch_name)?.staged(rel_path)?
+    }
+
+    /// Like `resolve_path`, but leaving out the branch's open transaction.
+    pub fn resolve_committed(&self, branch_name: &str, rel_path: &str) -> Result<Option<PathBuf>> {
+        self.resolve_committed_in(&self.branches.read(), branch_name, rel_path)
+    }
+
+    fn resolve_committed_in(
+        &self,
+        branches: &std::collections::HashMap<String, Branch>,
+        branch_name: &str,
+        rel_path: &str,
+    ) -> Result<Option<PathBuf>> {
+        let mut current = branch_name;
+        loop {
+            let branch = branches
+                .get(current)
+                .ok_or_else(|| BranchError::NotFound(current.to_string()))?;
+
+            // A directory recreated after being removed is the branch's
+            // delta; what it held before stays hidden by the tombstone
+            if branch.has_delta(rel_path) {
+                return Ok(Some(branch.delta_path(rel_path)));
+            }
+
+            if branch.is_deleted(rel_path) {
+                return Ok(None);
+            }
+
+            match &branch.parent {
+                Some(parent) => current = parent,
+                None => break,
+            }
+        }
+
+        let base = storage::join(&self.base_path, rel_path);
+        if storage::exists(&base) && !is_commit_staging(rel_path) {
+            Ok(Some(base))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns true if no other branch has `parent == name`.
+    fn is_leaf(name: &str, branches: &std::collections::HashMap<String, Branch>) -> bool {
+        !branches.values().any(|b| b.parent.as_deref() == Some(name))
+    }
+
+    /// Commit a leaf branch into its immediate parent, writing over hard
+    /// linked targets according to `hardlinks` and setting the mtime of the
+    /// files written according to `mtime`.  Refuses while files of the
+    /// branch are open for writing, unless `force`; writers forced past
+    /// keep their handles but no longer hold up the branch name.
+    /// Returns the parent branch name on success.
+    ///
+    /// The target is not modified until every file is staged and synced
+    /// (see `StagedCommit`); after that each file is renamed into place, so
+    /// a crash never leaves a partially written file, only a commit that
+    /// is partly applied.  The branch, the epoch and the recorded branch
+    /// tree change only once the renames are synced.  A daemon dying
+    /// before that leaves the commit's journal behind, and the next one
+    /// finishes the commit on startup (see `recover_commit`); one dying
+    /// while staging leaves the target as it was.
+    pub fn commit(
+        &self,
+        branch_name: &str,
+        hardlinks: HardlinkMode,
+        mtime: CommitMtime,
+        force: bool,
+    ) -> Result<String> {
+        let start = Instant::now();
+        if branch_name == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+
+        let _commit = self.commits.lock();
+
+        // The branch goes away, and its open transaction with it
+        if self
+            .with_branch(branch_name, |b| Ok(b.in_txn()))
+            .unwrap_or(false)
+        {
+            return Err(BranchError::TxnOpen(branch_name.to_string()));
+        }
+
+        // A file still being written would be copied half-done, and its
+        // delta removed from under the writer
+        let writers = self.open_writers(branch_name);
+        if !writers.is_empty() && !force {
+            return Err(BranchError::OpenForWrite(
+                branch_name.to_string(),
+                writers.join(", "),
+            ));
+        }
+
+        // Deltas are copied as plain files, and the commit changes what
+        // other branches' redirects inherit
+        self.materialize_redirects()?;
+
+        let mut branches = self.branches.write();
+
+        let branch = branches
+            .get(branch_name)
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+
+        if !Self::is_leaf(branch_name, &branches) {
+            return Err(BranchError::NotALeaf(branch_name.to_string()));
+        }
+
+        let parent_name = branch
+            .parent
+            .clone()
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+
+        let child_tombstones = branch.get_tombstones();
+        let child_files_dir = branch.files_dir.clone();
+        let mut committed: Vec<String> = child_tombstones.iter().cloned().collect();
+        let mut files = Vec::new();
+        self.walk_files(&child_files_dir, "", &mut |rel_path, src_path| {
+            files.push((rel_path.to_string(), src_path.to_path_buf()));
+        })?;
+
+        if parent_name == "main" {
+            // Direct child of main: apply to base filesystem.  Every file
+            // is staged before the first deletion, so a failure leaves the
+            // base as it was
+            let staged = self
+                .stage_commit(
+                    &self.base_path,
+                    self.base_path.join(base_staging_name()),
+                    hardlinks,
+                    mtime,
+                )?
+                .stage_all(&files)?;
+            let num_files = files.len();
+            let total_bytes: u64 = files
+                .iter()
+                .filter_map(|(_, src_path)| src_path.metadata().ok())
+                .map(|meta| meta.len())
+                .sum();
+            committed.extend(files.into_iter().map(|(rel_path, _)| rel_path));
+            self.apply_commit(CommitJournal {
+                branch: branch_name.to_string(),
+                into: parent_name.clone(),
+                commit: staged,
+                deletions: child_tombstones.iter().cloned().collect(),
+                tombstones: Vec::new(),
+                merged: Vec::new(),
+                remove_branch: true,
+            })?;
+
+            // Remove branch; main's activity starts over
+            if let Some(main) = branches.get("main") {
+                main.counters.reset();
+            }
+            branches.remove(branch_name);
+            self.writers.lock().retain(|_, (b, _)| b != branch_name);
+            let branch_dir = self.storage_path.join("branches").join(branch_name);
+            if branch_dir.exists() {
+                fs::remove_dir_all(&branch_dir)?;
+            }
+
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+
+            self.save_branch_tree(&branches);
+            CommitJournal::remove(&self.storage_path);
+            drop(branches);
+            self.invalidate_all_mounts();
+
+            let elapsed = start.elapsed();
+            log::debug!(
+                "[BENCH] commit '{}' to base: {:?} ({} us), {} deletions, {} files, {} bytes",
+                branch_name,
+                elapsed,
+                elapsed.as_micros(),
+                child_tombstones.len(),
+                num_files,
+                total_bytes
+            );
+        } else {
+            // Nested branch: merge delta into parent's delta
+            let parent = branches
+                .get(&parent_name)
+                .ok_or_else(|| BranchError::NotFound(parent_name.to_string()))?;
+
+            let parent_files_dir = parent.files_dir.clone();
+            let mut parent_tombstones = parent.get_tombstones();
+
+            // Step 1: Stage the child's delta files next to the parent's
+            let staged = self
+                .stage_commit(
+                    &parent_files_dir,
+                    self.commit_staging(branch_name)?,
+                    hardlinks,
+                    mtime,
+                )?
+                .sharing(parent)
+                .stage_all(&files)?;
+
+            // Step 2: Child tombstones shadow the parent's deltas, and each
+            // copied delta file removes that path from parent's tombstones
+            parent_tombstones.extend(child_tombstones.iter().cloned());
+            for (path, _) in &files {
+                parent_tombstones.remove(path);
+            }
+            committed.extend(files.into_iter().map(|(rel_path, _)| rel_path));
+
+            // Step 3: Remove what the child deleted from the parent's delta,
+            // then move the staged files in
+            self.apply_commit(CommitJournal {
+                branch: branch_name.to_string(),
+                into: parent_name.clone(),
+                commit: staged,
+                deletions: child_tombstones.iter().cloned().collect(),
+                tombstones: vec![(
+                    parent.tombstones_file.clone(),
+                    parent_tombstones.iter().cloned().collect(),
+                )],
+                merged: Vec::new(),
+                remove_branch: true,
+            })?;
+
+            // Write updated tombstones to parent
+            parent.set_tombstones(parent_tombstones)?;
+            parent.counters.reset();
+
+            // Remove child branch
+            branches.remove(branch_name);
+            self.writers.lock().retain(|_, (b, _)| b != branch_name);
+            let branch_dir = self.storage_path.join("branches").join(branch_name);
+            if branch_dir.exists() {
+                fs::remove_dir_all(&branch_dir)?;
+            }
+
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+
+            let affected = vec![branch_name.to_string(), parent_name.clone()];
+            self.save_branch_tree(&branches);
+            CommitJournal::remove(&self.storage_path);
+            drop(branches);
+            self.invalidate_branches(&affected);
+            self.invalidate_epoch_files();
+
+            let elapsed = start.elapsed();
+            log::debug!(
+                "[BENCH] commit '{}' into parent '{}': {:?} ({} us)",
+                branch_name,
+                parent_name,
+                elapsed,
+                elapsed.as_micros(),
+            );
+        }
+
+        committed.sort();
+        committed.dedup();
+        self.log_commit(CommitLogEntry {
+            timestamp: unix_secs(SystemTime::now()),
+            branch: branch_name.to_string(),
+            into: parent_name.clone(),
+            message: self.commit_messages.lock().remove(branch_name),
+            paths: committed,
+        });
+        self.metrics.commits.fetch_add(1, Ordering::Relaxed);
+
+        Ok(parent_name)
+    }
+
+    /// Commit only `paths` of a leaf branch into its immediate parent, as
+    /// `commit` would, and drop them from the branch.  A directory takes
+    /// every delta and deletion of the branch below it; the rest of the
+    /// branch stays as it was.  Refuses while a selected file is open for
+    /// writing.  The epoch moves only if something was merged.  Returns the
+    /// paths merged.
+    pub fn commit_paths(
+        &self,
+        branch_name: &str,
+        paths: &[String],
+        hardlinks: HardlinkMode,
+        mtime: CommitMtime,
+    ) -> Result<Vec<String>> {
+        if branch_name == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+        if paths.is_empty() {
+            return Err(BranchError::Invalid("no paths to commit".into()));
+        }
+        for path in paths {
+            storage::validate_rel_path(path)?;
+        }
+        let selected: Vec<String> = paths
+            .iter()
+            .map(|p| format!("/{}", p.trim_matches('/')))
+            .collect();
+        let is_selected = |path: &str| {
+            selected.iter().any(|s| {
+                s == "/"
+                    || path == s
+                    || path
+                        .strip_prefix(s.as_str())
+                        .is_some_and(|r| r.starts_with('/'))
+            })
+        };
+
+        let _commit = self.commits.lock();
+
+        let writers: Vec<String> = self
+            .open_writers(branch_name)
+            .into_iter()
+            .filter(|p| is_selected(p))
+            .collect();
+        if !writers.is_empty() {
+            return Err(BranchError::OpenForWrite(
+                branch_name.to_string(),
+                writers.join(", "),
+            ));
+        }
+
+        self.materialize_redirects()?;
+
+        let branches = self.branches.write();
+        let branch = branches
+            .get(branch_name)
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+        if !Self::is_leaf(branch_name, &branches) {
+            return Err(BranchError::NotALeaf(branch_name.to_string()));
+        }
+        let parent_name = branch
+            .parent
+            .clone()
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+        let parent = branches
+            .get(&parent_name)
+            .ok_or_else(|| BranchError::NotFound(parent_name.clone()))?;
+
+        let mut tombstones = branch.get_tombstones();
+        let deletions: Vec<String> = tombstones
+            .iter()
+            .filter(|t| is_selected(t))
+            .cloned()
+            .collect();
+        let mut files = Vec::new();
+        self.walk_files(&branch.files_dir, "", &mut |rel_path, src_path| {
+            if is_selected(rel_path) {
+                files.push((rel_path.to_string(), src_path.to_path_buf()));
+            }
+        })?;
+        if deletions.is_empty() && files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Same order as `commit`: stage the files, then delete and move
+        // them in
+        let to_base = parent_name == "main";
+        let (target_dir, staging) = if to_base {
+            (
+                self.base_path.clone(),
+                self.base_path.join(base_staging_name()),
+            )
+        } else {
+            (parent.files_dir.clone(), self.commit_staging(branch_name)?)
+        };
+        let mut staged = self.stage_commit(&target_dir, staging, hardlinks, mtime)?;
+        if !to_base {
+            staged = staged.sharing(parent);
+        }
+        let staged = staged.stage_all(&files)?;
+        let mut parent_tombstones = parent.get_tombstones();
+        for path in &deletions {
+            parent_tombstones.insert(path.clone());
+            tombstones.remove(path);
+        }
+        for (rel_path, _) in &files {
+            parent_tombstones.remove(rel_path);
+        }
+        let mut rewritten = vec![(
+            branch.tombstones_file.clone(),
+            tombstones.iter().cloned().collect(),
+        )];
+        if !to_base {
+            rewritten.push((
+                parent.tombstones_file.clone(),
+                parent_tombstones.iter().cloned().collect(),
+            ));
+        }
+        self.apply_commit(CommitJournal {
+            branch: branch_name.to_string(),
+            into: parent_name.clone(),
+            commit: staged,
+            deletions: deletions.clone(),
+            tombstones: rewritten,
+            merged: files.iter().map(|(_, src_path)| src_path.clone()).collect(),
+            remove_branch: false,
+        })?;
+        if !to_base {
+            parent.set_tombstones(parent_tombstones)?;
+        }
+
+        // Take the merged paths out of the branch, with the directories
+        // they leave empty
+        branch.set_tombstones(tombstones)?;
+        for (_, src_path) in &files {
+            fs::remove_file(src_path)?;
+            let mut dir = src_path.parent();
+            while let Some(d) = dir.filter(|d| *d != branch.files_dir) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        CommitJournal::remove(&self.storage_path);
+        drop(branches);
+        if to_base {
+            self.invalidate_all_mounts();
+        } else {
+            self.invalidate_branches(&[branch_name.to_string(), parent_name.clone()]);
+            self.invalidate_epoch_files();
+        }
+
+        let mut committed: Vec<String> = deletions;
+        committed.extend(files.into_iter().map(|(rel_path, _)| rel_path));
+        committed.sort();
+        committed.dedup();
+        log::info!(
+            "Committed {} paths of '{}' into '{}'",
+            committed.len(),
+            branch_name,
+            parent_name
+        );
+        self.log_commit(CommitLogEntry {
+            timestamp: unix_secs(SystemTime::now()),
+            branch: branch_name.to_string(),
+            into: parent_name,
+            message: self.commit_messages.lock().remove(branch_name),
+            paths: committed.clone(),
+        });
+        self.metrics.commits.fetch_add(1, Ordering::Relaxed);
+        Ok(committed)
+    }
+
+    /// Set (or with `None` clear) the message recorded in the commit log
+    /// for the next commit of `branch_name`.
+    pub fn set_commit_message(&self, branch_name: &str, message: Option<String>) -> Result<()> {
+        if !self.branches.read().contains_key(branch_name) {
+            return Err(BranchError::NotFound(branch_name.to_string()));
+        }
+        let mut messages = self.commit_messages.lock();
+        match message {
+            Some(message) => messages.insert(branch_name.to_string(), message),
+            None => messages.remove(branch_name),
+        };
+        Ok(())
+    }
+
+    /// Append `entry` to `COMMIT_LOG_FILE`.  Failures are only logged: the
+    /// commit itself has already been applied.
+    fn log_commit(&self, entry: CommitLogEntry) {
+        let path = self.storage_path.join(COMMIT_LOG_FILE);
+        let result = serde_json::to_string(&entry)
+            .map_err(BranchError::from)
+            .and_then(|line| {
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?;
+                writeln!(file, "{}", line)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to append to commit log {:?}: {}", path, e);
+        }
+    }
+
+    /// Describe what `commit` would do to a leaf branch's target without
+    /// changing anything.
+    ///
+    /// Paths are reported by their net effect: a file under a deleted
+    /// directory that the branch writes back is an overwrite, not a
+    /// deletion.
+    pub fn preview_commit(&self, branch_name: &str) -> Result<CommitPreview> {
+        if branch_name == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+        self.materialize_redirects()?;
+
+        let (parent_name, tombstones, files_dir, target_dir) = {
+            let branches = self.branches.read();
+            let branch = branches
+                .get(branch_name)
+                .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+            if !Self::is_leaf(branch_name, &branches) {
+                return Err(BranchError::NotALeaf(branch_name.to_string()));
+            }
+            let parent = branch
+                .parent
+                .clone()
+                .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+            // Where the commit writes files: the base for a child of main,
+            // the parent's delta otherwise
+            let target_dir = match parent.as_str() {
+                "main" => self.base_path.clone(),
+                _ => branches
+                    .get(&parent)
+                    .map(|p| p.files_dir.clone())
+                    .ok_or_else(|| BranchError::NotFound(parent.clone()))?,
+            };
+            (
+                parent,
+                branch.get_tombstones(),
+                branch.files_dir.clone(),
+                target_dir,
+            )
+        };
+
+        let mut written = Vec::new();
+        self.walk_files(&files_dir, "", &mut |rel_path, src_path| {
+            written.push((rel_path.to_string(), src_path.to_path_buf()));
+        })?;
+        written.sort();
+
+        let mut overwrite = Vec::new();
+        let mut create = Vec::new();
+        let mut hardlinked = Vec::new();
+        // Inodes `branchfs gc` shared with other branches are replaced,
+        // not rewritten in place
+        let shared = |path: &Path| {
+            parent_name != "main"
+                && self
+                    .with_branch(&parent_name, |p| Ok(p.is_shared(path)))
+                    .unwrap_or(false)
+        };
+        for (rel_path, src_path) in &written {
+            let dest = storage::join(&target_dir, rel_path);
+            if is_hardlinked(&dest) && !shared(&dest) {
+                hardlinked.push(rel_path.clone());
+            }
+            let after = self.hash_path(src_path)?;
+            match self.resolve_path(&parent_name, rel_path)? {
+                Some(old) if old.is_file() => overwrite.push(PreviewFile {
+                    path: rel_path.clone(),
+                    before: Some(self.hash_path(&old)?),
+                    after,
+                }),
+                _ => create.push(PreviewFile {
+                    path: rel_path.clone(),
+                    before: None,
+                    after,
+                }),
+            }
+        }
+
+        let mut delete = std::collections::BTreeSet::new();
+        for path in &tombstones {
+            match self.resolve_path(&parent_name, path)? {
+                Some(old) if old.is_dir() => {
+                    let mut entries = Vec::new();
+                    self.merged_entries(&parent_name, path, "", &mut entries)?;
+                    delete.insert(path.clone());
+                    for (suffix, _) in entries {
+                        delete.insert(format!("{}{}", path, suffix));
+                    }
+                }
+                Some(_) => {
+                    delete.insert(path.clone());
+                }
+                None => {}
+            }
+        }
+        // Written files, and the directories holding them, end up present
+        delete.retain(|path| {
+            let dir_prefix = format!("{}/", path);
+            !written
+                .iter()
+                .any(|(rel, _)| rel == path || rel.starts_with(&dir_prefix))
+        });
+
+        Ok(CommitPreview {
+            branch: branch_name.to_string(),
+            target: parent_name,
+            overwrite,
+            create,
+            delete: delete.into_iter().collect(),
+            hardlinked,
+        })
+    }
+
+    /// `preview_commit` rendered as a unified diff of every affected file.
+    pub fn preview_commit_patch(&self, branch_name: &str) -> Result<String> {
+        let preview = self.preview_commit(branch_name)?;
+        let read_target = |path: &str| -> Result<Option<Vec<u8>>> {
+            match self.resolve_path(&preview.target, path)? {
+                Some(old) if old.is_file() => Ok(Some(fs::read(old)?)),
+                _ => Ok(None),
+            }
+        };
+
+        let mut files = std::collections::BTreeMap::new();
+        for file in preview.overwrite.iter().chain(&preview.create) {
+            let delta = self.with_branch(branch_name, |b| Ok(b.delta_path(&file.path)))?;
+            files.insert(
+                file.path.clone(),
+                (read_target(&file.path)?, Some(fs::read(delta)?)),
+            );
+        }
+        for path in &preview.delete {
+            if let Some(old) = read_target(path)? {
+                files.insert(path.clone(), (Some(old), None));
+            }
+        }
+
+        let mut patch = String::new();
+        for (path, (old, new)) in &files {
+            if old != new {
+                patch.push_str(&unified_diff(path, old.as_deref(), new.as_deref()));
+            }
+        }
+        Ok(patch)
+    }
+
+    /// Take the advisory commit lock for `holder`, lapsing after `timeout`
+    /// if given.
+    ///
+    /// Fails while another holder has it.  Re-acquiring as the current
+    /// holder renews the timeout.  Commits do not check this lock; it only
+    /// lets cooperating orchestrators take turns.
+    pub fn acquire_commit_lock(
+        &self,
+        holder: &str,
+        timeout: Option<Duration>,
+    ) -> Result<CommitLock> {
+        if holder.is_empty() {
+            return Err(BranchError::Invalid(
+                "commit lock holder cannot be empty".into(),
+            ));
+        }
+        let now = unix_secs(SystemTime::now());
+        let mut lock = self.commit_lock.lock();
+        let acquired_at = match lock.as_ref() {
+            Some(current) if current.expired(now) => now,
+            Some(current) if current.holder != holder => {
+                return Err(BranchError::CommitLocked(current.holder.clone()));
+            }
+            Some(current) => current.acquired_at,
+            None => now,
+        };
+        let acquired = CommitLock {
+            holder: holder.to_string(),
+            acquired_at,
+            expires_at: timeout.map(|t| now + t.as_secs()),
+        };
+        *lock = Some(acquired.clone());
+        Ok(acquired)
+    }
+
+    /// Give up the advisory commit lock held by `holder`.
+    pub fn release_commit_lock(&self, holder: &str) -> Result<()> {
+        let now = unix_secs(SystemTime::now());
+        let mut lock = self.commit_lock.lock();
+        match lock.as_ref() {
+            Some(current) if current.holder == holder => {
+                *lock = None;
+                Ok(())
+            }
+            Some(current) if !current.expired(now) => {
+                Err(BranchError::CommitLocked(current.holder.clone()))
+            }
+            _ => Err(BranchError::Invalid(format!(
+                "commit lock is not held by '{}'",
+                holder
+            ))),
+        }
+    }
+
+    /// Current holder of the advisory commit lock, if it has not lapsed.
+    pub fn commit_lock(&self) -> Option<CommitLock> {
+        let now = unix_secs(SystemTime::now());
+        let mut lock = self.commit_lock.lock();
+        if lock.as_ref().is_some_and(|l| l.expired(now)) {
+            *lock = None;
+        }
+        lock.clone()
+    }
+
+    /// Whether a commit is being applied right now.
+    pub fn is_committing(&self) -> bool {
+        self.commits.is_locked()
+    }
+
+    /// Abort a leaf branch, discarding only that branch.
+    /// Returns the parent branch name on success.
+    pub fn abort(&self, branch_name: &str) -> Result<String> {
+        let start = Instant::now();
+        if branch_name == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+
+        let mut branches = self.branches.write();
+
+        let branch = branches
+            .get(branch_name)
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+
+        if !Self::is_leaf(branch_name, &branches) {
+            return Err(BranchError::NotALeaf(branch_name.to_string()));
+        }
+
+        let parent_name = branch
+            .parent
+            .clone()
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+
+        // Remove only this branch
+        branches.remove(branch_name);
+        let branch_dir = self.storage_path.join("branches").join(branch_name);
+        if branch_dir.exists() {
+            fs::remove_dir_all(&branch_dir)?;
+        }
+        self.redirects.lock().retain(|_, r| r.branch != branch_name);
+        self.commit_messages.lock().remove(branch_name);
+
+        self.save_branch_tree(&branches);
+
+        // Invalidate kernel cache for this branch only
+        drop(branches);
+        self.invalidate_branches(&[branch_name.to_string()]);
+        self.metrics.aborts.fetch_add(1, Ordering::Relaxed);
+
+        let elapsed = start.elapsed();
+        log::debug!(
+            "[BENCH] abort '{}': {:?} ({} us)",
+            branch_name,
+            elapsed,
+            elapsed.as_micros()
+        );
+
+        Ok(parent_name)
+    }
+
+    /// Open a transaction on a branch: until the matching `txn_commit`,
+    /// writes through mounts go to a staging directory that only the
+    /// branch's own view reads.  Transactions nest; inner ones are
+    /// published with the outermost.
+    pub fn txn_begin(&self, branch_name: &str) -> Result<()> {
+        self.with_branch(branch_name, |branch| {
+            let mut txn = branch.txn.write();
+            match txn.as_mut() {
+                Some(open) => open.depth += 1,
+                None => {
+                    let dir = branch.txn_dir();
+                    if dir.exists() {
+                        fs::remove_dir_all(&dir)?;
+                    }
+                    fs::create_dir_all(&dir)?;
+                    *txn = Some(Txn {
+                        depth: 1,
+                        deleted: HashSet::new(),
+                        restored: HashSet::new(),
+                    });
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Close the innermost transaction of a branch.  Closing the outermost
+    /// publishes the staged changes into the branch: the branch map stays
+    /// write-locked throughout, so no path is resolved against a half-moved
+    /// transaction and a commit sees all of it or none.
+    pub fn txn_commit(&self, branch_name: &str) -> Result<()> {
+        // Redirects are keyed by delta path, which publishing changes
+        self.materialize_redirects()?;
+
+        let branches = self.branches.write();
+        let branch = branches
+            .get(branch_name)
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+        let mut guard = branch.txn.write();
+        let txn = guard
+            .as_mut()
+            .ok_or_else(|| BranchError::NoTxn(branch_name.to_string()))?;
+        txn.depth -= 1;
+        if txn.depth > 0 {
+            return Ok(());
+        }
+        let Some(txn) = guard.take() else {
+            return Ok(());
+        };
+        drop(guard);
+
+        branch.remove_tombstones(txn.restored.iter().map(String::as_str))?;
+        for path in &txn.deleted {
+            branch.add_tombstone(path)?;
+            let delta = branch.delta_path(path);
+            if storage::is_dir(&delta) {
+                fs::remove_dir_all(&delta)?;
+            } else if storage::exists(&delta) {
+                fs::remove_file(&delta)?;
+            }
+        }
+        let dir = branch.txn_dir();
+        merge_into(&dir, &branch.files_dir)?;
+        fs::remove_dir_all(&dir)?;
+        drop(branches);
+
+        log::info!(
+            "Published transaction of branch '{}' ({} deletions)",
+            branch_name,
+            txn.deleted.len()
+        );
+        Ok(())
+    }
+
+    /// Drop a branch's open transaction, nested ones included, and every
+    /// change staged in it.
+    pub fn txn_abort(&self, branch_name: &str) -> Result<()> {
+        self.with_branch(branch_name, |branch| {
+            if branch.txn.write().take().is_none() {
+                return Err(BranchError::NoTxn(branch_name.to_string()));
+            }
+            fs::remove_dir_all(branch.txn_dir())?;
+            Ok(())
+        })?;
+        self.invalidate_branches(&[branch_name.to_string()]);
+        log::info!("Discarded transaction of branch '{}'", branch_name);
+        Ok(())
+    }
+
+    /// Delete a branch by name, whichever mount is on it.  A branch with
+    /// children is refused unless `recursive`, which aborts its whole
+    /// subtree, leaves first.  Returns the removed branches in that order
+    /// and the parent they were removed from.
+    pub fn delete_branch(&self, name: &str, recursive: bool) -> Result<(Vec<String>, String)> {
+        if name == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+        let descendants = self.child_branches(name, true)?;
+        if !descendants.is_empty() && !recursive {
+            let children: Vec<&str> = descendants
+                .iter()
+                .filter(|(_, parent)| parent == name)
+                .map(|(child, _)| child.as_str())
+                .collect();
+            return Err(BranchError::HasChildren(
+                name.to_string(),
+                children.join(", "),
+            ));
+        }
+
+        // Parents come before their children, so go backwards
+        let mut removed = Vec::new();
+        for (child, _) in descendants.iter().rev() {
+            self.abort(child)?;
+            removed.push(child.clone());
+        }
+        let parent = self.abort(name)?;
+        removed.push(name.to_string());
+        Ok((removed, parent))
+    }
+
+    /// Remove every branch except `main`, like aborting them all at once.
+    ///
+    /// Returns the removed branch names, sorted.  The base is untouched; the
+    /// epoch moves so every mount sees its caches as stale.
+    pub fn purge(&self) -> Result<Vec<String>> {
+        let mut branches = self.branches.write();
+
+        let mut removed: Vec<String> = branches
+            .keys()
+            .filter(|name| *name != "main")
+            .cloned()
+            .collect();
+        removed.sort();
+
+        for name in &removed {
+            branches.remove(name);
+            let branch_dir = self.storage_path.join("branches").join(name);
+            if branch_dir.exists() {
+                fs::remove_dir_all(&branch_dir)?;
+            }
+        }
+        self.redirects.lock().retain(|_, r| r.branch == "main");
+
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.save_branch_tree(&branches);
+        drop(branches);
+        self.invalidate_all_mounts();
+
+        log::info!("Purged {} branches", removed.len());
+        Ok(removed)
+    }
+
+    /// Read a file through a branch only if the epoch still equals `epoch`.
+    ///
+    /// Returns `None` when the epoch moved before or during the read, so a
+    /// caller can snapshot the epoch, read a batch of files and detect that a
+    /// commit happened underneath it.
+    pub fn read_if_epoch(
+        &self,
+        branch_name: &str,
+        rel_path: &str,
+        epoch: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        storage::validate_rel_path(rel_path)?;
+        if self.get_epoch() != epoch {
+            return Ok(None);
+        }
+        let rel_path = format!("/{}", rel_path.trim_start_matches('/'));
+        let resolved = self.resolve_path(branch_name, &rel_path)?.ok_or_else(|| {
+            BranchError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{}: not found in branch '{}'", rel_path, branch_name),
+            ))
+        })?;
+        self.materialize_redirect(&resolved)?;
+        let data = fs::read(&resolved)?;
+        if self.get_epoch() != epoch {
+            return Ok(None);
+        }
+        Ok(Some(data))
+    }
+
+    /// Unified diff of one file between the base and a branch's resolved view.
+    ///
+    /// Returns an empty string when both sides are identical, and a single
+    /// "Binary files ... differ" line when either side is not UTF-8 text.
+    pub fn diff_file(&self, branch_name: &str, rel_path: &str) -> Result<String> {
+        storage::validate_rel_path(rel_path)?;
+        let rel_path = format!("/{}", rel_path.trim_start_matches('/'));
+        let base = storage::join(&self.base_path, &rel_path);
+        let old = if base.is_file() {
+            Some(fs::read(&base)?)
+        } else {
+            None
+        };
+        let new = match self.resolve_path(branch_name, &rel_path)? {
+            Some(p) if p.is_file() => {
+                self.materialize_redirect(&p)?;
+                Some(fs::read(&p)?)
+            }
+            _ => None,
+        };
+
+        if old.is_none() && new.is_none() {
+            return Err(BranchError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{}: not a file in base or branch '{}'",
+                    rel_path, branch_name
+                ),
+            )));
+        }
+        if old == new {
+            return Ok(String::new());
+        }
+        Ok(unified_diff(&rel_path, old.as_deref(), new.as_deref()))
+    }
+
+    /// Set the size of a file in a branch without going through a mount.
+    ///
+    /// The file is copied up into the branch first so the base and parent
+    /// branches are untouched; a missing file is created empty (undeleting a
+    /// tombstoned path) before being extended to `size`.
+    pub fn truncate_file(&self, branch_name: &str, rel_path: &str, size: u64) -> Result<()> {
+        storage::validate_rel_path(rel_path)?;
+        let rel_path = format!("/{}", rel_path.trim_start_matches('/'));
+        let resolved = self.resolve_path(branch_name, &rel_path)?;
+        if resolved.as_ref().is_some_and(|p| p.is_dir()) {
+            return Err(BranchError::Invalid(format!(
+                "{}: is a directory",
+                rel_path
+            )));
+        }
+
+        let delta = self.with_branch(branch_name, |b| Ok(b.write_path(&rel_path)))?;
+        match resolved {
+            Some(src) if src != delta => {
+                self.materialize_redirect(&src)?;
+                self.materialize_parents(branch_name, &rel_path)?;
+                storage::copy_file_atomic(&src, &delta)?;
+            }
+            Some(_) => {
+                self.unshare(branch_name, &delta)?;
+                self.truncate_redirect(&delta, size)?;
+            }
+            None => self.materialize_parents(branch_name, &rel_path)?,
+        }
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&delta)?
+            .set_len(size)?;
+
+        self.with_branch(branch_name, |b| b.untombstone(&rel_path))?;
+        self.invalidate_branches(&[branch_name.to_string()]);
+        Ok(())
+    }
+
+    /// Give `branch_name`'s delta file `path` an inode of its own if
+    /// `branchfs gc` hard-linked it to another branch's copy, so changing
+    /// it in place leaves the other branch alone.  Other files are left
+    /// as they are.
+    pub fn unshare(&self, branch_name: &str, path: &Path) -> Result<()> {
+        if !self.with_branch(branch_name, |b| Ok(b.is_shared(path)))? {
+            return Ok(());
+        }
+        let meta = fs::metadata(path)?;
+        storage::copy_file_atomic(path, path)?;
+        // Only root may keep another user's ownership
+        let _ = std::os::unix::fs::lchown(path, Some(meta.uid()), Some(meta.gid()));
+        set_mtime(path, meta.modified()?)?;
+        Ok(())
+    }
+
+    /// Move a file or directory within a branch without going through a mount.
+    ///
+    /// A directory's merged view (base, ancestor deltas and this branch's
+    /// delta) is materialized under `to` in the branch's delta, so children
+    /// that only exist in the base come along.  Children already deleted in
+    /// the branch stay deleted.  Every source path is then tombstoned.
+    /// Returns the number of files moved.
+    pub fn rename_path(&self, branch_name: &str, from: &str, to: &str) -> Result<usize> {
+        let moved = self.move_path(branch_name, from, to, false)?;
+        self.invalidate_branches(&[branch_name.to_string()]);
+        Ok(moved)
+    }
+
+    /// `rename_path` with `rename(2)` semantics when `replace` is set: an
+    /// existing file at `to` is replaced, as is an empty directory when
+    /// moving a directory.  Mounts are not invalidated; the FUSE rename
+    /// handler calls this and the kernel updates its own caches.
+    pub fn move_path(
+        &self,
+        branch_name: &str,
+        from: &str,
+        to: &str,
+        replace: bool,
+    ) -> Result<usize> {
+        storage::validate_rel_path(from)?;
+        storage::validate_rel_path(to)?;
+        let from = format!("/{}", from.trim_matches('/'));
+        let to = format!("/{}", to.trim_matches('/'));
+        if from == "/" || to == "/" {
+            return Err(BranchError::Invalid("cannot move the root".to_string()));
+        }
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(BranchError::Invalid(format!(
+                "cannot move {} into itself",
+                from
+            )));
+        }
+        let resolved = self
+            .resolve_path(branch_name, &from)?
+            .ok_or_else(|| BranchError::NotFound(from.clone()))?;
+        if let Some(existing) = self.resolve_path(branch_name, &to)? {
+            if !replace {
+                return Err(BranchError::AlreadyExists(to));
+            }
+            if from == to {
+                return Ok(0);
+            }
+            let errno = match (storage::is_dir(&resolved), storage::is_dir(&existing)) {
+                (true, false) => Some(libc::ENOTDIR),
+                (false, true) => Some(libc::EISDIR),
+                (true, true) => (!self.is_dir_empty(branch_name, &to)?).then_some(libc::ENOTEMPTY),
+                (false, false) => None,
+            };
+            if let Some(errno) = errno {
+                return Err(std::io::Error::from_raw_os_error(errno).into());
+            }
+        }
+        self.materialize_redirects()?;
+
+        let mut entries = Vec::new();
+        if storage::is_dir(&resolved) {
+            entries.push((String::new(), true));
+            self.merged_entries(branch_name, &from, "", &mut entries)?;
+        } else {
+            entries.push((String::new(), false));
+        }
+
+        self.materialize_parents(branch_name, &to)?;
+        let mut moved = 0;
+        for (suffix, is_dir) in &entries {
+            let src_rel = format!("{}{}", from, suffix);
+            let dst_rel = format!("{}{}", to, suffix);
+            let dst = self.with_branch(branch_name, |b| Ok(b.write_path(&dst_rel)))?;
+            if *is_dir {
+                if storage::exists(&dst) && !storage::is_dir(&dst) {
+                    fs::remove_file(&dst)?;
+                }
+                fs::create_dir_all(&dst)?;
+            } else if let Some(src) = self.resolve_path(branch_name, &src_rel)? {
+                if storage::is_dir(&dst) {
+                    fs::remove_dir_all(&dst)?;
+                }
+                let own = self.with_branch(branch_name, |b| Ok(b.write_path(&src_rel)))?;
+                if src == own {
+                    // The branch's own file: move it, so open handles follow
+                    storage::ensure_parent_dirs(&dst)?;
+                    fs::rename(&src, &dst)?;
+                } else {
+                    storage::copy_file_atomic(&src, &dst)?;
+                }
+                moved += 1;
+            }
+            self.with_branch(branch_name, |b| b.untombstone(&dst_rel))?;
+        }
+
+        self.with_branch(branch_name, |b| {
+            for (suffix, _) in &entries {
+                b.tombstone(&format!("{}{}", from, suffix), Durability::Off)?;
+            }
+            let delta = b.write_path(&from);
+            if storage::is_dir(&delta) {
+                fs::remove_dir_all(&delta)?;
+            } else if storage::exists(&delta) {
+                fs::remove_file(&delta)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(moved)
+    }
+
+    /// Collect `(suffix, is_dir)` for everything visible below `dir` on a
+    /// branch, merging the base with every delta in the branch's ancestry.
+    fn merged_entries(
+        &self,
+        branch_name: &str,
+        dir: &str,
+        suffix: &str,
+        out: &mut Vec<(String, bool)>,
+    ) -> Result<()> {
+        let rel = format!("{}{}", dir, suffix);
+        let mut names = std::collections::BTreeSet::new();
+        for layer in self.dir_layers(branch_name, &rel)? {
+            if let Ok(dir) = fs::read_dir(&layer) {
+                for entry in dir.flatten() {
+                    names.insert(storage::name_to_string(&entry.file_name()));
+                }
+            }
+        }
+
+        for name in names {
+            let child_suffix = format!("{}/{}", suffix, name);
+            let child_rel = format!("{}{}", dir, child_suffix);
+            match self.resolve_path(branch_name, &child_rel)? {
+                Some(path) if storage::is_dir(&path) => {
+                    out.push((child_suffix.clone(), true));
+                    self.merged_entries(branch_name, dir, &child_suffix, out)?;
+                }
+                Some(_) => out.push((child_suffix, false)),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether directory `dir` lists nothing on a branch: every entry the
+    /// base or a delta in the branch's ancestry still holds is deleted in
+    /// the branch's view.
+    pub fn is_dir_empty(&self, branch_name: &str, dir: &str) -> Result<bool> {
+        let prefix = dir.trim_end_matches('/');
+        for layer in self.dir_layers(branch_name, dir)? {
+            if let Ok(entries) = fs::read_dir(&layer) {
+                for entry in entries.flatten() {
+                    let name = storage::name_to_string(&entry.file_name());
+                    let child = format!("{}/{}", prefix, name);
+                    if self.resolve_path(branch_name, &child)?.is_some() {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Where directory `rel` may have entries for a branch: the base, then
+    /// the delta of the branch and of each ancestor, and the branch's open
+    /// transaction.
+    fn dir_layers(&self, branch_name: &str, rel: &str) -> Result<Vec<PathBuf>> {
+        let mut layers = vec![storage::join(&self.base_path, rel)];
+        let branches = self.branches.read();
+        if let Some(branch) = branches.get(branch_name).filter(|b| b.in_txn()) {
+            layers.push(branch.write_path(rel));
+        }
+        let mut current = Some(branch_name);
+        while let Some(name) = current {
+            let branch = branches
+                .get(name)
+                .ok_or_else(|| BranchError::NotFound(name.to_string()))?;
+            layers.push(branch.delta_path(rel));
+            current = branch.parent.as_deref();
+        }
+        Ok(layers)
+    }
+
+    /// Every deletion in effect for `branch_name`: tombstones of the branch
+    /// and its ancestors, minus those a nearer branch wrote back.
+    ///
+    /// Sorted by path.  A path tombstoned at several levels is reported
+    /// once, for the nearest branch.
+    pub fn deletions(&self, branch_name: &str) -> Result<Vec<Deletion>> {
+        let branches = self.branches.read();
+
+        let mut chain = Vec::new();
+        let mut current = Some(branch_name);
+        while let Some(name) = current {
+            let branch = branches
+                .get(name)
+                .ok_or_else(|| BranchError::NotFound(name.to_string()))?;
+            chain.push(branch);
+            current = branch.parent.as_deref();
+        }
+
+        let mut seen = HashSet::new();
+        let mut deletions = Vec::new();
+        for (depth, branch) in chain.iter().enumerate() {
+            for path in branch.get_tombstones() {
+                if seen.contains(&path) || chain[..depth].iter().any(|b| b.has_delta(&path)) {
+                    continue;
+                }
+                seen.insert(path.clone());
+                deletions.push(Deletion {
+                    shadows: self
+                        .inherited_path(&branches, &branch.name, &path)
+                        .is_some(),
+                    path,
+                    branch: branch.name.clone(),
+                });
+            }
+        }
+        deletions.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(deletions)
+    }
+
+    /// Whether committing the branch would change nothing: it has no
+    /// tombstones and no files in its delta.  Directories without files
+    /// are ignored, as a commit does not carry them either.
+    pub fn is_empty(&self, branch_name: &str) -> Result<bool> {
+        let (files_dir, no_tombstones) = self.with_branch(branch_name, |b| {
+            Ok((b.files_dir.clone(), b.get_tombstones().is_empty()))
+        })?;
+        Ok(no_tombstones && !has_files(&files_dir)?)
+    }
+
+    /// Files the branch itself wrote and the paths it deleted, sorted by
+    /// path.  Unlike `deletions`, ancestors are not included.
+    pub fn deltas(&self, branch_name: &str) -> Result<Vec<DeltaEntry>> {
+        let (files_dir, tombstones) = self.with_branch(branch_name, |b| {
+            Ok((b.files_dir.clone(), b.get_tombstones()))
+        })?;
+
+        let mut entries: Vec<DeltaEntry> = tombstones
+            .into_iter()
+            .map(|path| DeltaEntry {
+                path,
+                size: None,
+                tombstone: true,
+            })
+            .collect();
+        self.walk_files(&files_dir, "", &mut |rel_path, path| {
+            entries.push(DeltaEntry {
+                path: rel_path.to_string(),
+                size: path.symlink_metadata().ok().map(|m| m.len()),
+                tombstone: false,
+            });
+        })?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// The branch's own changes as seen against the base, sorted by path:
+    /// written paths are modified if the base has them and added
+    /// otherwise.
+    pub fn diff(&self, branch_name: &str) -> Result<Vec<DiffEntry>> {
+        Ok(self
+            .deltas(branch_name)?
+            .into_iter()
+            .map(|delta| {
+                let status = if delta.tombstone {
+                    DiffStatus::Deleted
+                } else if storage::exists(&storage::join(&self.base_path, &delta.path)) {
+                    DiffStatus::Modified
+                } else {
+                    DiffStatus::Added
+                };
+                DiffEntry {
+                    path: delta.path,
+                    status,
+                }
+            })
+            .collect())
+    }
+
+    /// Bring `from`'s own changes into `into` without going through their
+    /// parent: its delta files are copied and its tombstones applied.
+    ///
+    /// A change of `from` at, above or below a path `into` changed itself
+    /// is a conflict; `into` keeps its version and the path is reported.
+    /// Changes `from` inherited from its ancestors are not carried over.
+    pub fn merge(&self, into: &str, from: &str) -> Result<MergeReport> {
+        if into == "main" || from == "main" {
+            return Err(BranchError::CannotOperateOnMain);
+        }
+        if into == from {
+            return Err(BranchError::Invalid(format!(
+                "cannot merge '{}' into itself",
+                into
+            )));
+        }
+
+        let _commit = self.commits.lock();
+
+        // A file still being written would be copied half-done
+        let writers = self.open_writers(from);
+        if !writers.is_empty() {
+            return Err(BranchError::OpenForWrite(
+                from.to_string(),
+                writers.join(", "),
+            ));
+        }
+        self.materialize_redirects()?;
+
+        let ours: std::collections::BTreeSet<String> =
+            self.deltas(into)?.into_iter().map(|d| d.path).collect();
+        let theirs = self.deltas(from)?;
+
+        let mut report = MergeReport::default();
+        {
+            let branches = self.branches.read();
+            let src = branches
+                .get(from)
+                .ok_or_else(|| BranchError::NotFound(from.to_string()))?;
+            let dst = branches
+                .get(into)
+                .ok_or_else(|| BranchError::NotFound(into.to_string()))?;
+            for delta in theirs {
+                if overlaps(&ours, &delta.path) {
+                    report.conflicts.push(delta.path);
+                    continue;
+                }
+                if delta.tombstone {
+                    dst.add_tombstone(&delta.path)?;
+                } else {
+                    storage::copy_file_atomic(
+                        &src.delta_path(&delta.path),
+                        &dst.delta_path(&delta.path),
+                    )?;
+                }
+                report.merged.push(delta.path);
+            }
+        }
+
+        self.invalidate_branches(&[into.to_string()]);
+        Ok(report)
+    }
+
+    /// Where `rel_path` lives on disk for `branch_name`: its own delta and
+    /// the file it resolves to.
+    pub fn locate(&self, branch_name: &str, rel_path: &str) -> Result<Location> {
+        storage::validate_rel_path(rel_path)?;
+        let rel_path = format!("/{}", rel_path.trim_start_matches('/'));
+        let branches = self.branches.read();
+        let branch = branches
+            .get(branch_name)
+            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+        let delta = branch.delta_path(&rel_path);
+        let resolved = self.resolve_in(&branches, branch_name, &rel_path)?;
+        Ok(Location {
+            delta_exists: delta.symlink_metadata().is_ok(),
+            delta,
+            tombstoned: branch.is_deleted(&rel_path),
+            redirected: resolved.as_deref().is_some_and(|p| self.is_redirected(p)),
+            resolved,
+        })
+    }
+
+    /// Whether `uid`/`gid` could access `rel_path` in a branch with the
+    /// `access(2)` `mask`, judged by the mode and owner of the file the path
+    /// resolves to, the same check the mount's `access` op makes.
+    pub fn check_access(
+        &self,
+        branch_name: &str,
+        rel_path: &str,
+        uid: u32,
+        gid: u32,
+        mask: i32,
+    ) -> Result<AccessCheck> {
+        storage::validate_rel_path(rel_path)?;
+        let rel_path = format!("/{}", rel_path.trim_start_matches('/'));
+        let resolved = self
+            .resolve_path(branch_name, &rel_path)?
+            .ok_or_else(|| BranchError::NotFound(rel_path.clone()))?;
+        let meta = fs::symlink_metadata(&resolved)?;
+        let mode = meta.mode() & 0o7777;
+        Ok(AccessCheck {
+            allowed: storage::access_allowed(
+                mode,
+                meta.is_dir(),
+                (meta.uid(), meta.gid()),
+                uid,
+                gid,
+                mask,
+            ),
+            mode,
+            uid: meta.uid(),
+            gid: meta.gid(),
+        })
+    }
+
+    /// BLAKE3 hash (hex) of a file as seen through a branch.
+    ///
+    /// The file is streamed through the hasher, so large files are never
+    /// held in memory.  Results are reused while the backing file keeps the
+    /// same inode, ctime and size.
+    pub fn hash_file(&self, branch_name: &str, rel_path: &str) -> Result<String> {
+        storage::validate_rel_path(rel_path)?;
+        let rel_path = format!("/{}", rel_path.trim_start_matches('/'));
+        let resolved = self
+            .resolve_path(branch_name, &rel_path)?
+            .ok_or_else(|| BranchError::NotFound(rel_path.clone()))?;
+        if !resolved.is_file() {
+            return Err(BranchError::Invalid(format!(
+                "{}: not a regular file",
+                rel_path
+            )));
+        }
+        self.materialize_redirect(&resolved)?;
+        self.hash_path(&resolved)
+    }
+
+    /// BLAKE3 hash (hex) of a backing file, through the hash cache.
+    fn hash_path(&self, path: &Path) -> Result<String> {
+        let meta = fs::metadata(path)?;
+        let key = (meta.dev(), meta.ino());
+        let ctime = (meta.ctime(), meta.ctime_nsec());
+
+        if let Some((cached_ctime, cached_len, hash)) = self.hash_cache.lock().get(&key) {
+            if *cached_ctime == ctime && *cached_len == meta.len() {
+                return Ok(hash.clone());
+            }
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        let hash = hasher.finalize().to_hex().to_string();
+
+        let mut cache = self.hash_cache.lock();
+        if cache.len() >= HASH_CACHE_LIMIT {
+            cache.clear();
+        }
+        cache.insert(key, (ctime, meta.len(), hash.clone()));
+        Ok(hash)
+    }
+
+    /// Create `branch_name`'s delta for `rel_path` as a redirect of `src`:
+    /// a sparse file with `src`'s size and mode whose unwritten ranges keep
+    /// reading from the inherited file.
+    pub fn redirect_file(
+        &self,
+        branch_name: &str,
+        rel_path: &str,
+        src: &Path,
+        delta: &Path,
+    ) -> Result<()> {
+        let meta = fs::metadata(src)?;
+        let own = self.with_branch(branch_name, |b| Ok(b.delta_path(rel_path)))?;
+        let redirect = Redirect {
+            branch: branch_name.to_string(),
+            rel_path: rel_path.to_string(),
+            source_len: meta.len(),
+            written: Vec::new(),
+            log: (own == delta).then(|| {
+                self.storage_path
+                    .join("branches")
+                    .join(branch_name)
+                    .join(REDIRECTS_FILE)
+            }),
+        };
+        // Logged before the sparse file exists, which is all zeros without it
+        redirect.log(
+            &RedirectLogEntry::New {
+                path: rel_path.to_string(),
+                source_len: meta.len(),
+            },
+            true,
+        )?;
+        storage::ensure_parent_dirs(delta)?;
+        File::create(delta)?.set_len(meta.len())?;
+        fs::set_permissions(delta, meta.permissions())?;
+        self.redirects.lock().insert(delta.to_path_buf(), redirect);
+        Ok(())
+    }
+
+    pub fn is_redirected(&self, path: &Path) -> bool {
+        self.redirects.lock().contains_key(path)
+    }
+
+    /// Mark `len` bytes at `offset` of a redirected delta as the branch's
+    /// own.  Called before the data is written, so materializing the file
+    /// concurrently cannot copy inherited bytes over it; `sync` fsyncs the
+    /// log for a write that is flushed before it returns.
+    pub fn record_redirect_write(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+        sync: bool,
+    ) -> Result<()> {
+        if let Some(redirect) = self.redirects.lock().get_mut(path) {
+            if !redirect.gaps(offset, offset + len).is_empty() {
+                redirect.log(
+                    &RedirectLogEntry::Write {
+                        path: redirect.rel_path.clone(),
+                        start: offset,
+                        end: offset + len,
+                    },
+                    sync,
+                )?;
+                redirect.mark_written(offset, offset + len);
+            }
+        }
+        Ok(())
+    }
+
+    /// Account for a redirected delta being resized to `size`.
+    pub fn truncate_redirect(&self, path: &Path, size: u64) -> Result<()> {
+        if let Some(redirect) = self.redirects.lock().get_mut(path) {
+            if size < redirect.source_len {
+                redirect.log(
+                    &RedirectLogEntry::Truncate {
+                        path: redirect.rel_path.clone(),
+                        size,
+                    },
+                    false,
+                )?;
+                redirect.truncate(size);
+            }
+        }
+        Ok(())
     }
 
-    /// Register an opened file inode for cache invalidation tracking
-    pub fn register_opened_inode(&self, branch_name: &str, ino: u64) {
-        self.opened_inodes
-            .lock()
-            .entry(branch_name.to_string())
-            .or_default()
-            .insert(ino);
+    /// Forget a redirected delta that was removed.
+    pub fn drop_redirect(&self, path: &Path) -> Result<()> {
+        let mut redirects = self.redirects.lock();
+        Self::forget_redirect(&mut redirects, path)
     }
 
-    /// Invalidate kernel cache for all mounts
-    fn invalidate_all_mounts(&self) {
-        let notifiers = self.notifiers.lock();
-        let opened_inodes = self.opened_inodes.lock();
+    /// fsync the log of a redirected delta, so the ranges written into it
+    /// are as durable as its data.
+    pub fn sync_redirect(&self, path: &Path) -> Result<()> {
+        if let Some(log) = self.redirects.lock().get(path).and_then(|r| r.log.as_ref()) {
+            File::open(log)?.sync_data()?;
+        }
+        Ok(())
+    }
 
-        for ((branch, mountpoint), notifier) in notifiers.iter() {
-            // Invalidate root inode first (directory cache)
-            if let Err(e) = notifier.inval_inode(ROOT_INO, 0, -1) {
-                log::debug!(
-                    "Failed to invalidate root inode for branch '{}' at {:?}: {}",
-                    branch,
-                    mountpoint,
-                    e
-                );
+    /// Remove `path` from `redirects` and its branch's log: the whole log
+    /// once no other redirect of the branch is left in it.
+    fn forget_redirect(
+        redirects: &mut std::collections::HashMap<PathBuf, Redirect>,
+        path: &Path,
+    ) -> Result<()> {
+        let Some(redirect) = redirects.remove(path) else {
+            return Ok(());
+        };
+        let Some(log) = &redirect.log else {
+            return Ok(());
+        };
+        if redirects.values().any(|r| r.log.as_ref() == Some(log)) {
+            redirect.log(
+                &RedirectLogEntry::Drop {
+                    path: redirect.rel_path.clone(),
+                },
+                false,
+            )
+        } else {
+            match fs::remove_file(log) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
             }
+        }
+    }
 
-            // Invalidate all opened file inodes for this branch
-            if let Some(inodes) = opened_inodes.get(branch) {
-                for &ino in inodes {
-                    if ino != ROOT_INO {
-                        if let Err(e) = notifier.inval_inode(ino, 0, -1) {
-                            log::debug!(
-                                "Failed to invalidate inode {} for branch '{}': {}",
-                                ino,
-                                branch,
-                                e
-                            );
-                        } else {
-                            log::debug!(
-                                "Invalidated inode {} for branch '{}' at {:?}",
-                                ino,
-                                branch,
-                                mountpoint
-                            );
-                        }
+    /// Register the redirected deltas `branch_name`'s log records, as a
+    /// daemon that died left them, and rewrite the log to just those.
+    fn load_redirects(&self, branch: &Branch) -> Result<()> {
+        let log = branch.files_dir.with_file_name(REDIRECTS_FILE);
+        let Ok(file) = File::open(&log) else {
+            return Ok(());
+        };
+        let mut loaded: std::collections::HashMap<String, Redirect> =
+            std::collections::HashMap::new();
+        for line in BufReader::new(file).lines() {
+            // A torn last line is a write the crash cut short
+            let Ok(entry) = serde_json::from_str::<RedirectLogEntry>(&line?) else {
+                continue;
+            };
+            match entry {
+                RedirectLogEntry::New { path, source_len } => {
+                    let redirect = Redirect {
+                        branch: branch.name.clone(),
+                        rel_path: path.clone(),
+                        source_len,
+                        written: Vec::new(),
+                        log: Some(log.clone()),
+                    };
+                    loaded.insert(path, redirect);
+                }
+                RedirectLogEntry::Write { path, start, end } => {
+                    if let Some(r) = loaded.get_mut(&path) {
+                        r.mark_written(start, end);
                     }
                 }
+                RedirectLogEntry::Truncate { path, size } => {
+                    if let Some(r) = loaded.get_mut(&path) {
+                        r.truncate(size);
+                    }
+                }
+                RedirectLogEntry::Drop { path } => {
+                    loaded.remove(&path);
+                }
             }
+        }
+        loaded.retain(|path, _| branch.delta_path(path).is_file());
 
-            log::info!(
-                "Invalidated cache for branch '{}' at {:?}",
-                branch,
-                mountpoint
+        let tmp = log.with_extension("tmp");
+        let mut out = File::create(&tmp)?;
+        for r in loaded.values() {
+            let mut entries = vec![RedirectLogEntry::New {
+                path: r.rel_path.clone(),
+                source_len: r.source_len,
+            }];
+            entries.extend(
+                r.written
+                    .iter()
+                    .map(|&(start, end)| RedirectLogEntry::Write {
+                        path: r.rel_path.clone(),
+                        start,
+                        end,
+                    }),
             );
+            for entry in entries {
+                serde_json::to_writer(&mut out, &entry)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        out.sync_data()?;
+        if loaded.is_empty() {
+            fs::remove_file(&tmp)?;
+            fs::remove_file(&log)?;
+        } else {
+            fs::rename(&tmp, &log)?;
+        }
+
+        let mut redirects = self.redirects.lock();
+        for (path, redirect) in loaded {
+            redirects.insert(branch.delta_path(&path), redirect);
         }
+        Ok(())
     }
 
-    /// Invalidate kernel cache for specific branches
-    pub fn invalidate_branches(&self, branch_names: &[String]) {
-        let notifiers = self.notifiers.lock();
-        let opened_inodes = self.opened_inodes.lock();
-
-        for ((branch, mountpoint), notifier) in notifiers.iter() {
-            if branch_names.contains(branch) {
-                // Invalidate root inode
-                if let Err(e) = notifier.inval_inode(ROOT_INO, 0, -1) {
-                    log::debug!(
-                        "Failed to invalidate root inode for branch '{}' at {:?}: {}",
-                        branch,
-                        mountpoint,
-                        e
-                    );
-                }
+    /// Read from a redirected delta, taking unwritten ranges from the file
+    /// it inherits.  Returns `None` if `path` is not redirected.
+    pub fn read_redirected(
+        &self,
+        path: &Path,
+        offset: u64,
+        size: usize,
+    ) -> Option<Result<Vec<u8>>> {
+        let branches = self.branches.read();
+        let redirects = self.redirects.lock();
+        redirects.get(path)?;
+        Some(self.read_overlay(&branches, &redirects, path, offset, size))
+    }
 
-                // Invalidate all opened file inodes
-                if let Some(inodes) = opened_inodes.get(branch) {
-                    for &ino in inodes {
-                        if ino != ROOT_INO {
-                            if let Err(e) = notifier.inval_inode(ino, 0, -1) {
-                                log::debug!(
-                                    "Failed to invalidate inode {} for branch '{}': {}",
-                                    ino,
-                                    branch,
-                                    e
-                                );
-                            }
-                        }
-                    }
-                }
+    fn read_overlay(
+        &self,
+        branches: &std::collections::HashMap<String, Branch>,
+        redirects: &std::collections::HashMap<PathBuf, Redirect>,
+        path: &Path,
+        offset: u64,
+        size: usize,
+    ) -> Result<Vec<u8>> {
+        let mut buf = read_at(path, offset, size)?;
+        let redirect = match redirects.get(path) {
+            Some(r) => r,
+            None => return Ok(buf),
+        };
+        let gaps = redirect.gaps(offset, offset + buf.len() as u64);
+        if gaps.is_empty() {
+            return Ok(buf);
+        }
 
-                log::info!(
-                    "Invalidated cache for branch '{}' at {:?}",
-                    branch,
-                    mountpoint
-                );
+        // The inherited file may itself be a redirect of an ancestor's
+        let source = self.inherited_path(branches, &redirect.branch, &redirect.rel_path);
+        for (start, end) in gaps {
+            let dst = &mut buf[(start - offset) as usize..(end - offset) as usize];
+            dst.fill(0);
+            if let Some(src) = &source {
+                let data =
+                    self.read_overlay(branches, redirects, src, start, (end - start) as usize)?;
+                dst[..data.len()].copy_from_slice(&data);
             }
         }
+        Ok(buf)
     }
 
-    /// Return the names of branches whose parent is `parent_name`.
-    pub fn get_children(&self, parent_name: &str) -> Vec<String> {
-        self.branches
-            .read()
-            .values()
-            .filter(|b| b.parent.as_deref() == Some(parent_name))
-            .map(|b| b.name.clone())
-            .collect()
+    /// What `rel_path` resolves to for `branch_name` ignoring the branch's
+    /// own delta.
+    fn inherited_path(
+        &self,
+        branches: &std::collections::HashMap<String, Branch>,
+        branch_name: &str,
+        rel_path: &str,
+    ) -> Option<PathBuf> {
+        match branches.get(branch_name)?.parent.as_deref() {
+            Some(parent) => self.resolve_in(branches, parent, rel_path).ok()?,
+            None => {
+                let base = storage::join(&self.base_path, rel_path);
+                base.exists().then_some(base)
+            }
+        }
     }
 
-    pub fn resolve_path(&self, branch_name: &str, rel_path: &str) -> Result<Option<PathBuf>> {
+    /// Copy the inherited ranges into a redirected delta so it stands on its
+    /// own.  Paths that are not redirected are left alone.
+    pub fn materialize_redirect(&self, path: &Path) -> Result<()> {
         let branches = self.branches.read();
+        let mut redirects = self.redirects.lock();
+        self.materialize_locked(&branches, &mut redirects, path)
+    }
 
-        let mut current = branch_name;
-        loop {
-            let branch = branches
-                .get(current)
-                .ok_or_else(|| BranchError::NotFound(current.to_string()))?;
+    /// Materialize every redirected delta.  Done before anything reads or
+    /// moves delta files from outside a mount (commit, rename, backup, ...),
+    /// and before a commit changes what the redirects inherit.
+    pub fn materialize_redirects(&self) -> Result<()> {
+        let branches = self.branches.read();
+        let mut redirects = self.redirects.lock();
+        let paths: Vec<PathBuf> = redirects.keys().cloned().collect();
+        for path in paths {
+            self.materialize_locked(&branches, &mut redirects, &path)?;
+        }
+        Ok(())
+    }
 
-            if branch.is_deleted(rel_path) {
-                return Ok(None);
-            }
+    fn materialize_locked(
+        &self,
+        branches: &std::collections::HashMap<String, Branch>,
+        redirects: &mut std::collections::HashMap<PathBuf, Redirect>,
+        path: &Path,
+    ) -> Result<()> {
+        use std::io::{Seek, SeekFrom};
+        const CHUNK: u64 = 1024 * 1024;
 
-            if branch.has_delta(rel_path) {
-                return Ok(Some(branch.delta_path(rel_path)));
-            }
+        let (gaps, source) = match redirects.get(path) {
+            Some(r) if path.exists() => (
+                r.gaps(0, r.source_len),
+                self.inherited_path(branches, &r.branch, &r.rel_path),
+            ),
+            Some(_) => return Self::forget_redirect(redirects, path),
+            None => return Ok(()),
+        };
 
-            match &branch.parent {
-                Some(parent) => current = parent,
-                None => break,
+        if let Some(src) = source.filter(|_| !gaps.is_empty()) {
+            let mut file = fs::OpenOptions::new().write(true).open(path)?;
+            for (start, end) in gaps {
+                let mut pos = start;
+                while pos < end {
+                    let len = (end - pos).min(CHUNK) as usize;
+                    let data = self.read_overlay(branches, redirects, &src, pos, len)?;
+                    if data.is_empty() {
+                        break;
+                    }
+                    file.seek(SeekFrom::Start(pos))?;
+                    file.write_all(&data)?;
+                    pos += data.len() as u64;
+                }
             }
+            // The log no longer covers the copied ranges once it drops them
+            file.sync_data()?;
         }
+        Self::forget_redirect(redirects, path)
+    }
 
-        let base = self.base_path.join(rel_path.trim_start_matches('/'));
-        if base.exists() {
-            Ok(Some(base))
-        } else {
-            Ok(None)
+    /// Write a branch's whole view (the base with every delta in its
+    /// ancestry, minus deletions) to `writer` as a tar archive, keeping
+    /// modes, owners, mtimes and symlinks.  Returns the number of entries.
+    ///
+    /// Redirected files are materialized first so the archive holds their
+    /// full content; nothing in the branch's view changes.
+    pub fn export_branch(&self, branch_name: &str, writer: impl Write) -> Result<usize> {
+        if !self.is_branch_valid(branch_name) {
+            return Err(BranchError::NotFound(branch_name.to_string()));
         }
-    }
+        self.materialize_redirects()?;
 
-    /// Returns true if no other branch has `parent == name`.
-    fn is_leaf(name: &str, branches: &std::collections::HashMap<String, Branch>) -> bool {
-        !branches.values().any(|b| b.parent.as_deref() == Some(name))
-    }
+        let mut entries = Vec::new();
+        self.merged_entries(branch_name, "", "", &mut entries)?;
 
-    /// Commit a leaf branch into its immediate parent.
-    /// Returns the parent branch name on success.
-    pub fn commit(&self, branch_name: &str) -> Result<String> {
-        let start = Instant::now();
-        if branch_name == "main" {
-            return Err(BranchError::CannotOperateOnMain);
+        let mut archive = tar::Builder::new(writer);
+        archive.follow_symlinks(false);
+        let mut count = 0;
+        for (rel, _) in &entries {
+            // Gone since the listing (e.g. deleted through a mount)
+            let Some(path) = self.resolve_path(branch_name, rel)? else {
+                continue;
+            };
+            archive.append_path_with_name(&path, storage::to_os_path(&rel[1..]))?;
+            count += 1;
         }
+        archive.into_inner()?.flush()?;
 
-        let mut branches = self.branches.write();
-
-        let branch = branches
-            .get(branch_name)
-            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+        log::info!("Exported {} entries of branch '{}'", count, branch_name);
+        Ok(count)
+    }
 
-        if !Self::is_leaf(branch_name, &branches) {
-            return Err(BranchError::NotALeaf(branch_name.to_string()));
+    /// Create branch `name` off `parent` with the contents of the tar
+    /// archive in `reader` as its deltas.  An entry `dir/.wh.<name>`
+    /// (a whiteout, as in overlay images) deletes `dir/<name>` in the
+    /// branch instead.  Returns the number of entries unpacked and of
+    /// deletions; on failure the branch is removed again.
+    pub fn import_branch(
+        &self,
+        name: &str,
+        parent: &str,
+        reader: impl std::io::Read,
+    ) -> Result<(usize, usize)> {
+        self.create_branch(name, parent, None)?;
+        let result = self.unpack_into(name, reader);
+        if result.is_err() {
+            if let Err(e) = self.abort(name) {
+                log::warn!("Failed to remove partly imported branch '{}': {}", name, e);
+            }
         }
+        let (count, deletions) = result?;
+        log::info!(
+            "Imported {} entries and {} deletions into branch '{}'",
+            count,
+            deletions,
+            name
+        );
+        Ok((count, deletions))
+    }
 
-        let parent_name = branch
-            .parent
-            .clone()
-            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
-
-        let child_tombstones = branch.get_tombstones();
-        let child_files_dir = branch.files_dir.clone();
+    fn unpack_into(&self, name: &str, reader: impl std::io::Read) -> Result<(usize, usize)> {
+        let files_dir = self.with_branch(name, |b| Ok(b.files_dir.clone()))?;
+        let mut archive = tar::Archive::new(reader);
+        // Only root may hand files to other owners
+        archive.set_preserve_ownerships(nix::unistd::geteuid().is_root());
+        archive.set_preserve_permissions(true);
 
-        if parent_name == "main" {
-            // Direct child of main: apply to base filesystem
-            // Apply tombstones as deletions
-            for path in &child_tombstones {
-                let full_path = self.base_path.join(path.trim_start_matches('/'));
-                if full_path.exists() {
-                    if full_path.is_dir() {
-                        fs::remove_dir_all(&full_path)?;
-                    } else {
-                        fs::remove_file(&full_path)?;
+        let mut count = 0;
+        let mut deletions = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let whiteout = path
+                .file_name()
+                .and_then(|n| n.as_bytes().strip_prefix(b".wh."))
+                .filter(|n| !n.is_empty())
+                .map(|n| path.with_file_name(std::ffi::OsStr::from_bytes(n)));
+            if let Some(deleted) = whiteout {
+                let mut rel = String::new();
+                for component in deleted.components() {
+                    match component {
+                        std::path::Component::CurDir => {}
+                        std::path::Component::Normal(name) => {
+                            rel.push('/');
+                            rel.push_str(&storage::name_to_string(name));
+                        }
+                        _ => {
+                            return Err(BranchError::Invalid(format!(
+                                "whiteout {:?} leaves the branch",
+                                path
+                            )))
+                        }
                     }
                 }
+                deletions.push(rel);
+                continue;
             }
+            // Refuses absolute paths and `..`, including through symlinks
+            if !entry.unpack_in(&files_dir)? {
+                return Err(BranchError::Invalid(format!(
+                    "archive entry {:?} leaves the branch",
+                    path
+                )));
+            }
+            count += 1;
+        }
 
-            // Copy delta files to base
-            let mut num_files = 0u64;
-            let mut total_bytes = 0u64;
-            self.walk_files(&child_files_dir, "", &mut |rel_path, src_path| {
-                let dest = self.base_path.join(rel_path.trim_start_matches('/'));
-                if let Some(parent_dir) = dest.parent() {
-                    let _ = fs::create_dir_all(parent_dir);
-                }
-                if let Ok(meta) = src_path.metadata() {
-                    total_bytes += meta.len();
-                }
-                let _ = fs::copy(src_path, &dest);
-                num_files += 1;
-            })?;
-
-            // Remove branch
-            branches.remove(branch_name);
-            let branch_dir = self.storage_path.join("branches").join(branch_name);
-            if branch_dir.exists() {
-                fs::remove_dir_all(&branch_dir)?;
+        self.with_branch(name, |branch| {
+            for path in &deletions {
+                branch.add_tombstone(path)?;
             }
+            Ok(())
+        })?;
+        Ok((count, deletions.len()))
+    }
 
-            self.epoch.fetch_add(1, Ordering::SeqCst);
+    /// Copy every branch (deltas and tombstones) into `dest` along with a
+    /// manifest describing the branch tree.
+    ///
+    /// The branch map is read-locked for the whole copy so commits and aborts
+    /// from live mounts cannot interleave with the snapshot.
+    pub fn backup(&self, dest: &Path) -> Result<usize> {
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(BranchError::Invalid(format!(
+                "backup destination {:?} is not empty",
+                dest
+            )));
+        }
+        self.materialize_redirects()?;
 
-            drop(branches);
-            self.invalidate_all_mounts();
+        let branches = self.branches.read();
+        for name in branches.keys() {
+            let src_dir = self.storage_path.join("branches").join(name);
+            storage::copy_dir_all(&src_dir, &dest.join("branches").join(name))?;
+        }
 
-            let elapsed = start.elapsed();
-            log::debug!(
-                "[BENCH] commit '{}' to base: {:?} ({} us), {} deletions, {} files, {} bytes",
-                branch_name,
-                elapsed,
-                elapsed.as_micros(),
-                child_tombstones.len(),
-                num_files,
-                total_bytes
-            );
-        } else {
-            // Nested branch: merge delta into parent's delta
-            let parent = branches
-                .get(&parent_name)
-                .ok_or_else(|| BranchError::NotFound(parent_name.to_string()))?;
+        let manifest = self.branch_manifest(&branches);
+        fs::write(
+            dest.join(BACKUP_MANIFEST),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
 
-            let parent_files_dir = parent.files_dir.clone();
-            let mut parent_tombstones = parent.get_tombstones();
+        log::info!(
+            "Backed up {} branches to {:?}",
+            manifest.branches.len(),
+            dest
+        );
+        Ok(manifest.branches.len())
+    }
 
-            // Step 1: For each child tombstone, remove matching file from parent delta
-            // and add tombstone to parent
-            for tombstone in &child_tombstones {
-                let parent_delta = parent_files_dir.join(tombstone.trim_start_matches('/'));
-                if parent_delta.exists() {
-                    if parent_delta.is_dir() {
-                        let _ = fs::remove_dir_all(&parent_delta);
-                    } else {
-                        let _ = fs::remove_file(&parent_delta);
-                    }
-                }
-                parent_tombstones.insert(tombstone.clone());
-            }
+    /// Recreate the branches recorded in a backup directory.
+    ///
+    /// Restored branch names must not already exist. The backup's `main`
+    /// deltas and tombstones are merged into the current `main`.
+    pub fn restore(&self, src: &Path) -> Result<usize> {
+        let manifest: BackupManifest =
+            serde_json::from_slice(&fs::read(src.join(BACKUP_MANIFEST))?)?;
+        // Restored `main` deltas replace files outright
+        self.materialize_redirects()?;
 
-            // Step 2: Copy child's delta files into parent's delta directory
-            let mut copied_paths = Vec::new();
-            self.walk_files(&child_files_dir, "", &mut |rel_path, src_path| {
-                let dest = parent_files_dir.join(rel_path.trim_start_matches('/'));
-                if let Some(parent_dir) = dest.parent() {
-                    let _ = fs::create_dir_all(parent_dir);
-                }
-                let _ = fs::copy(src_path, &dest);
-                copied_paths.push(rel_path.to_string());
-            })?;
+        let mut branches = self.branches.write();
 
-            // Step 3: For each copied delta file, remove that path from parent's tombstones
-            for path in &copied_paths {
-                parent_tombstones.remove(path);
+        // Validate everything up front so a bad backup restores nothing
+        let mut pending = Vec::new();
+        for entry in &manifest.branches {
+            if entry.name == "main" {
+                continue;
+            }
+            validate_branch_name(&entry.name)?;
+            if branches.contains_key(&entry.name) {
+                return Err(BranchError::AlreadyExists(entry.name.clone()));
+            }
+            let parent = entry
+                .parent
+                .clone()
+                .ok_or_else(|| BranchError::Invalid(format!("'{}' has no parent", entry.name)))?;
+            if !branches.contains_key(&parent)
+                && !manifest.branches.iter().any(|e| e.name == parent)
+            {
+                return Err(BranchError::ParentNotFound(parent));
             }
+            if !src.join("branches").join(&entry.name).is_dir() {
+                return Err(BranchError::Invalid(format!(
+                    "backup is missing data for branch '{}'",
+                    entry.name
+                )));
+            }
+            pending.push((
+                entry.name.clone(),
+                parent,
+                entry.created_at,
+                entry.created_by.clone(),
+            ));
+        }
 
-            // Write updated tombstones to parent
-            parent.set_tombstones(parent_tombstones)?;
+        let order = parents_first(pending, branches.keys().cloned().collect())?;
 
-            // Remove child branch
-            branches.remove(branch_name);
-            let branch_dir = self.storage_path.join("branches").join(branch_name);
-            if branch_dir.exists() {
-                fs::remove_dir_all(&branch_dir)?;
+        // Merge main's deltas and tombstones into the live main branch
+        let main_src = src.join("branches").join("main");
+        if main_src.is_dir() {
+            let main = branches
+                .get("main")
+                .ok_or_else(|| BranchError::NotFound("main".to_string()))?;
+            let files_src = main_src.join("files");
+            if files_src.is_dir() {
+                storage::copy_dir_all(&files_src, &main.files_dir)?;
             }
+            let mut tombstones = main.get_tombstones();
+            tombstones.extend(Branch::load_tombstones(&main_src.join("tombstones"))?);
+            main.set_tombstones(tombstones)?;
+        }
 
-            self.epoch.fetch_add(1, Ordering::SeqCst);
+        let restored = order.len();
+        for (name, parent, created_at, created_by) in order {
+            let branch_src = src.join("branches").join(&name);
+            let branch_dir = self.storage_path.join("branches").join(&name);
+            storage::copy_dir_all(&branch_src, &branch_dir)?;
+            // Copied files share no inode with another branch
+            let _ = fs::remove_file(branch_dir.join(dedup::SHARED_FILE));
+            let mut branch = Branch::new(&name, Some(&parent), &self.storage_path)?;
+            branch.created_by = created_by;
+            // Backups without timestamps fall back to the branch dir's mtime
+            branch.created_at = match created_at {
+                Some(secs) => UNIX_EPOCH + std::time::Duration::from_secs(secs),
+                None => fs::metadata(&branch_src)
+                    .and_then(|m| m.modified())
+                    .unwrap_or_else(|_| SystemTime::now()),
+            };
+            branches.insert(name, branch);
+        }
 
-            let affected = vec![branch_name.to_string(), parent_name.clone()];
-            drop(branches);
-            self.invalidate_branches(&affected);
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.save_branch_tree(&branches);
+        drop(branches);
+        self.invalidate_all_mounts();
 
-            let elapsed = start.elapsed();
-            log::debug!(
-                "[BENCH] commit '{}' into parent '{}': {:?} ({} us)",
-                branch_name,
-                parent_name,
-                elapsed,
-                elapsed.as_micros(),
-            );
-        }
+        log::info!("Restored {} branches from {:?}", restored, src);
+        Ok(restored)
+    }
 
-        Ok(parent_name)
+    /// Manifest describing every branch in `branches`
+    fn branch_manifest(
+        &self,
+        branches: &std::collections::HashMap<String, Branch>,
+    ) -> BackupManifest {
+        BackupManifest {
+            base_path: self.base_path.clone(),
+            branches: branches
+                .iter()
+                .map(|(name, branch)| BackupEntry {
+                    name: name.clone(),
+                    parent: branch.parent.clone(),
+                    created_at: Some(unix_secs(branch.created_at)),
+                    created_by: branch.created_by.clone(),
+                })
+                .collect(),
+        }
     }
 
-    /// Abort a leaf branch, discarding only that branch.
-    /// Returns the parent branch name on success.
-    pub fn abort(&self, branch_name: &str) -> Result<String> {
-        let start = Instant::now();
-        if branch_name == "main" {
-            return Err(BranchError::CannotOperateOnMain);
+    /// Record a fully staged commit in the journal and apply it.  The
+    /// caller removes the journal once the branch tree reflects the
+    /// commit; if applying fails, the journal goes at once, as there is
+    /// nothing left to roll forward.
+    fn apply_commit(&self, mut journal: CommitJournal) -> Result<()> {
+        crash_point("staged");
+        if let Err(e) = journal.write(&self.storage_path) {
+            journal.commit.discard();
+            return Err(e);
         }
+        if let Err(e) = journal.commit.apply(&journal.deletions) {
+            CommitJournal::remove(&self.storage_path);
+            journal.commit.discard();
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        let mut branches = self.branches.write();
+    /// Start a commit into `target` staged in `staging`, naming `staging`
+    /// in `COMMIT_STAGING_FILE` first.
+    fn stage_commit(
+        &self,
+        target: &Path,
+        staging: PathBuf,
+        hardlinks: HardlinkMode,
+        mtime: CommitMtime,
+    ) -> Result<StagedCommit> {
+        let mut marker = File::create(self.storage_path.join(COMMIT_STAGING_FILE))?;
+        marker.write_all(staging.as_os_str().as_bytes())?;
+        marker.sync_all()?;
+        StagedCommit::new(target, staging, hardlinks, mtime)
+    }
 
-        let branch = branches
-            .get(branch_name)
-            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+    /// Where committing `branch_name` into its parent branch stages files:
+    /// in the branch's own storage, on the parent delta's filesystem,
+    /// cleared of what an earlier commit left there.
+    fn commit_staging(&self, branch_name: &str) -> Result<PathBuf> {
+        let staging = self
+            .storage_path
+            .join("branches")
+            .join(branch_name)
+            .join("staging");
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        Ok(staging)
+    }
 
-        if !Self::is_leaf(branch_name, &branches) {
-            return Err(BranchError::NotALeaf(branch_name.to_string()));
+    /// Write the branch tree to `BRANCH_TREE_FILE` (the caller holds the
+    /// branch map lock).  Failures are only logged: the tree is needed
+    /// after a crash, not for the running daemon.
+    fn save_branch_tree(&self, branches: &std::collections::HashMap<String, Branch>) {
+        let path = self.storage_path.join(BRANCH_TREE_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let result = serde_json::to_vec_pretty(&self.branch_manifest(branches))
+            .map_err(BranchError::from)
+            .and_then(|data| {
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, &path)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to record branch tree in {:?}: {}", path, e);
         }
+    }
 
-        let parent_name = branch
-            .parent
-            .clone()
-            .ok_or_else(|| BranchError::NotFound(branch_name.to_string()))?;
+    /// Re-register the branches of a tree recorded by a daemon that did not
+    /// exit cleanly (see `read_branch_tree`), keeping their deltas and
+    /// tombstones.  Branches whose storage is gone are dropped; their
+    /// children are kept as orphans (see `orphans`).  Returns how many
+    /// branches were recovered.
+    pub fn recover_branches(&self, manifest: BackupManifest) -> Result<usize> {
+        if manifest.base_path != self.base_path {
+            return Err(BranchError::Invalid(format!(
+                "branch tree was recorded for base {:?}",
+                manifest.base_path
+            )));
+        }
 
-        // Remove only this branch
-        branches.remove(branch_name);
-        let branch_dir = self.storage_path.join("branches").join(branch_name);
-        if branch_dir.exists() {
-            fs::remove_dir_all(&branch_dir)?;
+        let mut branches = self.branches.write();
+        let mut pending = Vec::new();
+        for entry in manifest.branches {
+            if entry.name == "main" {
+                continue;
+            }
+            let Some(parent) = entry.parent else {
+                log::warn!("Dropping recorded branch '{}' without a parent", entry.name);
+                continue;
+            };
+            if validate_branch_name(&entry.name).is_err()
+                || !self
+                    .storage_path
+                    .join("branches")
+                    .join(&entry.name)
+                    .is_dir()
+            {
+                log::warn!("Dropping recorded branch '{}': no storage", entry.name);
+                continue;
+            }
+            pending.push((entry.name, parent, entry.created_at, entry.created_by));
         }
 
-        // Invalidate kernel cache for this branch only
-        drop(branches);
-        self.invalidate_branches(&[branch_name.to_string()]);
+        let recovered = pending.len();
+        for (name, parent, created_at, created_by) in pending {
+            let mut branch = Branch::new(&name, Some(&parent), &self.storage_path)?;
+            branch.created_by = created_by;
+            if let Some(secs) = created_at {
+                branch.created_at = UNIX_EPOCH + Duration::from_secs(secs);
+            }
+            self.load_redirects(&branch)?;
+            branches.insert(name, branch);
+        }
+        for (name, parent) in Self::orphans_in(&branches) {
+            log::warn!(
+                "Recovered branch '{}' is an orphan: parent '{}' is gone",
+                name,
+                parent
+            );
+        }
+        self.save_branch_tree(&branches);
 
-        let elapsed = start.elapsed();
-        log::debug!(
-            "[BENCH] abort '{}': {:?} ({} us)",
-            branch_name,
-            elapsed,
-            elapsed.as_micros()
-        );
+        log::info!("Recovered {} branches", recovered);
+        Ok(recovered)
+    }
 
-        Ok(parent_name)
+    /// Forget the recorded branch tree; called when the daemon exits
+    /// cleanly, since the next one starts from `main` again.
+    pub fn clear_branch_tree(&self) {
+        let _ = fs::remove_file(self.storage_path.join(BRANCH_TREE_FILE));
     }
 
     fn walk_files<F>(&self, dir: &Path, prefix: &str, f: &mut F) -> Result<()>
@@ -583,14 +4612,14 @@ impl BranchManager {
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
-            let name = entry.file_name().to_string_lossy().to_string();
+            let name = storage::name_to_string(&entry.file_name());
             let rel_path = if prefix.is_empty() {
                 format!("/{}", name)
             } else {
                 format!("{}/{}", prefix, name)
             };
 
-            if path.is_dir() {
+            if entry.file_type()?.is_dir() {
                 self.walk_files(&path, &rel_path, f)?;
             } else {
                 f(&rel_path, &path);
@@ -1,33 +1,302 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use fuser::{BackgroundSession, MountOption};
 use nix::unistd::{fork, setsid, ForkResult};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 
-use crate::branch::BranchManager;
+use crate::branch::{
+    read_branch_tree, recover_commit, unix_secs, BranchManager, MetricsSnapshot, PreviewFormat,
+};
 use crate::error::Result;
-use crate::fs::BranchFs;
+use crate::fs::{BranchFs, FuseFeatures, HandleStats, HandleTable, MountOptions, CTL_FILE};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "cmd", rename_all = "snake_case")]
 pub enum Request {
-    Mount { branch: String, mountpoint: String },
-    Unmount { mountpoint: String },
-    Create { name: String, parent: String },
-    NotifySwitch { mountpoint: String, branch: String },
-    GetMountBranch { mountpoint: String },
-    List,
+    Mount {
+        branch: String,
+        mountpoint: String,
+        #[serde(default)]
+        options: MountOptions,
+    },
+    Unmount {
+        mountpoint: String,
+    },
+    /// Mount `mountpoint` again after a daemon crash, on `branch` or else
+    /// on the branch (and with the options) the crashed daemon recorded
+    Remount {
+        mountpoint: String,
+        #[serde(default)]
+        branch: Option<String>,
+    },
+    Create {
+        name: String,
+        parent: String,
+        /// Recorded as the branch's creator
+        #[serde(default)]
+        created_by: Option<String>,
+    },
+    Rename {
+        old: String,
+        new: String,
+    },
+    NotifySwitch {
+        mountpoint: String,
+        branch: String,
+    },
+    GetMountBranch {
+        mountpoint: String,
+    },
+    MountInfo {
+        mountpoint: String,
+    },
+    ChildBranches {
+        branch: String,
+        mountpoint: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// Delete a branch by name, not necessarily a mount's current one;
+    /// `recursive` removes its descendants too
+    Delete {
+        name: String,
+        mountpoint: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// Remove every branch except `main`; mounts fall back to `main`
+    Purge {
+        mountpoint: String,
+    },
+    /// Switch a mount back to the branch it was on before its last switch
+    SwitchBack {
+        mountpoint: String,
+    },
+    /// Fail if the branch at `mountpoint` is another mount's current branch
+    CheckCommit {
+        mountpoint: String,
+    },
+    /// Commit the branch at `mountpoint` into its parent and switch the
+    /// mount to the parent, refusing while another mount is on the branch
+    CommitExclusive {
+        mountpoint: String,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Commit only `paths` of the branch at `mountpoint` into its parent;
+    /// the mount stays on the branch
+    CommitPaths {
+        mountpoint: String,
+        paths: Vec<String>,
+        #[serde(default)]
+        message: Option<String>,
+        /// Refuse while another mount is on the branch
+        #[serde(default)]
+        fail_if_mounted: bool,
+    },
+    /// Set the message logged with the next commit of the branch at
+    /// `mountpoint` (`None` clears it)
+    CommitMessage {
+        mountpoint: String,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Describe what committing the branch at `mountpoint` would change,
+    /// without committing
+    CommitDryRunDiff {
+        mountpoint: String,
+        #[serde(default)]
+        format: PreviewFormat,
+    },
+    DiffFile {
+        branch: String,
+        path: String,
+        mountpoint: String,
+    },
+    ReadIfEpoch {
+        branch: String,
+        path: String,
+        mountpoint: String,
+        epoch: u64,
+    },
+    Truncate {
+        branch: String,
+        path: String,
+        mountpoint: String,
+        size: u64,
+    },
+    /// Move a file or directory within a branch
+    RenamePath {
+        branch: String,
+        from: String,
+        to: String,
+        mountpoint: String,
+    },
+    /// Content hash of a file as seen through a branch
+    Hash {
+        branch: String,
+        path: String,
+        mountpoint: String,
+    },
+    /// Whether a uid/gid could access a path in a branch, by the resolved
+    /// file's mode and owner (`mask` as for `access(2)`)
+    CheckAccess {
+        branch: String,
+        path: String,
+        mountpoint: String,
+        uid: u32,
+        gid: u32,
+        mask: i32,
+    },
+    /// Mountpoints whose current branch is `branch`
+    BranchMounts {
+        branch: String,
+    },
+    /// Every mount of the daemon with its current branch
+    ListMounts,
+    /// Tombstones in effect for a branch, across its ancestors
+    Deletions {
+        branch: String,
+        mountpoint: String,
+    },
+    /// Whether committing a branch would be a no-op
+    IsEmpty {
+        branch: String,
+        mountpoint: String,
+    },
+    /// Files and tombstones in a branch's own delta
+    ListDeltas {
+        branch: String,
+        mountpoint: String,
+    },
+    /// Paths a branch added, modified or deleted relative to the base;
+    /// defaults to the mount's current branch
+    Diff {
+        mountpoint: String,
+        #[serde(default)]
+        branch: Option<String>,
+    },
+    /// Write a branch's whole view (by default the mount's current
+    /// branch) as a tar archive to `output`
+    Export {
+        mountpoint: String,
+        #[serde(default)]
+        branch: Option<String>,
+        output: String,
+    },
+    /// Create a branch holding the contents of the tar archive `input`
+    Import {
+        mountpoint: String,
+        branch: String,
+        parent: String,
+        input: String,
+    },
+    /// Copy a branch's own changes into another branch (by default the
+    /// mount's current one), keeping the target's version on conflicts
+    Merge {
+        mountpoint: String,
+        from: String,
+        #[serde(default)]
+        into: Option<String>,
+    },
+    Where {
+        branch: String,
+        path: String,
+        mountpoint: String,
+    },
+    Orphans {
+        mountpoint: String,
+        #[serde(default)]
+        repair: bool,
+    },
+    /// Check the daemon's branch state against storage while mounted
+    Validate {
+        mountpoint: String,
+    },
+    /// Test-only: bump the epoch without committing (`testing` feature)
+    #[cfg(feature = "testing")]
+    BumpEpoch {
+        mountpoint: String,
+    },
+    /// Test-only: record a notifier failure on a mount (`testing` feature)
+    #[cfg(feature = "testing")]
+    FailNotifier {
+        mountpoint: String,
+    },
+    /// Every branch, or only the children of `parent` and the branches
+    /// below `descendants_of`
+    List {
+        #[serde(default)]
+        parent: Option<String>,
+        #[serde(default)]
+        descendants_of: Option<String>,
+    },
+    BranchAge {
+        branch: String,
+    },
+    Info {
+        branch: String,
+    },
+    Backup {
+        dest: String,
+    },
+    Restore {
+        src: String,
+    },
+    /// Remove the legacy per-mount storage directory
+    Gc,
+    /// Take the advisory commit lock, lapsing after `timeout_secs` if given
+    AcquireCommitLock {
+        holder: String,
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+    },
+    ReleaseCommitLock {
+        holder: String,
+    },
+    /// Daemon-wide state: mount and handle counts, commit lock
+    Status,
+    /// Liveness probe: version, start time and mount count, answered
+    /// without touching any mount
+    Ping,
+    /// Daemon-wide counters in the Prometheus text format
+    Metrics,
     Shutdown,
 }
 
+impl Request {
+    /// The mount a request changes branches through, refused when it is
+    /// mounted `--read-only`
+    fn mutated_mount(&self) -> Option<&str> {
+        match self {
+            Request::Delete { mountpoint, .. }
+            | Request::Purge { mountpoint }
+            | Request::CommitExclusive { mountpoint, .. }
+            | Request::CommitPaths { mountpoint, .. }
+            | Request::CommitMessage { mountpoint, .. }
+            | Request::Truncate { mountpoint, .. }
+            | Request::RenamePath { mountpoint, .. }
+            | Request::Import { mountpoint, .. }
+            | Request::Merge { mountpoint, .. }
+            | Request::Orphans {
+                mountpoint,
+                repair: true,
+            } => Some(mountpoint),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
     pub ok: bool,
@@ -63,10 +332,57 @@ impl Response {
     }
 }
 
+/// How many previous branches a mount remembers for `SwitchBack`
+const BRANCH_HISTORY_LIMIT: usize = 16;
+
+/// Mounts of the running daemon, kept at the storage root for `Remount`
+/// after a crash
+const MOUNT_RECORDS_FILE: &str = "mounts.json";
+
+/// Marker holding the storage format version, written when a storage
+/// directory is first used
+const STORAGE_FORMAT_FILE: &str = "storage_format";
+
+/// Version of the storage layout this build reads and writes
+pub const STORAGE_FORMAT_VERSION: u32 = 1;
+
+/// Entries a storage directory may hold before it has a
+/// `STORAGE_FORMAT_FILE` (written by builds that predate the marker)
+const UNMARKED_STORAGE_ENTRIES: &[&str] = &[
+    "branches",
+    "branches.json",
+    "branches.json.tmp",
+    "mounts",
+    "mounts.json",
+    "mounts.json.tmp",
+    "base_path",
+    "daemon.sock",
+    "commits.log",
+];
+
+/// A mount as recorded in `MOUNT_RECORDS_FILE`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MountRecord {
+    mountpoint: PathBuf,
+    branch: String,
+    options: MountOptions,
+}
+
 /// Per-mount state including the FUSE session and current branch
 pub struct MountInfo {
     session: BackgroundSession,
     current_branch: String,
+    /// The FUSE filesystem's own view of its current branch
+    fs_branch: Arc<RwLock<String>>,
+    /// Epoch the FUSE filesystem is current with
+    fs_epoch: Arc<AtomicU64>,
+    /// Branches this mount was on before, most recent last
+    history: Vec<String>,
+    options: MountOptions,
+    /// The FUSE filesystem's open handles, reaped from the poll loop
+    handles: Arc<Mutex<HandleTable>>,
+    /// Kernel features the mount negotiated, once its `init` ran
+    features: Arc<Mutex<Option<FuseFeatures>>>,
 }
 
 pub struct Daemon {
@@ -74,6 +390,13 @@ pub struct Daemon {
     mounts: Mutex<HashMap<PathBuf, MountInfo>>,
     socket_path: PathBuf,
     shutdown: AtomicBool,
+    /// Set when the last mount goes away and cleared by a new mount, so a
+    /// remount that races the auto-exit keeps the daemon alive
+    idle: AtomicBool,
+    /// Mounts the previous daemon had when it died, until remounted
+    recovered_mounts: Mutex<HashMap<PathBuf, MountRecord>>,
+    /// When this daemon started, reported by `Ping`
+    started_at: SystemTime,
 }
 
 impl Daemon {
@@ -83,22 +406,31 @@ impl Daemon {
         _workspace_path: PathBuf,
     ) -> Result<Self> {
         let socket_path = storage_path.join("daemon.sock");
+        check_storage_format(&storage_path)?;
 
-        // Clean up branches from previous daemon run for fresh state
-        let branches_dir = storage_path.join("branches");
-        if branches_dir.exists() {
-            if let Err(e) = fs::remove_dir_all(&branches_dir) {
-                log::warn!("Failed to clean up branches directory: {}", e);
+        // A commit the previous daemon died in is finished (or, if it was
+        // still staging, dropped) before anything reads the branches
+        recover_commit(&storage_path);
+
+        // A daemon that exited cleanly removed its branch tree; one that
+        // died left it behind, and its branches are picked up again.
+        // Otherwise start fresh.
+        let tree = read_branch_tree(&storage_path).filter(|tree| tree.base_path == base_path);
+        let mut recovered_mounts = HashMap::new();
+        if tree.is_some() {
+            recovered_mounts = read_mount_records(&storage_path);
+        } else {
+            let branches_dir = storage_path.join("branches");
+            if branches_dir.exists() {
+                if let Err(e) = fs::remove_dir_all(&branches_dir) {
+                    log::warn!("Failed to clean up branches directory: {}", e);
+                }
             }
+            let _ = fs::remove_file(storage_path.join(MOUNT_RECORDS_FILE));
         }
 
         // Also clean up legacy mounts directory if present
-        let mounts_dir = storage_path.join("mounts");
-        if mounts_dir.exists() {
-            if let Err(e) = fs::remove_dir_all(&mounts_dir) {
-                log::warn!("Failed to clean up orphaned mounts directory: {}", e);
-            }
-        }
+        gc_mount_dirs(&storage_path);
 
         // Store base_path for later use (simple file, not state.json)
         let base_file = storage_path.join("base_path");
@@ -111,12 +443,26 @@ impl Daemon {
             base_path.clone(),
             base_path.clone(),
         )?);
+        if let Some(tree) = tree {
+            if let Err(e) = manager.recover_branches(tree) {
+                log::warn!("Failed to recover branches of the previous daemon: {}", e);
+            }
+        }
+        if !recovered_mounts.is_empty() {
+            log::info!(
+                "{} mounts of the previous daemon can be remounted",
+                recovered_mounts.len()
+            );
+        }
 
         Ok(Self {
             manager,
             mounts: Mutex::new(HashMap::new()),
             socket_path,
             shutdown: AtomicBool::new(false),
+            idle: AtomicBool::new(false),
+            recovered_mounts: Mutex::new(recovered_mounts),
+            started_at: SystemTime::now(),
         })
     }
 
@@ -124,8 +470,47 @@ impl Daemon {
         &self.socket_path
     }
 
-    pub fn spawn_mount(&self, branch_name: &str, mountpoint: &Path) -> Result<()> {
-        let fs = BranchFs::new(self.manager.clone(), branch_name.to_string());
+    pub fn spawn_mount(
+        &self,
+        branch_name: &str,
+        mountpoint: &Path,
+        mount_options: MountOptions,
+    ) -> Result<()> {
+        if self.mounts.lock().contains_key(mountpoint) {
+            return Err(crate::error::BranchError::Invalid(format!(
+                "{:?} is already mounted",
+                mountpoint
+            )));
+        }
+
+        if let Some(subdir) = &mount_options.subdir {
+            let rel = Path::new(subdir);
+            if !rel
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(crate::error::BranchError::Invalid(format!(
+                    "subdir must be a relative path inside the base: {}",
+                    subdir
+                )));
+            }
+            if !self.manager.base_path.join(rel).is_dir() {
+                return Err(crate::error::BranchError::Invalid(format!(
+                    "subdir is not a directory in the base: {}",
+                    subdir
+                )));
+            }
+        }
+
+        let fs = BranchFs::new(
+            self.manager.clone(),
+            branch_name.to_string(),
+            mount_options.clone(),
+        );
+        let fs_branch = fs.branch_handle();
+        let fs_epoch = fs.epoch_handle();
+        let handles = fs.handle_table();
+        let features = fs.features_handle();
         let options = vec![
             MountOption::FSName("branchfs".to_string()),
             MountOption::DefaultPermissions,
@@ -148,40 +533,100 @@ impl Daemon {
         let mount_info = MountInfo {
             session,
             current_branch: branch_name.to_string(),
+            fs_branch,
+            fs_epoch,
+            history: Vec::new(),
+            options: mount_options,
+            handles,
+            features,
         };
 
-        self.mounts
-            .lock()
-            .insert(mountpoint.to_path_buf(), mount_info);
+        let mut mounts = self.mounts.lock();
+        mounts.insert(mountpoint.to_path_buf(), mount_info);
+        self.save_mount_records(&mounts);
+        self.idle.store(false, Ordering::SeqCst);
 
         Ok(())
     }
 
     pub fn unmount(&self, mountpoint: &Path) -> Result<()> {
-        let (should_shutdown, mount_info) = {
-            let mut mounts = self.mounts.lock();
-            if let Some(info) = mounts.remove(mountpoint) {
-                log::info!("Unmounted {:?}", mountpoint);
-                // The BackgroundSession drop will handle FUSE cleanup
-                (mounts.is_empty(), Some(info))
-            } else {
-                return Err(crate::error::BranchError::MountNotFound(format!(
-                    "{:?}",
-                    mountpoint
-                )));
-            }
-        };
+        // Keep the lock until the session is gone and the idle state is
+        // decided, so a mount of the same path cannot interleave with it
+        let mut mounts = self.mounts.lock();
+        let info = mounts
+            .get(mountpoint)
+            .ok_or_else(|| crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint)))?;
+        self.finish_branch_on_unmount(&mounts, mountpoint, info)?;
+        let info = mounts
+            .remove(mountpoint)
+            .ok_or_else(|| crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint)))?;
+        self.manager
+            .unregister_notifier(&info.current_branch, mountpoint);
+        // The BackgroundSession drop will handle FUSE cleanup
+        drop(info);
+        self.save_mount_records(&mounts);
+        log::info!("Unmounted {:?}", mountpoint);
 
-        if let Some(info) = mount_info {
-            self.manager
-                .unregister_notifier(&info.current_branch, mountpoint);
+        if mounts.is_empty() {
+            log::info!("All mounts removed, daemon will exit");
+            self.idle.store(true, Ordering::SeqCst);
         }
 
-        if should_shutdown {
-            log::info!("All mounts removed, daemon will exit");
-            self.shutdown.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Commit or abort the branch of a mount set up with `--auto-commit` or
+    /// `--auto-abort` before it goes away.  Refused while another mount is
+    /// on the same branch, and any failure (open writers, children) keeps
+    /// the mount in place.
+    fn finish_branch_on_unmount(
+        &self,
+        mounts: &HashMap<PathBuf, MountInfo>,
+        mountpoint: &Path,
+        info: &MountInfo,
+    ) -> Result<()> {
+        let options = &info.options;
+        if !options.auto_commit && !options.auto_abort {
+            return Ok(());
+        }
+        let branch = info.fs_branch.read().clone();
+        if branch == "main" || !self.manager.is_branch_valid(&branch) {
+            return Ok(());
         }
 
+        let action = if options.auto_commit {
+            "commit"
+        } else {
+            "abort"
+        };
+        let others: Vec<String> = mounts
+            .iter()
+            .filter(|(path, other)| {
+                path.as_path() != mountpoint && *other.fs_branch.read() == branch
+            })
+            .map(|(path, _)| path.display().to_string())
+            .collect();
+        if !others.is_empty() {
+            return Err(crate::error::BranchError::Invalid(format!(
+                "branch '{}' is also mounted at {}; not running auto-{} on unmount",
+                branch,
+                others.join(", "),
+                action
+            )));
+        }
+
+        if options.auto_commit {
+            self.manager
+                .commit(&branch, options.hardlinks, options.commit_mtime, false)?;
+        } else {
+            self.manager.abort(&branch)?;
+        }
+        log::info!(
+            "Auto-{} of branch '{}' on unmount of {:?}",
+            action,
+            branch,
+            mountpoint
+        );
         Ok(())
     }
 
@@ -198,12 +643,387 @@ impl Daemon {
         }
     }
 
+    /// Mount `mountpoint` again after the daemon serving it died, on
+    /// `branch` or on the branch the old daemon recorded for it.  Returns
+    /// the branch mounted.
+    pub fn remount(&self, mountpoint: &Path, branch: Option<&str>) -> Result<String> {
+        let record = self.recovered_mounts.lock().get(mountpoint).cloned();
+        let (branch, options) = match (branch, record) {
+            (Some(branch), record) => (
+                branch.to_string(),
+                record.map(|r| r.options).unwrap_or_default(),
+            ),
+            (None, Some(record)) => (record.branch, record.options),
+            (None, None) => {
+                return Err(crate::error::BranchError::MountNotFound(format!(
+                    "{:?}",
+                    mountpoint
+                )))
+            }
+        };
+        if !self.manager.is_branch_valid(&branch) {
+            return Err(crate::error::BranchError::NotFound(branch));
+        }
+
+        // The dead daemon's FUSE mount is still attached and answers every
+        // access with ENOTCONN
+        if !self.mounts.lock().contains_key(mountpoint) && kernel_mountpoints().contains(mountpoint)
+        {
+            detach_stale_mount(mountpoint)?;
+        }
+
+        self.spawn_mount(&branch, mountpoint, options)?;
+        self.recovered_mounts.lock().remove(mountpoint);
+        log::info!("Remounted {:?} on branch '{}'", mountpoint, branch);
+        Ok(branch)
+    }
+
+    /// Record the current mounts in `MOUNT_RECORDS_FILE` (the caller holds
+    /// the mounts lock), along with recovered ones not remounted yet.
+    /// Failures are only logged.
+    fn save_mount_records(&self, mounts: &HashMap<PathBuf, MountInfo>) {
+        let mut records: Vec<MountRecord> = mounts
+            .iter()
+            .map(|(path, info)| MountRecord {
+                mountpoint: path.clone(),
+                branch: info.current_branch.clone(),
+                options: info.options.clone(),
+            })
+            .collect();
+        records.extend(
+            self.recovered_mounts
+                .lock()
+                .values()
+                .filter(|record| !mounts.contains_key(&record.mountpoint))
+                .cloned(),
+        );
+        let path = self.manager.storage_path.join(MOUNT_RECORDS_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let result = serde_json::to_vec_pretty(&records)
+            .map_err(std::io::Error::from)
+            .and_then(|data| {
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, &path)
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to record mounts in {:?}: {}", path, e);
+        }
+    }
+
+    /// Current branch of a tracked mount
+    fn mount_branch(&self, mountpoint: &Path) -> Result<String> {
+        self.mounts
+            .lock()
+            .get(mountpoint)
+            .map(|info| info.current_branch.clone())
+            .ok_or_else(|| crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint)))
+    }
+
+    /// Refuse to change branches through a `--read-only` mount, as its
+    /// filesystem does
+    fn check_writable(&self, mountpoint: &Path) -> Result<()> {
+        match self.mounts.lock().get(mountpoint) {
+            Some(info) if info.options.read_only => {
+                Err(std::io::Error::from_raw_os_error(libc::EROFS).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `path`, relative to the root of the mount at `mountpoint`, as a path
+    /// in its branches (see `subdir_path`)
+    fn mount_path(&self, mountpoint: &Path, path: &str) -> Result<String> {
+        self.mounts
+            .lock()
+            .get(mountpoint)
+            .map(|info| subdir_path(&info.options, path))
+            .ok_or_else(|| crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint)))
+    }
+
+    /// Mounts whose current branch is `branch`, sorted
+    fn mounts_on_branch(&self, branch: &str) -> Vec<PathBuf> {
+        mounts_on_branch_in(&self.mounts.lock(), branch)
+    }
+
+    /// Fail if a mount other than `exclude` is on `branch` (the caller
+    /// holds the mounts lock, so none can switch to it meanwhile)
+    fn check_no_other_mounts(
+        mounts: &HashMap<PathBuf, MountInfo>,
+        branch: &str,
+        exclude: &Path,
+    ) -> Result<()> {
+        let mut others = mounts_on_branch_in(mounts, branch);
+        others.retain(|path| path != exclude);
+        if others.is_empty() {
+            return Ok(());
+        }
+        let list: Vec<String> = others.iter().map(|p| p.display().to_string()).collect();
+        Err(crate::error::BranchError::MountedElsewhere(
+            branch.to_string(),
+            list.join(", "),
+        ))
+    }
+
     pub fn mount_count(&self) -> usize {
         self.mounts.lock().len()
     }
 
-    pub fn create_branch(&self, name: &str, parent: &str) -> Result<()> {
-        self.manager.create_branch(name, parent)
+    /// Handle counts summed over all mounts
+    fn handle_stats(&self) -> HandleStats {
+        self.mounts
+            .lock()
+            .values()
+            .fold(HandleStats::default(), |sum, info| {
+                sum + info.handles.lock().stats()
+            })
+    }
+
+    /// Kernel features negotiated by the first mount to finish `init`; all
+    /// mounts talk to the same kernel.
+    fn fuse_features(&self) -> Option<FuseFeatures> {
+        self.mounts
+            .lock()
+            .values()
+            .find_map(|info| info.features.lock().clone())
+    }
+
+    /// Drop handles that clients left unused past their mount's idle timeout.
+    fn reap_idle_handles(&self) {
+        for info in self.mounts.lock().values() {
+            info.handles.lock().reap_idle();
+        }
+    }
+
+    pub fn create_branch(&self, name: &str, parent: &str, created_by: Option<&str>) -> Result<()> {
+        self.manager.create_branch(name, parent, created_by)
+    }
+
+    /// Re-acquire the notifier of every mount whose invalidations failed.
+    fn repair_notifiers(&self) {
+        let broken = self.manager.unhealthy_notifiers();
+        if broken.is_empty() {
+            return;
+        }
+        let mounts = self.mounts.lock();
+        for path in broken {
+            if let Some(info) = mounts.get(&path) {
+                log::info!("Re-acquiring notifier for {:?}", path);
+                let notifier = Arc::new(info.session.notifier());
+                self.manager
+                    .repair_notifier(&info.current_branch, path, notifier);
+            }
+        }
+    }
+
+    /// Update the tracked branch of a mount and re-key its notifier in one
+    /// step (the caller holds the mounts lock).  Returns the previous branch.
+    fn record_switch(&self, path: &Path, info: &mut MountInfo, branch: &str) -> String {
+        let old_branch = std::mem::replace(&mut info.current_branch, branch.to_string());
+        let notifier = Arc::new(info.session.notifier());
+        self.manager
+            .swap_notifier(&old_branch, branch, path.to_path_buf(), notifier);
+        log::info!(
+            "Mount {:?} switched from '{}' to '{}'",
+            path,
+            old_branch,
+            branch
+        );
+        old_branch
+    }
+
+    /// Return a mount to the most recent branch in its history that still
+    /// exists, or to `main` when there is none.
+    pub fn switch_back(&self, mountpoint: &Path) -> Result<String> {
+        let target = {
+            let mut mounts = self.mounts.lock();
+            let info = mounts.get_mut(mountpoint).ok_or_else(|| {
+                crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint))
+            })?;
+            let mut target = "main".to_string();
+            while let Some(branch) = info.history.pop() {
+                if branch != info.current_branch && self.manager.is_branch_valid(&branch) {
+                    target = branch;
+                    break;
+                }
+            }
+            target
+        };
+
+        // Go through the mount's own ctl file so the filesystem resets its
+        // inode table and epoch exactly as for `branchfs switch`.  The FUSE
+        // session runs on its own threads, so this cannot block on us.
+        let mut ctl = fs::OpenOptions::new()
+            .write(true)
+            .open(mountpoint.join(CTL_FILE))?;
+        ctl.write_all(format!("switch:{}", target).as_bytes())?;
+
+        let mut mounts = self.mounts.lock();
+        if let Some(info) = mounts.get_mut(mountpoint) {
+            self.record_switch(mountpoint, info, &target);
+        }
+        self.save_mount_records(&mounts);
+        Ok(target)
+    }
+
+    /// Rename a branch and retarget every mount currently on it.
+    pub fn rename_branch(&self, old: &str, new: &str) -> Result<()> {
+        // Hold the mounts lock so a concurrent switch cannot slip in between
+        // the manager rename and the mount updates
+        let mut mounts = self.mounts.lock();
+        self.manager.rename_branch(old, new)?;
+        for (path, info) in mounts.iter_mut() {
+            if info.current_branch == old {
+                info.current_branch = new.to_string();
+                log::info!("Mount {:?} follows rename '{}' -> '{}'", path, old, new);
+            }
+            let mut fs_branch = info.fs_branch.write();
+            if *fs_branch == old {
+                *fs_branch = new.to_string();
+            }
+            for entry in info.history.iter_mut().filter(|b| *b == old) {
+                *entry = new.to_string();
+            }
+        }
+        self.save_mount_records(&mounts);
+        Ok(())
+    }
+
+    /// Delete a branch by name (with `recursive`, its subtree too) and move
+    /// mounts that were on a removed branch to the parent it hung from.
+    pub fn delete_branch(
+        &self,
+        mountpoint: &Path,
+        name: &str,
+        recursive: bool,
+    ) -> Result<Vec<String>> {
+        let mut mounts = self.mounts.lock();
+        if !mounts.contains_key(mountpoint) {
+            return Err(crate::error::BranchError::MountNotFound(format!(
+                "{:?}",
+                mountpoint
+            )));
+        }
+        let (removed, parent) = self.manager.delete_branch(name, recursive)?;
+        for (path, info) in mounts.iter_mut() {
+            if removed.contains(&info.current_branch) {
+                *info.fs_branch.write() = parent.clone();
+                self.record_switch(path, info, &parent);
+            }
+            info.history.retain(|b| !removed.contains(b));
+        }
+        self.save_mount_records(&mounts);
+        Ok(removed)
+    }
+
+    /// Commit `paths` of the branch at `mountpoint` into its parent (see
+    /// `BranchManager::commit_paths`), with `fail_if_mounted` only while
+    /// no other mount is on the branch.  The mount stays on the branch and
+    /// is brought up to the new epoch; other mounts go stale as after a
+    /// commit.
+    pub fn commit_paths(
+        &self,
+        mountpoint: &Path,
+        paths: &[String],
+        message: Option<String>,
+        fail_if_mounted: bool,
+    ) -> Result<Vec<String>> {
+        let mounts = self.mounts.lock();
+        let info = mounts
+            .get(mountpoint)
+            .ok_or_else(|| crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint)))?;
+        let branch = info.current_branch.clone();
+        if fail_if_mounted {
+            Self::check_no_other_mounts(&mounts, &branch, mountpoint)?;
+        }
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|p| subdir_path(&info.options, p))
+            .collect();
+        if message.is_some() {
+            self.manager.set_commit_message(&branch, message)?;
+        }
+        let result = self.manager.commit_paths(
+            &branch,
+            &paths,
+            info.options.hardlinks,
+            info.options.commit_mtime,
+        );
+        // Don't leave the message for a later commit
+        let _ = self.manager.set_commit_message(&branch, None);
+        let committed = result?;
+        if !committed.is_empty() {
+            info.fs_epoch
+                .store(self.manager.get_epoch(), Ordering::SeqCst);
+        }
+        Ok(committed)
+    }
+
+    /// Commit the branch at `mountpoint` into its parent unless another
+    /// mount is on it, checked and committed under the mounts lock so no
+    /// switch is recorded in between.  The mount then switches to the
+    /// parent through its ctl file, as `switch_back` does.
+    pub fn commit_exclusive(&self, mountpoint: &Path, force: bool) -> Result<String> {
+        let parent = {
+            let mounts = self.mounts.lock();
+            let info = mounts.get(mountpoint).ok_or_else(|| {
+                crate::error::BranchError::MountNotFound(format!("{:?}", mountpoint))
+            })?;
+            Self::check_no_other_mounts(&mounts, &info.current_branch, mountpoint)?;
+            self.manager.commit(
+                &info.current_branch,
+                info.options.hardlinks,
+                info.options.commit_mtime,
+                force,
+            )?
+        };
+
+        let mut ctl = fs::OpenOptions::new()
+            .write(true)
+            .open(mountpoint.join(CTL_FILE))?;
+        ctl.write_all(format!("switch:{}", parent).as_bytes())?;
+
+        let mut mounts = self.mounts.lock();
+        if let Some(info) = mounts.get_mut(mountpoint) {
+            self.record_switch(mountpoint, info, &parent);
+        }
+        self.save_mount_records(&mounts);
+        Ok(parent)
+    }
+
+    /// Drop all non-main branches and switch every mount to `main`.
+    pub fn purge(&self, mountpoint: &Path) -> Result<Vec<String>> {
+        let (removed, paths) = {
+            let mut mounts = self.mounts.lock();
+            if !mounts.contains_key(mountpoint) {
+                return Err(crate::error::BranchError::MountNotFound(format!(
+                    "{:?}",
+                    mountpoint
+                )));
+            }
+            let removed = self.manager.purge()?;
+            for (path, info) in mounts.iter_mut() {
+                if info.current_branch != "main" {
+                    *info.fs_branch.write() = "main".to_string();
+                    self.record_switch(path, info, "main");
+                }
+                info.history.clear();
+            }
+            self.save_mount_records(&mounts);
+            (removed, mounts.keys().cloned().collect::<Vec<_>>())
+        };
+
+        // The purge moved the epoch: switch each mount through its ctl file
+        // as `switch_back` does, so it resets its epoch and inode table
+        for path in paths {
+            let switched = fs::OpenOptions::new()
+                .write(true)
+                .open(path.join(CTL_FILE))
+                .and_then(|mut ctl| ctl.write_all(b"switch:main"));
+            if let Err(e) = switched {
+                log::warn!("Failed to switch {:?} to main after purge: {}", path, e);
+            }
+        }
+        Ok(removed)
     }
 
     pub fn list_branches(&self) -> Vec<(String, Option<String>)> {
@@ -227,13 +1047,13 @@ impl Daemon {
             .map_err(crate::error::BranchError::Io)?;
 
         log::info!("Daemon listening on {:?}", self.socket_path);
+        // Identify our socket so exiting never removes one bound by a newer
+        // daemon for the same storage
+        let socket_id = fs::metadata(&self.socket_path)
+            .map(|m| (m.dev(), m.ino()))
+            .ok();
 
         loop {
-            if self.shutdown.load(Ordering::SeqCst) {
-                log::info!("Shutdown flag set, exiting");
-                break;
-            }
-
             match listener.accept() {
                 Ok((stream, _)) => {
                     stream.set_nonblocking(false).ok();
@@ -242,6 +1062,19 @@ impl Daemon {
                     }
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.manager.flush_deferred_tombstones();
+                    self.repair_notifiers();
+                    self.reap_idle_handles();
+                    // Only exit once queued clients have been served; one of
+                    // them may be a remount that cancels the idle exit
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        log::info!("Shutdown flag set, exiting");
+                        break;
+                    }
+                    if self.idle.load(Ordering::SeqCst) {
+                        log::info!("No mounts left, exiting");
+                        break;
+                    }
                     std::thread::sleep(Duration::from_millis(100));
                 }
                 Err(e) => {
@@ -250,8 +1083,16 @@ impl Daemon {
             }
         }
 
-        if self.socket_path.exists() {
+        drop(listener);
+        let current = fs::metadata(&self.socket_path)
+            .map(|m| (m.dev(), m.ino()))
+            .ok();
+        if current.is_some() && current == socket_id {
             std::fs::remove_file(&self.socket_path).ok();
+            // A clean exit discards the branches; only a crash leaves them
+            // for the next daemon to recover
+            self.manager.clear_branch_tree();
+            let _ = fs::remove_file(self.manager.storage_path.join(MOUNT_RECORDS_FILE));
         }
 
         Ok(())
@@ -283,13 +1124,22 @@ impl Daemon {
     }
 
     fn handle_request(&self, request: Request) -> Response {
+        if let Some(mountpoint) = request.mutated_mount() {
+            if let Err(e) = self.check_writable(Path::new(mountpoint)) {
+                return Response::error(&format!("{}", e));
+            }
+        }
         match request {
-            Request::Mount { branch, mountpoint } => {
+            Request::Mount {
+                branch,
+                mountpoint,
+                options,
+            } => {
                 let path = PathBuf::from(&mountpoint);
                 if let Err(e) = fs::create_dir_all(&path) {
                     return Response::error(&format!("Failed to create mountpoint: {}", e));
                 }
-                match self.spawn_mount(&branch, &path) {
+                match self.spawn_mount(&branch, &path, options) {
                     Ok(()) => Response::success(),
                     Err(e) => Response::error(&format!("{}", e)),
                 }
@@ -301,29 +1151,43 @@ impl Daemon {
                     Err(e) => Response::error(&format!("{}", e)),
                 }
             }
-            Request::Create { name, parent } => match self.create_branch(&name, &parent) {
+            Request::Remount { mountpoint, branch } => {
+                match self.remount(Path::new(&mountpoint), branch.as_deref()) {
+                    Ok(branch) => {
+                        Response::success_with_data(serde_json::json!({ "branch": branch }))
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Create {
+                name,
+                parent,
+                created_by,
+            } => match self.create_branch(&name, &parent, created_by.as_deref()) {
+                // The branch may already be gone again (aborted from another
+                // mount); the create itself still succeeded
+                Ok(()) => match self.manager.branch_details(&name) {
+                    Ok(info) => Response::success_with_data(serde_json::json!(info)),
+                    Err(_) => Response::success(),
+                },
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::Rename { old, new } => match self.rename_branch(&old, &new) {
                 Ok(()) => Response::success(),
                 Err(e) => Response::error(&format!("{}", e)),
             },
             Request::NotifySwitch { mountpoint, branch } => {
                 let path = PathBuf::from(&mountpoint);
                 let mut mounts = self.mounts.lock();
-                if let Some(ref mut info) = mounts.get_mut(&path) {
-                    // Unregister old notifier
-                    self.manager
-                        .unregister_notifier(&info.current_branch, &path);
-                    // Update tracked branch
-                    let old_branch = std::mem::replace(&mut info.current_branch, branch.clone());
-                    // Register notifier for new branch
-                    let notifier = Arc::new(info.session.notifier());
-                    self.manager
-                        .register_notifier(&branch, path.clone(), notifier);
-                    log::info!(
-                        "Mount {:?} switched from '{}' to '{}'",
-                        path,
-                        old_branch,
-                        branch
-                    );
+                if let Some(info) = mounts.get_mut(&path) {
+                    let old_branch = self.record_switch(&path, info, &branch);
+                    if old_branch != branch {
+                        info.history.push(old_branch);
+                        if info.history.len() > BRANCH_HISTORY_LIMIT {
+                            info.history.remove(0);
+                        }
+                    }
+                    self.save_mount_records(&mounts);
                     Response::success()
                 } else {
                     Response::error(&format!("Mount not found: {:?}", path))
@@ -338,19 +1202,511 @@ impl Daemon {
                     Response::error(&format!("Mount not found: {:?}", path))
                 }
             }
-            Request::List => {
-                let branches: Vec<_> = self
-                    .list_branches()
+            Request::MountInfo { mountpoint } => {
+                let path = PathBuf::from(&mountpoint);
+                match self.mount_branch(&path) {
+                    Ok(branch) => {
+                        let branch_mounts: Vec<String> = self
+                            .mounts_on_branch(&branch)
+                            .iter()
+                            .map(|p| p.display().to_string())
+                            .collect();
+                        let fuse = self
+                            .mounts
+                            .lock()
+                            .get(&path)
+                            .and_then(|info| info.features.lock().clone());
+                        Response::success_with_data(serde_json::json!({
+                            "mountpoint": mountpoint,
+                            "branch": branch,
+                            "epoch": self.manager.get_epoch(),
+                            "opened_inodes": self.manager.opened_inode_count(&branch),
+                            "notifier_registered": self.manager.has_notifier(&branch, &path),
+                            "notifier_health": self.manager.notifier_health(&path),
+                            "counters": self.manager.branch_stats(&branch).unwrap_or_default(),
+                            "branch_mounts": branch_mounts,
+                            "open_for_write": self.manager.open_writers(&branch),
+                            "handles": self.mounts.lock().get(&path).map(|info| info.handles.lock().stats()),
+                            "fuse": fuse,
+                        }))
+                    }
+                    Err(_) => Response::error(&format!("Mount not found: {:?}", path)),
+                }
+            }
+            Request::ChildBranches {
+                branch,
+                mountpoint,
+                recursive,
+            } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|_| self.manager.child_branches(&branch, recursive));
+                match result {
+                    Ok(children) => {
+                        let children: Vec<_> = children
+                            .into_iter()
+                            .map(|(name, parent)| {
+                                serde_json::json!({
+                                    "name": name,
+                                    "parent": parent
+                                })
+                            })
+                            .collect();
+                        Response::success_with_data(serde_json::json!(children))
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Delete {
+                name,
+                mountpoint,
+                recursive,
+            } => match self.delete_branch(Path::new(&mountpoint), &name, recursive) {
+                Ok(removed) => {
+                    Response::success_with_data(serde_json::json!({ "removed": removed }))
+                }
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::Purge { mountpoint } => match self.purge(Path::new(&mountpoint)) {
+                Ok(removed) => {
+                    Response::success_with_data(serde_json::json!({ "removed": removed }))
+                }
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::SwitchBack { mountpoint } => match self.switch_back(Path::new(&mountpoint)) {
+                Ok(branch) => Response::success_with_data(serde_json::json!({ "branch": branch })),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::CommitPaths {
+                mountpoint,
+                paths,
+                message,
+                fail_if_mounted,
+            } => {
+                match self.commit_paths(Path::new(&mountpoint), &paths, message, fail_if_mounted) {
+                    Ok(committed) => Response::success_with_data(serde_json::json!(committed)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::CommitMessage {
+                mountpoint,
+                message,
+            } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|branch| self.manager.set_commit_message(&branch, message));
+                match result {
+                    Ok(()) => Response::success(),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::CheckCommit { mountpoint } => {
+                let path = PathBuf::from(&mountpoint);
+                let result = self.mount_branch(&path).and_then(|branch| {
+                    Self::check_no_other_mounts(&self.mounts.lock(), &branch, &path)?;
+                    Ok(branch)
+                });
+                match result {
+                    Ok(branch) => {
+                        Response::success_with_data(serde_json::json!({ "branch": branch }))
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::CommitExclusive { mountpoint, force } => {
+                match self.commit_exclusive(Path::new(&mountpoint), force) {
+                    Ok(parent) => {
+                        Response::success_with_data(serde_json::json!({ "branch": parent }))
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::CommitDryRunDiff { mountpoint, format } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|branch| match format {
+                        PreviewFormat::Json => self
+                            .manager
+                            .preview_commit(&branch)
+                            .map(|preview| serde_json::json!(preview)),
+                        PreviewFormat::Patch => self
+                            .manager
+                            .preview_commit_patch(&branch)
+                            .map(|patch| serde_json::json!(patch)),
+                    });
+                match result {
+                    Ok(data) => Response::success_with_data(data),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::DiffFile {
+                branch,
+                path,
+                mountpoint,
+            } => {
+                let result = self
+                    .mount_path(Path::new(&mountpoint), &path)
+                    .and_then(|path| self.manager.diff_file(&branch, &path));
+                match result {
+                    Ok(diff) => Response::success_with_data(serde_json::json!(diff)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::ReadIfEpoch {
+                branch,
+                path,
+                mountpoint,
+                epoch,
+            } => {
+                let result = self
+                    .mount_path(Path::new(&mountpoint), &path)
+                    .and_then(|path| self.manager.read_if_epoch(&branch, &path, epoch));
+                match result {
+                    Ok(Some(data)) => match String::from_utf8(data) {
+                        Ok(content) => Response::success_with_data(serde_json::json!({
+                            "stale": false,
+                            "epoch": epoch,
+                            "content": content,
+                        })),
+                        Err(_) => Response::error(&format!("{}: not valid UTF-8", path)),
+                    },
+                    Ok(None) => Response::success_with_data(serde_json::json!({
+                        "stale": true,
+                        "epoch": self.manager.get_epoch(),
+                    })),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::RenamePath {
+                branch,
+                from,
+                to,
+                mountpoint,
+            } => {
+                let mountpoint = Path::new(&mountpoint);
+                let result = self.mount_path(mountpoint, &from).and_then(|from| {
+                    let to = self.mount_path(mountpoint, &to)?;
+                    self.manager.rename_path(&branch, &from, &to)
+                });
+                match result {
+                    Ok(moved) => Response::success_with_data(serde_json::json!({ "moved": moved })),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Hash {
+                branch,
+                path,
+                mountpoint,
+            } => {
+                let result = self
+                    .mount_path(Path::new(&mountpoint), &path)
+                    .and_then(|path| self.manager.hash_file(&branch, &path));
+                match result {
+                    Ok(hash) => Response::success_with_data(serde_json::json!({
+                        "path": path,
+                        "hash": hash
+                    })),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::CheckAccess {
+                branch,
+                path,
+                mountpoint,
+                uid,
+                gid,
+                mask,
+            } => {
+                let result = self
+                    .mount_path(Path::new(&mountpoint), &path)
+                    .and_then(|path| self.manager.check_access(&branch, &path, uid, gid, mask));
+                match result {
+                    Ok(check) => Response::success_with_data(serde_json::json!(check)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::BranchMounts { branch } => {
+                if !self.manager.is_branch_valid(&branch) {
+                    return Response::error(&format!(
+                        "{}",
+                        crate::error::BranchError::NotFound(branch)
+                    ));
+                }
+                let mounts: Vec<String> = self
+                    .mounts_on_branch(&branch)
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect();
+                Response::success_with_data(serde_json::json!(mounts))
+            }
+            Request::ListMounts => {
+                let mut mounts: Vec<(PathBuf, String)> = self
+                    .mounts
+                    .lock()
+                    .iter()
+                    .map(|(path, info)| (path.clone(), info.current_branch.clone()))
+                    .collect();
+                mounts.sort();
+                let storage = self.manager.storage_path.display().to_string();
+                let mounts: Vec<serde_json::Value> = mounts
                     .into_iter()
-                    .map(|(name, parent)| {
+                    .map(|(path, branch)| {
                         serde_json::json!({
-                            "name": name,
-                            "parent": parent
+                            "mountpoint": path.display().to_string(),
+                            "current_branch": branch,
+                            "storage": storage,
+                        })
+                    })
+                    .collect();
+                Response::success_with_data(serde_json::json!(mounts))
+            }
+            Request::Deletions { branch, mountpoint } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|_| self.manager.deletions(&branch));
+                match result {
+                    Ok(deletions) => Response::success_with_data(serde_json::json!(deletions)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::IsEmpty { branch, mountpoint } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|_| self.manager.is_empty(&branch));
+                match result {
+                    Ok(empty) => Response::success_with_data(serde_json::json!({ "empty": empty })),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::ListDeltas { branch, mountpoint } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|_| self.manager.deltas(&branch));
+                match result {
+                    Ok(deltas) => Response::success_with_data(serde_json::json!(deltas)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Diff { mountpoint, branch } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|current| self.manager.diff(branch.as_deref().unwrap_or(&current)));
+                match result {
+                    Ok(diff) => Response::success_with_data(serde_json::json!(diff)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Export {
+                mountpoint,
+                branch,
+                output,
+            } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|current| {
+                        let file = fs::File::create(&output)?;
+                        let result = self.manager.export_branch(
+                            branch.as_deref().unwrap_or(&current),
+                            std::io::BufWriter::new(file),
+                        );
+                        if result.is_err() {
+                            let _ = fs::remove_file(&output);
+                        }
+                        result
+                    });
+                match result {
+                    Ok(count) => {
+                        Response::success_with_data(serde_json::json!({ "entries": count }))
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Import {
+                mountpoint,
+                branch,
+                parent,
+                input,
+            } => {
+                let result = self.mount_branch(Path::new(&mountpoint)).and_then(|_| {
+                    let file = fs::File::open(&input)?;
+                    self.manager
+                        .import_branch(&branch, &parent, std::io::BufReader::new(file))
+                });
+                match result {
+                    Ok((count, deletions)) => Response::success_with_data(serde_json::json!({
+                        "entries": count,
+                        "deletions": deletions
+                    })),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Merge {
+                mountpoint,
+                from,
+                into,
+            } => {
+                let result = self
+                    .mount_branch(Path::new(&mountpoint))
+                    .and_then(|current| {
+                        self.manager
+                            .merge(into.as_deref().unwrap_or(&current), &from)
+                    });
+                match result {
+                    Ok(report) => Response::success_with_data(serde_json::json!(report)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Where {
+                branch,
+                path,
+                mountpoint,
+            } => {
+                let result = self
+                    .mount_path(Path::new(&mountpoint), &path)
+                    .and_then(|path| self.manager.locate(&branch, &path));
+                match result {
+                    Ok(location) => Response::success_with_data(serde_json::json!(location)),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Orphans { mountpoint, repair } => {
+                match self.mount_branch(Path::new(&mountpoint)) {
+                    Ok(_) => {
+                        let orphans = if repair {
+                            self.manager.repair_orphans()
+                        } else {
+                            self.manager.orphans()
+                        };
+                        let orphans: Vec<_> = orphans
+                            .into_iter()
+                            .map(|(name, parent)| serde_json::json!({"name": name, "parent": parent}))
+                            .collect();
+                        Response::success_with_data(serde_json::json!(orphans))
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Validate { mountpoint } => match self.mount_branch(Path::new(&mountpoint)) {
+                Ok(_) => Response::success_with_data(serde_json::json!(self.manager.verify())),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::Truncate {
+                branch,
+                path,
+                mountpoint,
+                size,
+            } => {
+                let result = self
+                    .mount_path(Path::new(&mountpoint), &path)
+                    .and_then(|path| self.manager.truncate_file(&branch, &path, size));
+                match result {
+                    Ok(()) => Response::success(),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            #[cfg(feature = "testing")]
+            Request::BumpEpoch { mountpoint } => match self.mount_branch(Path::new(&mountpoint)) {
+                Ok(_) => Response::success_with_data(serde_json::json!({
+                    "epoch": self.manager.bump_epoch(),
+                })),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            #[cfg(feature = "testing")]
+            Request::FailNotifier { mountpoint } => {
+                let path = PathBuf::from(&mountpoint);
+                match self.mount_branch(&path) {
+                    Ok(_) => {
+                        self.manager.inject_notifier_failure(&path);
+                        Response::success()
+                    }
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::List {
+                parent,
+                descendants_of,
+            } => {
+                let infos = match self
+                    .manager
+                    .branch_info(parent.as_deref(), descendants_of.as_deref())
+                {
+                    Ok(infos) => infos,
+                    Err(e) => return Response::error(&format!("{}", e)),
+                };
+                let branches: Vec<_> = infos
+                    .into_iter()
+                    .map(|info| {
+                        serde_json::json!({
+                            "name": info.name,
+                            "parent": info.parent,
+                            "created_at": unix_secs(info.created_at),
+                            "created_by": info.created_by,
+                            "size": info.size,
                         })
                     })
                     .collect();
                 Response::success_with_data(serde_json::json!(branches))
             }
+            Request::Info { branch } => match self.manager.branch_details(&branch) {
+                Ok(info) => Response::success_with_data(serde_json::json!(info)),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::BranchAge { branch } => match self.manager.branch_created_at(&branch) {
+                Ok(created_at) => Response::success_with_data(serde_json::json!({
+                    "name": branch,
+                    "created_at": unix_secs(created_at),
+                    "age_secs": created_at.elapsed().map(|d| d.as_secs()).unwrap_or(0),
+                })),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::Gc => {
+                let removed: Vec<String> = gc_mount_dirs(&self.manager.storage_path)
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect();
+                Response::success_with_data(serde_json::json!({ "removed": removed }))
+            }
+            Request::AcquireCommitLock {
+                holder,
+                timeout_secs,
+            } => match self
+                .manager
+                .acquire_commit_lock(&holder, timeout_secs.map(Duration::from_secs))
+            {
+                Ok(lock) => Response::success_with_data(serde_json::json!(lock)),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::ReleaseCommitLock { holder } => {
+                match self.manager.release_commit_lock(&holder) {
+                    Ok(()) => Response::success(),
+                    Err(e) => Response::error(&format!("{}", e)),
+                }
+            }
+            Request::Status => Response::success_with_data(serde_json::json!({
+                "mounts": self.mount_count(),
+                "handles": self.handle_stats(),
+                "fuse": self.fuse_features(),
+                "commit_lock": self.manager.commit_lock(),
+                "committing": self.manager.is_committing(),
+            })),
+            Request::Ping => Response::success_with_data(serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "started_at": unix_secs(self.started_at),
+                "uptime_secs": self.started_at.elapsed().map(|d| d.as_secs()).unwrap_or(0),
+                "mounts": self.mount_count(),
+            })),
+            Request::Metrics => Response::success_with_data(serde_json::json!(render_metrics(
+                &self.manager.metrics.snapshot(),
+                self.mount_count(),
+            ))),
+            Request::Backup { dest } => match self.manager.backup(Path::new(&dest)) {
+                Ok(count) => Response::success_with_data(serde_json::json!({ "branches": count })),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
+            Request::Restore { src } => match self.manager.restore(Path::new(&src)) {
+                Ok(count) => Response::success_with_data(serde_json::json!({ "branches": count })),
+                Err(e) => Response::error(&format!("{}", e)),
+            },
             Request::Shutdown => {
                 log::info!("Shutdown requested, cleaning up all mounts");
                 self.cleanup_all_mounts();
@@ -361,8 +1717,207 @@ impl Daemon {
     }
 }
 
+/// `metrics` and the mount count in the Prometheus text exposition format.
+fn render_metrics(metrics: &MetricsSnapshot, mounts: usize) -> String {
+    let samples = [
+        (
+            "branchfs_commits_total",
+            "counter",
+            "Commits applied, whole or of selected paths",
+            metrics.commits,
+        ),
+        (
+            "branchfs_aborts_total",
+            "counter",
+            "Branches aborted",
+            metrics.aborts,
+        ),
+        (
+            "branchfs_branches_created_total",
+            "counter",
+            "Branches created",
+            metrics.branches_created,
+        ),
+        (
+            "branchfs_bytes_written_total",
+            "counter",
+            "Bytes written through the mounts",
+            metrics.bytes_written,
+        ),
+        ("branchfs_mounts", "gauge", "Active mounts", mounts as u64),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in samples {
+        out.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    }
+    out
+}
+
+/// Mounts in `mounts` whose current branch is `branch`, sorted
+fn mounts_on_branch_in(mounts: &HashMap<PathBuf, MountInfo>, branch: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = mounts
+        .iter()
+        .filter(|(_, info)| info.current_branch == branch)
+        .map(|(path, _)| path.clone())
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// `path`, relative to a mount's root, as a path in its branches: under
+/// the mount's `--subdir`, if it has one
+fn subdir_path(options: &MountOptions, path: &str) -> String {
+    match options.subdir.as_deref().map(|d| d.trim_matches('/')) {
+        Some(dir) if !dir.is_empty() => format!("/{}/{}", dir, path.trim_start_matches('/')),
+        _ => path.to_string(),
+    }
+}
+
+/// Mounts recorded by the previous daemon, keyed by mountpoint
+fn read_mount_records(storage_path: &Path) -> HashMap<PathBuf, MountRecord> {
+    let records: Vec<MountRecord> = fs::read(storage_path.join(MOUNT_RECORDS_FILE))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+    records
+        .into_iter()
+        .map(|record| (record.mountpoint.clone(), record))
+        .collect()
+}
+
+/// Lazily detach a FUSE mount whose daemon is gone, falling back to
+/// `fusermount` when we may not unmount it ourselves.
+fn detach_stale_mount(mountpoint: &Path) -> Result<()> {
+    use nix::mount::{umount2, MntFlags};
+
+    if umount2(mountpoint, MntFlags::MNT_DETACH).is_ok() {
+        return Ok(());
+    }
+    for tool in ["fusermount3", "fusermount"] {
+        let status = std::process::Command::new(tool)
+            .arg("-u")
+            .arg("-z")
+            .arg(mountpoint)
+            .status();
+        if status.is_ok_and(|s| s.success()) {
+            return Ok(());
+        }
+    }
+    Err(crate::error::BranchError::Invalid(format!(
+        "cannot detach the stale mount at {:?}",
+        mountpoint
+    )))
+}
+
+/// Mountpoints currently known to the kernel, from `/proc/mounts`.
+fn kernel_mountpoints() -> HashSet<PathBuf> {
+    // Fields are space-separated with spaces, tabs, newlines and
+    // backslashes octal-escaped
+    let unescape = |field: &str| -> PathBuf {
+        let bytes = field.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let octal = bytes
+                .get(i + 1..i + 4)
+                .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+            match octal {
+                Some(byte) if bytes[i] == b'\\' => {
+                    out.push(byte);
+                    i += 4;
+                }
+                _ => {
+                    out.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        PathBuf::from(std::ffi::OsString::from_vec(out))
+    };
+
+    fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .map(unescape)
+        .collect()
+}
+
+/// Make sure `storage_path` holds branchfs storage of this format before
+/// anything in it is touched.  A directory without the format marker is
+/// marked if it is empty or only holds what an older branchfs left there;
+/// anything else is refused so unrelated files are never cleaned up.
+pub fn check_storage_format(storage_path: &Path) -> Result<()> {
+    let marker = storage_path.join(STORAGE_FORMAT_FILE);
+    match fs::read_to_string(&marker) {
+        Ok(found) => {
+            let found = found.trim();
+            if found.parse::<u32>().ok() != Some(STORAGE_FORMAT_VERSION) {
+                return Err(crate::error::BranchError::StorageFormat(
+                    storage_path.display().to_string(),
+                    STORAGE_FORMAT_VERSION,
+                    format!("{:?}", found),
+                ));
+            }
+            return Ok(());
+        }
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        Err(_) => {}
+    }
+
+    fs::create_dir_all(storage_path)?;
+    let mut unrelated: Vec<String> = fs::read_dir(storage_path)?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !UNMARKED_STORAGE_ENTRIES.contains(&name.as_str()))
+        .collect();
+    if !unrelated.is_empty() {
+        unrelated.sort();
+        return Err(crate::error::BranchError::NotStorage(format!(
+            "{} has no {} marker and already holds other files ({}); use an empty directory",
+            storage_path.display(),
+            STORAGE_FORMAT_FILE,
+            unrelated.join(", ")
+        )));
+    }
+    fs::write(&marker, format!("{}\n", STORAGE_FORMAT_VERSION))?;
+    Ok(())
+}
+
+/// Remove the legacy `<storage>/mounts` directory, where older builds
+/// kept per-mount storage; nothing writes it any more.  Returns what was
+/// removed.
+pub fn gc_mount_dirs(storage_path: &Path) -> Vec<PathBuf> {
+    let mounts_dir = storage_path.join("mounts");
+    if !mounts_dir.exists() {
+        return Vec::new();
+    }
+    match fs::remove_dir_all(&mounts_dir) {
+        Ok(()) => vec![mounts_dir],
+        Err(e) => {
+            log::warn!("Failed to clean up orphaned mounts directory: {}", e);
+            Vec::new()
+        }
+    }
+}
+
 pub fn send_request(socket_path: &Path, request: &Request) -> std::io::Result<Response> {
+    send_request_timeout(socket_path, request, None)
+}
+
+/// `send_request`, failing with `TimedOut`/`WouldBlock` if the daemon
+/// takes longer than `timeout` to answer, so a wedged daemon is told apart
+/// from a healthy one.
+pub fn send_request_timeout(
+    socket_path: &Path,
+    request: &Request,
+    timeout: Option<Duration>,
+) -> std::io::Result<Response> {
     let mut stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
     let request_str = serde_json::to_string(request)?;
     writeln!(stream, "{}", request_str)?;
     stream.flush()?;
@@ -433,6 +1988,10 @@ pub fn ensure_daemon(base_path: Option<&Path>, storage_path: &Path) -> std::io::
         return Ok(());
     }
 
+    // Checked here too so the error reaches the caller, not just the
+    // forked daemon's log
+    check_storage_format(storage_path)?;
+
     let base_path = match base_path {
         Some(p) => p.to_path_buf(),
         None => {
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::storage;
+
+/// Inodes of a branch's delta that `dedup_deltas` hard-linked to another
+/// branch's copy, one `dev ino` per line, next to the branch's `files`.
+/// A change through a mount gives such a file an inode of its own first
+/// (`BranchManager::unshare`), so it never reaches the other branch.
+pub const SHARED_FILE: &str = "shared";
+
+/// Outcome of `dedup_deltas`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DedupReport {
+    /// Duplicates now sharing their data with a reflink
+    pub reflinked: usize,
+    /// Duplicates replaced by a hard link, where the filesystem can't
+    /// reflink
+    pub hardlinked: usize,
+    /// Duplicates left as they are: no reflinks, and a hard link would
+    /// change their mode or owner, or join links within a branch
+    pub skipped: usize,
+    /// Size of the duplicates that now share data
+    pub bytes: u64,
+}
+
+/// (dev, ino) pairs listed in a branch's `SHARED_FILE`
+pub fn load_shared(path: &Path) -> HashSet<(u64, u64)> {
+    let Ok(file) = File::open(path) else {
+        return HashSet::new();
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| {
+            let (dev, ino) = line.split_once(' ')?;
+            Some((dev.parse().ok()?, ino.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Add `key` to the `SHARED_FILE` of `branch_dir`, durably, before the
+/// link it describes exists.
+fn record_shared(branch_dir: &Path, key: (u64, u64)) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(branch_dir.join(SHARED_FILE))?;
+    writeln!(file, "{} {}", key.0, key.1)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A non-empty regular file of some branch's delta
+struct Candidate {
+    branch: String,
+    path: PathBuf,
+    meta: fs::Metadata,
+}
+
+impl Candidate {
+    fn inode(&self) -> (u64, u64) {
+        (self.meta.dev(), self.meta.ino())
+    }
+}
+
+fn collect(dir: &Path, branch: &str, out: &mut Vec<Candidate>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            collect(&entry.path(), branch, out)?;
+        } else if meta.is_file() && meta.len() > 0 {
+            out.push(Candidate {
+                branch: branch.to_string(),
+                path: entry.path(),
+                meta,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn content_hash(path: &Path) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Whether two files of the same size hold the same bytes
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let mut buf_a = vec![0u8; 1 << 16];
+    let mut buf_b = vec![0u8; 1 << 16];
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+        match b.read_exact(&mut buf_b[..n]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Make the byte-identical delta files of every branch in `storage_path`
+/// share their data.  Meant for a storage no daemon is serving: files are
+/// replaced underneath where the filesystem can't reflink.
+///
+/// A duplicate is reflinked to the first copy when the filesystem
+/// supports it, keeping its own inode.  Otherwise it is replaced by a hard
+/// link, but only across branches, at most one name per branch, and with
+/// the same mode and owner; both branches record the inode in
+/// `SHARED_FILE` so a later change copies it first.
+pub fn dedup_deltas(storage_path: &Path) -> Result<DedupReport> {
+    let branches_dir = storage_path.join("branches");
+    let mut names: Vec<String> = match fs::read_dir(&branches_dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|e| storage::name_to_string(&e.file_name()))
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    names.sort();
+
+    let mut files = Vec::new();
+    for name in &names {
+        collect(
+            &branches_dir.join(storage::to_os_path(name)).join("files"),
+            name,
+            &mut files,
+        )?;
+    }
+    files.sort_by(|a, b| (&a.branch, &a.path).cmp(&(&b.branch, &b.path)));
+
+    let mut by_len: BTreeMap<u64, Vec<&Candidate>> = BTreeMap::new();
+    for file in &files {
+        by_len.entry(file.meta.len()).or_default().push(file);
+    }
+
+    let mut report = DedupReport::default();
+    for group in by_len.values().filter(|g| g.len() > 1) {
+        let mut by_hash: HashMap<blake3::Hash, Vec<&Candidate>> = HashMap::new();
+        for file in group {
+            by_hash
+                .entry(content_hash(&file.path)?)
+                .or_default()
+                .push(file);
+        }
+        for same in by_hash.values().filter(|s| s.len() > 1) {
+            dedup_group(&branches_dir, same, &mut report)?;
+        }
+    }
+
+    // Drop recorded inodes the branch no longer shares
+    for name in &names {
+        let branch_dir = branches_dir.join(storage::to_os_path(name));
+        let recorded = load_shared(&branch_dir.join(SHARED_FILE));
+        if recorded.is_empty() {
+            continue;
+        }
+        let live: HashSet<(u64, u64)> = files
+            .iter()
+            .filter(|f| f.branch == *name)
+            .filter_map(|f| fs::symlink_metadata(&f.path).ok())
+            .filter(|m| m.nlink() > 1)
+            .map(|m| (m.dev(), m.ino()))
+            .collect();
+        let kept: Vec<String> = recorded
+            .intersection(&live)
+            .map(|(dev, ino)| format!("{} {}\n", dev, ino))
+            .collect();
+        if kept.is_empty() {
+            fs::remove_file(branch_dir.join(SHARED_FILE))?;
+        } else {
+            fs::write(branch_dir.join(SHARED_FILE), kept.concat())?;
+        }
+    }
+    Ok(report)
+}
+
+/// Share the data of every file of `group` (same size and hash) with the
+/// first one.
+fn dedup_group(branches_dir: &Path, group: &[&Candidate], report: &mut DedupReport) -> Result<()> {
+    let keeper = group[0];
+    let key = keeper.inode();
+    // Hard links may only join names of different branches, so every
+    // existing name of the keeper's inode must be one of this group's
+    let holders: Vec<&str> = group
+        .iter()
+        .filter(|f| f.inode() == key)
+        .map(|f| f.branch.as_str())
+        .collect();
+    let mut linked: HashSet<&str> = holders.iter().copied().collect();
+    let can_link = linked.len() == holders.len() && keeper.meta.nlink() as usize == holders.len();
+
+    for dup in &group[1..] {
+        if dup.inode() == key {
+            continue;
+        }
+        if !same_content(&keeper.path, &dup.path)? {
+            report.skipped += 1;
+            continue;
+        }
+        if storage::reflink_over(&keeper.path, &dup.path)? {
+            report.reflinked += 1;
+            report.bytes += dup.meta.len();
+            continue;
+        }
+        let linkable = can_link
+            && dup.meta.nlink() == 1
+            && !linked.contains(dup.branch.as_str())
+            && dup.meta.mode() == keeper.meta.mode()
+            && dup.meta.uid() == keeper.meta.uid()
+            && dup.meta.gid() == keeper.meta.gid();
+        if !linkable {
+            report.skipped += 1;
+            continue;
+        }
+
+        record_shared(&branches_dir.join(storage::to_os_path(&keeper.branch)), key)?;
+        record_shared(&branches_dir.join(storage::to_os_path(&dup.branch)), key)?;
+        let mut name = std::ffi::OsString::from(".");
+        name.push(dup.path.file_name().unwrap_or_default());
+        name.push(".branchfs-link");
+        let tmp = dup.path.with_file_name(name);
+        let _ = fs::remove_file(&tmp);
+        fs::hard_link(&keeper.path, &tmp)?;
+        if let Err(e) = fs::rename(&tmp, &dup.path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        linked.insert(dup.branch.as_str());
+        report.hardlinked += 1;
+        report.bytes += dup.meta.len();
+    }
+    Ok(())
+}
@@ -23,6 +23,30 @@ pub enum BranchError {
     #[error("cannot commit/abort non-leaf branch '{0}'")]
     NotALeaf(String),
 
+    #[error("commit lock is held by '{0}'")]
+    CommitLocked(String),
+
+    #[error("branch '{0}' has files open for writing: {1}")]
+    OpenForWrite(String, String),
+
+    #[error("branch '{0}' has an open transaction")]
+    TxnOpen(String),
+
+    #[error("branch '{0}' has no open transaction")]
+    NoTxn(String),
+
+    #[error("branch '{0}' has child branches: {1}")]
+    HasChildren(String, String),
+
+    #[error("branch '{0}' is the current branch of other mounts: {1}")]
+    MountedElsewhere(String, String),
+
+    #[error("not a branchfs storage: {0}")]
+    NotStorage(String),
+
+    #[error("storage format mismatch in {0}: expected version {1}, found {2}")]
+    StorageFormat(String, u32, String),
+
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -34,3 +58,15 @@ pub enum BranchError {
 }
 
 pub type Result<T> = std::result::Result<T, BranchError>;
+
+impl From<BranchError> for std::io::Error {
+    /// Keep the errno of an underlying I/O error so FUSE replies can report
+    /// it; other failures carry only their message.
+    fn from(e: BranchError) -> Self {
+        match e {
+            BranchError::Io(e) => e,
+            BranchError::Nix(errno) => std::io::Error::from_raw_os_error(errno as i32),
+            other => std::io::Error::other(other.to_string()),
+        }
+    }
+}
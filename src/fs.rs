@@ -2,20 +2,25 @@ use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{Read as IoRead, Seek, SeekFrom};
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use fuser::{
-    FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl,
-    ReplyOpen, ReplyWrite, Request, TimeOrNow,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyIoctl, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
 };
-use parking_lot::RwLock;
-
-use crate::branch::BranchManager;
-use crate::fs_path::{classify_path, PathContext};
-use crate::inode::{InodeManager, ROOT_INO};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use crate::branch::{BranchEvent, BranchManager, CommitMtime, HardlinkMode};
+use crate::error::BranchError;
+use crate::fs_ctl::ctl_errno;
+use crate::fs_path::PathContext;
+use crate::fs_readdir::DirSnapshot;
+use crate::inode::{InodeManager, CTL_INO_BASE, ROOT_INO};
 use crate::storage;
 
 // Zero TTL forces the kernel to always revalidate with FUSE, ensuring consistent
@@ -29,15 +34,237 @@ pub const BRANCHFS_IOC_ABORT: u32 = 0x4202;
 
 pub(crate) const CTL_FILE: &str = ".branchfs_ctl";
 pub(crate) const CTL_INO: u64 = u64::MAX - 1;
+pub(crate) const EPOCH_FILE: &str = ".branchfs_epoch";
+/// Reserved inode for the read-only epoch file (`--epoch-file`)
+pub(crate) const EPOCH_INO: u64 = CTL_INO - 1;
+
+/// Default readahead window for sequential reads.
+pub const DEFAULT_READAHEAD: usize = 512 * 1024;
+/// Upper bound for the readahead window; larger values are clamped.
+pub const MAX_READAHEAD: usize = 8 * 1024 * 1024;
+
+/// Default cap on the handles a mount keeps open at once.
+pub const DEFAULT_MAX_HANDLES: usize = 65536;
+/// Default time in seconds after which an unused handle is reaped.
+pub const DEFAULT_HANDLE_IDLE_TIMEOUT: u64 = 3600;
+
+/// When tombstones written through a mount are flushed to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Leave flushing to the OS
+    #[default]
+    Off,
+    /// fsync the tombstones file before `unlink` returns
+    Sync,
+    /// fsync from the daemon's poll loop (within ~100ms), batching deletes
+    Deferred,
+}
+
+impl std::str::FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "sync" => Ok(Self::Sync),
+            "deferred" => Ok(Self::Deferred),
+            _ => Err(format!(
+                "invalid durability '{}' (expected off, sync or deferred)",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether reads update the access time of the file they read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AtimeMode {
+    /// Never touch atime (reads stay read-only on disk)
+    #[default]
+    None,
+    /// Bump atime only when it is not newer than mtime/ctime, and only for
+    /// files the branch already has in its delta
+    Relatime,
+    /// Bump atime on every read, copying base files up into the branch
+    Strict,
+}
+
+impl std::str::FromStr for AtimeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "relatime" => Ok(Self::Relatime),
+            "strict" => Ok(Self::Strict),
+            _ => Err(format!(
+                "invalid atime mode '{}' (expected none, relatime or strict)",
+                s
+            )),
+        }
+    }
+}
+
+/// What the first write to a file the branch has not copied up yet does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    /// Copy the whole inherited file into the branch, then write
+    #[default]
+    Copyup,
+    /// Write into a sparse delta and keep reading unwritten ranges from the
+    /// inherited file until the delta is materialized
+    Redirect,
+    /// Start the delta empty, discarding the inherited content
+    Overwrite,
+}
+
+impl std::str::FromStr for WriteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "copyup" => Ok(Self::Copyup),
+            "redirect" => Ok(Self::Redirect),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => Err(format!(
+                "invalid write mode '{}' (expected copyup, redirect or overwrite)",
+                s
+            )),
+        }
+    }
+}
+
+/// Per-mount options passed through `Request::Mount`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MountOptions {
+    /// Do not expose `@branch` virtual directories at the root
+    pub hide_branches: bool,
+    /// Leave the root ctl file out of directory listings (it can still be
+    /// opened by name, which the CLI relies on)
+    pub hide_ctl: bool,
+    /// Bytes fetched from the backing file when a read continues where the
+    /// previous one ended (0 disables readahead)
+    pub readahead: usize,
+    /// How deletions are made crash-consistent
+    pub durable: Durability,
+    /// Access-time updates on read
+    pub atime: AtimeMode,
+    /// Expose the manager's epoch as a read-only `/.branchfs_epoch` file
+    pub epoch_file: bool,
+    /// Strategy for the first write to an inherited file
+    pub write_mode: WriteMode,
+    /// How commits through this mount write hard-linked targets
+    pub hardlinks: HardlinkMode,
+    /// Modification time commits through this mount give the files written
+    pub commit_mtime: CommitMtime,
+    /// Reply with the errno of the failed backing call (`EACCES`, `ENOSPC`,
+    /// ...) instead of a generic `EIO`
+    pub strict_errno: bool,
+    /// Handles open at once before `open` fails with `ENFILE`
+    pub max_handles: usize,
+    /// Seconds a handle may go unused before the daemon reaps it (0 never)
+    pub handle_idle_timeout: u64,
+    /// Inodes the mount keeps before evicting the least recently used
+    /// (0 never evicts)
+    pub max_inodes: usize,
+    /// Flush every write to disk before acknowledging it
+    pub sync_writes: bool,
+    /// Refuse every change with `EROFS`; reads, `@branch` paths and
+    /// `switch:` still work
+    pub read_only: bool,
+    /// Serve only this directory of the base (relative to it) as the
+    /// mount's root; branches apply within it
+    pub subdir: Option<String>,
+    /// Commit the mount's branch when it is unmounted (used by the daemon)
+    pub auto_commit: bool,
+    /// Abort the mount's branch when it is unmounted (used by the daemon)
+    pub auto_abort: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            hide_branches: false,
+            hide_ctl: false,
+            readahead: DEFAULT_READAHEAD,
+            durable: Durability::Off,
+            atime: AtimeMode::None,
+            epoch_file: false,
+            write_mode: WriteMode::Copyup,
+            hardlinks: HardlinkMode::Preserve,
+            commit_mtime: CommitMtime::Now,
+            strict_errno: true,
+            max_handles: DEFAULT_MAX_HANDLES,
+            handle_idle_timeout: DEFAULT_HANDLE_IDLE_TIMEOUT,
+            max_inodes: 0,
+            sync_writes: false,
+            read_only: false,
+            subdir: None,
+            auto_commit: false,
+            auto_abort: false,
+        }
+    }
+}
+
+/// Flush a write gets before it is acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteSync {
+    None,
+    /// `fdatasync`: the data and the metadata needed to read it back
+    Data,
+    /// `fsync`: everything, as `O_SYNC` asks
+    All,
+}
+
+impl WriteSync {
+    /// The flush owed by a write through a handle opened with `flags`.
+    /// `O_SYNC` includes the `O_DSYNC` bit on Linux, so it is checked first.
+    fn for_write(flags: Option<i32>, sync_writes: bool) -> Self {
+        match flags {
+            Some(flags) if flags & libc::O_SYNC == libc::O_SYNC => WriteSync::All,
+            Some(flags) if flags & libc::O_DSYNC != 0 => WriteSync::Data,
+            _ if sync_writes => WriteSync::Data,
+            _ => WriteSync::None,
+        }
+    }
+
+    fn apply(self, file: &File) -> std::io::Result<()> {
+        match self {
+            WriteSync::None => Ok(()),
+            WriteSync::Data => file.sync_data(),
+            WriteSync::All => file.sync_all(),
+        }
+    }
+}
 
 /// Cached open file descriptor for the most recently read inode.
 /// Eliminates per-read resolve_path() (2-3 stat syscalls on non-existent
 /// delta paths) and File::open()/close() overhead.  Invalidated on write
 /// (COW changes the backing path) and on epoch change (branch switch).
+///
+/// Sequential reads (offset == end of the previous read) fetch up to the
+/// readahead window from the backing file in one go and serve the following
+/// reads from that buffer.
 struct OpenFileCache {
     ino: u64,
     epoch: u64,
     file: Option<File>,
+    /// Readahead buffer and the file offset of its first byte
+    buf: Vec<u8>,
+    buf_offset: u64,
+    /// Buffer ends at EOF, so short reads past it are final
+    buf_eof: bool,
+    /// Offset the next sequential read is expected at
+    next_offset: u64,
+    /// Reads issued against the backing file since the last release
+    backing_reads: u64,
+    /// Backing path when it is a redirected delta, whose reads go through
+    /// the manager
+    redirect: Option<PathBuf>,
 }
 
 impl OpenFileCache {
@@ -46,208 +273,423 @@ impl OpenFileCache {
             ino: 0,
             epoch: 0,
             file: None,
+            buf: Vec::new(),
+            buf_offset: 0,
+            buf_eof: false,
+            next_offset: 0,
+            backing_reads: 0,
+            redirect: None,
         }
     }
 
-    /// Return a mutable reference to the cached File if it matches.
-    fn get(&mut self, ino: u64, epoch: u64) -> Option<&mut File> {
-        if self.ino == ino && self.epoch == epoch {
-            self.file.as_mut()
-        } else {
-            None
-        }
+    fn matches(&self, ino: u64, epoch: u64) -> bool {
+        self.ino == ino && self.epoch == epoch && self.file.is_some()
     }
 
     /// Replace the cached entry.
-    fn insert(&mut self, ino: u64, epoch: u64, file: File) {
+    fn insert(&mut self, ino: u64, epoch: u64, file: File, redirect: Option<PathBuf>) {
         self.ino = ino;
         self.epoch = epoch;
         self.file = Some(file);
+        self.redirect = redirect;
+        self.drop_buffer();
+        self.next_offset = 0;
+        self.backing_reads = 0;
     }
 
     fn invalidate_ino(&mut self, ino: u64) {
         if self.ino == ino {
             self.ino = 0;
             self.file = None;
+            self.redirect = None;
+            self.drop_buffer();
+        }
+    }
+
+    /// Log per-stream statistics when `ino` is closed and drop the entry, so
+    /// the next open re-resolves the path (a write through another inode,
+    /// e.g. the `@branch` alias, may have copied the file up meanwhile).
+    fn release(&mut self, ino: u64) {
+        if self.ino != ino {
+            return;
         }
+        if self.backing_reads > 0 {
+            log::debug!(
+                "[BENCH] read ino {}: {} backing reads, up to offset {}",
+                ino,
+                self.backing_reads,
+                self.next_offset
+            );
+        }
+        self.invalidate_ino(ino);
+    }
+
+    /// Discard readahead data (the backing file may have changed).
+    fn drop_buffer(&mut self) {
+        self.buf.clear();
+        self.buf_offset = 0;
+        self.buf_eof = false;
+    }
+
+    /// Read `size` bytes at `offset` from the cached file.  Callers must
+    /// check `matches()` first.
+    fn read(&mut self, offset: u64, size: usize, window: usize) -> std::io::Result<Vec<u8>> {
+        let buf_end = self.buf_offset + self.buf.len() as u64;
+        let data = if !self.buf.is_empty()
+            && offset >= self.buf_offset
+            && (offset + size as u64 <= buf_end || (self.buf_eof && offset <= buf_end))
+        {
+            let start = (offset - self.buf_offset) as usize;
+            let end = (start + size).min(self.buf.len());
+            self.buf[start..end].to_vec()
+        } else {
+            let sequential = window > size && offset == self.next_offset;
+            let want = if sequential { window } else { size };
+            let file = self
+                .file
+                .as_mut()
+                .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EBADF))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut chunk = Vec::with_capacity(want);
+            file.by_ref().take(want as u64).read_to_end(&mut chunk)?;
+            self.backing_reads += 1;
+
+            if sequential {
+                let data = chunk[..size.min(chunk.len())].to_vec();
+                self.buf_eof = chunk.len() < want;
+                self.buf = chunk;
+                self.buf_offset = offset;
+                data
+            } else {
+                chunk
+            }
+        };
+        self.next_offset = offset + data.len() as u64;
+        Ok(data)
+    }
+}
+
+/// Open a backing file for reading without letting the host filesystem
+/// bump its atime; `AtimeMode` decides that instead.  `O_NOATIME` needs
+/// file ownership (or CAP_FOWNER), so fall back to a plain open.
+fn open_noatime(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME)
+        .open(path)
+    {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => File::open(path),
+        result => result,
     }
 }
 
-/// Cached open file descriptor for writes (delta files).
-/// Same idea as OpenFileCache but opened in write mode.
-struct WriteFileCache {
+/// Delta file a handle writes through, opened by its first write (after
+/// COW) and closed on release.
+struct OpenFile {
     ino: u64,
     epoch: u64,
-    file: Option<File>,
+    file: File,
+    /// Delta path when it is redirected, so writes can be recorded
+    redirect: Option<PathBuf>,
 }
 
-impl WriteFileCache {
-    fn new() -> Self {
+/// Open file handles, keyed by the `fh` returned from `open`/`create`.
+///
+/// Records the flags each handle was opened with so later calls can be
+/// checked against its access mode, and when it was last used so handles a
+/// client never releases can be reaped.  Shared with the daemon, whose poll
+/// loop does the reaping.  Handle 0 is never allocated.
+pub struct HandleTable {
+    next_fh: u64,
+    handles: HashMap<u64, OpenHandle>,
+    max: usize,
+    idle_timeout: Option<Duration>,
+    stats: HandleStats,
+}
+
+struct OpenHandle {
+    flags: i32,
+    last_used: Instant,
+}
+
+/// FUSE init flags `init` looks for, by the name they are reported under.
+/// fuser only defines the newer ones behind its ABI features, so the bits
+/// are spelled out.
+const FUSE_FEATURES: [(&str, u64); 5] = [
+    ("atomic_o_trunc", fuser::consts::FUSE_ATOMIC_O_TRUNC),
+    ("readdirplus", 1 << 13),
+    ("writeback_cache", 1 << 16),
+    ("max_pages", 1 << 22),
+    // Only offered through the extended init flags, which fuser does not
+    // negotiate without its abi-7-36 feature
+    ("passthrough", 1 << 37),
+];
+
+/// Reserved init flag no kernel offers
+const FUSE_INIT_RESERVED: u64 = 1 << 31;
+
+/// Whether the kernel offers `flag`, without asking for it.  Adding it
+/// together with the reserved flag always fails before anything is
+/// requested, and the error says which of the two the kernel lacks.
+fn kernel_offers(config: &mut fuser::KernelConfig, flag: u64) -> bool {
+    match config.add_capabilities(flag | FUSE_INIT_RESERVED) {
+        Ok(()) => true,
+        Err(missing) => missing & flag == 0,
+    }
+}
+
+/// Kernel FUSE features of a mount as negotiated at `init`, reported by
+/// `Status` and `MountInfo`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FuseFeatures {
+    /// Features the kernel offered
+    pub supported: Vec<String>,
+    /// Features the mount asked for and got
+    pub enabled: Vec<String>,
+}
+
+/// Handle counts of a mount, as reported by `Status` and `MountInfo`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct HandleStats {
+    /// Handles currently open
+    pub open: usize,
+    /// Handles handed out since the mount started
+    pub opened: u64,
+    pub released: u64,
+    /// Handles dropped by the reaper after going unused
+    pub reaped: u64,
+    /// Opens refused because the table was full
+    pub refused: u64,
+}
+
+/// Sum of two mounts' counts
+impl std::ops::Add for HandleStats {
+    type Output = HandleStats;
+
+    fn add(self, other: HandleStats) -> HandleStats {
+        HandleStats {
+            open: self.open + other.open,
+            opened: self.opened + other.opened,
+            released: self.released + other.released,
+            reaped: self.reaped + other.reaped,
+            refused: self.refused + other.refused,
+        }
+    }
+}
+
+impl HandleTable {
+    fn new(max: usize, idle_timeout_secs: u64) -> Self {
         Self {
-            ino: 0,
-            epoch: 0,
-            file: None,
+            next_fh: 1,
+            handles: HashMap::new(),
+            max: max.max(1),
+            idle_timeout: (idle_timeout_secs > 0).then(|| Duration::from_secs(idle_timeout_secs)),
+            stats: HandleStats::default(),
+        }
+    }
+
+    /// Allocate a handle, or `None` when the table is full even after
+    /// reaping idle handles.
+    fn open(&mut self, flags: i32) -> Option<u64> {
+        if self.handles.len() >= self.max && self.reap_idle() == 0 {
+            self.stats.refused += 1;
+            log::warn!(
+                "Handle table full ({} open, {} opened, {} released); refusing open",
+                self.handles.len(),
+                self.stats.opened,
+                self.stats.released
+            );
+            return None;
+        }
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            OpenHandle {
+                flags,
+                last_used: Instant::now(),
+            },
+        );
+        self.stats.opened += 1;
+        Some(fh)
+    }
+
+    fn flags(&self, fh: u64) -> Option<i32> {
+        self.handles.get(&fh).map(|h| h.flags)
+    }
+
+    fn contains(&self, fh: u64) -> bool {
+        self.handles.contains_key(&fh)
+    }
+
+    /// Note that `fh` was used, keeping it from being reaped.
+    fn touch(&mut self, fh: u64) {
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.last_used = Instant::now();
         }
     }
 
-    fn get(&mut self, ino: u64, epoch: u64) -> Option<&mut File> {
-        if self.ino == ino && self.epoch == epoch {
-            self.file.as_mut()
+    fn release(&mut self, fh: u64) {
+        if self.handles.remove(&fh).is_some() {
+            self.stats.released += 1;
         } else {
-            None
+            log::debug!("Release of unknown handle {} (reaped?)", fh);
         }
     }
 
-    fn insert(&mut self, ino: u64, epoch: u64, file: File) {
-        self.ino = ino;
-        self.epoch = epoch;
-        self.file = Some(file);
+    /// Drop handles unused for longer than the idle timeout.  Returns how
+    /// many were dropped.
+    pub fn reap_idle(&mut self) -> usize {
+        let Some(timeout) = self.idle_timeout else {
+            return 0;
+        };
+        let before = self.handles.len();
+        self.handles.retain(|_, h| h.last_used.elapsed() <= timeout);
+        let reaped = before - self.handles.len();
+        if reaped > 0 {
+            self.stats.reaped += reaped as u64;
+            log::warn!(
+                "Reaped {} handles unused for {:?}; a client opened them without releasing",
+                reaped,
+                timeout
+            );
+        }
+        reaped
     }
 
-    fn invalidate_ino(&mut self, ino: u64) {
-        if self.ino == ino {
-            self.ino = 0;
-            self.file = None;
+    pub fn stats(&self) -> HandleStats {
+        HandleStats {
+            open: self.handles.len(),
+            ..self.stats
         }
     }
 }
 
+/// Whether `flags` ask for write access (explicitly or via `O_TRUNC`).
+fn wants_write(flags: i32) -> bool {
+    flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+}
+
+/// Validate `open` flags against the target: `O_DIRECTORY` needs a
+/// directory, directories cannot be opened for writing, and nothing
+/// read-only can be.
+fn check_open_flags(flags: i32, is_dir: bool, read_only: bool) -> Result<(), i32> {
+    if flags & libc::O_DIRECTORY != 0 && !is_dir {
+        return Err(libc::ENOTDIR);
+    }
+    if wants_write(flags) {
+        if is_dir {
+            return Err(libc::EISDIR);
+        }
+        if read_only {
+            return Err(libc::EROFS);
+        }
+    }
+    Ok(())
+}
+
 pub struct BranchFs {
     pub(crate) manager: Arc<BranchManager>,
     pub(crate) inodes: InodeManager,
-    pub(crate) branch_name: RwLock<String>,
-    pub(crate) current_epoch: AtomicU64,
+    /// Current branch; shared with the daemon so a branch rename can retarget
+    /// a live mount
+    pub(crate) branch_name: Arc<RwLock<String>>,
+    /// Epoch the mount's view was last brought up to; shared with the
+    /// daemon so a partial commit can keep its own mount current
+    pub(crate) current_epoch: Arc<AtomicU64>,
     /// Per-branch ctl inode numbers: branch_name → ino
     pub(crate) branch_ctl_inodes: RwLock<HashMap<String, u64>>,
     pub(crate) next_ctl_ino: AtomicU64,
     pub(crate) uid: AtomicU32,
     pub(crate) gid: AtomicU32,
+    pub(crate) options: MountOptions,
     /// Cached open file — avoids re-resolve + re-open on consecutive reads
     /// to the same inode.
     open_cache: OpenFileCache,
-    /// Cached write fd — avoids re-open on consecutive writes to the same
-    /// delta file (after COW).
-    write_cache: WriteFileCache,
+    handles: Arc<Mutex<HandleTable>>,
+    /// Delta files written through each handle, keyed by handle, so
+    /// handles never share a write fd or its position
+    fhandles: HashMap<u64, OpenFile>,
+    /// Directory listings taken at `opendir`, keyed by handle
+    dir_snapshots: HashMap<u64, DirSnapshot>,
+    /// Files registered with `register_opened_inode`, keyed by handle:
+    /// (branch, ino)
+    open_inodes: HashMap<u64, (String, u64)>,
+    /// Writer ids from `register_writer`, keyed by handle
+    open_writers: HashMap<u64, u64>,
+    /// `@name` paths already warned about for shadowing a branch directory
+    pub(crate) shadow_warnings: std::collections::HashSet<String>,
+    /// `--subdir` as `"/a/b"`, prepended to every path handed to the
+    /// manager; empty when the whole base is mounted
+    pub(crate) root_prefix: String,
+    /// Set by `init`
+    features: Arc<Mutex<Option<FuseFeatures>>>,
 }
 
 impl BranchFs {
-    pub fn new(manager: Arc<BranchManager>, branch_name: String) -> Self {
+    pub fn new(manager: Arc<BranchManager>, branch_name: String, options: MountOptions) -> Self {
         let current_epoch = manager.get_epoch();
+        let root_prefix = match options.subdir.as_deref().map(|d| d.trim_matches('/')) {
+            Some(dir) if !dir.is_empty() => format!("/{}", dir),
+            _ => String::new(),
+        };
         Self {
             manager,
-            inodes: InodeManager::new(),
-            branch_name: RwLock::new(branch_name),
-            current_epoch: AtomicU64::new(current_epoch),
+            inodes: InodeManager::with_capacity(options.max_inodes),
+            branch_name: Arc::new(RwLock::new(branch_name)),
+            current_epoch: Arc::new(AtomicU64::new(current_epoch)),
             branch_ctl_inodes: RwLock::new(HashMap::new()),
-            // Reserve a range well below CTL_INO (u64::MAX - 1) for branch ctl inodes.
-            // Start from u64::MAX - 1_000_000 downward.
-            next_ctl_ino: AtomicU64::new(u64::MAX - 1_000_000),
+            // Branch ctl inodes count up from CTL_INO_BASE towards EPOCH_INO
+            next_ctl_ino: AtomicU64::new(CTL_INO_BASE),
             uid: AtomicU32::new(nix::unistd::getuid().as_raw()),
             gid: AtomicU32::new(nix::unistd::getgid().as_raw()),
             open_cache: OpenFileCache::new(),
-            write_cache: WriteFileCache::new(),
+            handles: Arc::new(Mutex::new(HandleTable::new(
+                options.max_handles,
+                options.handle_idle_timeout,
+            ))),
+            options,
+            dir_snapshots: HashMap::new(),
+            fhandles: HashMap::new(),
+            open_inodes: HashMap::new(),
+            open_writers: HashMap::new(),
+            shadow_warnings: std::collections::HashSet::new(),
+            root_prefix,
+            features: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub(crate) fn get_branch_name(&self) -> String {
-        self.branch_name.read().clone()
-    }
-
-    pub(crate) fn is_stale(&self) -> bool {
-        let branch_name = self.get_branch_name();
-        self.manager.get_epoch() != self.current_epoch.load(Ordering::SeqCst)
-            || !self.manager.is_branch_valid(&branch_name)
-    }
-
-    /// Switch to a different branch (used after commit/abort to switch to main)
-    pub(crate) fn switch_to_branch(&self, new_branch: &str) {
-        *self.branch_name.write() = new_branch.to_string();
-        self.current_epoch
-            .store(self.manager.get_epoch(), Ordering::SeqCst);
-        // Clear inode cache since we're on a different branch now
-        self.inodes.clear();
-        // Note: read_cache is invalidated automatically via epoch mismatch
+    fn readahead_window(&self) -> usize {
+        self.options.readahead.min(MAX_READAHEAD)
     }
 
-    fn apply_setattr(
-        delta: &Path,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        atime: Option<TimeOrNow>,
-        mtime: Option<TimeOrNow>,
-    ) {
-        use std::os::unix::fs::PermissionsExt;
-
-        if let Some(m) = mode {
-            let perm = std::fs::Permissions::from_mode(m);
-            let _ = std::fs::set_permissions(delta, perm);
-        }
-        if uid.is_some() || gid.is_some() {
-            let _ = nix::unistd::chown(
-                delta,
-                uid.map(nix::unistd::Uid::from_raw),
-                gid.map(nix::unistd::Gid::from_raw),
-            );
-        }
-        if atime.is_some() || mtime.is_some() {
-            let to_timespec = |t: Option<TimeOrNow>| -> nix::sys::time::TimeSpec {
-                match t {
-                    Some(TimeOrNow::SpecificTime(st)) => {
-                        let d = st.duration_since(UNIX_EPOCH).unwrap_or_default();
-                        nix::sys::time::TimeSpec::new(d.as_secs() as i64, d.subsec_nanos() as i64)
-                    }
-                    Some(TimeOrNow::Now) => nix::sys::time::TimeSpec::new(0, libc::UTIME_NOW),
-                    None => nix::sys::time::TimeSpec::new(0, libc::UTIME_OMIT),
-                }
-            };
-            let _ = nix::sys::stat::utimensat(
-                None,
-                delta,
-                &to_timespec(atime),
-                &to_timespec(mtime),
-                nix::sys::stat::UtimensatFlags::FollowSymlink,
-            );
-        }
+    /// Apply the mount's atime mode after a successful read of `ino`.
+    ///
+    /// Base files are never touched: `relatime` skips files the branch has
+    /// not copied up, and `strict` copies them up first, turning every first
+    /// read of a base file into a full copy.
+    /// `path` if it is a redirected delta.
+    fn redirect_of(&self, path: PathBuf) -> Option<PathBuf> {
+        self.manager.is_redirected(&path).then_some(path)
     }
 
-    /// Classify an inode number. Returns None for root and CTL_INO (handled separately).
-    fn classify_ino(&self, ino: u64) -> Option<PathContext> {
-        if ino == ROOT_INO {
-            return Some(PathContext::RootPath("/".to_string()));
-        }
-        if ino == CTL_INO {
-            return Some(PathContext::RootCtl);
-        }
-        // Check if it's a branch ctl inode
-        if let Some(branch) = self.branch_for_ctl_ino(ino) {
-            return Some(PathContext::BranchCtl(branch));
+    /// Errno to reply with when a backing call failed with `e`: its own
+    /// under `strict_errno`, otherwise (or when it carries none) `flattened`.
+    pub(crate) fn io_err_to_errno(&self, e: &std::io::Error, flattened: i32) -> i32 {
+        match e.raw_os_error() {
+            Some(errno) if self.options.strict_errno => errno,
+            _ => flattened,
         }
-        let path = self.inodes.get_path(ino)?;
-        Some(classify_path(&path))
     }
-}
 
-impl Filesystem for BranchFs {
-    fn init(
-        &mut self,
-        req: &Request,
-        _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        // The init request may come from the kernel (uid=0) rather than the
-        // mounting user, so only override the process-derived defaults when
-        // the request carries a real (non-root) uid.
-        if req.uid() != 0 {
-            self.uid.store(req.uid(), Ordering::Relaxed);
-            self.gid.store(req.gid(), Ordering::Relaxed);
+    /// `unlink` (`dir` false) or `rmdir` (`dir` true) of `name` in
+    /// directory `parent`.
+    fn remove(&mut self, parent: u64, name: &OsStr, dir: bool, reply: ReplyEmpty) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
         }
-
-        Ok(())
-    }
-
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let name_str = name.to_string_lossy();
-
         let parent_path = match self.inodes.get_path(parent) {
             Some(p) => p,
             None => {
@@ -256,214 +698,702 @@ impl Filesystem for BranchFs {
             }
         };
 
-        // === Root-level lookups (parent is /) ===
-        if parent_path == "/" {
-            // Root ctl file
-            if name_str == CTL_FILE {
-                reply.entry(&TTL, &self.ctl_file_attr(CTL_INO), 0);
-                return;
-            }
-
-            // @branch virtual directory
-            if let Some(branch) = name_str.strip_prefix('@') {
-                if self.manager.is_branch_valid(branch) {
-                    let inode_path = format!("/@{}", branch);
-                    let ino = self.inodes.get_or_create(&inode_path, true);
-                    reply.entry(&TTL, &self.synthetic_dir_attr(ino), 0);
-                    return;
-                } else {
-                    reply.error(libc::ENOENT);
-                    return;
-                }
-            }
-
-            // Regular root child — use current branch
-            if self.is_stale() {
-                reply.error(libc::ESTALE);
-                return;
-            }
-
-            let path = format!("/{}", name_str);
-            let resolved = match self.resolve(&path) {
-                Some(p) => p,
-                None => {
-                    reply.error(libc::ENOENT);
-                    return;
-                }
-            };
-            let is_dir = resolved.is_dir();
-            let ino = self.inodes.get_or_create(&path, is_dir);
-            match self.make_attr(ino, &resolved) {
-                Some(attr) => reply.entry(&TTL, &attr, 0),
-                None => reply.error(libc::ENOENT),
-            }
-            return;
-        }
+        let name_str = storage::name_to_string(name);
 
-        // === Parent is inside an @branch subtree ===
-        let branch_ctx = match classify_path(&parent_path) {
+        let branch_ctx = match self.classify(&parent_path) {
             PathContext::BranchDir(b) => Some((b, "/".to_string())),
             PathContext::BranchPath(b, rel) => Some((b, rel)),
             _ => None,
         };
 
         if let Some((branch, parent_rel)) = branch_ctx {
-            // Looking up .branchfs_ctl inside a branch dir (only at branch root)
-            if parent_rel == "/" && name_str == CTL_FILE {
-                let ctl_ino = self.get_or_create_branch_ctl_ino(&branch);
-                reply.entry(&TTL, &self.ctl_file_attr(ctl_ino), 0);
-                return;
-            }
-
-            // Looking up @child inside a branch dir (nested branch)
-            if let Some(child_branch) = name_str.strip_prefix('@') {
-                let children = self.manager.get_children(&branch);
-                if parent_rel == "/" && children.iter().any(|c| c == child_branch) {
-                    let inode_path = format!("/@{}/@{}", branch, child_branch);
-                    let ino = self.inodes.get_or_create(&inode_path, true);
-                    reply.entry(&TTL, &self.synthetic_dir_attr(ino), 0);
-                } else {
-                    reply.error(libc::ENOENT);
-                }
+            // Can't unlink @child dirs or .branchfs_ctl
+            let is_child_dir = parent_rel == "/"
+                && name_str.starts_with('@')
+                && !self.has_real_entry(Some(&branch), &format!("/{}", name_str));
+            if is_child_dir || *name_str == *CTL_FILE {
+                reply.error(libc::EPERM);
                 return;
             }
 
-            // Regular file/dir inside branch
             if !self.manager.is_branch_valid(&branch) {
                 reply.error(libc::ENOENT);
                 return;
             }
 
-            let child_rel = if parent_rel == "/" {
+            let rel_path = if parent_rel == "/" {
                 format!("/{}", name_str)
             } else {
                 format!("{}/{}", parent_rel, name_str)
             };
 
-            let resolved = match self.resolve_for_branch(&branch, &child_rel) {
-                Some(p) => p,
-                None => {
-                    reply.error(libc::ENOENT);
-                    return;
-                }
-            };
+            if let Some(errno) = self.removal_errno(&branch, &rel_path, dir) {
+                reply.error(errno);
+                return;
+            }
+            let result = self.remove_entry(&branch, &rel_path);
 
-            let inode_path = format!("/@{}{}", branch, child_rel);
-            let is_dir = resolved.is_dir();
-            let ino = self.inodes.get_or_create(&inode_path, is_dir);
-            match self.make_attr(ino, &resolved) {
-                Some(attr) => reply.entry(&TTL, &attr, 0),
-                None => reply.error(libc::ENOENT),
+            if let Err(e) = result {
+                reply.error(self.io_err_to_errno(&e.into(), libc::EIO));
+                return;
             }
+
+            let inode_path = format!("/@{}{}", branch, rel_path);
+            self.inodes.remove(&inode_path);
+            self.manager.record_event(&branch, BranchEvent::Unlink);
+            reply.ok();
         } else {
-            // Parent is a regular root-path subdir
-            if self.is_stale() {
-                reply.error(libc::ESTALE);
-                return;
-            }
+            // Root-path unlink (or EPERM for ctl files)
+            match self.classify(&parent_path) {
+                PathContext::BranchCtl(_) | PathContext::RootCtl => {
+                    reply.error(libc::EPERM);
+                }
+                PathContext::RootPath(rp) => {
+                    if rp == "/" && self.options.epoch_file && name_str == EPOCH_FILE {
+                        reply.error(libc::EPERM);
+                        return;
+                    }
+                    let path = if rp == "/" {
+                        format!("/{}", name_str)
+                    } else {
+                        format!("{}/{}", rp, name_str)
+                    };
 
-            let path = format!("{}/{}", parent_path, name_str);
-            let resolved = match self.resolve(&path) {
-                Some(p) => p,
-                None => {
+                    if self.is_stale() {
+                        reply.error(libc::ESTALE);
+                        return;
+                    }
+                    if let Some(errno) = self.removal_errno(&self.get_branch_name(), &path, dir) {
+                        reply.error(errno);
+                        return;
+                    }
+                    let result = self.remove_entry(&self.get_branch_name(), &path);
+
+                    if let Err(e) = result {
+                        reply.error(self.io_err_to_errno(&e.into(), libc::ESTALE));
+                        return;
+                    }
+                    if self.is_stale() {
+                        reply.error(libc::ESTALE);
+                        return;
+                    }
+
+                    self.inodes.remove(&path);
+                    self.manager
+                        .record_event(&self.get_branch_name(), BranchEvent::Unlink);
+                    reply.ok();
+                }
+                _ => {
                     reply.error(libc::ENOENT);
-                    return;
                 }
-            };
-            let is_dir = resolved.is_dir();
-            let ino = self.inodes.get_or_create(&path, is_dir);
-            match self.make_attr(ino, &resolved) {
-                Some(attr) => reply.entry(&TTL, &attr, 0),
-                None => reply.error(libc::ENOENT),
             }
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+    /// Make sure write handle `fh` holds an fd on `ino`'s delta: on its
+    /// first write (or after a switch) resolve the path, ensure COW, open
+    /// the delta and keep the fd on the handle.  Returns whether the inode
+    /// was resolved through the mount root just now, so the caller has to
+    /// check for staleness afterwards.
+    fn open_write_handle(&mut self, ino: u64, fh: u64) -> Result<bool, i32> {
+        let epoch = self.current_epoch.load(Ordering::SeqCst);
+        if self
+            .fhandles
+            .get(&fh)
+            .is_some_and(|f| f.ino == ino && f.epoch == epoch)
+        {
+            return Ok(false);
+        }
+        let path = self.inodes.get_path(ino).ok_or(libc::ENOENT)?;
+
+        let mut is_root = false;
+        let delta = match self.classify(&path) {
+            PathContext::BranchDir(_) | PathContext::BranchCtl(_) => return Err(libc::EPERM),
+            PathContext::BranchPath(branch, rel_path) => {
+                if !self.manager.is_branch_valid(&branch) {
+                    return Err(libc::ENOENT);
+                }
+                self.prepare_write_for_branch(&branch, &rel_path)
+            }
+            _ => {
+                is_root = true;
+                self.prepare_write_for_branch(&self.get_branch_name(), &path)
+            }
+        }
+        .map_err(|e| self.io_err_to_errno(&e, libc::EIO))?;
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&delta)
+            .map_err(|e| self.io_err_to_errno(&e, libc::EIO))?;
+        let redirect = self.redirect_of(delta);
+        self.fhandles.insert(
+            fh,
+            OpenFile {
+                ino,
+                epoch,
+                file,
+                redirect,
+            },
+        );
+        Ok(is_root)
+    }
+
+    /// Read through the cached fd, or through the manager while the cached
+    /// file is a redirected delta.
+    fn read_cached(&mut self, offset: u64, size: usize, window: usize) -> std::io::Result<Vec<u8>> {
+        if let Some(path) = &self.open_cache.redirect {
+            if let Some(result) = self.manager.read_redirected(path, offset, size) {
+                return result.map_err(std::io::Error::from);
+            }
+        }
+        self.open_cache.read(offset, size, window)
+    }
+
+    fn touch_atime(&mut self, ino: u64) {
+        if self.options.atime == AtimeMode::None || self.options.read_only {
+            return;
+        }
+        let (branch, rel_path) = match self.classify_ino(ino) {
+            Some(PathContext::BranchPath(branch, rel_path)) => (branch, rel_path),
+            Some(PathContext::RootPath(rel_path)) => (self.get_branch_name(), rel_path),
+            _ => return,
+        };
+
+        let delta = match self.options.atime {
+            AtimeMode::Strict => {
+                let had_delta = self
+                    .get_delta_path_for_branch(&branch, &rel_path)
+                    .is_some_and(|p| p.exists());
+                match self.ensure_cow_for_branch(&branch, &rel_path) {
+                    Ok(delta) => {
+                        // Later reads must come from the copy
+                        if !had_delta {
+                            self.open_cache.invalidate_ino(ino);
+                        }
+                        delta
+                    }
+                    Err(_) => return,
+                }
+            }
+            _ => match self.get_delta_path_for_branch(&branch, &rel_path) {
+                Some(delta) => delta,
+                None => return,
+            },
+        };
+
+        let meta = match std::fs::metadata(&delta) {
+            Ok(m) if m.is_file() => m,
+            _ => return,
+        };
+        if self.options.atime == AtimeMode::Relatime
+            && (meta.atime(), meta.atime_nsec()) > (meta.mtime(), meta.mtime_nsec())
+            && (meta.atime(), meta.atime_nsec()) > (meta.ctime(), meta.ctime_nsec())
+        {
+            return;
+        }
+        if let Ok(file) = File::open(&delta) {
+            let times = std::fs::FileTimes::new().set_accessed(SystemTime::now());
+            if let Err(e) = file.set_times(times) {
+                log::debug!("atime update failed for {:?}: {}", delta, e);
+            }
+        }
+    }
+
+    /// Validate `flags` and reply with a fresh handle, or the errno.
+    fn open_handle(
+        &mut self,
+        flags: i32,
+        is_dir: bool,
+        read_only: bool,
+        open_flags: u32,
+        reply: ReplyOpen,
+    ) {
+        match check_open_flags(flags, is_dir, read_only) {
+            Ok(()) => {
+                self.reply_opened(flags, open_flags, reply);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Allocate a handle, first closing the write fds of reaped handles,
+    /// which will never be released.
+    fn alloc_handle(&mut self, flags: i32) -> Option<u64> {
+        let mut handles = self.handles.lock();
+        self.fhandles.retain(|fh, _| handles.contains(*fh));
+        handles.open(flags)
+    }
+
+    /// Close every handle's write fd for `ino`; the next write through each
+    /// reopens the delta.
+    fn close_write_fds(&mut self, ino: u64) {
+        self.fhandles.retain(|_, f| f.ino != ino);
+    }
+
+    /// Reply with a fresh handle, or `ENFILE` when the mount has too many.
+    /// Returns the handle on success.
+    fn reply_opened(&mut self, flags: i32, open_flags: u32, reply: ReplyOpen) -> Option<u64> {
+        let fh = self.alloc_handle(flags);
+        match fh {
+            Some(fh) => reply.opened(fh, open_flags),
+            None => reply.error(libc::ENFILE),
+        }
+        fh
+    }
+
+    /// Reply with `attr`, counting the lookup the kernel now holds on it
+    /// until `forget`.
+    fn reply_entry(&self, attr: &FileAttr, reply: ReplyEntry) {
+        self.inodes.lookup(attr.ino);
+        reply.entry(&TTL, attr, 0);
+    }
+
+    /// `reply_opened` for `create`.
+    fn reply_created(
+        &mut self,
+        attr: &fuser::FileAttr,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) -> Option<u64> {
+        let fh = self.alloc_handle(flags);
+        match fh {
+            Some(fh) => {
+                self.inodes.lookup(attr.ino);
+                reply.created(&TTL, attr, 0, fh, 0)
+            }
+            None => reply.error(libc::ENFILE),
+        }
+        fh
+    }
+
+    /// Record `fh` as open for writing `path` of `branch`, so commits of the
+    /// branch can refuse while it is written to.  Read-only handles are
+    /// ignored.
+    fn track_writer(&mut self, fh: u64, flags: i32, branch: &str, path: &str) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            let path = self.branch_rel(path);
+            let id = self.manager.register_writer(branch, &path);
+            self.open_writers.insert(fh, id);
+        }
+    }
+
+    /// Handle to the kernel features negotiated at `init`, for the daemon
+    /// to report.
+    pub fn features_handle(&self) -> Arc<Mutex<Option<FuseFeatures>>> {
+        self.features.clone()
+    }
+
+    /// Handle to the mount's handle table, for the daemon's reaper.
+    pub fn handle_table(&self) -> Arc<Mutex<HandleTable>> {
+        self.handles.clone()
+    }
+
+    /// Handle to the epoch the mount is current with, for the daemon to keep.
+    pub fn epoch_handle(&self) -> Arc<AtomicU64> {
+        self.current_epoch.clone()
+    }
+
+    /// Handle to the mount's current branch name, for the daemon to keep.
+    pub fn branch_handle(&self) -> Arc<RwLock<String>> {
+        self.branch_name.clone()
+    }
+
+    pub(crate) fn get_branch_name(&self) -> String {
+        self.branch_name.read().clone()
+    }
+
+    pub(crate) fn is_stale(&self) -> bool {
+        let branch_name = self.get_branch_name();
+        self.manager.get_epoch() != self.current_epoch.load(Ordering::SeqCst)
+            || !self.manager.is_branch_valid(&branch_name)
+    }
+
+    /// Attributes reported for `ino` (`getattr`), or the errno to reply with.
+    pub(crate) fn attr_of(&self, ino: u64) -> Result<FileAttr, i32> {
         // Root ctl file
         if ino == CTL_INO {
-            reply.attr(&TTL, &self.ctl_file_attr(CTL_INO));
-            return;
+            return Ok(self.ctl_file_attr(CTL_INO));
+        }
+
+        if ino == EPOCH_INO && self.options.epoch_file {
+            return Ok(self.epoch_file_attr());
         }
 
         // Branch ctl file
         if let Some(branch) = self.branch_for_ctl_ino(ino) {
             if self.manager.is_branch_valid(&branch) {
-                reply.attr(&TTL, &self.ctl_file_attr(ino));
-            } else {
-                reply.error(libc::ENOENT);
+                return Ok(self.ctl_file_attr(ino));
             }
-            return;
+            return Err(libc::ENOENT);
         }
 
         let path = match self.inodes.get_path(ino) {
             Some(p) => p,
-            None => {
-                if ino == ROOT_INO {
-                    reply.attr(&TTL, &self.synthetic_dir_attr(ROOT_INO));
-                    return;
+            None if ino == ROOT_INO => return Ok(self.synthetic_dir_attr(ROOT_INO)),
+            None => return Err(libc::ENOENT),
+        };
+
+        let resolved = match self.classify(&path) {
+            PathContext::BranchDir(ref branch) => {
+                if self.manager.is_branch_valid(branch) {
+                    return Ok(self.synthetic_dir_attr(ino));
+                }
+                return Err(libc::ENOENT);
+            }
+            PathContext::BranchCtl(ref branch) => {
+                if self.manager.is_branch_valid(branch) {
+                    return Ok(self.ctl_file_attr(ino));
+                }
+                return Err(libc::ENOENT);
+            }
+            PathContext::BranchPath(ref branch, ref rel_path) => {
+                if !self.manager.is_branch_valid(branch) {
+                    return Err(libc::ENOENT);
+                }
+                self.resolve_for_branch(branch, rel_path)
+                    .ok_or(libc::ENOENT)?
+            }
+            PathContext::RootCtl => return Ok(self.ctl_file_attr(CTL_INO)),
+            PathContext::RootPath(ref rp) => {
+                if ino != ROOT_INO && self.is_stale() {
+                    return Err(libc::ESTALE);
+                }
+                match self.resolve(rp) {
+                    Some(p) => p,
+                    None if ino == ROOT_INO => return Ok(self.synthetic_dir_attr(ROOT_INO)),
+                    None => return Err(libc::ENOENT),
+                }
+            }
+        };
+        self.make_attr(ino, &resolved).ok_or(libc::ENOENT)
+    }
+
+    /// Switch to a different branch (used after commit/abort to switch to main)
+    pub(crate) fn switch_to_branch(&self, new_branch: &str) {
+        *self.branch_name.write() = new_branch.to_string();
+        self.current_epoch
+            .store(self.manager.get_epoch(), Ordering::SeqCst);
+        // Clear inode cache since we're on a different branch now
+        self.inodes.clear();
+        // Note: read_cache is invalidated automatically via epoch mismatch
+    }
+
+    fn apply_setattr(
+        delta: &Path,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(m) = mode {
+            let perm = std::fs::Permissions::from_mode(m);
+            std::fs::set_permissions(delta, perm)?;
+        }
+        if uid.is_some() || gid.is_some() {
+            nix::unistd::chown(
+                delta,
+                uid.map(nix::unistd::Uid::from_raw),
+                gid.map(nix::unistd::Gid::from_raw),
+            )?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            let to_timespec = |t: Option<TimeOrNow>| -> nix::sys::time::TimeSpec {
+                match t {
+                    Some(TimeOrNow::SpecificTime(st)) => {
+                        let d = st.duration_since(UNIX_EPOCH).unwrap_or_default();
+                        nix::sys::time::TimeSpec::new(d.as_secs() as i64, d.subsec_nanos() as i64)
+                    }
+                    Some(TimeOrNow::Now) => nix::sys::time::TimeSpec::new(0, libc::UTIME_NOW),
+                    None => nix::sys::time::TimeSpec::new(0, libc::UTIME_OMIT),
                 }
+            };
+            nix::sys::stat::utimensat(
+                None,
+                delta,
+                &to_timespec(atime),
+                &to_timespec(mtime),
+                nix::sys::stat::UtimensatFlags::FollowSymlink,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Classify an inode number. Returns None for root and CTL_INO (handled separately).
+    fn classify_ino(&self, ino: u64) -> Option<PathContext> {
+        if ino == ROOT_INO {
+            return Some(PathContext::RootPath("/".to_string()));
+        }
+        if ino == CTL_INO {
+            return Some(PathContext::RootCtl);
+        }
+        // Check if it's a branch ctl inode
+        if let Some(branch) = self.branch_for_ctl_ino(ino) {
+            return Some(PathContext::BranchCtl(branch));
+        }
+        let path = self.inodes.get_path(ino)?;
+        Some(self.classify(&path))
+    }
+}
+
+impl Filesystem for BranchFs {
+    fn destroy(&mut self) {
+        let stats = self.handles.lock().stats();
+        if stats.open > 0 {
+            log::warn!(
+                "{} handles never released ({} opened, {} released, {} reaped)",
+                stats.open,
+                stats.opened,
+                stats.released,
+                stats.reaped
+            );
+        }
+        self.fhandles.clear();
+        for (_, (branch, ino)) in self.open_inodes.drain() {
+            self.manager.release_opened_inode(&branch, ino);
+        }
+        for (_, id) in self.open_writers.drain() {
+            self.manager.release_writer(id);
+        }
+    }
+
+    fn init(&mut self, req: &Request, config: &mut fuser::KernelConfig) -> Result<(), libc::c_int> {
+        let mut features = FuseFeatures::default();
+        for (name, flag) in FUSE_FEATURES {
+            if kernel_offers(config, flag) {
+                features.supported.push(name.to_string());
+            }
+        }
+        // Let `open` truncate, so O_TRUNC drops an inherited file without
+        // copying it up first
+        if config
+            .add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC)
+            .is_ok()
+        {
+            features.enabled.push("atomic_o_trunc".to_string());
+        }
+        log::info!(
+            "FUSE features: supported [{}], enabled [{}]",
+            features.supported.join(", "),
+            features.enabled.join(", ")
+        );
+        *self.features.lock() = Some(features);
+
+        // The init request may come from the kernel (uid=0) rather than the
+        // mounting user, so only override the process-derived defaults when
+        // the request carries a real (non-root) uid.
+        if req.uid() != 0 {
+            self.uid.store(req.uid(), Ordering::Relaxed);
+            self.gid.store(req.gid(), Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name_str = storage::name_to_string(name);
+
+        let parent_path = match self.inodes.get_path(parent) {
+            Some(p) => p,
+            None => {
                 reply.error(libc::ENOENT);
                 return;
             }
         };
 
-        match classify_path(&path) {
-            PathContext::BranchDir(ref branch) => {
-                if self.manager.is_branch_valid(branch) {
-                    reply.attr(&TTL, &self.synthetic_dir_attr(ino));
-                } else {
+        // === Root-level lookups (parent is /) ===
+        if parent_path == "/" {
+            // Root ctl file
+            if name_str == CTL_FILE {
+                self.reply_entry(&self.ctl_file_attr(CTL_INO), reply);
+                return;
+            }
+
+            if self.options.epoch_file && name_str == EPOCH_FILE {
+                self.reply_entry(&self.epoch_file_attr(), reply);
+                return;
+            }
+
+            // @branch virtual directory, unless a real entry has the name
+            let path = format!("/{}", name_str);
+            if let Some(branch) = name_str
+                .strip_prefix('@')
+                .filter(|b| !self.shadows_branch(None, &path, b))
+            {
+                if self.options.hide_branches {
                     reply.error(libc::ENOENT);
+                    return;
                 }
-            }
-            PathContext::BranchCtl(ref branch) => {
                 if self.manager.is_branch_valid(branch) {
-                    reply.attr(&TTL, &self.ctl_file_attr(ino));
+                    let inode_path = format!("/@{}", branch);
+                    let ino = self.inodes.get_or_create(&inode_path, true);
+                    self.reply_entry(&self.synthetic_dir_attr(ino), reply);
+                    return;
                 } else {
                     reply.error(libc::ENOENT);
+                    return;
                 }
             }
-            PathContext::BranchPath(ref branch, ref rel_path) => {
-                if !self.manager.is_branch_valid(branch) {
+
+            // Regular root child — use current branch
+            if self.is_stale() {
+                reply.error(libc::ESTALE);
+                return;
+            }
+
+            let resolved = match self.resolve(&path) {
+                Some(p) => p,
+                None => {
                     reply.error(libc::ENOENT);
                     return;
                 }
-                let resolved = match self.resolve_for_branch(branch, rel_path) {
-                    Some(p) => p,
-                    None => {
-                        reply.error(libc::ENOENT);
-                        return;
-                    }
-                };
-                match self.make_attr(ino, &resolved) {
-                    Some(attr) => reply.attr(&TTL, &attr),
-                    None => reply.error(libc::ENOENT),
+            };
+            let is_dir = storage::is_dir(&resolved);
+            let ino = self.inodes.get_or_create(&path, is_dir);
+            match self.make_attr(ino, &resolved) {
+                Some(attr) => self.reply_entry(&attr, reply),
+                None => reply.error(libc::ENOENT),
+            }
+            return;
+        }
+
+        // === Parent is inside an @branch subtree ===
+        let branch_ctx = match self.classify(&parent_path) {
+            PathContext::BranchDir(b) => Some((b, "/".to_string())),
+            PathContext::BranchPath(b, rel) => Some((b, rel)),
+            _ => None,
+        };
+
+        if let Some((branch, parent_rel)) = branch_ctx {
+            // Looking up .branchfs_ctl inside a branch dir (only at branch root)
+            if parent_rel == "/" && name_str == CTL_FILE {
+                match self.get_or_create_branch_ctl_ino(&branch) {
+                    Some(ctl_ino) => self.reply_entry(&self.ctl_file_attr(ctl_ino), reply),
+                    None => reply.error(libc::ENOSPC),
+                }
+                return;
+            }
+
+            // Looking up @child inside a branch dir (nested branch), unless
+            // the branch has a real entry of that name
+            let child_name = format!("/{}", name_str);
+            if let Some(child_branch) = name_str.strip_prefix('@').filter(|c| {
+                parent_rel == "/" && !self.shadows_branch(Some(&branch), &child_name, c)
+            }) {
+                let children = self.manager.get_children(&branch);
+                if children.iter().any(|c| c == child_branch) {
+                    let inode_path = format!("/@{}/@{}", branch, child_branch);
+                    let ino = self.inodes.get_or_create(&inode_path, true);
+                    self.reply_entry(&self.synthetic_dir_attr(ino), reply);
+                } else {
+                    reply.error(libc::ENOENT);
                 }
+                return;
             }
-            PathContext::RootCtl => {
-                reply.attr(&TTL, &self.ctl_file_attr(CTL_INO));
+
+            // Regular file/dir inside branch
+            if !self.manager.is_branch_valid(&branch) {
+                reply.error(libc::ENOENT);
+                return;
             }
-            PathContext::RootPath(ref rp) => {
-                if ino != ROOT_INO && self.is_stale() {
-                    reply.error(libc::ESTALE);
+
+            let child_rel = if parent_rel == "/" {
+                format!("/{}", name_str)
+            } else {
+                format!("{}/{}", parent_rel, name_str)
+            };
+
+            let resolved = match self.resolve_for_branch(&branch, &child_rel) {
+                Some(p) => p,
+                None => {
+                    reply.error(libc::ENOENT);
                     return;
                 }
-                let resolved = match self.resolve(rp) {
-                    Some(p) => p,
-                    None => {
-                        if ino == ROOT_INO {
-                            reply.attr(&TTL, &self.synthetic_dir_attr(ROOT_INO));
-                            return;
-                        }
-                        reply.error(libc::ENOENT);
-                        return;
-                    }
-                };
-                match self.make_attr(ino, &resolved) {
-                    Some(attr) => reply.attr(&TTL, &attr),
-                    None => reply.error(libc::ENOENT),
+            };
+
+            let inode_path = format!("/@{}{}", branch, child_rel);
+            let is_dir = storage::is_dir(&resolved);
+            let ino = self.inodes.get_or_create(&inode_path, is_dir);
+            match self.make_attr(ino, &resolved) {
+                Some(attr) => self.reply_entry(&attr, reply),
+                None => reply.error(libc::ENOENT),
+            }
+        } else {
+            // Parent is a regular root-path subdir
+            if self.is_stale() {
+                reply.error(libc::ESTALE);
+                return;
+            }
+
+            let path = format!("{}/{}", parent_path, name_str);
+            let resolved = match self.resolve(&path) {
+                Some(p) => p,
+                None => {
+                    reply.error(libc::ENOENT);
+                    return;
                 }
+            };
+            let is_dir = storage::is_dir(&resolved);
+            let ino = self.inodes.get_or_create(&path, is_dir);
+            match self.make_attr(ino, &resolved) {
+                Some(attr) => self.reply_entry(&attr, reply),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request, nodes: &[fuser::fuse_forget_one]) {
+        for node in nodes {
+            self.inodes.forget(node.nodeid, node.nlookup);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr_of(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let attr = match self.attr_of(ino) {
+            Ok(attr) => attr,
+            Err(errno) => {
+                reply.error(errno);
+                return;
             }
+        };
+        // Synthetic entries are the mounting user's to use however the
+        // caller probes them
+        if req.uid() == attr.uid && self.is_synthetic_ino(ino) {
+            reply.ok();
+            return;
+        }
+        if self.options.read_only && mask & libc::W_OK != 0 {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let allowed = storage::access_allowed(
+            attr.perm as u32 & 0o7777,
+            attr.kind == FileType::Directory,
+            (attr.uid, attr.gid),
+            req.uid(),
+            req.gid(),
+            mask,
+        );
+        if allowed {
+            reply.ok();
+        } else {
+            reply.error(libc::EACCES);
         }
     }
 
@@ -471,26 +1401,35 @@ impl Filesystem for BranchFs {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
+        self.handles.lock().touch(fh);
+        if ino == EPOCH_INO && self.options.epoch_file {
+            let content = self.epoch_file_content();
+            let start = (offset as usize).min(content.len());
+            let end = (start + size as usize).min(content.len());
+            reply.data(&content.as_bytes()[start..end]);
+            return;
+        }
+
         let epoch = self.current_epoch.load(Ordering::SeqCst);
 
         // Fast path: reuse cached fd for the same inode+epoch (avoids
         // resolve_path's stat() calls and File::open()/close() every time).
-        if let Some(file) = self.open_cache.get(ino, epoch) {
-            if file.seek(SeekFrom::Start(offset as u64)).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-            let mut buf = vec![0u8; size as usize];
-            match file.read(&mut buf) {
-                Ok(n) => reply.data(&buf[..n]),
-                Err(_) => reply.error(libc::EIO),
+        let window = self.readahead_window();
+        if self.open_cache.matches(ino, epoch) {
+            match self.read_cached(offset as u64, size as usize, window) {
+                Ok(data) => {
+                    reply.data(&data);
+                    self.record_ino_event(ino, BranchEvent::Read);
+                    self.touch_atime(ino);
+                }
+                Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
             }
             return;
         }
@@ -509,13 +1448,14 @@ impl Filesystem for BranchFs {
                         return;
                     }
                 };
-                match File::open(&resolved) {
+                match open_noatime(&resolved) {
                     Ok(file) => {
-                        self.open_cache.insert(ino, epoch, file);
+                        let redirect = self.redirect_of(resolved);
+                        self.open_cache.insert(ino, epoch, file, redirect);
                         false
                     }
-                    Err(_) => {
-                        reply.error(libc::EIO);
+                    Err(e) => {
+                        reply.error(self.io_err_to_errno(&e, libc::EIO));
                         return;
                     }
                 }
@@ -543,13 +1483,14 @@ impl Filesystem for BranchFs {
                         return;
                     }
                 };
-                match File::open(&resolved) {
+                match open_noatime(&resolved) {
                     Ok(file) => {
-                        self.open_cache.insert(ino, epoch, file);
+                        let redirect = self.redirect_of(resolved);
+                        self.open_cache.insert(ino, epoch, file, redirect);
                         true
                     }
-                    Err(_) => {
-                        reply.error(libc::EIO);
+                    Err(e) => {
+                        reply.error(self.io_err_to_errno(&e, libc::EIO));
                         return;
                     }
                 }
@@ -557,143 +1498,291 @@ impl Filesystem for BranchFs {
         };
 
         // Now serve from the just-cached fd
-        if let Some(file) = self.open_cache.get(ino, epoch) {
-            if file.seek(SeekFrom::Start(offset as u64)).is_err() {
-                reply.error(libc::EIO);
+        if self.open_cache.matches(ino, epoch) {
+            match self.read_cached(offset as u64, size as usize, window) {
+                Ok(data) => {
+                    if is_root && self.is_stale() {
+                        reply.error(libc::ESTALE);
+                        return;
+                    }
+                    reply.data(&data);
+                    self.record_ino_event(ino, BranchEvent::Read);
+                    self.touch_atime(ino);
+                }
+                Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
+            }
+        } else {
+            reply.error(libc::EIO);
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        // A handle opened read-only cannot be written through, ctl files
+        // included
+        let flags = {
+            let mut handles = self.handles.lock();
+            handles.touch(fh);
+            handles.flags(fh)
+        };
+        if flags.is_some_and(|flags| flags & libc::O_ACCMODE == libc::O_RDONLY) {
+            reply.error(libc::EBADF);
+            return;
+        }
+        let sync = WriteSync::for_write(flags, self.options.sync_writes);
+        // O_APPEND writes go to the end of the delta as it is now, not to
+        // the end the kernel last saw
+        let pos = match flags {
+            Some(flags) if flags & libc::O_APPEND != 0 => SeekFrom::End(0),
+            _ => SeekFrom::Start(offset as u64),
+        };
+
+        // Invalidate read cache — COW will redirect to delta, so the cached
+        // read fd (pointing to base) becomes wrong.  Readahead data is
+        // dropped even for other inodes since @branch and root paths can
+        // share a backing file.
+        self.open_cache.invalidate_ino(ino);
+        self.open_cache.drop_buffer();
+
+        // === Root ctl file ===
+        if ino == CTL_INO {
+            self.handle_root_ctl_write(data, reply);
+            return;
+        }
+
+        if ino == EPOCH_INO && self.options.epoch_file {
+            reply.error(libc::EBADF);
+            return;
+        }
+
+        // === Per-branch ctl file ===
+        if let Some(branch) = self.branch_for_ctl_ino(ino) {
+            self.handle_branch_ctl_write(&branch, data, reply);
+            return;
+        }
+
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        // Slow path on a handle's first write (or after a switch)
+        let is_root = match self.open_write_handle(ino, fh) {
+            Ok(is_root) => is_root,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let Some(open) = self.fhandles.get_mut(&fh) else {
+            reply.error(libc::EIO);
+            return;
+        };
+        use std::io::Write;
+        let start = match open.file.seek(pos) {
+            Ok(start) => start,
+            Err(e) => {
+                let errno = self.io_err_to_errno(&e, libc::EIO);
+                reply.error(errno);
+                return;
+            }
+        };
+        if let Some(redirect) = &open.redirect {
+            if let Err(e) = self.manager.record_redirect_write(
+                redirect,
+                start,
+                data.len() as u64,
+                sync != WriteSync::None,
+            ) {
+                reply.error(self.io_err_to_errno(&e.into(), libc::EIO));
                 return;
             }
-            let mut buf = vec![0u8; size as usize];
-            match file.read(&mut buf) {
-                Ok(n) => {
-                    if is_root && self.is_stale() {
-                        reply.error(libc::ESTALE);
-                        return;
-                    }
-                    reply.data(&buf[..n])
+        }
+        let file = &mut open.file;
+        match file.write(data).and_then(|n| sync.apply(file).map(|_| n)) {
+            Ok(n) => {
+                if is_root && self.is_stale() {
+                    reply.error(libc::ESTALE);
+                    return;
                 }
-                Err(_) => reply.error(libc::EIO),
+                reply.written(n as u32);
+                self.record_ino_event(ino, BranchEvent::Write);
+                self.manager.metrics.record_write(n as u64);
+            }
+            Err(e) => {
+                let errno = self.io_err_to_errno(&e, libc::EIO);
+                reply.error(errno);
             }
-        } else {
-            reply.error(libc::EIO);
         }
     }
 
-    fn write(
+    /// Copy between two files of the mount without passing the data
+    /// through the kernel's FUSE channel: the destination is copied up like
+    /// on a write, then the backing files are copied with
+    /// `copy_file_range(2)` (a reflink on filesystems that support it).
+    fn copy_file_range(
         &mut self,
         _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
         reply: ReplyWrite,
     ) {
-        // Invalidate read cache — COW will redirect to delta, so the cached
-        // read fd (pointing to base) becomes wrong.
-        self.open_cache.invalidate_ino(ino);
-
-        // === Root ctl file ===
-        if ino == CTL_INO {
-            self.handle_root_ctl_write(data, reply);
+        if self.options.read_only {
+            reply.error(libc::EROFS);
             return;
         }
-
-        // === Per-branch ctl file ===
-        if let Some(branch) = self.branch_for_ctl_ino(ino) {
-            self.handle_branch_ctl_write(&branch, data, reply);
+        let (flags_in, flags_out) = {
+            let mut handles = self.handles.lock();
+            handles.touch(fh_in);
+            handles.touch(fh_out);
+            (handles.flags(fh_in), handles.flags(fh_out))
+        };
+        if flags_in.is_some_and(|flags| flags & libc::O_ACCMODE == libc::O_WRONLY)
+            || flags_out.is_some_and(|flags| flags & libc::O_ACCMODE == libc::O_RDONLY)
+        {
+            reply.error(libc::EBADF);
+            return;
+        }
+        // Ctl and epoch files have no backing file to copy from or to
+        let synthetic =
+            |ino: u64| ino == CTL_INO || ino == EPOCH_INO || self.branch_for_ctl_ino(ino).is_some();
+        if synthetic(ino_in) || synthetic(ino_out) {
+            reply.error(libc::EOPNOTSUPP);
             return;
         }
 
-        let epoch = self.current_epoch.load(Ordering::SeqCst);
-
-        // Fast path: reuse cached write fd for consecutive writes
-        // to the same inode (after COW is already done).
-        if let Some(file) = self.write_cache.get(ino, epoch) {
-            use std::io::{Seek, SeekFrom, Write};
-            if file.seek(SeekFrom::Start(offset as u64)).is_err() {
-                reply.error(libc::EIO);
+        self.open_cache.invalidate_ino(ino_out);
+        self.open_cache.drop_buffer();
+        // The destination first: when both are the same file, the source
+        // must be read from the copy made for the write
+        let out_root = match self.open_write_handle(ino_out, fh_out) {
+            Ok(is_root) => is_root,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
-            match file.write(data) {
-                Ok(n) => reply.written(n as u32),
-                Err(_) => reply.error(libc::EIO),
+        };
+
+        let Some(path) = self.inodes.get_path(ino_in) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let (resolved, in_root) = match self.classify(&path) {
+            PathContext::BranchPath(branch, rel_path) => {
+                (self.resolve_for_branch(&branch, &rel_path), false)
+            }
+            PathContext::BranchDir(_) => {
+                reply.error(libc::EISDIR);
+                return;
             }
+            _ => (self.resolve(&path), true),
+        };
+        let Some(src) = resolved else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        // A redirected delta is partly still the file it inherits
+        if let Err(e) = self.manager.materialize_redirect(&src) {
+            reply.error(self.io_err_to_errno(&e.into(), libc::EIO));
             return;
         }
-
-        // Slow path: resolve, ensure COW, open delta, cache fd.
-        let path = match self.inodes.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(libc::ENOENT);
+        let src_file = match open_noatime(&src) {
+            Ok(file) => file,
+            Err(e) => {
+                reply.error(self.io_err_to_errno(&e, libc::EIO));
                 return;
             }
         };
 
-        let (delta, is_root) = match classify_path(&path) {
-            PathContext::BranchDir(_) | PathContext::BranchCtl(_) => {
-                reply.error(libc::EPERM);
+        let Some(open) = self.fhandles.get(&fh_out) else {
+            reply.error(libc::EIO);
+            return;
+        };
+        // The reply carries a 32-bit count
+        let len = len.min(u64::from(u32::MAX) & !0xfff);
+        let sync = WriteSync::for_write(flags_out, self.options.sync_writes);
+        if let Some(redirect) = &open.redirect {
+            if let Err(e) = self.manager.record_redirect_write(
+                redirect,
+                offset_out as u64,
+                len,
+                sync != WriteSync::None,
+            ) {
+                reply.error(self.io_err_to_errno(&e.into(), libc::EIO));
                 return;
             }
-            PathContext::BranchPath(branch, rel_path) => {
-                if !self.manager.is_branch_valid(&branch) {
-                    reply.error(libc::ENOENT);
+        }
+        let result = storage::copy_range(
+            &src_file,
+            offset_in as u64,
+            &open.file,
+            offset_out as u64,
+            len,
+        )
+        .and_then(|n| sync.apply(&open.file).map(|_| n));
+        match result {
+            Ok(n) => {
+                if (in_root || out_root) && self.is_stale() {
+                    reply.error(libc::ESTALE);
                     return;
                 }
-                match self.ensure_cow_for_branch(&branch, &rel_path) {
-                    Ok(p) => (p, false),
-                    Err(_) => {
-                        reply.error(libc::EIO);
-                        return;
-                    }
-                }
+                reply.written(n as u32);
+                self.record_ino_event(ino_in, BranchEvent::Read);
+                self.record_ino_event(ino_out, BranchEvent::Write);
+                self.manager.metrics.record_write(n);
             }
-            _ => match self.ensure_cow(&path) {
-                Ok(p) => (p, true),
-                Err(_) => {
-                    reply.error(libc::EIO);
-                    return;
-                }
-            },
-        };
+            Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
+        }
+    }
 
-        // Open delta for writing and cache the fd
-        match std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&delta)
-        {
-            Ok(file) => {
-                self.write_cache.insert(ino, epoch, file);
-            }
-            Err(_) => {
-                reply.error(libc::EIO);
-                return;
+    fn opendir(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        // Listings of reaped handles will never be released
+        let handles = self.handles.lock();
+        let reaped: Vec<u64> = self
+            .dir_snapshots
+            .keys()
+            .filter(|fh| !handles.contains(**fh))
+            .copied()
+            .collect();
+        drop(handles);
+        for fh in reaped {
+            if let Some(snapshot) = self.dir_snapshots.remove(&fh) {
+                self.inodes.unpin(snapshot.ino);
             }
         }
 
-        // Serve from the just-cached write fd
-        if let Some(file) = self.write_cache.get(ino, epoch) {
-            use std::io::{Seek, SeekFrom, Write};
-            if file.seek(SeekFrom::Start(offset as u64)).is_err() {
-                reply.error(libc::EIO);
+        // Taking the listing may evict inodes; the handle's own must stay
+        self.inodes.pin(ino);
+        let snapshot = match self.snapshot_dir(ino) {
+            Ok(snapshot) => snapshot,
+            Err(errno) => {
+                self.inodes.unpin(ino);
+                reply.error(errno);
                 return;
             }
-            match file.write(data) {
-                Ok(n) => {
-                    if is_root && self.is_stale() {
-                        reply.error(libc::ESTALE);
-                        return;
-                    }
-                    reply.written(n as u32)
-                }
-                Err(_) => reply.error(libc::EIO),
+        };
+        match self.reply_opened(flags, 0, reply) {
+            Some(fh) => {
+                self.dir_snapshots.insert(fh, snapshot);
             }
-        } else {
-            reply.error(libc::EIO);
+            None => self.inodes.unpin(ino),
         }
     }
 
@@ -701,143 +1790,74 @@ impl Filesystem for BranchFs {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let path = match self.inodes.get_path(ino) {
-            Some(p) => p,
-            None => {
-                if ino == ROOT_INO {
-                    // fallback
-                    "/".to_string()
-                } else {
-                    reply.error(libc::ENOENT);
-                    return;
-                }
-            }
-        };
-
-        match classify_path(&path) {
-            PathContext::BranchDir(branch) => {
-                // Reading a branch dir root: `.`, `..`, `.branchfs_ctl`, @child dirs, real files
-                if !self.manager.is_branch_valid(&branch) {
-                    reply.error(libc::ENOENT);
+        self.handles.lock().touch(fh);
+        // A handle without a snapshot (reaped, or none at all) lists the
+        // directory as it is now, kept under the handle so later pages
+        // continue it instead of listing it all again
+        if self.dir_snapshots.get(&fh).is_none_or(|s| s.ino != ino) {
+            self.inodes.pin(ino);
+            let snapshot = match self.snapshot_dir(ino) {
+                Ok(snapshot) => snapshot,
+                Err(errno) => {
+                    self.inodes.unpin(ino);
+                    reply.error(errno);
                     return;
                 }
-
-                let inode_prefix = format!("/@{}", branch);
-                let mut entries = self.collect_readdir_entries(&branch, "/", ino, &inode_prefix);
-
-                // Add .branchfs_ctl
-                let ctl_ino = self.get_or_create_branch_ctl_ino(&branch);
-                entries.push((ctl_ino, FileType::RegularFile, CTL_FILE.to_string()));
-
-                // Add @child virtual dirs for children of this branch
-                let children = self.manager.get_children(&branch);
-                for child in children {
-                    let child_inode_path = format!("/@{}/@{}", branch, child);
-                    let child_ino = self.inodes.get_or_create(&child_inode_path, true);
-                    entries.push((child_ino, FileType::Directory, format!("@{}", child)));
-                }
-
-                for (i, (e_ino, kind, name)) in
-                    entries.into_iter().enumerate().skip(offset as usize)
-                {
-                    if reply.add(e_ino, (i + 1) as i64, kind, &name) {
-                        break;
-                    }
-                }
-                reply.ok();
+            };
+            if let Some(old) = self.dir_snapshots.insert(fh, snapshot) {
+                self.inodes.unpin(old.ino);
             }
-            PathContext::BranchPath(branch, rel_path) => {
-                // Reading a subdirectory inside a branch
-                if !self.manager.is_branch_valid(&branch) {
-                    reply.error(libc::ENOENT);
-                    return;
-                }
+        }
+        let snapshot = &self.dir_snapshots[&fh];
 
-                let inode_prefix = format!("/@{}", branch);
-                let entries = self.collect_readdir_entries(&branch, &rel_path, ino, &inode_prefix);
+        if snapshot.root_view && self.is_stale() {
+            reply.error(libc::ESTALE);
+            return;
+        }
 
-                for (i, (e_ino, kind, name)) in
-                    entries.into_iter().enumerate().skip(offset as usize)
-                {
-                    if reply.add(e_ino, (i + 1) as i64, kind, &name) {
-                        break;
-                    }
-                }
-                reply.ok();
+        let skip = usize::try_from(offset).unwrap_or(0);
+        for (i, (e_ino, kind, name)) in snapshot.entries.iter().enumerate().skip(skip) {
+            if reply.add(*e_ino, i as i64 + 1, *kind, storage::to_os_path(name)) {
+                break;
             }
-            PathContext::RootPath(ref rp) if rp == "/" => {
-                // Root directory: existing entries + @branch virtual dirs
-                if self.is_stale() {
-                    reply.error(libc::ESTALE);
-                    return;
-                }
-
-                let branch_name = self.get_branch_name();
-                let mut entries = self.collect_readdir_entries(&branch_name, "/", ino, "");
-
-                // Add .branchfs_ctl
-                entries.push((CTL_INO, FileType::RegularFile, CTL_FILE.to_string()));
-
-                // Add @branch virtual dirs for branches that are children of
-                // the root's current branch (i.e. main's children typically)
-                // We list ALL non-main branches as @branch dirs at root level.
-                let branches = self.manager.list_branches();
-                for (bname, _parent) in branches {
-                    if bname != "main" {
-                        let inode_path = format!("/@{}", bname);
-                        let bino = self.inodes.get_or_create(&inode_path, true);
-                        entries.push((bino, FileType::Directory, format!("@{}", bname)));
-                    }
-                }
-
-                if self.is_stale() {
-                    reply.error(libc::ESTALE);
-                    return;
-                }
-
-                for (i, (e_ino, kind, name)) in
-                    entries.into_iter().enumerate().skip(offset as usize)
-                {
-                    if reply.add(e_ino, (i + 1) as i64, kind, &name) {
-                        break;
-                    }
-                }
+        }
+        reply.ok();
+    }
 
-                reply.ok();
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.handles.lock().touch(fh);
+        let Some((branch, delta)) = self.delta_of_ino(ino) else {
+            reply.ok();
+            return;
+        };
+        let sync = if datasync {
+            WriteSync::Data
+        } else {
+            WriteSync::All
+        };
+        if delta.is_dir() {
+            if let Err(e) = File::open(&delta).and_then(|dir| sync.apply(&dir)) {
+                reply.error(self.io_err_to_errno(&e, libc::EIO));
+                return;
             }
-            PathContext::RootPath(ref rp2) => {
-                // Non-root subdir via current branch (existing logic)
-                if self.is_stale() {
-                    reply.error(libc::ESTALE);
-                    return;
-                }
-
-                let branch_name = self.get_branch_name();
-                let entries = self.collect_readdir_entries(&branch_name, rp2, ino, "");
-
-                if self.is_stale() {
-                    reply.error(libc::ESTALE);
-                    return;
-                }
-
-                for (i, (e_ino, kind, name)) in
-                    entries.into_iter().enumerate().skip(offset as usize)
-                {
-                    if reply.add(e_ino, (i + 1) as i64, kind, &name) {
-                        break;
-                    }
-                }
+        }
+        // Deletions are recorded in the tombstones file, not the directory
+        match self.manager.sync_tombstones(&branch) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.io_err_to_errno(&e.into(), libc::EIO)),
+        }
+    }
 
-                reply.ok();
-            }
-            _ => {
-                reply.error(libc::ENOTDIR);
-            }
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        if let Some(snapshot) = self.dir_snapshots.remove(&fh) {
+            self.inodes.unpin(snapshot.ino);
         }
+        self.handles.lock().release(fh);
+        reply.ok();
     }
 
     fn create(
@@ -847,9 +1867,13 @@ impl Filesystem for BranchFs {
         name: &OsStr,
         mode: u32,
         umask: u32,
-        _flags: i32,
+        flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
         let parent_path = match self.inodes.get_path(parent) {
             Some(p) => p,
             None => {
@@ -858,9 +1882,9 @@ impl Filesystem for BranchFs {
             }
         };
 
-        let name_str = name.to_string_lossy();
+        let name_str = storage::name_to_string(name);
 
-        let branch_ctx = match classify_path(&parent_path) {
+        let branch_ctx = match self.classify(&parent_path) {
             PathContext::BranchDir(b) => Some((b, "/".to_string())),
             PathContext::BranchPath(b, rel) => Some((b, rel)),
             _ => None,
@@ -877,175 +1901,440 @@ impl Filesystem for BranchFs {
             } else {
                 format!("{}/{}", parent_rel, name_str)
             };
-            let delta = self.get_delta_path_for_branch(&branch, &rel_path);
-            if storage::ensure_parent_dirs(&delta).is_err() {
-                reply.error(libc::EIO);
+            match self.create_delta_for_branch(&branch, &rel_path, flags) {
+                Ok((delta, existed)) => {
+                    if !existed {
+                        use std::os::unix::fs::PermissionsExt;
+                        let perm = std::fs::Permissions::from_mode(mode & !umask);
+                        let _ = std::fs::set_permissions(&delta, perm);
+                    }
+                    // The branch may have been aborted while the file was
+                    // created; don't leave a delta behind for it
+                    if !self.manager.is_branch_valid(&branch) {
+                        self.discard_orphan_delta(&branch, &delta);
+                        reply.error(libc::ENOENT);
+                        return;
+                    }
+                    let inode_path = format!("/@{}{}", branch, rel_path);
+                    let ino = self.inodes.get_or_create(&inode_path, false);
+                    if let Some(attr) = self.make_attr(ino, &delta) {
+                        if let Some(fh) = self.reply_created(&attr, flags, reply) {
+                            self.track_writer(fh, flags, &branch, &rel_path);
+                        }
+                    } else {
+                        reply.error(libc::EIO);
+                    }
+                }
+                Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
+            }
+        } else {
+            match self.classify(&parent_path) {
+                PathContext::BranchCtl(_) | PathContext::RootCtl => {
+                    reply.error(libc::EPERM);
+                }
+                PathContext::RootPath(rp) => {
+                    // Existing root-path logic
+                    let path = if rp == "/" {
+                        format!("/{}", name_str)
+                    } else {
+                        format!("{}/{}", rp, name_str)
+                    };
+
+                    if self.is_stale() {
+                        reply.error(libc::ESTALE);
+                        return;
+                    }
+                    let branch_name = self.get_branch_name();
+                    match self.create_delta_for_branch(&branch_name, &path, flags) {
+                        Ok((delta, existed)) => {
+                            if !existed {
+                                use std::os::unix::fs::PermissionsExt;
+                                let perm = std::fs::Permissions::from_mode(mode & !umask);
+                                let _ = std::fs::set_permissions(&delta, perm);
+                            }
+                            // The mount went stale meanwhile: only a file
+                            // this call created is ours to take back
+                            if self.is_stale() {
+                                if !existed {
+                                    let _ = std::fs::remove_file(&delta);
+                                }
+                                reply.error(libc::ESTALE);
+                                return;
+                            }
+                            let ino = self.inodes.get_or_create(&path, false);
+                            if let Some(attr) = self.make_attr(ino, &delta) {
+                                if let Some(fh) = self.reply_created(&attr, flags, reply) {
+                                    self.track_writer(fh, flags, &branch_name, &path);
+                                }
+                            } else {
+                                reply.error(libc::EIO);
+                            }
+                        }
+                        Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
+                    }
+                }
+                _ => {
+                    reply.error(libc::ENOENT);
+                }
+            }
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.remove(parent, name, false, reply);
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.remove(parent, name, true, reply);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let (from, to) = match (
+            self.entry_target(parent, name),
+            self.entry_target(newparent, newname),
+        ) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(errno), _) | (_, Err(errno)) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        // Both ends must share a branch and a view of it: `@branch` paths
+        // and root paths have separate inodes
+        if from.0 != to.0 || from.1 != to.1 {
+            reply.error(libc::EXDEV);
+            return;
+        }
+        let (via_root, branch, from_rel) = from;
+        let to_rel = to.2;
+
+        if via_root && self.is_stale() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+        if !self.manager.is_branch_valid(&branch) {
+            reply.error(libc::ENOENT);
+            return;
+        }
+
+        let replace = flags & libc::RENAME_NOREPLACE == 0;
+        if let Err(e) = self.manager.move_path(
+            &branch,
+            &self.branch_rel(&from_rel),
+            &self.branch_rel(&to_rel),
+            replace,
+        ) {
+            let errno = match e {
+                BranchError::NotFound(_) => libc::ENOENT,
+                BranchError::AlreadyExists(_) => libc::EEXIST,
+                BranchError::Invalid(_) => libc::EINVAL,
+                BranchError::Io(ref io) => match io.raw_os_error() {
+                    Some(errno @ (libc::ENOTDIR | libc::EISDIR | libc::ENOTEMPTY)) => errno,
+                    _ => self.io_err_to_errno(io, libc::EIO),
+                },
+                _ => libc::EIO,
+            };
+            reply.error(errno);
+            return;
+        }
+
+        let (old_ino_path, new_ino_path) = if via_root {
+            (from_rel, to_rel)
+        } else {
+            (
+                format!("/@{}{}", branch, from_rel),
+                format!("/@{}{}", branch, to_rel),
+            )
+        };
+        if let Some(ino) = self.inodes.get_ino(&old_ino_path) {
+            self.open_cache.invalidate_ino(ino);
+            self.close_write_fds(ino);
+        }
+        self.inodes.rename(&old_ino_path, &new_ino_path);
+        reply.ok();
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let (via_root, branch, rel_path) = match self.entry_target(parent, link_name) {
+            Ok(t) => t,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
-            match std::fs::File::create(&delta) {
-                Ok(_) => {
-                    use std::os::unix::fs::PermissionsExt;
-                    let perm = std::fs::Permissions::from_mode(mode & !umask);
-                    let _ = std::fs::set_permissions(&delta, perm);
-                    let inode_path = format!("/@{}{}", branch, rel_path);
-                    let ino = self.inodes.get_or_create(&inode_path, false);
-                    if let Some(attr) = self.make_attr(ino, &delta) {
-                        reply.created(&TTL, &attr, 0, 0, 0);
-                    } else {
-                        reply.error(libc::EIO);
-                    }
-                }
-                Err(_) => reply.error(libc::EIO),
+        };
+        if via_root && self.is_stale() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+        if !self.manager.is_branch_valid(&branch) {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        if self.resolve_for_branch(&branch, &rel_path).is_some() {
+            reply.error(libc::EEXIST);
+            return;
+        }
+        let delta = match self.get_delta_path_for_branch(&branch, &rel_path) {
+            Some(d) => d,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
             }
+        };
+        let result = self
+            .ensure_delta_parents(&branch, &rel_path)
+            .and_then(|()| std::os::unix::fs::symlink(target, &delta));
+        if let Err(e) = result {
+            reply.error(self.io_err_to_errno(&e, libc::EIO));
+            return;
+        }
+        // A previously deleted path becomes visible again
+        let branch_path = self.branch_rel(&rel_path);
+        let _ = self
+            .manager
+            .with_branch(&branch, |b| b.untombstone(&branch_path));
+
+        let inode_path = if via_root {
+            rel_path
         } else {
-            match classify_path(&parent_path) {
-                PathContext::BranchCtl(_) | PathContext::RootCtl => {
-                    reply.error(libc::EPERM);
-                }
-                PathContext::RootPath(rp) => {
-                    // Existing root-path logic
-                    let path = if rp == "/" {
-                        format!("/{}", name_str)
-                    } else {
-                        format!("{}/{}", rp, name_str)
-                    };
-
-                    let delta = self.get_delta_path(&path);
-                    if storage::ensure_parent_dirs(&delta).is_err() {
-                        reply.error(libc::EIO);
-                        return;
-                    }
-
-                    match std::fs::File::create(&delta) {
-                        Ok(_) => {
-                            use std::os::unix::fs::PermissionsExt;
-                            let perm = std::fs::Permissions::from_mode(mode & !umask);
-                            let _ = std::fs::set_permissions(&delta, perm);
-                            if self.is_stale() {
-                                let _ = std::fs::remove_file(&delta);
-                                reply.error(libc::ESTALE);
-                                return;
-                            }
-                            let ino = self.inodes.get_or_create(&path, false);
-                            if let Some(attr) = self.make_attr(ino, &delta) {
-                                reply.created(&TTL, &attr, 0, 0, 0);
-                            } else {
-                                reply.error(libc::EIO);
-                            }
-                        }
-                        Err(_) => reply.error(libc::EIO),
-                    }
-                }
-                _ => {
-                    reply.error(libc::ENOENT);
-                }
+            format!("/@{}{}", branch, rel_path)
+        };
+        let ino = self.inodes.get_or_create(&inode_path, false);
+        match self.make_attr(ino, &delta) {
+            Some(attr) => {
+                self.manager.record_event(&branch, BranchEvent::Create);
+                self.reply_entry(&attr, reply);
             }
+            None => reply.error(libc::EIO),
         }
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let parent_path = match self.inodes.get_path(parent) {
-            Some(p) => p,
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let source = match self.inodes.get_path(ino).map(|p| self.classify(&p)) {
+            Some(PathContext::BranchPath(branch, rel_path)) => (false, branch, rel_path),
+            Some(PathContext::RootPath(rp)) if rp != "/" => (true, self.get_branch_name(), rp),
+            Some(_) => {
+                reply.error(libc::EPERM);
+                return;
+            }
             None => {
                 reply.error(libc::ENOENT);
                 return;
             }
         };
-
-        let name_str = name.to_string_lossy();
-
-        let branch_ctx = match classify_path(&parent_path) {
-            PathContext::BranchDir(b) => Some((b, "/".to_string())),
-            PathContext::BranchPath(b, rel) => Some((b, rel)),
-            _ => None,
+        let (via_root, branch, new_rel) = match self.entry_target(newparent, newname) {
+            Ok(t) => t,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
         };
+        // Same rule as rename: one branch, seen through one view
+        if source.0 != via_root || source.1 != branch {
+            reply.error(libc::EXDEV);
+            return;
+        }
+        let rel_path = source.2;
 
-        if let Some((branch, parent_rel)) = branch_ctx {
-            // Can't unlink @child dirs or .branchfs_ctl
-            if name_str.starts_with('@') || *name_str == *CTL_FILE {
+        if via_root && self.is_stale() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+        if !self.manager.is_branch_valid(&branch) {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        match self.resolve_for_branch(&branch, &rel_path) {
+            Some(resolved) if storage::is_dir(&resolved) => {
                 reply.error(libc::EPERM);
                 return;
             }
-
-            if !self.manager.is_branch_valid(&branch) {
+            Some(_) => {}
+            None => {
                 reply.error(libc::ENOENT);
                 return;
             }
+        }
+        if self.resolve_for_branch(&branch, &new_rel).is_some() {
+            reply.error(libc::EEXIST);
+            return;
+        }
 
-            let rel_path = if parent_rel == "/" {
-                format!("/{}", name_str)
-            } else {
-                format!("{}/{}", parent_rel, name_str)
-            };
-
-            let result = self.manager.with_branch(&branch, |b| {
-                b.add_tombstone(&rel_path)?;
-                let delta = b.delta_path(&rel_path);
-                if delta.exists() {
-                    std::fs::remove_file(&delta)?;
-                }
-                Ok(())
+        // Both names must share the branch's own copy of the file
+        let result = self
+            .ensure_cow_for_branch(&branch, &rel_path)
+            .and_then(|src| {
+                self.manager
+                    .materialize_redirect(&src)
+                    .map_err(std::io::Error::from)?;
+                let dst = self.require_delta_path(&branch, &new_rel)?;
+                self.ensure_delta_parents(&branch, &new_rel)?;
+                std::fs::hard_link(&src, &dst)?;
+                Ok(dst)
             });
-
-            if result.is_err() {
-                reply.error(libc::EIO);
+        let dst = match result {
+            Ok(dst) => dst,
+            Err(e) => {
+                reply.error(self.io_err_to_errno(&e, libc::EIO));
                 return;
             }
-
-            let inode_path = format!("/@{}{}", branch, rel_path);
-            self.inodes.remove(&inode_path);
-            reply.ok();
+        };
+        // A previously deleted path becomes visible again
+        let branch_path = self.branch_rel(&new_rel);
+        let _ = self
+            .manager
+            .with_branch(&branch, |b| b.untombstone(&branch_path));
+
+        let inode_path = if via_root {
+            new_rel
         } else {
-            // Root-path unlink (or EPERM for ctl files)
-            match classify_path(&parent_path) {
-                PathContext::BranchCtl(_) | PathContext::RootCtl => {
-                    reply.error(libc::EPERM);
-                }
-                PathContext::RootPath(rp) => {
-                    let path = if rp == "/" {
-                        format!("/{}", name_str)
-                    } else {
-                        format!("{}/{}", rp, name_str)
-                    };
-
-                    let result = self.manager.with_branch(&self.get_branch_name(), |b| {
-                        b.add_tombstone(&path)?;
-                        let delta = b.delta_path(&path);
-                        if delta.exists() {
-                            std::fs::remove_file(&delta)?;
-                        }
-                        Ok(())
-                    });
-
-                    if result.is_err() || self.is_stale() {
-                        reply.error(libc::ESTALE);
-                        return;
-                    }
+            format!("/@{}{}", branch, new_rel)
+        };
+        let new_ino = self.inodes.get_or_create(&inode_path, false);
+        match self.make_attr(new_ino, &dst) {
+            Some(attr) => {
+                self.manager.record_event(&branch, BranchEvent::Create);
+                self.reply_entry(&attr, reply);
+            }
+            None => reply.error(libc::EIO),
+        }
+    }
 
-                    self.inodes.remove(&path);
-                    reply.ok();
-                }
-                _ => {
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let path = match self.inodes.get_path(ino) {
+            Some(p) => p,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        let (rel_path, resolved) = match self.classify(&path) {
+            PathContext::BranchPath(branch, rel_path) => {
+                if !self.manager.is_branch_valid(&branch) {
                     reply.error(libc::ENOENT);
+                    return;
+                }
+                let resolved = self.resolve_for_branch(&branch, &rel_path);
+                (rel_path, resolved)
+            }
+            PathContext::RootPath(rp) => {
+                if self.is_stale() {
+                    reply.error(libc::ESTALE);
+                    return;
                 }
+                let resolved = self.resolve(&rp);
+                (rp, resolved)
+            }
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        let resolved = match resolved {
+            Some(r) => r,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        match std::fs::read_link(&resolved) {
+            Ok(target) => {
+                use std::os::unix::ffi::OsStrExt;
+                let target = self.mount_link_target(&rel_path, target);
+                reply.data(target.as_os_str().as_bytes());
             }
+            Err(e) => reply.error(self.io_err_to_errno(&e, libc::EINVAL)),
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        self.unlink(_req, parent, name, reply);
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        // Every write lands in a delta under the storage directory, so its
+        // filesystem is the one that runs out of space.  There are no
+        // per-branch quotas yet; once there are, a quota'd branch should
+        // report its remaining allowance here instead.
+        // Counts are rescaled to BLOCK_SIZE units, the unit getattr reports
+        // file blocks in
+        match nix::sys::statvfs::statvfs(&self.manager.storage_path) {
+            Ok(st) => {
+                let frsize = st.fragment_size().max(1);
+                let scale = |blocks: u64| blocks.saturating_mul(frsize) / BLOCK_SIZE as u64;
+                let namelen = match st.name_max() {
+                    0 => 255,
+                    n => n as u32,
+                };
+                reply.statfs(
+                    scale(st.blocks()),
+                    scale(st.blocks_free()),
+                    scale(st.blocks_available()),
+                    st.files(),
+                    st.files_free(),
+                    BLOCK_SIZE,
+                    namelen,
+                    BLOCK_SIZE,
+                )
+            }
+            Err(e) => reply.error(e as i32),
+        }
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        // Control file is always openable (no epoch check)
-        if ino == CTL_INO {
-            reply.opened(0, 0);
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        // Control files are always openable (no epoch check), for reading
+        // or writing
+        if ino == CTL_INO || self.branch_for_ctl_ino(ino).is_some() {
+            self.open_handle(flags, false, false, 0, reply);
+            return;
+        }
+
+        // Epoch file is read-only; direct I/O so every read sees the
+        // current epoch rather than cached pages
+        if ino == EPOCH_INO && self.options.epoch_file {
+            self.open_handle(flags, false, true, fuser::consts::FOPEN_DIRECT_IO, reply);
             return;
         }
 
-        // Branch ctl files are always openable
-        if self.branch_for_ctl_ino(ino).is_some() {
-            reply.opened(0, 0);
+        if self.options.read_only
+            && (flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0)
+        {
+            reply.error(libc::EROFS);
             return;
         }
 
@@ -1057,24 +2346,22 @@ impl Filesystem for BranchFs {
             }
         };
 
-        match classify_path(&path) {
+        let resolved = match self.classify(&path) {
             PathContext::BranchDir(_) => {
-                reply.opened(0, 0);
+                self.open_handle(flags, true, false, 0, reply);
+                return;
             }
             PathContext::BranchCtl(_) => {
-                reply.opened(0, 0);
+                self.open_handle(flags, false, false, 0, reply);
+                return;
             }
             PathContext::BranchPath(branch, rel_path) => {
                 if !self.manager.is_branch_valid(&branch) {
                     reply.error(libc::ENOENT);
                     return;
                 }
-                if self.resolve_for_branch(&branch, &rel_path).is_some() {
-                    self.manager.register_opened_inode(&branch, ino);
-                    reply.opened(0, 0);
-                } else {
-                    reply.error(libc::ENOENT);
-                }
+                self.resolve_for_branch(&branch, &rel_path)
+                    .map(|resolved| (branch, rel_path, resolved))
             }
             _ => {
                 // Root path
@@ -1082,15 +2369,95 @@ impl Filesystem for BranchFs {
                     reply.error(libc::ESTALE);
                     return;
                 }
-                if self.resolve(&path).is_some() {
-                    self.manager
-                        .register_opened_inode(&self.get_branch_name(), ino);
-                    reply.opened(0, 0);
-                } else {
-                    reply.error(libc::ENOENT);
-                }
+                self.resolve(&path)
+                    .map(|resolved| (self.get_branch_name(), path.clone(), resolved))
+            }
+        };
+
+        let (branch, rel_path, resolved) = match resolved {
+            Some(r) => r,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if let Err(errno) = check_open_flags(flags, resolved.is_dir(), false) {
+            reply.error(errno);
+            return;
+        }
+        if flags & libc::O_TRUNC != 0 {
+            if let Err(e) = self.create_delta_for_branch(&branch, &rel_path, flags) {
+                reply.error(self.io_err_to_errno(&e, libc::EIO));
+                return;
             }
+            self.open_cache.invalidate_ino(ino);
+            self.close_write_fds(ino);
+            self.record_ino_event(ino, BranchEvent::Write);
+        }
+        if let Some(fh) = self.reply_opened(flags, 0, reply) {
+            self.track_writer(fh, flags, &branch, &rel_path);
+            self.manager.register_opened_inode(&branch, ino);
+            self.inodes.pin(ino);
+            self.open_inodes.insert(fh, (branch, ino));
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        // Writes reach the delta before they are acknowledged, so a close
+        // has nothing left to push; the fd stays until release
+        self.handles.lock().touch(fh);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.handles.lock().touch(fh);
+        let sync = if datasync {
+            WriteSync::Data
+        } else {
+            WriteSync::All
+        };
+        // Writes land in the delta, so syncing it (and a redirected delta's
+        // log of the ranges written) covers them; a file the branch never
+        // wrote to is the base's and has nothing pending
+        let result = match self.fhandles.get(&fh).filter(|f| f.ino == ino) {
+            Some(open) => sync.apply(&open.file).and_then(|()| match &open.redirect {
+                Some(redirect) => Ok(self.manager.sync_redirect(redirect)?),
+                None => Ok(()),
+            }),
+            None => match self.delta_of_ino(ino) {
+                Some((_, delta)) if delta.is_file() => {
+                    File::open(&delta).and_then(|file| sync.apply(&file))
+                }
+                _ => Ok(()),
+            },
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.lock().release(fh);
+        self.open_cache.release(ino);
+        self.fhandles.remove(&fh);
+        if let Some((branch, ino)) = self.open_inodes.remove(&fh) {
+            self.manager.release_opened_inode(&branch, ino);
+            self.inodes.unpin(ino);
         }
+        if let Some(id) = self.open_writers.remove(&fh) {
+            self.manager.release_writer(id);
+        }
+        reply.ok();
     }
 
     fn setattr(
@@ -1114,7 +2481,7 @@ impl Filesystem for BranchFs {
         // Truncation triggers COW — invalidate cached fds
         if size.is_some() {
             self.open_cache.invalidate_ino(ino);
-            self.write_cache.invalidate_ino(ino);
+            self.close_write_fds(ino);
         }
 
         // Handle root ctl file (virtual — not in inode table)
@@ -1123,6 +2490,11 @@ impl Filesystem for BranchFs {
             return;
         }
 
+        if ino == EPOCH_INO && self.options.epoch_file {
+            reply.error(libc::EPERM);
+            return;
+        }
+
         // Handle per-branch ctl files (virtual — not in inode table)
         if let Some(branch) = self.branch_for_ctl_ino(ino) {
             if self.manager.is_branch_valid(&branch) {
@@ -1133,6 +2505,11 @@ impl Filesystem for BranchFs {
             return;
         }
 
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let path = match self.inodes.get_path(ino) {
             Some(p) => p,
             None => {
@@ -1141,77 +2518,55 @@ impl Filesystem for BranchFs {
             }
         };
 
-        match classify_path(&path) {
+        let (branch, rel_path, is_root) = match self.classify(&path) {
             PathContext::BranchDir(_) | PathContext::BranchCtl(_) => {
                 reply.error(libc::EPERM);
+                return;
             }
             PathContext::BranchPath(branch, rel_path) => {
                 if !self.manager.is_branch_valid(&branch) {
                     reply.error(libc::ENOENT);
                     return;
                 }
-                if let Some(new_size) = size {
-                    if let Ok(delta) = self.ensure_cow_for_branch(&branch, &rel_path) {
-                        let file = std::fs::OpenOptions::new().write(true).open(&delta);
-                        if let Ok(f) = file {
-                            let _ = f.set_len(new_size);
-                        }
-                    }
-                }
-                if mode.is_some()
-                    || uid.is_some()
-                    || gid.is_some()
-                    || atime.is_some()
-                    || mtime.is_some()
-                {
-                    if let Ok(delta) = self.ensure_cow_for_branch(&branch, &rel_path) {
-                        Self::apply_setattr(&delta, mode, uid, gid, atime, mtime);
-                    }
-                }
-                if let Some(resolved) = self.resolve_for_branch(&branch, &rel_path) {
-                    if let Some(attr) = self.make_attr(ino, &resolved) {
-                        reply.attr(&TTL, &attr);
-                        return;
-                    }
-                }
-                reply.error(libc::ENOENT);
+                (branch, rel_path, false)
             }
-            _ => {
-                // Root path (existing logic)
-                if let Some(new_size) = size {
-                    if let Ok(delta) = self.ensure_cow(&path) {
-                        let file = std::fs::OpenOptions::new().write(true).open(&delta);
-                        if let Ok(f) = file {
-                            let _ = f.set_len(new_size);
-                        }
-                    }
-                }
-                if mode.is_some()
-                    || uid.is_some()
-                    || gid.is_some()
-                    || atime.is_some()
-                    || mtime.is_some()
-                {
-                    if let Ok(delta) = self.ensure_cow(&path) {
-                        Self::apply_setattr(&delta, mode, uid, gid, atime, mtime);
-                    }
-                }
-
-                if self.is_stale() {
-                    reply.error(libc::ESTALE);
-                    return;
-                }
+            _ => (self.get_branch_name(), path, true),
+        };
 
-                if let Some(resolved) = self.resolve(&path) {
-                    if let Some(attr) = self.make_attr(ino, &resolved) {
-                        reply.attr(&TTL, &attr);
-                        return;
+        // Every change lands on the branch's own copy, so it survives commit
+        let changes_attrs =
+            mode.is_some() || uid.is_some() || gid.is_some() || atime.is_some() || mtime.is_some();
+        if size.is_some() || changes_attrs {
+            let result = self
+                .ensure_cow_for_branch(&branch, &rel_path)
+                .and_then(|delta| {
+                    if let Some(new_size) = size {
+                        self.manager.truncate_redirect(&delta, new_size)?;
+                        std::fs::OpenOptions::new()
+                            .write(true)
+                            .open(&delta)?
+                            .set_len(new_size)?;
                     }
-                }
+                    Self::apply_setattr(&delta, mode, uid, gid, atime, mtime)
+                });
+            if let Err(e) = result {
+                reply.error(self.io_err_to_errno(&e, libc::EIO));
+                return;
+            }
+        }
 
-                reply.error(libc::ENOENT);
+        if is_root && self.is_stale() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+
+        if let Some(resolved) = self.resolve_for_branch(&branch, &rel_path) {
+            if let Some(attr) = self.make_attr(ino, &resolved) {
+                reply.attr(&TTL, &attr);
+                return;
             }
         }
+        reply.error(libc::ENOENT);
     }
 
     fn ioctl(
@@ -1225,11 +2580,20 @@ impl Filesystem for BranchFs {
         _out_size: u32,
         reply: ReplyIoctl,
     ) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
         let branch_name = self.get_branch_name();
         match cmd {
             BRANCHFS_IOC_COMMIT => {
                 log::info!("ioctl: COMMIT for branch '{}'", branch_name);
-                match self.manager.commit(&branch_name) {
+                match self.manager.commit(
+                    &branch_name,
+                    self.options.hardlinks,
+                    self.options.commit_mtime,
+                    false,
+                ) {
                     Ok(parent) => {
                         self.switch_to_branch(&parent);
                         log::info!("Switched to branch '{}' after commit", parent);
@@ -1237,7 +2601,7 @@ impl Filesystem for BranchFs {
                     }
                     Err(e) => {
                         log::error!("commit failed: {}", e);
-                        reply.error(libc::EIO);
+                        reply.error(ctl_errno(&e));
                     }
                 }
             }
@@ -1271,6 +2635,10 @@ impl Filesystem for BranchFs {
         umask: u32,
         reply: ReplyEntry,
     ) {
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
         let parent_path = match self.inodes.get_path(parent) {
             Some(p) => p,
             None => {
@@ -1279,9 +2647,9 @@ impl Filesystem for BranchFs {
             }
         };
 
-        let name_str = name.to_string_lossy();
+        let name_str = storage::name_to_string(name);
 
-        let branch_ctx = match classify_path(&parent_path) {
+        let branch_ctx = match self.classify(&parent_path) {
             PathContext::BranchDir(b) => Some((b, "/".to_string())),
             PathContext::BranchPath(b, rel) => Some((b, rel)),
             _ => None,
@@ -1297,8 +2665,17 @@ impl Filesystem for BranchFs {
             } else {
                 format!("{}/{}", parent_rel, name_str)
             };
-            let delta = self.get_delta_path_for_branch(&branch, &rel_path);
-            match std::fs::create_dir_all(&delta) {
+            let delta = match self.get_delta_path_for_branch(&branch, &rel_path) {
+                Some(d) => d,
+                None => {
+                    reply.error(libc::ENOENT);
+                    return;
+                }
+            };
+            match self
+                .ensure_delta_parents(&branch, &rel_path)
+                .and_then(|()| std::fs::create_dir_all(&delta))
+            {
                 Ok(_) => {
                     use std::os::unix::fs::PermissionsExt;
                     let perm = std::fs::Permissions::from_mode(mode & !umask);
@@ -1306,15 +2683,16 @@ impl Filesystem for BranchFs {
                     let inode_path = format!("/@{}{}", branch, rel_path);
                     let ino = self.inodes.get_or_create(&inode_path, true);
                     if let Some(attr) = self.make_attr(ino, &delta) {
-                        reply.entry(&TTL, &attr, 0);
+                        self.manager.record_event(&branch, BranchEvent::Create);
+                        self.reply_entry(&attr, reply);
                     } else {
                         reply.error(libc::EIO);
                     }
                 }
-                Err(_) => reply.error(libc::EIO),
+                Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
             }
         } else {
-            match classify_path(&parent_path) {
+            match self.classify(&parent_path) {
                 PathContext::BranchCtl(_) | PathContext::RootCtl => {
                     reply.error(libc::EPERM);
                 }
@@ -1325,8 +2703,18 @@ impl Filesystem for BranchFs {
                         format!("{}/{}", rp, name_str)
                     };
 
-                    let delta = self.get_delta_path(&path);
-                    match std::fs::create_dir_all(&delta) {
+                    let delta = match self.get_delta_path(&path) {
+                        Some(d) => d,
+                        None => {
+                            reply.error(libc::ESTALE);
+                            return;
+                        }
+                    };
+                    let branch_name = self.get_branch_name();
+                    match self
+                        .ensure_delta_parents(&branch_name, &path)
+                        .and_then(|()| std::fs::create_dir_all(&delta))
+                    {
                         Ok(_) => {
                             use std::os::unix::fs::PermissionsExt;
                             let perm = std::fs::Permissions::from_mode(mode & !umask);
@@ -1338,12 +2726,13 @@ impl Filesystem for BranchFs {
                             }
                             let ino = self.inodes.get_or_create(&path, true);
                             if let Some(attr) = self.make_attr(ino, &delta) {
-                                reply.entry(&TTL, &attr, 0);
+                                self.record_ino_event(ino, BranchEvent::Create);
+                                self.reply_entry(&attr, reply);
                             } else {
                                 reply.error(libc::EIO);
                             }
                         }
-                        Err(_) => reply.error(libc::EIO),
+                        Err(e) => reply.error(self.io_err_to_errno(&e, libc::EIO)),
                     }
                 }
                 _ => {
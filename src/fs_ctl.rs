@@ -2,24 +2,53 @@ use std::sync::atomic::Ordering;
 
 use fuser::ReplyWrite;
 
-use crate::fs::BranchFs;
+use crate::error::BranchError;
+use crate::fs::{BranchFs, EPOCH_INO};
+
+/// Errno a failed ctl command replies with: `EBUSY` when files are still
+/// open for writing (the caller may retry or force) or a transaction is
+/// open, `EINVAL` for a transaction command without one, `EIO` otherwise.
+pub(crate) fn ctl_errno(e: &BranchError) -> i32 {
+    match e {
+        BranchError::OpenForWrite(..) | BranchError::TxnOpen(_) => libc::EBUSY,
+        BranchError::NoTxn(_) => libc::EINVAL,
+        _ => libc::EIO,
+    }
+}
 
 impl BranchFs {
     /// Get or create the ctl inode number for a branch.
-    pub(crate) fn get_or_create_branch_ctl_ino(&self, branch: &str) -> u64 {
+    ///
+    /// Numbers come from `[CTL_INO_BASE, EPOCH_INO)`.  Once that range is used
+    /// up, the number of a branch that no longer exists is reused; `None`
+    /// means every ctl inode belongs to a live branch.
+    pub(crate) fn get_or_create_branch_ctl_ino(&self, branch: &str) -> Option<u64> {
         {
             let map = self.branch_ctl_inodes.read();
             if let Some(&ino) = map.get(branch) {
-                return ino;
+                return Some(ino);
             }
         }
         let mut map = self.branch_ctl_inodes.write();
         if let Some(&ino) = map.get(branch) {
-            return ino;
+            return Some(ino);
         }
-        let ino = self.next_ctl_ino.fetch_add(1, Ordering::SeqCst);
+        let ino = match self
+            .next_ctl_ino
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < EPOCH_INO).then_some(n + 1)
+            }) {
+            Ok(ino) => ino,
+            Err(_) => {
+                let dead = map
+                    .keys()
+                    .find(|name| !self.manager.is_branch_valid(name))?
+                    .clone();
+                map.remove(&dead)?
+            }
+        };
         map.insert(branch.to_string(), ino);
-        ino
+        Some(ino)
     }
 
     /// Check if an inode number is a branch ctl inode, returning the branch name.
@@ -33,6 +62,27 @@ impl BranchFs {
         None
     }
 
+    /// Run `txn:begin`, `txn:commit` or `txn:abort` on `branch`; `None`
+    /// if `cmd` is none of them.
+    fn txn_command(&self, branch: &str, cmd: &str) -> Option<crate::error::Result<()>> {
+        Some(match cmd {
+            "txn:begin" => self.manager.txn_begin(branch),
+            "txn:commit" => self.manager.txn_commit(branch),
+            "txn:abort" => self.manager.txn_abort(branch),
+            _ => return None,
+        })
+    }
+
+    fn reply_txn(result: crate::error::Result<()>, len: usize, reply: ReplyWrite) {
+        match result {
+            Ok(()) => reply.written(len as u32),
+            Err(e) => {
+                log::error!("Transaction command failed: {}", e);
+                reply.error(ctl_errno(&e));
+            }
+        }
+    }
+
     /// Handle a write to the root ctl file.
     pub(crate) fn handle_root_ctl_write(&mut self, data: &[u8], reply: ReplyWrite) {
         let cmd = String::from_utf8_lossy(data).trim().to_string();
@@ -59,8 +109,30 @@ impl BranchFs {
             return;
         }
 
+        // Everything else changes a branch
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        if let Some(result) = self.txn_command(&branch_name, &cmd_lower) {
+            Self::reply_txn(result, data.len(), reply);
+            return;
+        }
+
         let result = match cmd_lower.as_str() {
-            "commit" => self.manager.commit(&branch_name),
+            "commit" => self.manager.commit(
+                &branch_name,
+                self.options.hardlinks,
+                self.options.commit_mtime,
+                false,
+            ),
+            "commit:force" => self.manager.commit(
+                &branch_name,
+                self.options.hardlinks,
+                self.options.commit_mtime,
+                true,
+            ),
             "abort" => self.manager.abort(&branch_name),
             _ => {
                 log::warn!("Unknown control command: {}", cmd);
@@ -77,7 +149,7 @@ impl BranchFs {
             }
             Err(e) => {
                 log::error!("Control command failed: {}", e);
-                reply.error(libc::EIO);
+                reply.error(ctl_errno(&e));
             }
         }
     }
@@ -88,8 +160,30 @@ impl BranchFs {
         let cmd_lower = cmd.to_lowercase();
         log::info!("Branch ctl command: '{}' for branch '{}'", cmd, branch);
 
+        // Every branch ctl command changes the branch
+        if self.options.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        if let Some(result) = self.txn_command(branch, &cmd_lower) {
+            Self::reply_txn(result, data.len(), reply);
+            return;
+        }
+
         let result = match cmd_lower.as_str() {
-            "commit" => self.manager.commit(branch),
+            "commit" => self.manager.commit(
+                branch,
+                self.options.hardlinks,
+                self.options.commit_mtime,
+                false,
+            ),
+            "commit:force" => self.manager.commit(
+                branch,
+                self.options.hardlinks,
+                self.options.commit_mtime,
+                true,
+            ),
             "abort" => self.manager.abort(branch),
             _ => {
                 log::warn!("Unknown branch ctl command: {}", cmd);
@@ -115,7 +209,7 @@ impl BranchFs {
             }
             Err(e) => {
                 log::error!("Branch ctl command failed: {}", e);
-                reply.error(libc::EIO);
+                reply.error(ctl_errno(&e));
             }
         }
     }
@@ -1,17 +1,31 @@
+use std::ffi::OsStr;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::UNIX_EPOCH;
 
 use fuser::{FileAttr, FileType};
 
-use crate::fs::{BranchFs, BLOCK_SIZE};
+use crate::branch::BranchEvent;
+use crate::fs::{BranchFs, WriteMode, BLOCK_SIZE, CTL_FILE, CTL_INO, EPOCH_FILE, EPOCH_INO};
+use crate::fs_path::PathContext;
+use crate::inode::ROOT_INO;
 use crate::storage;
 
 impl BranchFs {
+    /// Path in the branch of a path relative to the mount's root (they
+    /// differ with `--subdir`).
+    pub(crate) fn branch_rel(&self, path: &str) -> String {
+        if self.root_prefix.is_empty() {
+            path.to_string()
+        } else if path == "/" {
+            self.root_prefix.clone()
+        } else {
+            format!("{}{}", self.root_prefix, path)
+        }
+    }
+
     pub(crate) fn resolve(&self, path: &str) -> Option<std::path::PathBuf> {
-        self.manager
-            .resolve_path(&self.get_branch_name(), path)
-            .ok()?
+        self.resolve_for_branch(&self.get_branch_name(), path)
     }
 
     /// Resolve a path within a specific branch (not the root's current branch).
@@ -20,27 +34,60 @@ impl BranchFs {
         branch: &str,
         path: &str,
     ) -> Option<std::path::PathBuf> {
-        self.manager.resolve_path(branch, path).ok()?
+        self.manager
+            .resolve_path(branch, &self.branch_rel(path))
+            .ok()?
     }
 
-    pub(crate) fn get_delta_path(&self, rel_path: &str) -> std::path::PathBuf {
-        self.manager
-            .with_branch(&self.get_branch_name(), |b| Ok(b.delta_path(rel_path)))
-            .unwrap()
+    /// Delta path on the root's current branch, or `None` if that branch
+    /// no longer exists.
+    pub(crate) fn get_delta_path(&self, rel_path: &str) -> Option<std::path::PathBuf> {
+        self.get_delta_path_for_branch(&self.get_branch_name(), rel_path)
     }
 
+    /// Delta path on `branch` (in its open transaction, if any), or `None`
+    /// if the branch was removed (e.g. aborted from another mount while a
+    /// file was still open).
     pub(crate) fn get_delta_path_for_branch(
         &self,
         branch: &str,
         rel_path: &str,
-    ) -> std::path::PathBuf {
+    ) -> Option<std::path::PathBuf> {
         self.manager
-            .with_branch(branch, |b| Ok(b.delta_path(rel_path)))
-            .unwrap()
+            .with_branch(branch, |b| Ok(b.write_path(&self.branch_rel(rel_path))))
+            .ok()
     }
 
-    pub(crate) fn ensure_cow(&self, rel_path: &str) -> std::io::Result<std::path::PathBuf> {
-        self.ensure_cow_for_branch(&self.get_branch_name(), rel_path)
+    /// Branch and delta path backing `ino`, or `None` for inodes with no
+    /// backing file (ctl files, the epoch file) or on a removed branch.
+    pub(crate) fn delta_of_ino(&self, ino: u64) -> Option<(String, PathBuf)> {
+        let path = self.inodes.get_path(ino)?;
+        let (branch, rel_path) = match self.classify(&path) {
+            PathContext::BranchPath(branch, rel_path) => (branch, rel_path),
+            PathContext::BranchDir(branch) => (branch, "/".to_string()),
+            PathContext::RootPath(rp) => (self.get_branch_name(), rp),
+            PathContext::BranchCtl(_) | PathContext::RootCtl => return None,
+        };
+        let delta = self.get_delta_path_for_branch(&branch, &rel_path)?;
+        Some((branch, delta))
+    }
+
+    /// Delta path on `branch`, failing with `ESTALE` if the branch is gone.
+    pub(crate) fn require_delta_path(
+        &self,
+        branch: &str,
+        rel_path: &str,
+    ) -> std::io::Result<std::path::PathBuf> {
+        self.get_delta_path_for_branch(branch, rel_path)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ESTALE))
+    }
+
+    /// Create the delta directories above `rel_path` on `branch`, keeping
+    /// the mode and owner of the inherited directories they shadow.
+    pub(crate) fn ensure_delta_parents(&self, branch: &str, rel_path: &str) -> std::io::Result<()> {
+        self.manager
+            .materialize_parents(branch, &self.branch_rel(rel_path))
+            .map_err(std::io::Error::from)
     }
 
     pub(crate) fn ensure_cow_for_branch(
@@ -48,24 +95,189 @@ impl BranchFs {
         branch: &str,
         rel_path: &str,
     ) -> std::io::Result<std::path::PathBuf> {
-        let delta = self.get_delta_path_for_branch(branch, rel_path);
+        let delta = self.require_delta_path(branch, rel_path)?;
 
-        if !delta.exists() {
+        if !storage::exists(&delta) {
             if let Some(src) = self.resolve_for_branch(branch, rel_path) {
                 if src.exists() && src.is_file() {
-                    storage::copy_file(&src, &delta)
-                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                    // An ancestor's redirected delta is only whole once
+                    // materialized
+                    self.ensure_delta_parents(branch, rel_path)?;
+                    self.manager
+                        .materialize_redirect(&src)
+                        .and_then(|()| storage::copy_file_atomic(&src, &delta))
+                        .map_err(std::io::Error::from)?;
+                    self.manager.record_event(branch, BranchEvent::Cow);
+                } else if storage::is_dir(&src) {
+                    // Attribute changes to an inherited directory need a
+                    // directory of its own to land on
+                    self.ensure_delta_parents(branch, rel_path)?;
+                    storage::create_dir_like(&src, &delta).map_err(std::io::Error::from)?;
                 }
             }
+        } else {
+            self.manager
+                .unshare(branch, &delta)
+                .map_err(std::io::Error::from)?;
         }
 
-        storage::ensure_parent_dirs(&delta).map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.ensure_delta_parents(branch, rel_path)?;
+
+        Ok(delta)
+    }
+
+    /// Delta file a write to `rel_path` on `branch` goes to, prepared
+    /// according to the mount's write mode.
+    ///
+    /// Only the first write to an inherited file differs between modes;
+    /// new files and files already in the branch's delta are written in
+    /// place.
+    pub(crate) fn prepare_write_for_branch(
+        &self,
+        branch: &str,
+        rel_path: &str,
+    ) -> std::io::Result<std::path::PathBuf> {
+        let mode = self.options.write_mode;
+        let delta = self.require_delta_path(branch, rel_path)?;
+        let src = match self.resolve_for_branch(branch, rel_path) {
+            Some(src) if mode != WriteMode::Copyup && src != delta && src.is_file() => src,
+            _ => return self.ensure_cow_for_branch(branch, rel_path),
+        };
 
+        match mode {
+            WriteMode::Redirect => self
+                .manager
+                .materialize_redirect(&src)
+                .and_then(|()| {
+                    self.manager
+                        .redirect_file(branch, &self.branch_rel(rel_path), &src, &delta)
+                })
+                .map_err(std::io::Error::from)?,
+            _ => {
+                self.ensure_delta_parents(branch, rel_path)?;
+                std::fs::File::create(&delta)?;
+                if let Ok(meta) = std::fs::metadata(&src) {
+                    let _ = std::fs::set_permissions(&delta, meta.permissions());
+                }
+            }
+        }
         Ok(delta)
     }
 
+    /// Prepare the delta file backing a `create` call.
+    ///
+    /// A file that already resolves on the branch is copied up unless
+    /// `O_TRUNC` was given, so `open(O_CREAT)` keeps existing content.
+    /// Returns the delta path and whether the file existed beforehand.
+    pub(crate) fn create_delta_for_branch(
+        &self,
+        branch: &str,
+        rel_path: &str,
+        flags: i32,
+    ) -> std::io::Result<(std::path::PathBuf, bool)> {
+        let existing = self
+            .resolve_for_branch(branch, rel_path)
+            .filter(|p| p.is_file());
+        let existed = existing.is_some();
+
+        let delta = match existing {
+            Some(_) if flags & libc::O_TRUNC == 0 => {
+                self.ensure_cow_for_branch(branch, rel_path)?
+            }
+            Some(src) => {
+                let delta = self.require_delta_path(branch, rel_path)?;
+                self.ensure_delta_parents(branch, rel_path)?;
+                self.manager
+                    .unshare(branch, &delta)
+                    .map_err(std::io::Error::from)?;
+                std::fs::File::create(&delta)?;
+                self.manager.drop_redirect(&delta)?;
+                // Truncating an existing file keeps its mode
+                if delta != src {
+                    if let Ok(meta) = std::fs::metadata(&src) {
+                        let _ = std::fs::set_permissions(&delta, meta.permissions());
+                    }
+                }
+                delta
+            }
+            None => {
+                let delta = self.require_delta_path(branch, rel_path)?;
+                self.ensure_delta_parents(branch, rel_path)?;
+                std::fs::File::create(&delta)?;
+                // A previously deleted path becomes visible again
+                let _ = self
+                    .manager
+                    .with_branch(branch, |b| b.untombstone(&self.branch_rel(rel_path)));
+                delta
+            }
+        };
+
+        if !existed {
+            self.manager.record_event(branch, BranchEvent::Create);
+        }
+        Ok((delta, existed))
+    }
+
+    /// Why `rel_path` cannot be removed from `branch` by `rmdir` (`dir`)
+    /// or `unlink`, as an errno: it does not exist, is of the other kind,
+    /// or is a directory that still lists entries.
+    pub(crate) fn removal_errno(&self, branch: &str, rel_path: &str, dir: bool) -> Option<i32> {
+        let resolved = match self.resolve_for_branch(branch, rel_path) {
+            Some(p) => p,
+            None => return Some(libc::ENOENT),
+        };
+        match (dir, storage::is_dir(&resolved)) {
+            (true, false) => Some(libc::ENOTDIR),
+            (false, true) => Some(libc::EISDIR),
+            (true, true) => match self
+                .manager
+                .is_dir_empty(branch, &self.branch_rel(rel_path))
+            {
+                Ok(true) => None,
+                Ok(false) => Some(libc::ENOTEMPTY),
+                Err(_) => Some(libc::ENOENT),
+            },
+            (false, false) => None,
+        }
+    }
+
+    /// Delete `rel_path` from `branch`: tombstone it and drop the branch's
+    /// own copy (or only stage that, in an open transaction).  A removed
+    /// directory's delta goes as a whole, and the tombstone hides what
+    /// lower layers hold below it.
+    pub(crate) fn remove_entry(&self, branch: &str, rel_path: &str) -> crate::error::Result<()> {
+        let durable = self.options.durable;
+        let branch_path = self.branch_rel(rel_path);
+        self.manager.with_branch(branch, |b| {
+            b.tombstone(&branch_path, durable)?;
+            let delta = b.write_path(&branch_path);
+            if storage::is_dir(&delta) {
+                std::fs::remove_dir_all(&delta)?;
+            } else if storage::exists(&delta) {
+                std::fs::remove_file(&delta)?;
+                self.manager.drop_redirect(&delta)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Undo a `create` into a branch that was removed meanwhile: the delta
+    /// file and any directories left empty up to the branch's storage
+    /// directory, so no orphaned branch directory is recreated.
+    pub(crate) fn discard_orphan_delta(&self, branch: &str, delta: &Path) {
+        let _ = std::fs::remove_file(delta);
+        let branch_dir = self.manager.storage_path.join("branches").join(branch);
+        let mut dir = delta.parent();
+        while let Some(d) = dir.filter(|d| d.starts_with(&branch_dir)) {
+            if std::fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+
     pub(crate) fn make_attr(&self, ino: u64, path: &Path) -> Option<FileAttr> {
-        let meta = std::fs::metadata(path).ok()?;
+        let meta = std::fs::symlink_metadata(path).ok()?;
         let kind = if meta.is_dir() {
             FileType::Directory
         } else if meta.is_symlink() {
@@ -74,10 +286,12 @@ impl BranchFs {
             FileType::RegularFile
         };
 
+        // Allocation of the backing file, so holes in sparse files do not
+        // count (st_blocks is in 512-byte units, like FUSE's)
         Some(FileAttr {
             ino,
             size: meta.len(),
-            blocks: meta.len().div_ceil(BLOCK_SIZE as u64),
+            blocks: meta.blocks(),
             atime: meta.accessed().unwrap_or(UNIX_EPOCH),
             mtime: meta.modified().unwrap_or(UNIX_EPOCH),
             ctime: UNIX_EPOCH,
@@ -88,11 +302,25 @@ impl BranchFs {
             uid: meta.uid(),
             gid: meta.gid(),
             rdev: 0,
-            blksize: BLOCK_SIZE,
+            blksize: meta.blksize() as u32,
             flags: 0,
         })
     }
 
+    /// Whether `ino` has no file behind it: a ctl file, the epoch file or
+    /// an `@branch` directory.
+    pub(crate) fn is_synthetic_ino(&self, ino: u64) -> bool {
+        if ino == CTL_INO || (ino == EPOCH_INO && self.options.epoch_file) {
+            return true;
+        }
+        if self.branch_for_ctl_ino(ino).is_some() {
+            return true;
+        }
+        self.inodes
+            .get_path(ino)
+            .is_some_and(|path| matches!(self.classify(&path), PathContext::BranchDir(_)))
+    }
+
     /// Return a synthetic directory FileAttr.
     pub(crate) fn synthetic_dir_attr(&self, ino: u64) -> FileAttr {
         FileAttr {
@@ -135,79 +363,117 @@ impl BranchFs {
         }
     }
 
-    /// Collect readdir entries for a directory resolved via a specific branch.
-    ///
-    /// `inode_prefix` controls how child inode paths are formed:
-    /// - `"/@branch"` for branch subtrees (produces `/@branch/child`)
-    /// - `""` for root-level paths (produces `/child`)
-    pub(crate) fn collect_readdir_entries(
-        &self,
-        branch: &str,
-        rel_path: &str,
-        ino: u64,
-        inode_prefix: &str,
-    ) -> Vec<(u64, FileType, String)> {
-        let mut entries = vec![
-            (ino, FileType::Directory, ".".to_string()),
-            (ino, FileType::Directory, "..".to_string()),
-        ];
-
-        let mut seen = std::collections::HashSet::new();
-
-        // Collect from base directory
-        let base_dir = self
-            .manager
-            .base_path
-            .join(rel_path.trim_start_matches('/'));
-        if let Ok(dir) = std::fs::read_dir(&base_dir) {
-            for entry in dir.flatten() {
-                let name = entry.file_name().to_string_lossy().to_string();
-                if seen.insert(name.clone()) {
-                    let child_rel = if rel_path == "/" {
-                        format!("/{}", name)
-                    } else {
-                        format!("{}/{}", rel_path, name)
-                    };
-                    let inode_path = format!("{}{}", inode_prefix, child_rel);
-                    let is_dir = entry.path().is_dir();
-                    let child_ino = self.inodes.get_or_create(&inode_path, is_dir);
-                    let kind = if is_dir {
-                        FileType::Directory
-                    } else {
-                        FileType::RegularFile
-                    };
-                    entries.push((child_ino, kind, name));
-                }
-            }
+    /// Text served by the epoch file: the manager's current epoch.
+    pub(crate) fn epoch_file_content(&self) -> String {
+        format!("{}\n", self.manager.get_epoch())
+    }
+
+    /// Return the read-only epoch file's FileAttr, sized to its content.
+    pub(crate) fn epoch_file_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: EPOCH_INO,
+            size: self.epoch_file_content().len() as u64,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: self.uid.load(std::sync::atomic::Ordering::Relaxed),
+            gid: self.gid.load(std::sync::atomic::Ordering::Relaxed),
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
         }
+    }
 
-        // Collect from branch deltas
-        if let Some(resolved) = self.resolve_for_branch(branch, rel_path) {
-            if resolved != base_dir {
-                if let Ok(dir) = std::fs::read_dir(&resolved) {
-                    for entry in dir.flatten() {
-                        let name = entry.file_name().to_string_lossy().to_string();
-                        if seen.insert(name.clone()) {
-                            let child_rel = if rel_path == "/" {
-                                format!("/{}", name)
-                            } else {
-                                format!("{}/{}", rel_path, name)
-                            };
-                            let inode_path = format!("{}{}", inode_prefix, child_rel);
-                            let is_dir = entry.path().is_dir();
-                            let child_ino = self.inodes.get_or_create(&inode_path, is_dir);
-                            let kind = if is_dir {
-                                FileType::Directory
-                            } else {
-                                FileType::RegularFile
-                            };
-                            entries.push((child_ino, kind, name));
-                        }
-                    }
+    /// Branch an inode's I/O is charged to: its `@branch` for branch paths,
+    /// the root's current branch otherwise.
+    pub(crate) fn record_ino_event(&self, ino: u64, event: BranchEvent) {
+        let path = match self.inodes.get_path(ino) {
+            Some(p) => p,
+            None => return,
+        };
+        match self.classify(&path) {
+            PathContext::BranchPath(branch, _) => self.manager.record_event(&branch, event),
+            PathContext::RootPath(_) => self.manager.record_event(&self.get_branch_name(), event),
+            _ => {}
+        }
+    }
+
+    /// Target a symlink at `rel_path` reports through the mount.  Absolute
+    /// targets inside the mounted part of the base are made relative to
+    /// the link, so they resolve within the mount (and the branch) rather
+    /// than in the base on the host.
+    pub(crate) fn mount_link_target(&self, rel_path: &str, target: PathBuf) -> PathBuf {
+        let root = self.manager.base_path.join(storage::to_os_path(
+            self.root_prefix.trim_start_matches('/'),
+        ));
+        let inside = match target.strip_prefix(&root) {
+            Ok(inside) if target.is_absolute() => inside,
+            _ => return target,
+        };
+        let depth = Path::new(rel_path.trim_start_matches('/'))
+            .parent()
+            .map_or(0, |dir| dir.components().count());
+        let mut relative: PathBuf = std::iter::repeat_n("..", depth).collect();
+        relative.push(inside);
+        if relative.as_os_str().is_empty() {
+            relative.push(".");
+        }
+        relative
+    }
+
+    /// Inode of the directory containing `ino`, falling back to the root.
+    pub(crate) fn parent_ino(&self, ino: u64) -> u64 {
+        let path = match self.inodes.get_path(ino) {
+            Some(p) => p,
+            None => return ROOT_INO,
+        };
+        match path.rfind('/') {
+            Some(pos) if pos > 0 => self.inodes.get_ino(&path[..pos]).unwrap_or(ROOT_INO),
+            _ => ROOT_INO,
+        }
+    }
+
+    /// Where an entry about to be created or renamed lives:
+    /// `(via_root, branch, path)`, the path relative to the mount's root.
+    /// Control files, `@branch` directories and the epoch file cannot be
+    /// created, renamed or replaced.
+    pub(crate) fn entry_target(
+        &self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<(bool, String, String), i32> {
+        let parent_path = self.inodes.get_path(parent).ok_or(libc::ENOENT)?;
+        let name_str = storage::name_to_string(name);
+        if *name_str == *CTL_FILE {
+            return Err(libc::EPERM);
+        }
+        let (via_root, branch, parent_rel) = match self.classify(&parent_path) {
+            PathContext::BranchDir(b) => (false, b, "/".to_string()),
+            PathContext::BranchPath(b, rel) => (false, b, rel),
+            PathContext::RootPath(rp) => {
+                if rp == "/" && self.options.epoch_file && name_str == EPOCH_FILE {
+                    return Err(libc::EPERM);
                 }
+                (true, self.get_branch_name(), rp)
             }
+            PathContext::BranchCtl(_) | PathContext::RootCtl => return Err(libc::EPERM),
+        };
+        let rel_path = if parent_rel == "/" {
+            format!("/{}", name_str)
+        } else {
+            format!("{}/{}", parent_rel, name_str)
+        };
+        // At the top, `@name` is a branch directory unless a real entry
+        // already has the name
+        let view = (!via_root).then_some(branch.as_str());
+        if parent_rel == "/" && name_str.starts_with('@') && !self.has_real_entry(view, &rel_path) {
+            return Err(libc::EPERM);
         }
-
-        entries
+        Ok((via_root, branch, rel_path))
     }
 }
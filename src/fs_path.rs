@@ -1,4 +1,4 @@
-use crate::fs::CTL_FILE;
+use crate::fs::{BranchFs, CTL_FILE};
 
 /// Classified path context for an inode path.
 pub(crate) enum PathContext {
@@ -15,33 +15,93 @@ pub(crate) enum PathContext {
 }
 
 /// Classify an inode path into a PathContext.
-pub(crate) fn classify_path(path: &str) -> PathContext {
+///
+/// `is_real(branch, path)` says whether a real entry exists at `path`
+/// (e.g. `/@x`) in the view of `branch`, or of the root's current branch
+/// for `None`.  A real entry named `@...` wins over the virtual `@branch`
+/// directory of the same name.
+pub(crate) fn classify_path(
+    path: &str,
+    is_real: impl Fn(Option<&str>, &str) -> bool,
+) -> PathContext {
     if path == "/" {
         return PathContext::RootPath("/".to_string());
     }
 
     // Paths under /@branch/...
-    if let Some(rest) = path.strip_prefix("/@") {
-        // Find the next '/' if any
-        if let Some(slash_pos) = rest.find('/') {
-            let branch = &rest[..slash_pos];
-            let remainder = &rest[slash_pos..]; // e.g. "/.branchfs_ctl" or "/src/main.rs"
-
-            // Handle nested @child: /@parent/@child/... → recurse as /@child/...
-            if remainder.starts_with("/@") {
-                return classify_path(remainder);
-            }
-
-            if remainder == format!("/{}", CTL_FILE).as_str() {
-                PathContext::BranchCtl(branch.to_string())
-            } else {
-                PathContext::BranchPath(branch.to_string(), remainder.to_string())
-            }
-        } else {
-            // Just "/@branch" with no trailing content
-            PathContext::BranchDir(rest.to_string())
+    match path.strip_prefix("/@") {
+        Some(_) if is_real(None, first_component(path)) => PathContext::RootPath(path.to_string()),
+        Some(rest) => classify_branch_path(rest, &is_real),
+        None => PathContext::RootPath(path.to_string()),
+    }
+}
+
+/// Classify what follows `/@` in a path naming a branch.
+fn classify_branch_path(rest: &str, is_real: &impl Fn(Option<&str>, &str) -> bool) -> PathContext {
+    // Find the next '/' if any
+    let Some(slash_pos) = rest.find('/') else {
+        // Just "/@branch" with no trailing content
+        return PathContext::BranchDir(rest.to_string());
+    };
+    let branch = &rest[..slash_pos];
+    let remainder = &rest[slash_pos..]; // e.g. "/.branchfs_ctl" or "/src/main.rs"
+
+    // Handle nested @child: /@parent/@child/... → recurse as /@child/...
+    if let Some(nested) = remainder.strip_prefix("/@") {
+        if !is_real(Some(branch), first_component(remainder)) {
+            return classify_branch_path(nested, is_real);
         }
+    }
+
+    if remainder == format!("/{}", CTL_FILE).as_str() {
+        PathContext::BranchCtl(branch.to_string())
     } else {
-        PathContext::RootPath(path.to_string())
+        PathContext::BranchPath(branch.to_string(), remainder.to_string())
+    }
+}
+
+/// `/a` of `/a/b/c`
+fn first_component(path: &str) -> &str {
+    match path[1..].find('/') {
+        Some(i) => &path[..i + 1],
+        None => path,
+    }
+}
+
+impl BranchFs {
+    /// `classify_path` against what the branches hold right now.
+    pub(crate) fn classify(&self, path: &str) -> PathContext {
+        classify_path(path, |branch, path| self.has_real_entry(branch, path))
+    }
+
+    /// Whether a real entry at `path` (`/@name`, in `branch` or the root's
+    /// current branch) takes the place of the virtual `@name` directory.
+    /// Warns once per path when that hides an existing branch.
+    pub(crate) fn shadows_branch(&mut self, branch: Option<&str>, path: &str, name: &str) -> bool {
+        if !self.has_real_entry(branch, path) {
+            return false;
+        }
+        let key = format!(
+            "{}{}",
+            branch.map(|b| format!("/@{}", b)).unwrap_or_default(),
+            path
+        );
+        if self.manager.is_branch_valid(name) && self.shadow_warnings.insert(key.clone()) {
+            log::warn!(
+                "Real entry {} shadows the directory of branch '{}'; the branch stays reachable by switching to it",
+                key,
+                name
+            );
+        }
+        true
+    }
+
+    /// Whether a real file or directory exists at `path` in `branch`, or in
+    /// the root's current branch for `None`.
+    pub(crate) fn has_real_entry(&self, branch: Option<&str>, path: &str) -> bool {
+        match branch {
+            Some(branch) => self.resolve_for_branch(branch, path).is_some(),
+            None => self.resolve(path).is_some(),
+        }
     }
 }
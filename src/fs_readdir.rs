@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use fuser::FileType;
+
+use crate::branch::BranchManager;
+use crate::fs::{BranchFs, CTL_FILE, CTL_INO, EPOCH_FILE, EPOCH_INO};
+use crate::fs_path::PathContext;
+use crate::inode::{InodeManager, ROOT_INO};
+use crate::storage;
+
+pub(crate) type DirEntry = (u64, FileType, String);
+
+/// Merged listing of one directory: base entries in `read_dir` order,
+/// then delta entries the base does not have, then entries staged by an
+/// open transaction that neither has.
+struct MergedDir {
+    base_dir: PathBuf,
+    base: Option<std::fs::ReadDir>,
+    delta_dir: Option<PathBuf>,
+    delta: Option<std::fs::ReadDir>,
+    staged: Option<std::fs::ReadDir>,
+}
+
+/// Kind of a directory entry, without following symlinks
+fn entry_kind(entry: &std::fs::DirEntry) -> FileType {
+    match entry.file_type() {
+        Ok(t) if t.is_dir() => FileType::Directory,
+        Ok(t) if t.is_symlink() => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// Path of entry `name` of directory `dir`
+fn child_path(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl Iterator for MergedDir {
+    /// Entry name and kind
+    type Item = (String, FileType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(dir) = self.base.as_mut() {
+            if let Some(entry) = dir.flatten().next() {
+                let name = storage::name_to_string(&entry.file_name());
+                return Some((name, entry_kind(&entry)));
+            }
+            self.base = None;
+        }
+
+        let base_dir = &self.base_dir;
+        let in_base =
+            |e: &std::fs::DirEntry| base_dir.join(e.file_name()).symlink_metadata().is_ok();
+        if let Some(dir) = self.delta.as_mut() {
+            if let Some(entry) = dir.flatten().find(|e| !in_base(e)) {
+                let name = storage::name_to_string(&entry.file_name());
+                return Some((name, entry_kind(&entry)));
+            }
+            self.delta = None;
+        }
+
+        let delta_dir = &self.delta_dir;
+        let in_delta = |e: &std::fs::DirEntry| {
+            delta_dir
+                .as_ref()
+                .is_some_and(|d| d.join(e.file_name()).symlink_metadata().is_ok())
+        };
+        let entry = self
+            .staged
+            .as_mut()?
+            .flatten()
+            .find(|e| !in_base(e) && !in_delta(e))?;
+        let name = storage::name_to_string(&entry.file_name());
+        Some((name, entry_kind(&entry)))
+    }
+}
+
+/// Enumerates one directory for `DirSnapshot::take`: `.` and `..`, the
+/// merged entries, then synthetic ones.
+struct DirCursor {
+    ino: u64,
+    /// The listing follows the root's current branch, so it must fail once
+    /// the mount goes stale
+    root_view: bool,
+    /// `.` and `..`
+    head: VecDeque<DirEntry>,
+    merged: MergedDir,
+    /// Branch the listing is resolved in
+    branch: String,
+    rel_path: String,
+    /// `rel_path` within the branch (differs with `--subdir`)
+    branch_path: String,
+    /// `"/@branch"` for branch subtrees, `""` for root-level paths
+    inode_prefix: String,
+    /// Synthetic entries listed after the real ones (ctl files, `@branch`
+    /// directories)
+    tail: VecDeque<DirEntry>,
+}
+
+impl DirCursor {
+    /// Next entry, allocating inodes for real entries as they are reached.
+    fn next_entry(&mut self, inodes: &InodeManager, manager: &BranchManager) -> Option<DirEntry> {
+        if let Some(entry) = self.head.pop_front() {
+            return Some(entry);
+        }
+        // Entries removed in the branch, or below a directory removed in
+        // it, may still be on disk in a lower layer
+        for (name, kind) in self.merged.by_ref() {
+            if manager.is_hidden(&self.branch, &child_path(&self.branch_path, &name)) {
+                continue;
+            }
+            let child_rel = child_path(&self.rel_path, &name);
+            let inode_path = format!("{}{}", self.inode_prefix, child_rel);
+            let ino = inodes.get_or_create(&inode_path, kind == FileType::Directory);
+            return Some((ino, kind, name));
+        }
+        self.tail.pop_front()
+    }
+}
+
+/// Listing of a directory as it was when its handle was opened, so
+/// `readdir` calls on the handle page through one consistent view by
+/// offset however the directory changes meanwhile.
+pub(crate) struct DirSnapshot {
+    pub(crate) ino: u64,
+    /// Taken through the root's current branch: fails once the mount goes
+    /// stale
+    pub(crate) root_view: bool,
+    pub(crate) entries: Vec<DirEntry>,
+}
+
+impl BranchFs {
+    /// Read the whole listing of directory `ino`, or fail with an errno.
+    pub(crate) fn snapshot_dir(&self, ino: u64) -> Result<DirSnapshot, i32> {
+        let mut cursor = self.open_dir_cursor(ino)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = cursor.next_entry(&self.inodes, &self.manager) {
+            entries.push(entry);
+        }
+        Ok(DirSnapshot {
+            ino: cursor.ino,
+            root_view: cursor.root_view,
+            entries,
+        })
+    }
+
+    /// Start listing directory `ino` from its first entry, or fail with an
+    /// errno.
+    fn open_dir_cursor(&self, ino: u64) -> Result<DirCursor, i32> {
+        let path = match self.inodes.get_path(ino) {
+            Some(p) => p,
+            None if ino == ROOT_INO => "/".to_string(),
+            None => return Err(libc::ENOENT),
+        };
+
+        match self.classify(&path) {
+            PathContext::BranchDir(branch) => {
+                // Branch dir root: `.`, `..`, real files, `.branchfs_ctl`, @child dirs
+                if !self.manager.is_branch_valid(&branch) {
+                    return Err(libc::ENOENT);
+                }
+                let inode_prefix = format!("/@{}", branch);
+                let mut cursor = self.dir_cursor(&branch, "/", ino, &inode_prefix);
+
+                if let Some(ctl_ino) = self.get_or_create_branch_ctl_ino(&branch) {
+                    cursor
+                        .tail
+                        .push_back((ctl_ino, FileType::RegularFile, CTL_FILE.to_string()));
+                }
+                // A real entry of the same name is listed instead (see
+                // `classify_path`)
+                for child in self.manager.get_children(&branch) {
+                    if self.has_real_entry(Some(&branch), &format!("/@{}", child)) {
+                        continue;
+                    }
+                    let child_inode_path = format!("/@{}/@{}", branch, child);
+                    let child_ino = self.inodes.get_or_create(&child_inode_path, true);
+                    cursor
+                        .tail
+                        .push_back((child_ino, FileType::Directory, format!("@{}", child)));
+                }
+                Ok(cursor)
+            }
+            PathContext::BranchPath(branch, rel_path) => {
+                if !self.manager.is_branch_valid(&branch) {
+                    return Err(libc::ENOENT);
+                }
+                let inode_prefix = format!("/@{}", branch);
+                Ok(self.dir_cursor(&branch, &rel_path, ino, &inode_prefix))
+            }
+            PathContext::RootPath(rel_path) => {
+                if self.is_stale() {
+                    return Err(libc::ESTALE);
+                }
+                let mut cursor = self.dir_cursor(&self.get_branch_name(), &rel_path, ino, "");
+                cursor.root_view = true;
+                if rel_path != "/" {
+                    return Ok(cursor);
+                }
+
+                // Root directory: real entries + ctl files + @branch virtual dirs
+                if !self.options.hide_ctl {
+                    cursor
+                        .tail
+                        .push_back((CTL_INO, FileType::RegularFile, CTL_FILE.to_string()));
+                }
+                if self.options.epoch_file {
+                    cursor.tail.push_back((
+                        EPOCH_INO,
+                        FileType::RegularFile,
+                        EPOCH_FILE.to_string(),
+                    ));
+                }
+                // Every branch but main, each of which lookup resolves, unless
+                // a real entry takes its name
+                if !self.options.hide_branches {
+                    for (bname, _parent) in self.manager.list_branches() {
+                        if bname != "main" && !self.has_real_entry(None, &format!("/@{}", bname)) {
+                            let inode_path = format!("/@{}", bname);
+                            let bino = self.inodes.get_or_create(&inode_path, true);
+                            cursor.tail.push_back((
+                                bino,
+                                FileType::Directory,
+                                format!("@{}", bname),
+                            ));
+                        }
+                    }
+                }
+                Ok(cursor)
+            }
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    /// Cursor over a directory resolved via a specific branch.
+    fn dir_cursor(&self, branch: &str, rel_path: &str, ino: u64, inode_prefix: &str) -> DirCursor {
+        let head = VecDeque::from([
+            (ino, FileType::Directory, ".".to_string()),
+            (self.parent_ino(ino), FileType::Directory, "..".to_string()),
+        ]);
+
+        let branch_path = self.branch_rel(rel_path);
+        let base_dir = storage::join(&self.manager.base_path, &branch_path);
+        let base = std::fs::read_dir(&base_dir).ok();
+        let delta_dir = self
+            .manager
+            .resolve_committed(branch, &branch_path)
+            .ok()
+            .flatten()
+            .filter(|resolved| *resolved != base_dir);
+        let delta = delta_dir
+            .as_ref()
+            .and_then(|dir| std::fs::read_dir(dir).ok());
+        let staged = self
+            .manager
+            .staged_path(branch, &branch_path)
+            .and_then(|dir| std::fs::read_dir(dir).ok());
+
+        DirCursor {
+            ino,
+            root_view: false,
+            head,
+            merged: MergedDir {
+                base_dir,
+                base,
+                delta_dir,
+                delta,
+                staged,
+            },
+            branch: branch.to_string(),
+            rel_path: rel_path.to_string(),
+            branch_path,
+            inode_prefix: inode_prefix.to_string(),
+            tail: VecDeque::new(),
+        }
+    }
+}
@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::{AtomicU64, Ordering};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 pub const ROOT_INO: u64 = 1;
 
+/// Start of the inode range reserved for ctl files.  Regular inodes are
+/// allocated from `ROOT_INO + 1` upward and must stay below it; per-branch
+/// ctl inodes are handed out from here up to the reserved inodes of the
+/// epoch and root ctl files.
+pub const CTL_INO_BASE: u64 = u64::MAX - 1_000_000;
+
 #[derive(Debug, Clone)]
 pub struct InodeInfo {
     pub ino: u64,
@@ -12,10 +18,60 @@ pub struct InodeInfo {
     pub is_dir: bool,
 }
 
+/// Use order of the evictable inodes, for `InodeManager::with_capacity`.
+/// Root and pinned inodes are left out, so the oldest entry can always go.
+#[derive(Default)]
+struct LruOrder {
+    tick: u64,
+    by_tick: BTreeMap<u64, u64>,
+    ticks: HashMap<u64, u64>,
+}
+
+impl LruOrder {
+    fn touch(&mut self, ino: u64) {
+        self.tick += 1;
+        if let Some(old) = self.ticks.insert(ino, self.tick) {
+            self.by_tick.remove(&old);
+        }
+        self.by_tick.insert(self.tick, ino);
+    }
+
+    /// Bump `ino` if it is tracked, without starting to track it
+    fn refresh(&mut self, ino: u64) {
+        if self.ticks.contains_key(&ino) {
+            self.touch(ino);
+        }
+    }
+
+    fn remove(&mut self, ino: u64) {
+        if let Some(tick) = self.ticks.remove(&ino) {
+            self.by_tick.remove(&tick);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<u64> {
+        let (_, ino) = self.by_tick.pop_first()?;
+        self.ticks.remove(&ino);
+        Some(ino)
+    }
+
+    fn clear(&mut self) {
+        self.by_tick.clear();
+        self.ticks.clear();
+    }
+}
+
 pub struct InodeManager {
     next_ino: AtomicU64,
     path_to_ino: RwLock<HashMap<String, u64>>,
     ino_to_info: RwLock<HashMap<u64, InodeInfo>>,
+    /// Inodes kept beyond root before the least recently used are evicted
+    /// (0 keeps every inode)
+    capacity: usize,
+    lru: Mutex<LruOrder>,
+    /// Open handles plus lookups the kernel has not forgotten, per inode;
+    /// pinned inodes are never evicted
+    pins: Mutex<HashMap<u64, u64>>,
 }
 
 impl InodeManager {
@@ -37,6 +93,21 @@ impl InodeManager {
             next_ino: AtomicU64::new(ROOT_INO + 1),
             path_to_ino: RwLock::new(path_to_ino),
             ino_to_info: RwLock::new(ino_to_info),
+            capacity: 0,
+            lru: Mutex::new(LruOrder::default()),
+            pins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// An inode table holding at most `capacity` inodes besides root and
+    /// those pinned by open handles or kernel lookups, evicting the least
+    /// recently used.  Only inodes the kernel has forgotten are evicted, so
+    /// it looks their paths up again and gets new inode numbers.  0 means
+    /// unbounded.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::new()
         }
     }
 
@@ -44,6 +115,7 @@ impl InodeManager {
         {
             let map = self.path_to_ino.read();
             if let Some(&ino) = map.get(path) {
+                self.touch(ino);
                 return ino;
             }
         }
@@ -52,10 +124,17 @@ impl InodeManager {
         let mut info_map = self.ino_to_info.write();
 
         if let Some(&ino) = path_map.get(path) {
+            self.touch(ino);
             return ino;
         }
 
         let ino = self.next_ino.fetch_add(1, Ordering::SeqCst);
+        // Unreachable in practice (2^64 allocations), but a collision with a
+        // ctl inode would silently alias a file with a control file
+        assert!(
+            ino < CTL_INO_BASE,
+            "regular inode numbers ran into the ctl range"
+        );
         path_map.insert(path.to_string(), ino);
         info_map.insert(
             ino,
@@ -66,19 +145,86 @@ impl InodeManager {
             },
         );
 
+        if self.capacity > 0 {
+            let mut lru = self.lru.lock();
+            lru.touch(ino);
+            while lru.ticks.len() > self.capacity {
+                let Some(old) = lru.pop_oldest() else {
+                    break;
+                };
+                if let Some(info) = info_map.remove(&old) {
+                    path_map.remove(&info.path);
+                }
+            }
+        }
+
         ino
     }
 
+    /// Mark `ino` as used for LRU eviction
+    fn touch(&self, ino: u64) {
+        if self.capacity > 0 {
+            self.lru.lock().refresh(ino);
+        }
+    }
+
     pub fn get_path(&self, ino: u64) -> Option<String> {
-        self.ino_to_info.read().get(&ino).map(|i| i.path.clone())
+        let path = self.ino_to_info.read().get(&ino).map(|i| i.path.clone());
+        if path.is_some() {
+            self.touch(ino);
+        }
+        path
     }
 
     pub fn get_ino(&self, path: &str) -> Option<u64> {
-        self.path_to_ino.read().get(path).copied()
+        let ino = self.path_to_ino.read().get(path).copied();
+        if let Some(ino) = ino {
+            self.touch(ino);
+        }
+        ino
     }
 
     pub fn get_info(&self, ino: u64) -> Option<InodeInfo> {
-        self.ino_to_info.read().get(&ino).cloned()
+        let info = self.ino_to_info.read().get(&ino).cloned();
+        if info.is_some() {
+            self.touch(ino);
+        }
+        info
+    }
+
+    /// Keep `ino` from being evicted until a matching `unpin`
+    pub fn pin(&self, ino: u64) {
+        *self.pins.lock().entry(ino).or_default() += 1;
+        self.lru.lock().remove(ino);
+    }
+
+    pub fn unpin(&self, ino: u64) {
+        self.unpin_n(ino, 1);
+    }
+
+    /// Count a lookup the kernel holds on `ino` after an entry reply
+    /// (lookup, create, mkdir, symlink, link); `ino` stays until `forget`
+    pub fn lookup(&self, ino: u64) {
+        self.pin(ino);
+    }
+
+    /// The kernel dropped `nlookup` of its lookups on `ino`
+    pub fn forget(&self, ino: u64, nlookup: u64) {
+        self.unpin_n(ino, nlookup);
+    }
+
+    fn unpin_n(&self, ino: u64, n: u64) {
+        let mut pins = self.pins.lock();
+        let Some(count) = pins.get_mut(&ino) else {
+            return;
+        };
+        *count = count.saturating_sub(n);
+        if *count == 0 {
+            pins.remove(&ino);
+            if self.capacity > 0 && ino != ROOT_INO && self.ino_to_info.read().contains_key(&ino) {
+                self.lru.lock().touch(ino);
+            }
+        }
     }
 
     pub fn remove(&self, path: &str) {
@@ -87,6 +233,44 @@ impl InodeManager {
 
         if let Some(ino) = path_map.remove(path) {
             info_map.remove(&ino);
+            self.lru.lock().remove(ino);
+        }
+    }
+
+    /// Re-key `old` and everything below it to `new`, keeping inode
+    /// numbers.  Whatever was registered at `new` is dropped.
+    pub fn rename(&self, old: &str, new: &str) {
+        let mut path_map = self.path_to_ino.write();
+        let mut info_map = self.ino_to_info.write();
+
+        let new_prefix = format!("{}/", new);
+        let stale: Vec<String> = path_map
+            .keys()
+            .filter(|p| *p == new || p.starts_with(&new_prefix))
+            .cloned()
+            .collect();
+        let mut lru = self.lru.lock();
+        for path in stale {
+            if let Some(ino) = path_map.remove(&path) {
+                info_map.remove(&ino);
+                lru.remove(ino);
+            }
+        }
+
+        let old_prefix = format!("{}/", old);
+        let moving: Vec<String> = path_map
+            .keys()
+            .filter(|p| *p == old || p.starts_with(&old_prefix))
+            .cloned()
+            .collect();
+        for path in moving {
+            if let Some(ino) = path_map.remove(&path) {
+                let moved = format!("{}{}", new, &path[old.len()..]);
+                if let Some(info) = info_map.get_mut(&ino) {
+                    info.path = moved.clone();
+                }
+                path_map.insert(moved, ino);
+            }
         }
     }
 
@@ -105,9 +289,11 @@ impl InodeManager {
             .cloned()
             .collect();
 
+        let mut lru = self.lru.lock();
         for path in to_remove {
             if let Some(ino) = path_map.remove(&path) {
                 info_map.remove(&ino);
+                lru.remove(ino);
             }
         }
     }
@@ -119,6 +305,7 @@ impl InodeManager {
 
         path_map.clear();
         info_map.clear();
+        self.lru.lock().clear();
 
         // Re-add root
         path_map.insert("/".to_string(), ROOT_INO);
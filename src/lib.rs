@@ -1,10 +1,12 @@
 pub mod branch;
 pub mod daemon;
+pub mod dedup;
 pub mod error;
 pub mod fs;
 mod fs_ctl;
 mod fs_helpers;
 pub(crate) mod fs_path;
+mod fs_readdir;
 pub mod inode;
 pub mod storage;
 
@@ -3,9 +3,18 @@ use std::path::{Path, PathBuf};
 use std::process;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
+use branchfs::branch::{
+    read_commit_log, BranchDetails, CommitLock, CommitMtime, CommitPreview, Deletion, DeltaEntry,
+    DiffEntry, HardlinkMode, Location, MergeReport, PreviewFormat,
+};
 use branchfs::daemon::{self, Request, Response};
+use branchfs::dedup;
+use branchfs::fs::{
+    AtimeMode, Durability, FuseFeatures, HandleStats, MountOptions, WriteMode,
+    DEFAULT_HANDLE_IDLE_TIMEOUT, DEFAULT_MAX_HANDLES, DEFAULT_READAHEAD,
+};
 
 #[derive(Parser)]
 #[command(name = "branchfs")]
@@ -15,6 +24,17 @@ struct Cli {
     command: Commands,
 }
 
+/// Sort order for `list`
+#[derive(Clone, Copy, ValueEnum)]
+enum SortKey {
+    /// Alphabetical by branch name
+    Name,
+    /// Oldest branch first
+    Age,
+    /// Largest delta first
+    Size,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Mount the filesystem (always starts on main branch)
@@ -27,10 +47,110 @@ enum Commands {
         #[arg(long, default_value = "/var/lib/branchfs")]
         storage: PathBuf,
 
+        /// Do not show @branch virtual directories at the mount root
+        #[arg(long)]
+        hide_branches: bool,
+
+        /// Leave .branchfs_ctl out of root directory listings
+        #[arg(long)]
+        hide_ctl: bool,
+
+        /// fsync tombstones on delete: `sync` (the default when the flag is
+        /// given without a value) or batched `deferred`
+        #[arg(long, num_args = 0..=1, default_value = "off", default_missing_value = "sync")]
+        durable: Durability,
+
+        /// Readahead window in bytes for sequential reads (0 disables)
+        #[arg(long, default_value_t = DEFAULT_READAHEAD)]
+        readahead: usize,
+
+        /// Access-time updates on read: none, relatime or strict (strict
+        /// copies base files into the branch on first read)
+        #[arg(long, default_value = "none")]
+        atime: AtimeMode,
+
+        /// Expose the commit epoch as a read-only /.branchfs_epoch file
+        #[arg(long)]
+        epoch_file: bool,
+
+        /// First write to an inherited file: copyup (copy it whole),
+        /// redirect (keep reading unwritten ranges from it) or overwrite
+        /// (start from an empty file)
+        #[arg(long, default_value = "copyup")]
+        write_mode: WriteMode,
+
+        /// Commits over hard-linked base files: preserve (rewrite the shared
+        /// file, so every link changes) or break (replace only the committed
+        /// path)
+        #[arg(long, default_value = "preserve")]
+        hardlinks: HardlinkMode,
+
+        /// mtime of the files a commit writes: now (the commit time), branch
+        /// (when the branch last wrote the file) or preserve (the replaced
+        /// file's)
+        #[arg(long, default_value = "now")]
+        commit_mtime: CommitMtime,
+
+        /// Report the real errno of failed backing calls (EACCES, ENOSPC,
+        /// ...); --strict-errno=false flattens them to EIO
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        strict_errno: bool,
+
+        /// Open handles allowed at once; further opens fail with ENFILE
+        #[arg(long, default_value_t = DEFAULT_MAX_HANDLES)]
+        max_handles: usize,
+
+        /// Seconds a handle may go unused before it is reaped (0 never)
+        #[arg(long, default_value_t = DEFAULT_HANDLE_IDLE_TIMEOUT)]
+        handle_idle_timeout: u64,
+
+        /// Inodes kept in the mount's table before the least recently used
+        /// are evicted (0 never evicts)
+        #[arg(long, default_value_t = 0)]
+        max_inodes: usize,
+
+        /// Flush every write to disk before acknowledging it (handles opened
+        /// with O_SYNC/O_DSYNC are flushed either way)
+        #[arg(long)]
+        sync_writes: bool,
+
+        /// Refuse every change through the mount with EROFS (browsing
+        /// branches and switch: still work)
+        #[arg(long)]
+        read_only: bool,
+
+        /// Mount only this directory of the base (relative to it) instead
+        /// of the whole base
+        #[arg(long)]
+        subdir: Option<String>,
+
+        /// Commit the mount's branch into its parent on unmount (unless it
+        /// is main)
+        #[arg(long, conflicts_with = "auto_abort")]
+        auto_commit: bool,
+
+        /// Discard the mount's branch on unmount (unless it is main)
+        #[arg(long)]
+        auto_abort: bool,
+
         /// Mount point
         mountpoint: PathBuf,
     },
 
+    /// Mount a mountpoint again after its daemon died, keeping its branch
+    Remount {
+        /// Mount point served by the dead daemon
+        mountpoint: PathBuf,
+
+        /// Branch to mount (default: the branch the mount was on)
+        #[arg(long)]
+        branch: Option<String>,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
     /// Create a new branch and switch to it
     Create {
         /// Branch name
@@ -43,6 +163,41 @@ enum Commands {
         #[arg(long, short, default_value = "main")]
         parent: String,
 
+        /// Print the new branch's metadata as JSON instead of a message
+        #[arg(long)]
+        json: bool,
+
+        /// Who is creating the branch, recorded for auditing (default:
+        /// $BRANCHFS_CREATED_BY)
+        #[arg(long)]
+        created_by: Option<String>,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Rename a branch (mounts on it follow the new name)
+    Rename {
+        /// Current branch name
+        old: String,
+
+        /// New branch name
+        new: String,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Switch a mount to an existing branch
+    Switch {
+        /// Branch name
+        name: String,
+
+        /// Mount point to switch
+        mountpoint: PathBuf,
+
         /// Storage directory
         #[arg(long, default_value = "/var/lib/branchfs")]
         storage: PathBuf,
@@ -53,6 +208,41 @@ enum Commands {
         /// Mount point of the branch to commit
         mountpoint: PathBuf,
 
+        /// Refuse to commit if another mount is currently on this branch
+        /// (by default the commit goes ahead and those mounts go stale)
+        #[arg(long)]
+        fail_if_mounted: bool,
+
+        /// Print what the commit would change instead of committing
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Commit even while files of the branch are open for writing
+        #[arg(long, conflicts_with = "dry_run")]
+        force: bool,
+
+        /// Message recorded with the commit in the commit log
+        #[arg(short, long, conflicts_with = "dry_run")]
+        message: Option<String>,
+
+        /// Commit only this path (a directory takes everything below it),
+        /// keeping the rest of the branch; repeatable.  The mount stays on
+        /// the branch
+        #[arg(long = "path", value_name = "PATH", conflicts_with_all = ["dry_run", "force"])]
+        paths: Vec<PathBuf>,
+
+        /// Dry-run output: `json` (files overwritten, created and deleted,
+        /// with content hashes) or a unified `patch`
+        #[arg(long, default_value = "json", requires = "dry_run")]
+        format: PreviewFormat,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Show the commit log, newest first
+    Log {
         /// Storage directory
         #[arg(long, default_value = "/var/lib/branchfs")]
         storage: PathBuf,
@@ -68,248 +258,2247 @@ enum Commands {
         storage: PathBuf,
     },
 
+    /// Show a branch's metadata as JSON
+    Info {
+        /// Branch name
+        branch: String,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
     /// List branches
     List {
+        /// Sort branches by name, age or delta size
+        #[arg(long, value_enum)]
+        sort: Option<SortKey>,
+
+        /// Only list the children of this branch
+        #[arg(long)]
+        parent: Option<String>,
+
+        /// Only list the branches below this one, at any depth
+        #[arg(long)]
+        under: Option<String>,
+
         /// Storage directory
         #[arg(long, default_value = "/var/lib/branchfs")]
         storage: PathBuf,
     },
 
-    /// Unmount a branch (daemon auto-exits when last mount is removed)
-    Unmount {
-        /// Mount point to unmount
+    /// Switch a mount back to the branch it was on before
+    Back {
+        /// Mount point
         mountpoint: PathBuf,
 
         /// Storage directory
         #[arg(long, default_value = "/var/lib/branchfs")]
         storage: PathBuf,
     },
-}
-
-fn get_socket_path(storage: &Path) -> PathBuf {
-    storage.join("daemon.sock")
-}
 
-fn send_request(storage: &Path, request: &Request) -> Result<Response> {
-    let socket_path = get_socket_path(storage);
-    daemon::send_request(&socket_path, request)
-        .map_err(|e| anyhow::anyhow!("Failed to communicate with daemon: {}", e))
-}
+    /// List the child branches of a branch
+    Children {
+        /// Branch name
+        branch: String,
 
-/// Determine the parent branch of the mount's current branch.
-/// Returns "main" if the current branch is unknown or has no parent.
-fn get_parent_branch(storage: &Path, mountpoint: &Path) -> String {
-    // Ask the daemon what branch this mount is currently on
-    let current = match send_request(
-        storage,
-        &Request::GetMountBranch {
-            mountpoint: mountpoint.to_string_lossy().to_string(),
-        },
-    ) {
-        Ok(resp) if resp.ok => resp
-            .data
-            .and_then(|d| d.as_str().map(|s| s.to_string()))
-            .unwrap_or_else(|| "main".to_string()),
-        _ => return "main".to_string(),
-    };
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
 
-    if current == "main" {
-        return "main".to_string();
-    }
+        /// Include the whole subtree, not only direct children
+        #[arg(long, short)]
+        recursive: bool,
 
-    // Get the branch list to find the parent
-    let list_resp = match send_request(storage, &Request::List) {
-        Ok(resp) if resp.ok => resp,
-        _ => return "main".to_string(),
-    };
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-    if let Some(data) = list_resp.data {
-        if let Some(branches) = data.as_array() {
-            for branch in branches {
-                if branch["name"].as_str() == Some(&current) {
-                    return branch["parent"].as_str().unwrap_or("main").to_string();
-                }
-            }
-        }
-    }
+    /// List the mountpoints whose current branch is a branch
+    Who {
+        /// Branch name
+        branch: String,
 
-    "main".to_string()
-}
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let cli = Cli::parse();
+    /// List the daemon's mounts with the branch each is on
+    Mounts {
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-    match cli.command {
-        Commands::Mount {
-            base,
-            storage,
-            mountpoint,
-        } => {
-            std::fs::create_dir_all(&storage)?;
-            let storage = storage.canonicalize()?;
+    /// Delete a branch by name, whether or not a mount is on it (mounts on
+    /// it move to its parent)
+    Delete {
+        /// Branch name
+        name: String,
 
-            // Canonicalize base if provided
-            let base = base.map(|b| b.canonicalize()).transpose()?;
+        /// Mount point of the storage
+        mountpoint: PathBuf,
 
-            // Ensure daemon is running (auto-start if needed)
-            daemon::ensure_daemon(base.as_deref(), &storage)
-                .map_err(|e| anyhow::anyhow!("{}", e))?;
+        /// Also delete the branch's descendants instead of refusing
+        #[arg(long)]
+        recursive: bool,
 
-            // Create mountpoint
-            std::fs::create_dir_all(&mountpoint)?;
-            let mountpoint = mountpoint.canonicalize()?;
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-            // Send mount request (always mounts main branch)
-            let response = send_request(
-                &storage,
-                &Request::Mount {
-                    branch: "main".to_string(),
-                    mountpoint: mountpoint.to_string_lossy().to_string(),
-                },
-            )?;
+    /// Delete every branch except main (the base is left untouched)
+    Purge {
+        /// Mount point of the storage to purge
+        mountpoint: PathBuf,
 
-            if response.ok {
-                println!("Mounted at {:?}", mountpoint);
-            } else {
-                eprintln!("Error: {}", response.error.unwrap_or_default());
-                process::exit(1);
-            }
-        }
+        /// Confirm that all branches and their changes should be discarded
+        #[arg(long)]
+        yes: bool,
 
-        Commands::Create {
-            name,
-            mountpoint,
-            parent,
-            storage,
-        } => {
-            let storage = storage.canonicalize()?;
-            let mountpoint = mountpoint.canonicalize()?;
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-            let response = send_request(
-                &storage,
-                &Request::Create {
-                    name: name.clone(),
-                    parent: parent.clone(),
-                },
-            )?;
+    /// Copy all branches of a running storage into a backup directory
+    Backup {
+        /// Storage directory to back up
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
 
-            if response.ok {
-                // Switch to the new branch
-                let ctl_path = mountpoint.join(".branchfs_ctl");
+        /// Backup destination (must be empty or not exist)
+        #[arg(long)]
+        to: PathBuf,
+    },
 
-                let mut file = std::fs::OpenOptions::new()
-                    .write(true)
-                    .open(&ctl_path)
-                    .map_err(|e| {
-                        anyhow::anyhow!(
-                            "Failed to open control file (is {} mounted?): {}",
-                            mountpoint.display(),
-                            e
-                        )
-                    })?;
+    /// Restore branches from a backup directory into a running storage
+    Restore {
+        /// Storage directory to restore into
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
 
-                file.write_all(format!("switch:{}", name).as_bytes())
-                    .map_err(|e| anyhow::anyhow!("Failed to switch to branch: {}", e))?;
+        /// Backup directory created by `branchfs backup`
+        #[arg(long)]
+        from: PathBuf,
+    },
 
-                // Notify daemon of the switch
-                let _ = send_request(
-                    &storage,
-                    &Request::NotifySwitch {
-                        mountpoint: mountpoint.to_string_lossy().to_string(),
-                        branch: name.clone(),
-                    },
-                );
+    /// Remove the legacy per-mount storage directory; with no daemon
+    /// running, also share the data of identical delta files across
+    /// branches
+    Gc {
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-                println!(
-                    "Created and switched to branch '{}' (parent: '{}')",
-                    name, parent
-                );
-            } else {
-                eprintln!("Error: {}", response.error.unwrap_or_default());
-                process::exit(1);
-            }
-        }
+    /// Take the advisory commit lock so cooperating agents commit in turn
+    Lock {
+        /// Name identifying the lock holder
+        #[arg(long)]
+        holder: String,
 
-        Commands::Commit {
-            mountpoint,
-            storage,
-        } => {
-            let mountpoint = mountpoint.canonicalize()?;
-            let storage = storage.canonicalize()?;
-            let ctl_path = mountpoint.join(".branchfs_ctl");
+        /// Release the lock automatically after this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
 
-            // Determine parent branch before commit (FUSE handler will switch to it)
-            let parent = get_parent_branch(&storage, &mountpoint);
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-            let mut file = std::fs::OpenOptions::new()
-                .write(true)
-                .open(&ctl_path)
-                .map_err(|e| anyhow::anyhow!("Failed to open control file: {}", e))?;
+    /// Release the advisory commit lock
+    Unlock {
+        /// Name the lock was taken with
+        #[arg(long)]
+        holder: String,
 
-            file.write_all(b"commit")
-                .map_err(|e| anyhow::anyhow!("Commit failed: {}", e))?;
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
 
-            // Notify daemon that we've switched to the parent branch
-            let _ = send_request(
+    /// Print the daemon's counters in the Prometheus text format
+    Metrics {
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Unmount every mount of the daemon and stop it
+    Shutdown {
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Show daemon-wide state: version, uptime, mounts and the commit lock
+    Status {
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Show a unified diff of one file between base and a branch
+    DiffFile {
+        /// Branch name
+        branch: String,
+
+        /// File path relative to the mount root
+        path: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Print a file from a branch only if the epoch has not changed
+    ReadIfEpoch {
+        /// Branch name
+        branch: String,
+
+        /// File path relative to the mount root
+        path: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Epoch the caller last observed (see `branchfs inspect`)
+        #[arg(long)]
+        epoch: u64,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Set the size of a file in a branch, creating it if absent
+    Truncate {
+        /// Branch name
+        branch: String,
+
+        /// File path relative to the mount root
+        path: String,
+
+        /// New size in bytes
+        size: u64,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Move a file or directory within a branch (directories bring along
+    /// children that only exist in the base)
+    Mv {
+        /// Branch name
+        branch: String,
+
+        /// Source path relative to the mount root
+        from: String,
+
+        /// Destination path relative to the mount root
+        to: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Print the BLAKE3 hash of a file as seen through a branch
+    Hash {
+        /// Branch name
+        branch: String,
+
+        /// File path relative to the mount root
+        path: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Check whether a uid could access a path in a branch, from the file's
+    /// mode and owner; prints "allowed" or "denied" (exit status 2)
+    Access {
+        /// Branch name
+        branch: String,
+
+        /// Path relative to the mount root
+        path: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// User to check for
+        #[arg(long)]
+        uid: u32,
+
+        /// Group to check for (defaults to the user's primary group)
+        #[arg(long)]
+        gid: Option<u32>,
+
+        /// Access to check: any of r, w and x, or f for existence only
+        #[arg(long, default_value = "r")]
+        mask: String,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// List the deletions a branch sees, including its ancestors' (tab
+    /// separated: path, branch that deleted it, shadows or gone)
+    Deletions {
+        /// Branch name
+        branch: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Check whether a branch changed anything: prints "empty" (exit 0) or
+    /// "not empty" (exit 2)
+    IsEmpty {
+        /// Branch name
+        branch: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// List the files a branch itself wrote and the paths it deleted (tab
+    /// separated: path, then the delta size or "tombstone")
+    Deltas {
+        /// Branch name
+        branch: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// List the paths a branch added, modified or deleted relative to the
+    /// base (tab separated: status, path)
+    Diff {
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Branch name (default: the mount's current branch)
+        #[arg(long)]
+        branch: Option<String>,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Write a branch's whole view (base, deltas and deletions merged) to a
+    /// tar archive
+    Export {
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Branch name (default: the mount's current branch)
+        #[arg(long)]
+        branch: Option<String>,
+
+        /// Archive to write
+        #[arg(long)]
+        output: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Create a branch from a tar archive: its entries become the branch's
+    /// changes, and an entry `dir/.wh.NAME` deletes `dir/NAME`
+    Import {
+        /// Mount point of the storage
+        mountpoint: PathBuf,
+
+        /// Name of the new branch
+        #[arg(long)]
+        branch: String,
+
+        /// Parent branch name
+        #[arg(long, short, default_value = "main")]
+        parent: String,
+
+        /// Archive to read
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Copy a branch's own changes into another branch; paths both changed
+    /// are left as the target has them and reported (tab separated:
+    /// merged or conflict, path; exit status 2 on conflicts)
+    Merge {
+        /// Branch whose changes are merged
+        from: String,
+
+        /// Mount point the branches are visible through
+        mountpoint: PathBuf,
+
+        /// Branch to merge into (default: the mount's current branch)
+        #[arg(long)]
+        into: Option<String>,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Show where a branch's view of a path is stored on disk
+    Where {
+        /// Branch name
+        branch: String,
+
+        /// Path relative to the branch root
+        path: String,
+
+        /// Mount point the branch is visible through
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// List branches whose parent no longer exists
+    Orphans {
+        /// Any mount point served by the daemon
+        mountpoint: PathBuf,
+
+        /// Re-parent the orphans onto main
+        #[arg(long)]
+        repair: bool,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Check the daemon's branch state against its storage: prints "ok"
+    /// (exit 0) or one line per problem found (exit 2)
+    Check {
+        /// Any mount point served by the daemon
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Force every mount stale without committing (test builds only)
+    #[cfg(feature = "testing")]
+    BumpEpoch {
+        /// Any mount point served by the daemon
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Record a notifier failure on a mount (test builds only)
+    #[cfg(feature = "testing")]
+    FailNotifier {
+        /// Mount point whose notifier is marked failed
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Show the state of a single mount
+    Inspect {
+        /// Mount point to inspect
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+
+    /// Unmount a branch (daemon auto-exits when last mount is removed)
+    Unmount {
+        /// Mount point to unmount
+        mountpoint: PathBuf,
+
+        /// Storage directory
+        #[arg(long, default_value = "/var/lib/branchfs")]
+        storage: PathBuf,
+    },
+}
+
+fn get_socket_path(storage: &Path) -> PathBuf {
+    storage.join("daemon.sock")
+}
+
+fn send_request(storage: &Path, request: &Request) -> Result<Response> {
+    let socket_path = get_socket_path(storage);
+    daemon::send_request(&socket_path, request)
+        .map_err(|e| anyhow::anyhow!("Failed to communicate with daemon: {}", e))
+}
+
+/// Describe the files keeping the branch at `mountpoint` from committing,
+/// as the daemon reports them; `None` if there are none (the branch has an
+/// open transaction instead).
+fn open_writers_error(storage: &Path, mountpoint: &Path) -> Option<String> {
+    let data = send_request(
+        storage,
+        &Request::MountInfo {
+            mountpoint: mountpoint.to_string_lossy().to_string(),
+        },
+    )
+    .ok()
+    .filter(|resp| resp.ok)
+    .and_then(|resp| resp.data)
+    .unwrap_or_default();
+    let paths: Vec<&str> = data["open_for_write"]
+        .as_array()
+        .map(|paths| paths.iter().filter_map(|p| p.as_str()).collect())
+        .unwrap_or_default();
+    if paths.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "branch '{}' has files open for writing: {}",
+        data["branch"].as_str().unwrap_or("?"),
+        paths.join(", ")
+    ))
+}
+
+/// Determine the parent branch of the mount's current branch.
+/// Returns "main" if the current branch is unknown or has no parent.
+fn get_parent_branch(storage: &Path, mountpoint: &Path) -> String {
+    // Ask the daemon what branch this mount is currently on
+    let current = match send_request(
+        storage,
+        &Request::GetMountBranch {
+            mountpoint: mountpoint.to_string_lossy().to_string(),
+        },
+    ) {
+        Ok(resp) if resp.ok => resp
+            .data
+            .and_then(|d| d.as_str().map(|s| s.to_string()))
+            .unwrap_or_else(|| "main".to_string()),
+        _ => return "main".to_string(),
+    };
+
+    if current == "main" {
+        return "main".to_string();
+    }
+
+    // Get the branch list to find the parent
+    let list_resp = match send_request(
+        storage,
+        &Request::List {
+            parent: None,
+            descendants_of: None,
+        },
+    ) {
+        Ok(resp) if resp.ok => resp,
+        _ => return "main".to_string(),
+    };
+
+    if let Some(data) = list_resp.data {
+        if let Some(branches) = data.as_array() {
+            for branch in branches {
+                if branch["name"].as_str() == Some(&current) {
+                    return branch["parent"].as_str().unwrap_or("main").to_string();
+                }
+            }
+        }
+    }
+
+    "main".to_string()
+}
+
+/// Render an age in seconds as a short human-readable string (e.g. "3h").
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// One-line summary of a mount's handle counts
+fn format_fuse(features: &FuseFeatures) -> String {
+    format!(
+        "supported={} enabled={}",
+        features.supported.join(","),
+        features.enabled.join(",")
+    )
+}
+
+fn format_handles(handles: &HandleStats) -> String {
+    format!(
+        "{} open (opened={} released={} reaped={} refused={})",
+        handles.open, handles.opened, handles.released, handles.reaped, handles.refused
+    )
+}
+
+/// Explain a failed `create` whose parent is missing from `storage`'s
+/// daemon: list the branches it does have, and point out when the mount
+/// belongs to a different daemon (branches are per storage directory).
+/// Returns `None` if the parent exists, i.e. the failure had another cause.
+/// `access(2)` mask from letters r, w and x; "f" checks existence only.
+fn parse_access_mask(spec: &str) -> Result<i32> {
+    let mut mask = libc::F_OK;
+    for c in spec.chars() {
+        mask |= match c {
+            'r' => libc::R_OK,
+            'w' => libc::W_OK,
+            'x' => libc::X_OK,
+            'f' => libc::F_OK,
+            _ => anyhow::bail!("invalid access mask '{}' (expected r, w, x or f)", spec),
+        };
+    }
+    Ok(mask)
+}
+
+fn missing_parent_hint(storage: &Path, mountpoint: &Path, parent: &str) -> Option<String> {
+    let response = send_request(
+        storage,
+        &Request::List {
+            parent: None,
+            descendants_of: None,
+        },
+    )
+    .ok()?;
+    let mut names: Vec<String> = response
+        .data?
+        .as_array()?
+        .iter()
+        .filter_map(|b| b["name"].as_str().map(|s| s.to_string()))
+        .collect();
+    if names.iter().any(|n| n == parent) {
+        return None;
+    }
+    names.sort();
+
+    let mut hint = format!(
+        "parent branch '{}' does not exist\nAvailable branches: {}\nPick one with --parent <name>",
+        parent,
+        names.join(", ")
+    );
+    let served = send_request(
+        storage,
+        &Request::GetMountBranch {
+            mountpoint: mountpoint.to_string_lossy().to_string(),
+        },
+    )
+    .map(|r| r.ok)
+    .unwrap_or(false);
+    if !served {
+        hint.push_str(&format!(
+            "\nNote: {} is not served by the daemon for {}; branches of another storage are not visible here",
+            mountpoint.display(),
+            storage.display()
+        ));
+    }
+    Some(hint)
+}
+
+/// Switch a mount to `branch` through its ctl file and tell the daemon.
+fn switch_mount(storage: &Path, mountpoint: &Path, branch: &str) -> Result<()> {
+    let ctl_path = mountpoint.join(".branchfs_ctl");
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&ctl_path)
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to open control file (is {} mounted?): {}",
+                mountpoint.display(),
+                e
+            )
+        })?;
+
+    // Every mount of the storage shares one set of branches, so a missing
+    // branch is missing everywhere
+    file.write_all(format!("switch:{}", branch).as_bytes())
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOENT) => anyhow::anyhow!("branch '{}' does not exist", branch),
+            _ => anyhow::anyhow!("Failed to switch to branch: {}", e),
+        })?;
+
+    // Notify daemon of the switch
+    let _ = send_request(
+        storage,
+        &Request::NotifySwitch {
+            mountpoint: mountpoint.to_string_lossy().to_string(),
+            branch: branch.to_string(),
+        },
+    );
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::Mount {
+            base,
+            storage,
+            hide_branches,
+            hide_ctl,
+            durable,
+            readahead,
+            atime,
+            epoch_file,
+            write_mode,
+            hardlinks,
+            commit_mtime,
+            strict_errno,
+            max_handles,
+            handle_idle_timeout,
+            max_inodes,
+            sync_writes,
+            read_only,
+            subdir,
+            auto_commit,
+            auto_abort,
+            mountpoint,
+        } => {
+            std::fs::create_dir_all(&storage)?;
+            let storage = storage.canonicalize()?;
+
+            // Canonicalize base if provided
+            let base = base.map(|b| b.canonicalize()).transpose()?;
+
+            // Ensure daemon is running (auto-start if needed)
+            daemon::ensure_daemon(base.as_deref(), &storage)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+            // Create mountpoint
+            std::fs::create_dir_all(&mountpoint)?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            // Send mount request (always mounts main branch)
+            let request = Request::Mount {
+                branch: "main".to_string(),
+                mountpoint: mountpoint.to_string_lossy().to_string(),
+                options: MountOptions {
+                    hide_branches,
+                    hide_ctl,
+                    readahead,
+                    durable,
+                    atime,
+                    epoch_file,
+                    write_mode,
+                    hardlinks,
+                    commit_mtime,
+                    strict_errno,
+                    max_handles,
+                    handle_idle_timeout,
+                    max_inodes,
+                    sync_writes,
+                    read_only,
+                    subdir,
+                    auto_commit,
+                    auto_abort,
+                },
+            };
+            // A daemon whose last mount was just removed may exit between the
+            // liveness check and the request; start a fresh one and retry once
+            let response = match send_request(&storage, &request) {
+                Ok(response) => response,
+                Err(_) => {
+                    daemon::ensure_daemon(base.as_deref(), &storage)
+                        .map_err(|e| anyhow::anyhow!("{}", e))?;
+                    send_request(&storage, &request)?
+                }
+            };
+
+            if response.ok {
+                println!("Mounted at {:?}", mountpoint);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Remount {
+            mountpoint,
+            branch,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            // The dead mount itself cannot be resolved (ENOTCONN)
+            let mountpoint = match (mountpoint.parent(), mountpoint.file_name()) {
+                (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+                    parent.canonicalize()?.join(name)
+                }
+                _ => std::env::current_dir()?.join(&mountpoint),
+            };
+
+            daemon::ensure_daemon(None, &storage).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+            let response = send_request(
+                &storage,
+                &Request::Remount {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    branch,
+                },
+            )?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                println!(
+                    "Remounted {:?} on branch '{}'",
+                    mountpoint,
+                    data["branch"].as_str().unwrap_or("main")
+                );
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Create {
+            name,
+            mountpoint,
+            parent,
+            json,
+            created_by,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Create {
+                    name: name.clone(),
+                    parent: parent.clone(),
+                    created_by: created_by.or_else(|| std::env::var("BRANCHFS_CREATED_BY").ok()),
+                },
+            )?;
+
+            if response.ok {
+                switch_mount(&storage, &mountpoint, &name)?;
+
+                let info = response
+                    .data
+                    .and_then(|data| serde_json::from_value::<BranchDetails>(data).ok());
+                match info {
+                    Some(info) if json => println!("{}", serde_json::to_string_pretty(&info)?),
+                    _ => println!(
+                        "Created and switched to branch '{}' (parent: '{}')",
+                        name, parent
+                    ),
+                }
+            } else {
+                match missing_parent_hint(&storage, &mountpoint, &parent) {
+                    Some(hint) => eprintln!("Error: {}", hint),
+                    None => eprintln!("Error: {}", response.error.unwrap_or_default()),
+                }
+                process::exit(1);
+            }
+        }
+
+        Commands::Info { branch, storage } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(&storage, &Request::Info { branch })?;
+
+            if response.ok {
+                let info: BranchDetails =
+                    serde_json::from_value(response.data.unwrap_or_default())?;
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Rename { old, new, storage } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Rename {
+                    old: old.clone(),
+                    new: new.clone(),
+                },
+            )?;
+
+            if response.ok {
+                println!("Renamed branch '{}' to '{}'", old, new);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Switch {
+            name,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            switch_mount(&storage, &mountpoint, &name)?;
+            println!("Switched to branch '{}'", name);
+        }
+
+        Commands::Commit {
+            mountpoint,
+            fail_if_mounted,
+            dry_run,
+            force,
+            message,
+            paths,
+            format,
+            storage,
+        } => {
+            let mountpoint = mountpoint.canonicalize()?;
+            let storage = storage.canonicalize()?;
+            let ctl_path = mountpoint.join(".branchfs_ctl");
+
+            if dry_run {
+                let response = send_request(
+                    &storage,
+                    &Request::CommitDryRunDiff {
+                        mountpoint: mountpoint.to_string_lossy().to_string(),
+                        format,
+                    },
+                )?;
+                if !response.ok {
+                    eprintln!("Error: {}", response.error.unwrap_or_default());
+                    process::exit(1);
+                }
+                match (format, response.data) {
+                    (PreviewFormat::Patch, Some(serde_json::Value::String(patch))) => {
+                        print!("{}", patch)
+                    }
+                    (_, Some(data)) => {
+                        let preview: CommitPreview = serde_json::from_value(data)?;
+                        println!("{}", serde_json::to_string_pretty(&preview)?);
+                    }
+                    _ => {}
+                }
+                return Ok(());
+            }
+
+            if !paths.is_empty() {
+                // Relative to the mount's root, whether given from it or
+                // through the mount
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|p| {
+                        let p = p.strip_prefix(&mountpoint).unwrap_or(p);
+                        format!("/{}", p.to_string_lossy().trim_start_matches('/'))
+                    })
+                    .collect();
+                let response = send_request(
+                    &storage,
+                    &Request::CommitPaths {
+                        mountpoint: mountpoint.to_string_lossy().to_string(),
+                        paths,
+                        message,
+                        fail_if_mounted,
+                    },
+                )?;
+                if !response.ok {
+                    eprintln!("Error: {}", response.error.unwrap_or_default());
+                    process::exit(1);
+                }
+                let committed: Vec<String> =
+                    serde_json::from_value(response.data.unwrap_or_default()).unwrap_or_default();
+                if committed.is_empty() {
+                    println!("Nothing to commit under the given paths");
+                    process::exit(2);
+                }
+                for path in &committed {
+                    println!("{}", path);
+                }
+                return Ok(());
+            }
+
+            // Determine parent branch before commit (FUSE handler will switch to it)
+            let parent = get_parent_branch(&storage, &mountpoint);
+
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&ctl_path)
+                .map_err(|e| anyhow::anyhow!("Failed to open control file: {}", e))?;
+
+            let has_message = message.is_some();
+            if has_message {
+                let response = send_request(
+                    &storage,
+                    &Request::CommitMessage {
+                        mountpoint: mountpoint.to_string_lossy().to_string(),
+                        message,
+                    },
+                )?;
+                if !response.ok {
+                    eprintln!("Error: {}", response.error.unwrap_or_default());
+                    process::exit(1);
+                }
+            }
+
+            // The daemon checks for other mounts and commits in one step
+            if fail_if_mounted {
+                let response = send_request(
+                    &storage,
+                    &Request::CommitExclusive {
+                        mountpoint: mountpoint.to_string_lossy().to_string(),
+                        force,
+                    },
+                )?;
+                if !response.ok {
+                    if has_message {
+                        let _ = send_request(
+                            &storage,
+                            &Request::CommitMessage {
+                                mountpoint: mountpoint.to_string_lossy().to_string(),
+                                message: None,
+                            },
+                        );
+                    }
+                    eprintln!("Error: {}", response.error.unwrap_or_default());
+                    process::exit(1);
+                }
+                println!("Committed branch at {:?}", mountpoint);
+                return Ok(());
+            }
+
+            let command: &[u8] = if force { b"commit:force" } else { b"commit" };
+            if let Err(e) = file.write_all(command) {
+                // Don't leave the message for a later commit
+                if has_message {
+                    let _ = send_request(
+                        &storage,
+                        &Request::CommitMessage {
+                            mountpoint: mountpoint.to_string_lossy().to_string(),
+                            message: None,
+                        },
+                    );
+                }
+                if e.raw_os_error() == Some(libc::EBUSY) {
+                    match open_writers_error(&storage, &mountpoint) {
+                        Some(writers) => {
+                            eprintln!("Error: {} (use --force to commit anyway)", writers)
+                        }
+                        None => eprintln!(
+                            "Error: the branch has an open transaction (end it with txn:commit or txn:abort first)"
+                        ),
+                    }
+                    process::exit(1);
+                }
+                return Err(anyhow::anyhow!("Commit failed: {}", e));
+            }
+
+            // Notify daemon that we've switched to the parent branch
+            let _ = send_request(
+                &storage,
+                &Request::NotifySwitch {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    branch: parent,
+                },
+            );
+
+            println!("Committed branch at {:?}", mountpoint);
+        }
+
+        Commands::Log { storage } => {
+            let storage = storage.canonicalize()?;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            for entry in read_commit_log(&storage)?.iter().rev() {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    format_age(now.saturating_sub(entry.timestamp)),
+                    entry.branch,
+                    entry.into,
+                    entry.message.as_deref().unwrap_or("-")
+                );
+                for path in &entry.paths {
+                    println!("    {}", path);
+                }
+            }
+        }
+
+        Commands::Abort {
+            mountpoint,
+            storage,
+        } => {
+            let mountpoint = mountpoint.canonicalize()?;
+            let storage = storage.canonicalize()?;
+            let ctl_path = mountpoint.join(".branchfs_ctl");
+
+            // Determine parent branch before abort (FUSE handler will switch to it)
+            let parent = get_parent_branch(&storage, &mountpoint);
+
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&ctl_path)
+                .map_err(|e| anyhow::anyhow!("Failed to open control file: {}", e))?;
+
+            file.write_all(b"abort")
+                .map_err(|e| anyhow::anyhow!("Abort failed: {}", e))?;
+
+            // Notify daemon that we've switched to the parent branch
+            let _ = send_request(
+                &storage,
+                &Request::NotifySwitch {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    branch: parent,
+                },
+            );
+
+            println!("Aborted branch at {:?}", mountpoint);
+        }
+
+        Commands::List {
+            sort,
+            parent,
+            under,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::List {
+                    parent,
+                    descendants_of: under,
+                },
+            )?;
+
+            if response.ok {
+                println!(
+                    "{:<20} {:<20} {:>8} {:>12}  CREATED BY",
+                    "BRANCH", "PARENT", "AGE", "SIZE"
+                );
+                println!(
+                    "{:<20} {:<20} {:>8} {:>12}  ----------",
+                    "------", "------", "---", "----"
+                );
+
+                if let Some(serde_json::Value::Array(mut branches)) = response.data {
+                    match sort {
+                        Some(SortKey::Name) => {
+                            branches.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()))
+                        }
+                        Some(SortKey::Age) => branches.sort_by_key(|b| b["created_at"].as_u64()),
+                        Some(SortKey::Size) => branches
+                            .sort_by_key(|b| std::cmp::Reverse(b["size"].as_u64().unwrap_or(0))),
+                        None => {}
+                    }
+
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    for branch in &branches {
+                        let name = branch["name"].as_str().unwrap_or("-");
+                        let parent = branch["parent"].as_str().unwrap_or("-");
+                        let age = branch["created_at"]
+                            .as_u64()
+                            .map(|t| format_age(now.saturating_sub(t)))
+                            .unwrap_or_else(|| "-".to_string());
+                        let size = branch["size"].as_u64().unwrap_or(0);
+                        let created_by = branch["created_by"].as_str().unwrap_or("-");
+                        println!(
+                            "{:<20} {:<20} {:>8} {:>12}  {}",
+                            name, parent, age, size, created_by
+                        );
+                    }
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Back {
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::SwitchBack {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let branch = response
+                    .data
+                    .as_ref()
+                    .and_then(|d| d["branch"].as_str())
+                    .unwrap_or("main");
+                println!("Switched to branch '{}'", branch);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Children {
+            branch,
+            mountpoint,
+            recursive,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::ChildBranches {
+                    branch,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    recursive,
+                },
+            )?;
+
+            if response.ok {
+                if let Some(children) = response.data.as_ref().and_then(|d| d.as_array()) {
+                    for child in children {
+                        if recursive {
+                            println!(
+                                "{:<20} {:<20}",
+                                child["name"].as_str().unwrap_or("-"),
+                                child["parent"].as_str().unwrap_or("-")
+                            );
+                        } else {
+                            println!("{}", child["name"].as_str().unwrap_or("-"));
+                        }
+                    }
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Delete {
+            name,
+            mountpoint,
+            recursive,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Delete {
+                    name,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    recursive,
+                },
+            )?;
+
+            if response.ok {
+                let removed = response
+                    .data
+                    .as_ref()
+                    .and_then(|d| d["removed"].as_array())
+                    .cloned()
+                    .unwrap_or_default();
+                for name in removed.iter().filter_map(|n| n.as_str()) {
+                    println!("Deleted branch '{}'", name);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Purge {
+            mountpoint,
+            yes,
+            storage,
+        } => {
+            if !yes {
+                eprintln!("Error: purge discards every branch except main; pass --yes to confirm");
+                process::exit(1);
+            }
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Purge {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let removed = response
+                    .data
+                    .as_ref()
+                    .and_then(|d| d["removed"].as_array())
+                    .map(|r| r.len())
+                    .unwrap_or(0);
+                println!("Purged {} branches", removed);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Backup { storage, to } => {
+            let storage = storage.canonicalize()?;
+            std::fs::create_dir_all(&to)?;
+            let to = to.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Backup {
+                    dest: to.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let count = response.data.unwrap_or_default()["branches"].clone();
+                println!("Backed up {} branches to {:?}", count, to);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Restore { storage, from } => {
+            let storage = storage.canonicalize()?;
+            let from = from.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Restore {
+                    src: from.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let count = response.data.unwrap_or_default()["branches"].clone();
+                println!("Restored {} branches from {:?}", count, from);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Gc { storage } => {
+            let storage = storage.canonicalize()?;
+
+            // Nothing serves the storage: clean up directly, and share the
+            // data of identical deltas, which replaces files underneath
+            if !daemon::is_daemon_running(&get_socket_path(&storage)) {
+                let removed = daemon::gc_mount_dirs(&storage);
+                for dir in &removed {
+                    println!("Removed {}", dir.display());
+                }
+                println!("Removed {} orphaned mount directories", removed.len());
+                match dedup::dedup_deltas(&storage) {
+                    Ok(report) => println!(
+                        "Deduplicated {} delta files ({} reflinked, {} hard-linked, {} skipped), reclaiming {} bytes",
+                        report.reflinked + report.hardlinked,
+                        report.reflinked,
+                        report.hardlinked,
+                        report.skipped,
+                        report.bytes
+                    ),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    }
+                }
+                return Ok(());
+            }
+
+            let response = send_request(&storage, &Request::Gc)?;
+
+            if response.ok {
+                if let Some(serde_json::Value::Array(removed)) =
+                    response.data.and_then(|d| d.get("removed").cloned())
+                {
+                    for dir in &removed {
+                        println!("Removed {}", dir.as_str().unwrap_or_default());
+                    }
+                    println!("Removed {} orphaned mount directories", removed.len());
+                }
+                println!("Delta files are deduplicated only while no daemon is running");
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Lock {
+            holder,
+            timeout,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::AcquireCommitLock {
+                    holder: holder.clone(),
+                    timeout_secs: timeout,
+                },
+            )?;
+
+            if response.ok {
+                println!("Commit lock taken by '{}'", holder);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Unlock { holder, storage } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::ReleaseCommitLock {
+                    holder: holder.clone(),
+                },
+            )?;
+
+            if response.ok {
+                println!("Commit lock released by '{}'", holder);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Who { branch, storage } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(&storage, &Request::BranchMounts { branch })?;
+
+            if response.ok {
+                if let Some(mounts) = response.data.as_ref().and_then(|d| d.as_array()) {
+                    for mount in mounts {
+                        println!("{}", mount.as_str().unwrap_or("-"));
+                    }
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Mounts { storage } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(&storage, &Request::ListMounts)?;
+
+            if response.ok {
+                if let Some(mounts) = response.data.as_ref().and_then(|d| d.as_array()) {
+                    for mount in mounts {
+                        println!(
+                            "{}\t{}",
+                            mount["mountpoint"].as_str().unwrap_or("-"),
+                            mount["current_branch"].as_str().unwrap_or("-")
+                        );
+                    }
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Metrics { storage } => {
+            let storage = storage.canonicalize()?;
+
+            let response = send_request(&storage, &Request::Metrics)?;
+
+            if response.ok {
+                if let Some(text) = response.data.as_ref().and_then(|d| d.as_str()) {
+                    print!("{}", text);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Shutdown { storage } => {
+            let storage = storage.canonicalize()?;
+            let socket_path = get_socket_path(&storage);
+
+            let response = send_request(&storage, &Request::Shutdown)?;
+
+            if response.ok {
+                // The mounts are gone once the reply arrives; wait for the
+                // daemon itself so a new mount does not race its exit
+                for _ in 0..50 {
+                    if !daemon::is_daemon_running(&socket_path) {
+                        break;
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                println!("Daemon shut down");
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Status { storage } => {
+            let storage = storage.canonicalize()?;
+
+            // A daemon that accepts the connection but never answers is
+            // wedged, not healthy
+            let ping = daemon::send_request_timeout(
+                &get_socket_path(&storage),
+                &Request::Ping,
+                Some(std::time::Duration::from_secs(5)),
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Error: daemon not responding: {}", e);
+                process::exit(1);
+            });
+            let data = ping.data.unwrap_or_default();
+            println!("version: {}", data["version"].as_str().unwrap_or("unknown"));
+            println!(
+                "uptime: {}",
+                format_age(data["uptime_secs"].as_u64().unwrap_or(0))
+            );
+
+            let response = send_request(&storage, &Request::Status)?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                println!("mounts: {}", data["mounts"].as_u64().unwrap_or(0));
+                if let Ok(handles) = serde_json::from_value::<HandleStats>(data["handles"].clone())
+                {
+                    println!("handles: {}", format_handles(&handles));
+                }
+                if let Ok(features) = serde_json::from_value::<FuseFeatures>(data["fuse"].clone()) {
+                    println!("fuse: {}", format_fuse(&features));
+                }
+                match serde_json::from_value::<CommitLock>(data["commit_lock"].clone()) {
+                    Ok(lock) => {
+                        let now = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0);
+                        let expiry = lock
+                            .expires_at
+                            .map(|at| {
+                                format!(", expires in {}", format_age(at.saturating_sub(now)))
+                            })
+                            .unwrap_or_default();
+                        println!(
+                            "commit lock: held by '{}' for {}{}",
+                            lock.holder,
+                            format_age(now.saturating_sub(lock.acquired_at)),
+                            expiry
+                        );
+                    }
+                    Err(_) => println!("commit lock: free"),
+                }
+                let committing = data["committing"].as_bool().unwrap_or(false);
+                println!("committing: {}", if committing { "yes" } else { "no" });
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::DiffFile {
+            branch,
+            path,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::DiffFile {
+                    branch,
+                    path,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                if let Some(diff) = response.data.as_ref().and_then(|d| d.as_str()) {
+                    print!("{}", diff);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::ReadIfEpoch {
+            branch,
+            path,
+            mountpoint,
+            epoch,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::ReadIfEpoch {
+                    branch,
+                    path,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    epoch,
+                },
+            )?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                if data["stale"].as_bool() == Some(true) {
+                    eprintln!("stale: epoch is now {}", data["epoch"]);
+                    process::exit(2);
+                }
+                print!("{}", data["content"].as_str().unwrap_or_default());
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Truncate {
+            branch,
+            path,
+            size,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Truncate {
+                    branch: branch.clone(),
+                    path: path.clone(),
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    size,
+                },
+            )?;
+
+            if response.ok {
+                println!(
+                    "Truncated '{}' in branch '{}' to {} bytes",
+                    path, branch, size
+                );
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Mv {
+            branch,
+            from,
+            to,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::RenamePath {
+                    branch: branch.clone(),
+                    from: from.clone(),
+                    to: to.clone(),
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                println!("Moved '{}' to '{}' in branch '{}'", from, to, branch);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Hash {
+            branch,
+            path,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Hash {
+                    branch,
+                    path: path.clone(),
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let hash = response
+                    .data
+                    .as_ref()
+                    .and_then(|d| d["hash"].as_str())
+                    .unwrap_or_default();
+                println!("{}  {}", hash, path);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Access {
+            branch,
+            path,
+            mountpoint,
+            uid,
+            gid,
+            mask,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+            let mask = parse_access_mask(&mask)?;
+            let gid = match gid {
+                Some(gid) => gid,
+                None => nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid))?
+                    .map(|u| u.gid.as_raw())
+                    .ok_or_else(|| anyhow::anyhow!("no user with uid {}; pass --gid", uid))?,
+            };
+
+            let response = send_request(
+                &storage,
+                &Request::CheckAccess {
+                    branch,
+                    path,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    uid,
+                    gid,
+                    mask,
+                },
+            )?;
+
+            if response.ok {
+                let allowed = response
+                    .data
+                    .as_ref()
+                    .and_then(|d| d["allowed"].as_bool())
+                    .unwrap_or(false);
+                if allowed {
+                    println!("allowed");
+                } else {
+                    println!("denied");
+                    process::exit(2);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Deletions {
+            branch,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Deletions {
+                    branch,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let deletions: Vec<Deletion> =
+                    serde_json::from_value(response.data.unwrap_or_default())?;
+                for deletion in &deletions {
+                    let state = if deletion.shadows { "shadows" } else { "gone" };
+                    println!("{}\t{}\t{}", deletion.path, deletion.branch, state);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::IsEmpty {
+            branch,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
                 &storage,
-                &Request::NotifySwitch {
+                &Request::IsEmpty {
+                    branch,
                     mountpoint: mountpoint.to_string_lossy().to_string(),
-                    branch: parent,
                 },
-            );
+            )?;
 
-            println!("Committed branch at {:?}", mountpoint);
+            if response.ok {
+                if response.data.unwrap_or_default()["empty"].as_bool() == Some(true) {
+                    println!("empty");
+                } else {
+                    println!("not empty");
+                    process::exit(2);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
         }
 
-        Commands::Abort {
+        Commands::Deltas {
+            branch,
             mountpoint,
             storage,
         } => {
+            let storage = storage.canonicalize()?;
             let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::ListDeltas {
+                    branch,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let deltas: Vec<DeltaEntry> =
+                    serde_json::from_value(response.data.unwrap_or_default())?;
+                for delta in &deltas {
+                    match delta.size {
+                        Some(size) if !delta.tombstone => println!("{}\t{}", delta.path, size),
+                        _ => println!("{}\ttombstone", delta.path),
+                    }
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Diff {
+            mountpoint,
+            branch,
+            storage,
+        } => {
             let storage = storage.canonicalize()?;
-            let ctl_path = mountpoint.join(".branchfs_ctl");
+            let mountpoint = mountpoint.canonicalize()?;
 
-            // Determine parent branch before abort (FUSE handler will switch to it)
-            let parent = get_parent_branch(&storage, &mountpoint);
+            let response = send_request(
+                &storage,
+                &Request::Diff {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    branch,
+                },
+            )?;
 
-            let mut file = std::fs::OpenOptions::new()
-                .write(true)
-                .open(&ctl_path)
-                .map_err(|e| anyhow::anyhow!("Failed to open control file: {}", e))?;
+            if response.ok {
+                let diff: Vec<DiffEntry> =
+                    serde_json::from_value(response.data.unwrap_or_default())?;
+                for entry in &diff {
+                    println!("{}\t{}", entry.status, entry.path);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
 
-            file.write_all(b"abort")
-                .map_err(|e| anyhow::anyhow!("Abort failed: {}", e))?;
+        Commands::Export {
+            mountpoint,
+            branch,
+            output,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+            // The daemon writes the archive, so it needs an absolute path
+            let output = std::path::absolute(&output)?;
 
-            // Notify daemon that we've switched to the parent branch
-            let _ = send_request(
+            let response = send_request(
                 &storage,
-                &Request::NotifySwitch {
+                &Request::Export {
                     mountpoint: mountpoint.to_string_lossy().to_string(),
-                    branch: parent,
+                    branch,
+                    output: output.to_string_lossy().to_string(),
                 },
-            );
+            )?;
 
-            println!("Aborted branch at {:?}", mountpoint);
+            if response.ok {
+                let count = response.data.unwrap_or_default()["entries"].clone();
+                println!("Exported {} entries to {:?}", count, output);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
         }
 
-        Commands::List { storage } => {
+        Commands::Import {
+            mountpoint,
+            branch,
+            parent,
+            input,
+            storage,
+        } => {
             let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+            let input = input.canonicalize()?;
 
-            let response = send_request(&storage, &Request::List)?;
+            let response = send_request(
+                &storage,
+                &Request::Import {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    branch: branch.clone(),
+                    parent: parent.clone(),
+                    input: input.to_string_lossy().to_string(),
+                },
+            )?;
 
             if response.ok {
-                println!("{:<20} {:<20}", "BRANCH", "PARENT");
-                println!("{:<20} {:<20}", "------", "------");
+                let data = response.data.unwrap_or_default();
+                println!(
+                    "Imported {} entries and {} deletions into branch '{}' (parent: '{}')",
+                    data["entries"], data["deletions"], branch, parent
+                );
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
 
-                if let Some(data) = response.data {
-                    if let Some(branches) = data.as_array() {
-                        for branch in branches {
-                            let name = branch["name"].as_str().unwrap_or("-");
-                            let parent = branch["parent"].as_str().unwrap_or("-");
-                            println!("{:<20} {:<20}", name, parent);
-                        }
+        Commands::Merge {
+            from,
+            mountpoint,
+            into,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Merge {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    from,
+                    into,
+                },
+            )?;
+
+            if response.ok {
+                let report: MergeReport =
+                    serde_json::from_value(response.data.unwrap_or_default())?;
+                for path in &report.merged {
+                    println!("merged\t{}", path);
+                }
+                for path in &report.conflicts {
+                    println!("conflict\t{}", path);
+                }
+                if !report.conflicts.is_empty() {
+                    process::exit(2);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Where {
+            branch,
+            path,
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Where {
+                    branch,
+                    path,
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let location: Location = serde_json::from_value(response.data.unwrap_or_default())?;
+                let state = if location.delta_exists {
+                    "exists"
+                } else if location.tombstoned {
+                    "tombstoned"
+                } else {
+                    "missing"
+                };
+                println!("{:<10} {} ({})", "delta:", location.delta.display(), state);
+                match &location.resolved {
+                    Some(resolved) if location.redirected => {
+                        println!("{:<10} {} (redirected)", "resolved:", resolved.display())
+                    }
+                    Some(resolved) => println!("{:<10} {}", "resolved:", resolved.display()),
+                    None => println!("{:<10} (none)", "resolved:"),
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Orphans {
+            mountpoint,
+            repair,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Orphans {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                    repair,
+                },
+            )?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                for orphan in data.as_array().into_iter().flatten() {
+                    let name = orphan["name"].as_str().unwrap_or_default();
+                    let parent = orphan["parent"].as_str().unwrap_or_default();
+                    if repair {
+                        println!("Re-parented '{}' (was '{}') onto main", name, parent);
+                    } else {
+                        println!("{}\t{}", name, parent);
+                    }
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Check {
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::Validate {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                let issues = data.as_array().cloned().unwrap_or_default();
+                if issues.is_empty() {
+                    println!("ok");
+                } else {
+                    for issue in &issues {
+                        println!(
+                            "{}\t{}\t{}",
+                            issue["kind"].as_str().unwrap_or_default(),
+                            issue["branch"].as_str().unwrap_or_default(),
+                            issue["detail"].as_str().unwrap_or_default()
+                        );
+                    }
+                    process::exit(2);
+                }
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        #[cfg(feature = "testing")]
+        Commands::BumpEpoch {
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::BumpEpoch {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                println!("Epoch is now {}", data["epoch"]);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        #[cfg(feature = "testing")]
+        Commands::FailNotifier {
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::FailNotifier {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                println!("Marked notifier of {:?} as failed", mountpoint);
+            } else {
+                eprintln!("Error: {}", response.error.unwrap_or_default());
+                process::exit(1);
+            }
+        }
+
+        Commands::Inspect {
+            mountpoint,
+            storage,
+        } => {
+            let storage = storage.canonicalize()?;
+            let mountpoint = mountpoint.canonicalize()?;
+
+            let response = send_request(
+                &storage,
+                &Request::MountInfo {
+                    mountpoint: mountpoint.to_string_lossy().to_string(),
+                },
+            )?;
+
+            if response.ok {
+                let data = response.data.unwrap_or_default();
+                println!("{:<20} {}", "mountpoint:", mountpoint.display());
+                println!(
+                    "{:<20} {}",
+                    "branch:",
+                    data["branch"].as_str().unwrap_or("-")
+                );
+                println!("{:<20} {}", "epoch:", data["epoch"]);
+                println!("{:<20} {}", "opened inodes:", data["opened_inodes"]);
+                if let Ok(handles) = serde_json::from_value::<HandleStats>(data["handles"].clone())
+                {
+                    println!("{:<20} {}", "handles:", format_handles(&handles));
+                }
+                if let Ok(features) = serde_json::from_value::<FuseFeatures>(data["fuse"].clone()) {
+                    println!("{:<20} {}", "fuse:", format_fuse(&features));
+                }
+                if let Some(mounts) = data["branch_mounts"].as_array() {
+                    let mounts: Vec<&str> = mounts.iter().filter_map(|m| m.as_str()).collect();
+                    println!("{:<20} {}", "branch mounts:", mounts.join(", "));
+                }
+                if let Some(paths) = data["open_for_write"].as_array() {
+                    if !paths.is_empty() {
+                        let paths: Vec<&str> = paths.iter().filter_map(|p| p.as_str()).collect();
+                        println!("{:<20} {}", "open for write:", paths.join(", "));
+                    }
+                }
+                let counters = &data["counters"];
+                println!(
+                    "{:<20} reads={} writes={} cow={} creates={} unlinks={}",
+                    "counters:",
+                    counters["reads"],
+                    counters["writes"],
+                    counters["cow_copies"],
+                    counters["creates"],
+                    counters["unlinks"]
+                );
+                println!(
+                    "{:<20} {}",
+                    "notifier:",
+                    if data["notifier_registered"].as_bool() == Some(true) {
+                        "registered"
+                    } else {
+                        "missing"
+                    }
+                );
+                let health = &data["notifier_health"];
+                if health.is_object() {
+                    let state = if health["healthy"].as_bool() == Some(true) {
+                        "healthy"
+                    } else {
+                        "failing"
+                    };
+                    let mut line = format!(
+                        "{} (failures={} repairs={})",
+                        state, health["failures"], health["repairs"]
+                    );
+                    if let Some(error) = health["last_error"].as_str() {
+                        line.push_str(&format!(", last error: {}", error));
                     }
+                    println!("{:<20} {}", "notifier health:", line);
                 }
             } else {
                 eprintln!("Error: {}", response.error.unwrap_or_default());
@@ -1,9 +1,85 @@
+use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
 
 use crate::error::Result;
 
+/// Paths inside branches are kept as `String`s (inode paths, tombstones).
+/// A byte of a file name that is not valid UTF-8 is stored as the
+/// private-use character `ESCAPE_BASE + byte`, so the name maps back to
+/// the same bytes on disk and never collides with another name.
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+/// A file name as stored in branch paths; see `ESCAPE_BASE`.
+pub fn name_to_string(name: &OsStr) -> String {
+    let mut bytes = name.as_bytes();
+    let mut out = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                out.push_str(valid);
+                return out;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let bad = e.error_len().unwrap_or(rest.len());
+                for &b in &rest[..bad] {
+                    out.extend(char::from_u32(ESCAPE_BASE + b as u32));
+                }
+                bytes = &rest[bad..];
+            }
+        }
+    }
+}
+
+/// The on-disk form of a branch path, undoing `name_to_string`.
+pub fn to_os_path(path: &str) -> PathBuf {
+    let escaped = |c: char| (ESCAPE_BASE + 0x80..=ESCAPE_BASE + 0xff).contains(&(c as u32));
+    if !path.chars().any(escaped) {
+        return PathBuf::from(path);
+    }
+    let mut bytes = Vec::with_capacity(path.len());
+    for c in path.chars() {
+        if escaped(c) {
+            bytes.push((c as u32 - ESCAPE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+/// `rel_path` (a branch path such as `/src/main.rs`) under `dir`.
+pub fn join(dir: &Path, rel_path: &str) -> PathBuf {
+    dir.join(to_os_path(rel_path.trim_start_matches('/')))
+}
+
+/// Refuse a branch path from outside the daemon (a socket request) that
+/// could leave the directory it is joined to: `..`, or a root or prefix
+/// left after the leading slashes.
+pub fn validate_rel_path(rel_path: &str) -> Result<()> {
+    use std::path::Component;
+
+    let escapes = Path::new(rel_path.trim_start_matches('/'))
+        .components()
+        .any(|c| {
+            matches!(
+                c,
+                Component::ParentDir | Component::RootDir | Component::Prefix(_)
+            )
+        });
+    if escapes {
+        return Err(crate::error::BranchError::Invalid(format!(
+            "{}: path must stay inside the branch",
+            rel_path
+        )));
+    }
+    Ok(())
+}
+
 pub fn ensure_parent_dirs(path: &Path) -> Result<()> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
@@ -11,12 +87,207 @@ pub fn ensure_parent_dirs(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Create directory `dst` with the mode and, where permitted, the owner of
+/// directory `src`.  Unless running as root, the owner keeps write and
+/// search access, or nothing could be created below `dst` afterwards.
+pub fn create_dir_like(src: &Path, dst: &Path) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let meta = fs::metadata(src)?;
+    fs::create_dir(dst)?;
+    // Only root may give a directory away; anyone else keeps ownership
+    let _ = std::os::unix::fs::chown(dst, Some(meta.uid()), Some(meta.gid()));
+    let mut mode = meta.permissions().mode();
+    if !nix::unistd::geteuid().is_root() {
+        mode |= 0o300;
+    }
+    fs::set_permissions(dst, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Copy a file, or recreate a symlink with the same target.
 pub fn copy_file(src: &Path, dst: &Path) -> Result<()> {
     ensure_parent_dirs(dst)?;
-    fs::copy(src, dst)?;
+    if is_symlink(src) {
+        let target = fs::read_link(src)?;
+        if exists(dst) {
+            fs::remove_file(dst)?;
+        }
+        std::os::unix::fs::symlink(target, dst)?;
+    } else if !clone_file(src, dst)? {
+        fs::copy(src, dst)?;
+    }
     Ok(())
 }
 
+/// Reflink `src` to `dst` with `FICLONE`, sharing extents instead of
+/// copying data (Btrfs, XFS).  Returns false, for the caller to fall back
+/// to a byte copy, when the filesystem can't clone or the two paths are on
+/// different filesystems.
+fn clone_file(src: &Path, dst: &Path) -> Result<bool> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let input = File::open(src)?;
+    let meta = input.metadata()?;
+    if !meta.is_file() {
+        return Ok(false);
+    }
+    let output = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(meta.permissions().mode())
+        .open(dst)?;
+    if !ficlone(&input, &output)? {
+        return Ok(false);
+    }
+    // Like fs::copy, give dst the source's mode whatever the umask
+    output.set_permissions(meta.permissions())?;
+    Ok(true)
+}
+
+/// Make the existing file `dst` share `src`'s extents with `FICLONE`,
+/// keeping its inode, mode and modification time.  Returns false when the
+/// filesystem can't clone, like `clone_file`.
+pub fn reflink_over(src: &Path, dst: &Path) -> Result<bool> {
+    let input = File::open(src)?;
+    let output = fs::OpenOptions::new().write(true).open(dst)?;
+    let mtime = output.metadata()?.modified()?;
+    if !ficlone(&input, &output)? {
+        return Ok(false);
+    }
+    output.set_modified(mtime)?;
+    Ok(true)
+}
+
+/// `ioctl(FICLONE)` of `input` into `output`; false if unsupported.
+fn ficlone(input: &File, output: &File) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY | libc::ENOSYS) => {
+                Ok(false)
+            }
+            _ => Err(err.into()),
+        };
+    }
+    Ok(true)
+}
+
+/// `copy_file` through a temporary sibling that is renamed over `dst` only
+/// once complete, so a failed copy (a full disk) leaves nothing at `dst`.
+pub fn copy_file_atomic(src: &Path, dst: &Path) -> Result<()> {
+    let mut name = OsString::from(".");
+    name.push(dst.file_name().unwrap_or_default());
+    name.push(".branchfs-copy");
+    let tmp = dst.with_file_name(name);
+    let result = copy_file(src, &tmp).and_then(|()| Ok(fs::rename(&tmp, dst)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Copy up to `len` bytes from `src` at `off_in` to `dst` at `off_out`,
+/// stopping early at the end of `src`.  Uses `copy_file_range(2)`, which
+/// reflinks or copies in the kernel, and falls back to reading and writing
+/// through a buffer where the syscall is not supported (older kernels,
+/// files on different filesystems).  Returns the bytes copied.
+pub fn copy_range(
+    src: &File,
+    off_in: u64,
+    dst: &File,
+    off_out: u64,
+    len: u64,
+) -> std::io::Result<u64> {
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut copied = 0u64;
+    let mut kernel_copy = true;
+    let mut buf = Vec::new();
+    while copied < len {
+        let chunk = (len - copied).min(1 << 30) as usize;
+        let n = if kernel_copy {
+            let mut pos_in = (off_in + copied) as libc::loff_t;
+            let mut pos_out = (off_out + copied) as libc::loff_t;
+            let n = unsafe {
+                libc::copy_file_range(
+                    src.as_raw_fd(),
+                    &mut pos_in,
+                    dst.as_raw_fd(),
+                    &mut pos_out,
+                    chunk,
+                    0,
+                )
+            };
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL) => {
+                        kernel_copy = false;
+                        continue;
+                    }
+                    _ => return Err(err),
+                }
+            }
+            n as usize
+        } else {
+            buf.resize(chunk.min(1 << 20), 0);
+            let n = src.read_at(&mut buf, off_in + copied)?;
+            dst.write_all_at(&buf[..n], off_out + copied)?;
+            n
+        };
+        if n == 0 {
+            break;
+        }
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// Whether `uid`/`gid` passes an `access(2)` check with `mask` against a
+/// file with the given mode bits and owner, using the owner, group or other
+/// class as the kernel does.  Root passes read and write checks, and execute
+/// checks on directories or when any execute bit is set.
+pub fn access_allowed(
+    mode: u32,
+    is_dir: bool,
+    owner: (u32, u32),
+    uid: u32,
+    gid: u32,
+    mask: i32,
+) -> bool {
+    let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+    if uid == 0 {
+        return wanted & libc::X_OK as u32 == 0 || is_dir || mode & 0o111 != 0;
+    }
+    let granted = if uid == owner.0 {
+        (mode >> 6) & 0o7
+    } else if gid == owner.1 {
+        (mode >> 3) & 0o7
+    } else {
+        mode & 0o7
+    };
+    granted & wanted == wanted
+}
+
+/// Whether anything, including a dangling symlink, is at `path`.
+pub fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Whether `path` is a directory itself rather than a symlink to one.
+pub fn is_dir(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_dir())
+}
+
 pub fn read_file(path: &Path) -> Result<Vec<u8>> {
     let mut file = File::open(path)?;
     let mut buf = Vec::new();
@@ -48,3 +319,21 @@ pub fn remove_dir_all(path: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+pub fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            // The link itself, whether or not its target exists
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
@@ -38,7 +38,7 @@ echo "  ✓ /dev/fuse accessible"
 # Build if needed
 echo ""
 echo -e "${YELLOW}Building branchfs...${NC}"
-(cd "$PROJECT_ROOT" && cargo build --release 2>&1) || {
+(cd "$PROJECT_ROOT" && cargo build --release --features testing 2>&1) || {
     echo -e "${RED}Build failed${NC}"
     exit 1
 }
@@ -146,6 +146,142 @@ test_abort_non_leaf_fails() {
     do_unmount
 }
 
+test_abort_with_open_write_fd() {
+    setup
+    do_mount
+    do_mount2
+
+    # Second mount switches to the branch and opens a file for writing
+    "$BRANCHFS" create "vanishing" "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    exec 3>> "$TEST_MNT2/file1.txt"
+
+    # First mount aborts the branch underneath it
+    echo "abort" > "$TEST_MNT/@vanishing/.branchfs_ctl"
+
+    # The write may fail, but must not take down the mount's FUSE session
+    echo "late write" >&3 2>/dev/null || true
+    exec 3>&-
+
+    assert "stat '$TEST_MNT2/.branchfs_ctl' > /dev/null" "Second mount still answers"
+    echo -n "switch:main" > "$TEST_MNT2/.branchfs_ctl"
+    assert_file_contains "$TEST_MNT2/file1.txt" "base content" "Second mount usable after switching to main"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base untouched by late write"
+    assert "do_list > /dev/null" "Daemon still responds"
+
+    do_unmount2
+    do_unmount
+}
+
+test_purge_removes_all_branches() {
+    setup
+    do_mount
+    do_mount2
+    "$BRANCHFS" create "purge_b2" "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    echo "b2" > "$TEST_MNT2/b2.txt"
+
+    do_create "purge_a" "main"
+    echo "a" > "$TEST_MNT/a.txt"
+    do_create "purge_b" "main"
+    do_create "purge_c" "purge_b"
+    echo "c" > "$TEST_MNT/c.txt"
+
+    assert "! '$BRANCHFS' purge '$TEST_MNT' --storage '$TEST_STORAGE' 2>/dev/null" "Purge without --yes fails"
+    assert_branch_exists "purge_a" "Branches survive unconfirmed purge"
+
+    "$BRANCHFS" purge "$TEST_MNT" --yes --storage "$TEST_STORAGE" >/dev/null
+    assert "[[ \$? -eq 0 ]]" "Purge succeeds"
+
+    local branches
+    branches=$("$BRANCHFS" list --storage "$TEST_STORAGE" | tail -n +3 | awk '{print $1}' | tr '\n' ' ')
+    assert_eq "$branches" "main " "Only main remains"
+    assert "[[ ! -d '$TEST_STORAGE/branches/purge_a' ]]" "Branch storage removed"
+    assert_file_not_exists "$TEST_BASE/a.txt" "Base untouched"
+    assert_file_exists "$TEST_MNT/file1.txt" "Mount serves main"
+    assert_file_not_exists "$TEST_MNT/c.txt" "Purged changes are gone from the mount"
+
+    # The other mount moves to main too, with its notifier and epoch
+    assert_file_contains "$TEST_MNT2/file1.txt" "base content" "Other mount serves main"
+    assert_file_not_exists "$TEST_MNT2/b2.txt" "Other mount's purged changes are gone"
+    local info
+    info=$("$BRANCHFS" inspect "$TEST_MNT2" --storage "$TEST_STORAGE")
+    assert "grep -q '^branch: *main' <<< \"\$info\"" "Other mount tracked on main"
+    assert "grep -q '^notifier: *registered' <<< \"\$info\"" "Other mount's notifier follows it"
+
+    do_unmount2
+    do_unmount
+}
+
+test_create_races_abort() {
+    setup
+    do_mount
+    do_mount2
+
+    local round leftovers=0
+    for round in 1 2 3 4 5 6 7 8 9 10; do
+        "$BRANCHFS" create "race_$round" "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+
+        # The first mount keeps creating files through @branch until the
+        # branch is gone; the second mount aborts it concurrently
+        (
+            n=0
+            while : > "$TEST_MNT/@race_$round/subdir/new_$n" 2>/dev/null; do
+                n=$((n + 1))
+            done
+        ) &
+        sleep 0.2
+        echo "abort" > "$TEST_MNT2/.branchfs_ctl"
+        wait
+
+        [[ -e "$TEST_STORAGE/branches/race_$round" ]] && leftovers=$((leftovers + 1))
+    done
+    assert_eq "$leftovers" "0" "No delta left behind for aborted branches"
+    assert "[[ -z \"\$(ls '$TEST_BASE/subdir' | grep new_)\" ]]" "Base untouched"
+
+    do_unmount2
+    do_unmount
+}
+
+test_delete_branch() {
+    setup
+    do_mount
+    do_mount2
+
+    do_create "del_parent" "main"
+    do_create "del_child" "del_parent"
+    echo "child change" > "$TEST_MNT/file1.txt"
+    do_create "del_other" "main"
+    "$BRANCHFS" switch main "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    "$BRANCHFS" switch del_child "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+
+    # A branch with children is refused without --recursive
+    local output status=0
+    output=$("$BRANCHFS" delete del_parent "$TEST_MNT" --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Delete with children refused"
+    assert "[[ \"\$output\" == *'has child branches: del_child'* ]]" "Error lists the children"
+    assert_branch_exists "del_parent" "Parent kept"
+
+    # Any branch can be deleted by name, not only a mount's current one
+    output=$("$BRANCHFS" delete del_other "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "Deleted branch 'del_other'" "Unmounted leaf deleted"
+    assert_branch_not_exists "del_other"
+
+    output=$("$BRANCHFS" delete del_parent "$TEST_MNT" --recursive --storage "$TEST_STORAGE")
+    assert_eq "$output" "$(printf "Deleted branch 'del_child'\nDeleted branch 'del_parent'")" "Subtree deleted leaves first"
+    assert_branch_not_exists "del_parent"
+    assert_branch_not_exists "del_child"
+    assert "[[ ! -e '$TEST_STORAGE/branches/del_child' ]]" "Child storage removed"
+
+    # The mount on the deleted child falls back to the surviving parent
+    assert_file_contains "$TEST_MNT2/file1.txt" "base content" "Mount moved to main"
+    echo "after delete" > "$TEST_MNT2/new.txt"
+    assert_file_contains "$TEST_MNT2/new.txt" "after delete" "Mount still writable"
+
+    assert "! '$BRANCHFS' delete main '$TEST_MNT' --storage '$TEST_STORAGE' 2>/dev/null" "Cannot delete main"
+
+    do_unmount2
+    do_unmount
+}
+
 # Run tests
 run_test "Abort Discards Changes" test_abort_discards_changes
 run_test "Abort Switches to Main" test_abort_switches_to_main
@@ -153,5 +289,9 @@ run_test "Abort Nested Discards Leaf Only" test_abort_nested_discards_leaf_only
 run_test "Abort Preserves Siblings" test_abort_preserves_siblings
 run_test "Abort Main Fails" test_abort_main_fails
 run_test "Abort Non-Leaf Fails" test_abort_non_leaf_fails
+run_test "Abort With Open Write Fd" test_abort_with_open_write_fd
+run_test "Purge Removes All Branches" test_purge_removes_all_branches
+run_test "Create Races Abort" test_create_races_abort
+run_test "Delete Branch" test_delete_branch
 
 print_summary
@@ -0,0 +1,175 @@
+#!/bin/bash
+# Test backup and restore of a storage's branch set
+
+source "$(dirname "$0")/test_helper.sh"
+
+TEST_BACKUP="/tmp/branchfs_test_backup_$TEST_ID"
+
+do_backup() {
+    "$BRANCHFS" backup --storage "$TEST_STORAGE" --to "$TEST_BACKUP"
+}
+
+do_restore() {
+    "$BRANCHFS" restore --storage "$TEST_STORAGE" --from "$TEST_BACKUP"
+}
+
+test_backup_restore_roundtrip() {
+    setup
+    do_mount
+    do_create "backup_parent" "main"
+    echo "parent content" > "$TEST_MNT/parent_file.txt"
+    do_create "backup_child" "backup_parent"
+    echo "child content" > "$TEST_MNT/child_file.txt"
+    rm "$TEST_MNT/file2.txt"
+
+    do_backup
+    assert "[[ -f '$TEST_BACKUP/backup.json' ]]" "Backup manifest written"
+
+    # Unmounting the last mount restarts the daemon with an empty branch set
+    do_unmount
+    do_mount
+    assert_branch_not_exists "backup_parent" "Branches gone after remount"
+
+    do_restore
+    assert_branch_exists "backup_parent" "Parent branch restored"
+    assert_branch_exists "backup_child" "Child branch restored"
+
+    assert_file_contains "$TEST_MNT/@backup_parent/parent_file.txt" "parent content" "Parent delta restored"
+    assert_file_contains "$TEST_MNT/@backup_child/child_file.txt" "child content" "Child delta restored"
+    assert_file_contains "$TEST_MNT/@backup_child/parent_file.txt" "parent content" "Child still inherits from parent"
+    assert_file_not_exists "$TEST_MNT/@backup_child/file2.txt" "Tombstone restored"
+
+    do_unmount
+    rm -rf "$TEST_BACKUP"
+}
+
+test_backup_restore_symlinks() {
+    setup
+    echo "host content" > "/tmp/branchfs_test_host_$TEST_ID"
+    do_mount
+    do_create "link_branch" "main"
+    ln -s /nonexistent/target "$TEST_MNT/dangling"
+    ln -s file1.txt "$TEST_MNT/rel"
+    ln -s "/tmp/branchfs_test_host_$TEST_ID" "$TEST_MNT/host"
+
+    assert "do_backup" "Backup of a branch holding symlinks succeeds"
+    assert "[[ -L '$TEST_BACKUP/branches/link_branch/files/host' ]]" "Link archived as a link"
+
+    do_unmount
+    do_mount
+    do_restore
+    assert "[[ \$(readlink '$TEST_MNT/@link_branch/dangling') == /nonexistent/target ]]" "Dangling link restored"
+    assert "[[ \$(readlink '$TEST_MNT/@link_branch/rel') == file1.txt ]]" "Relative link restored"
+    assert_file_contains "$TEST_MNT/@link_branch/rel" "base content" "Relative link resolves in the branch"
+    assert "[[ \$(readlink '$TEST_MNT/@link_branch/host') == /tmp/branchfs_test_host_$TEST_ID ]]" "Link to a host file restored as a link"
+
+    do_unmount
+    rm -rf "$TEST_BACKUP" "/tmp/branchfs_test_host_$TEST_ID"
+}
+
+test_restore_rejects_existing_branch() {
+    setup
+    do_mount
+    do_create "dup_branch" "main"
+
+    do_backup
+
+    if do_restore 2>/dev/null; then
+        assert "false" "Restore over an existing branch fails"
+    else
+        assert "true" "Restore over an existing branch fails"
+    fi
+
+    do_unmount
+    rm -rf "$TEST_BACKUP"
+}
+
+test_export_branch() {
+    setup
+    do_mount
+    do_create "export_parent" "main"
+    echo "parent content" > "$TEST_MNT/parent_file.txt"
+    do_create "export_child" "export_parent"
+    echo "child content" > "$TEST_MNT/subdir/child_file.txt"
+    chmod 751 "$TEST_MNT/subdir/child_file.txt"
+    ln -s parent_file.txt "$TEST_MNT/link"
+    rm "$TEST_MNT/file2.txt"
+
+    local tarball="/tmp/branchfs_test_export_$TEST_ID.tar"
+    local out="/tmp/branchfs_test_export_$TEST_ID"
+    "$BRANCHFS" export "$TEST_MNT" --branch export_child --output "$tarball" \
+        --storage "$TEST_STORAGE" >/dev/null
+    rm -rf "$out"
+    mkdir -p "$out"
+    tar -xpf "$tarball" -C "$out"
+
+    assert_file_contains "$out/file1.txt" "base content" "Base file exported"
+    assert_file_contains "$out/subdir/nested.txt" "nested file" "Base subdirectory exported"
+    assert_file_contains "$out/parent_file.txt" "parent content" "Parent delta exported"
+    assert_file_contains "$out/subdir/child_file.txt" "child content" "Branch delta exported"
+    assert_file_not_exists "$out/file2.txt" "Deleted file left out"
+    assert "[[ \$(stat -c %a '$out/subdir/child_file.txt') == 751 ]]" "Mode kept"
+    assert "[[ \$(readlink '$out/link') == parent_file.txt ]]" "Symlink kept"
+
+    assert_branch_exists "export_child" "Branch still exists"
+    assert_file_exists "$TEST_MNT/subdir/child_file.txt" "Branch view unchanged"
+
+    do_unmount
+    rm -rf "$out" "$tarball"
+}
+
+test_import_branch() {
+    setup
+    do_mount
+
+    local src="/tmp/branchfs_test_import_src_$TEST_ID"
+    local tarball="/tmp/branchfs_test_import_$TEST_ID.tar"
+    rm -rf "$src"
+    mkdir -p "$src/subdir"
+    echo "imported content" > "$src/new_file.txt"
+    echo "imported nested" > "$src/subdir/nested.txt"
+    chmod 640 "$src/new_file.txt"
+    touch "$src/.wh.file2.txt"
+    tar -cf "$tarball" -C "$src" .
+
+    "$BRANCHFS" import "$TEST_MNT" --branch imported --input "$tarball" \
+        --storage "$TEST_STORAGE" >/dev/null
+    assert_branch_exists "imported" "Branch created"
+
+    assert_file_contains "$TEST_MNT/@imported/new_file.txt" "imported content" "New file imported"
+    assert_file_contains "$TEST_MNT/@imported/subdir/nested.txt" "imported nested" "Base file replaced"
+    assert_file_contains "$TEST_MNT/@imported/file1.txt" "base content" "Parent files still visible"
+    assert_file_not_exists "$TEST_MNT/@imported/file2.txt" "Whiteout deletes the file"
+    assert_file_not_exists "$TEST_MNT/@imported/.wh.file2.txt" "Whiteout not unpacked"
+    assert "[[ \$(stat -c %a '$TEST_MNT/@imported/new_file.txt') == 640 ]]" "Mode kept"
+    assert_file_exists "$TEST_MNT/file2.txt" "Main untouched"
+
+    # An entry escaping the branch fails the import and leaves no branch
+    python3 - "$tarball" <<'PY'
+import io, sys, tarfile
+with tarfile.open(sys.argv[1], "w") as tar:
+    data = b"escaped"
+    info = tarfile.TarInfo("../escaped.txt")
+    info.size = len(data)
+    tar.addfile(info, io.BytesIO(data))
+PY
+    if "$BRANCHFS" import "$TEST_MNT" --branch escaping --input "$tarball" \
+        --storage "$TEST_STORAGE" >/dev/null 2>&1; then
+        assert "false" "Escaping entry fails the import"
+    else
+        assert "true" "Escaping entry fails the import"
+    fi
+    assert_branch_not_exists "escaping" "Failed import removes the branch"
+
+    do_unmount
+    rm -rf "$src" "$tarball"
+}
+
+# Run tests
+run_test "Backup Restore Roundtrip" test_backup_restore_roundtrip
+run_test "Backup Restore Symlinks" test_backup_restore_symlinks
+run_test "Restore Rejects Existing Branch" test_restore_rejects_existing_branch
+run_test "Export Branch" test_export_branch
+run_test "Import Branch" test_import_branch
+
+print_summary
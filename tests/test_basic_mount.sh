@@ -53,9 +53,267 @@ test_daemon_auto_start_stop() {
     assert "[[ ! -S '$TEST_STORAGE/daemon.sock' ]]" "Daemon socket removed after unmount"
 }
 
+test_remount_after_crash() {
+    setup
+    do_mount
+    do_create "crash_parent" "main"
+    do_create "crash_branch" "crash_parent"
+    echo "in progress" > "$TEST_MNT/work.txt"
+
+    # Kill the daemon; its branches stay on disk
+    pkill -9 -f -- "--storage $TEST_STORAGE" || true
+    sleep 0.3
+
+    # Not captured: the daemon it starts keeps stdout open
+    "$BRANCHFS" remount "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    local output
+    output=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'branch:'*'crash_branch'* ]]" "Remount returns to the recorded branch"
+    assert_file_contains "$TEST_MNT/work.txt" "in progress" "Branch data survives the restart"
+    output=$("$BRANCHFS" children crash_parent "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "crash_branch" "Branch tree recovered"
+
+    # A clean exit discards the branches as before
+    do_unmount
+    sleep 0.5
+    do_mount
+    assert_branch_not_exists "crash_branch" "Branches dropped after a clean exit"
+    do_unmount
+}
+
+test_gc_dedup_after_crash() {
+    setup
+    do_mount
+    do_create "dedup_a" "main"
+    seq 1 20000 > "$TEST_MNT/file1.txt"
+    echo "only in a" > "$TEST_MNT/dedup_own.txt"
+    do_create "dedup_b" "main"
+    seq 1 20000 > "$TEST_MNT/file1.txt"
+
+    pkill -9 -f -- "--storage $TEST_STORAGE" || true
+    sleep 0.3
+    fusermount3 -u "$TEST_MNT" 2>/dev/null || fusermount -u "$TEST_MNT" 2>/dev/null || umount -l "$TEST_MNT"
+
+    # With no daemon running, gc shares the identical deltas; ext4 can't
+    # reflink, so they become one hard-linked file
+    local output size
+    size=$(seq 1 20000 | wc -c)
+    output=$("$BRANCHFS" gc --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'1 hard-linked'*'reclaiming $size bytes'* ]]" "Gc reports the reclaimed bytes"
+    assert_eq "$(stat -c %h "$TEST_STORAGE/branches/dedup_a/files/file1.txt")" "2" "Identical deltas linked"
+    assert_eq "$(stat -c %h "$TEST_STORAGE/branches/dedup_a/files/dedup_own.txt")" "1" "Unique delta left alone"
+
+    "$BRANCHFS" remount "$TEST_MNT" --branch dedup_a --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert_file_contains "$TEST_MNT/@dedup_b/file1.txt" "$(seq 1 20000)" "Linked delta reads as before"
+
+    # A write through one branch copies the file first
+    echo "changed in a" >> "$TEST_MNT/file1.txt"
+    assert "tail -n 1 '$TEST_MNT/file1.txt' | grep -qx 'changed in a'" "Write lands in the branch"
+    assert_file_contains "$TEST_MNT/@dedup_b/file1.txt" "$(seq 1 20000)" "Other branch unaffected by the write"
+    assert_eq "$(stat -c %h "$TEST_STORAGE/branches/dedup_b/files/file1.txt")" "1" "Link broken on write"
+
+    do_unmount
+}
+
+test_orphans_after_crash() {
+    setup
+    do_mount
+    do_create "orphan_parent" "main"
+    do_create "orphan_child" "orphan_parent"
+    echo "child work" > "$TEST_MNT/child.txt"
+
+    # Lose the parent's storage while the daemon is down
+    pkill -9 -f -- "--storage $TEST_STORAGE" || true
+    sleep 0.3
+    rm -rf "$TEST_STORAGE/branches/orphan_parent"
+
+    "$BRANCHFS" remount "$TEST_MNT" --branch main --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    local output
+    output=$("$BRANCHFS" orphans "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "$(printf 'orphan_child\torphan_parent')" "Child of the lost parent listed as orphan"
+
+    output=$("$BRANCHFS" orphans "$TEST_MNT" --repair --storage "$TEST_STORAGE")
+    assert "[[ \"\$output\" == *orphan_child*onto\ main* ]]" "Repair reports the orphan"
+    output=$("$BRANCHFS" orphans "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "" "No orphans left after repair"
+    output=$("$BRANCHFS" children main "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'orphan_child'* ]]" "Orphan re-parented onto main"
+
+    do_switch "orphan_child"
+    assert_file_contains "$TEST_MNT/child.txt" "child work" "Repaired branch keeps its delta"
+
+    do_unmount
+}
+
+test_statfs_reports_storage() {
+    setup
+    do_mount
+    local expected actual
+    expected=$(stat -f -c '%S %b %c' "$TEST_STORAGE" | awk '{print $1 * $2, $3}')
+    actual=$(stat -f -c '%S %b %c' "$TEST_MNT" | awk '{print $1 * $2, $3}')
+    assert_eq "$actual" "$expected" "Mount reports the storage filesystem's capacity"
+    assert_eq "$(stat -f -c '%S %l' "$TEST_MNT")" "512 255" "Block size and name length"
+    assert "[[ \"\$(df --output=size \"$TEST_MNT\" | tail -1 | tr -d ' ')\" != 0 ]]" "df sees a non-empty filesystem"
+    do_unmount
+}
+
+test_mount_subdir() {
+    setup
+    mkdir -p "$TEST_BASE/subdir/deeper"
+    echo "deep" > "$TEST_BASE/subdir/deeper/leaf.txt"
+
+    assert "! '$BRANCHFS' mount --base '$TEST_BASE' --storage '$TEST_STORAGE' --subdir missing '$TEST_MNT' 2>/dev/null" \
+        "Missing subdir is rejected"
+    assert "! '$BRANCHFS' mount --base '$TEST_BASE' --storage '$TEST_STORAGE' --subdir ../subdir '$TEST_MNT' 2>/dev/null" \
+        "Subdir outside the base is rejected"
+
+    do_mount --subdir subdir
+    assert_file_contains "$TEST_MNT/nested.txt" "nested file" "Subdir files at the mount root"
+    assert_file_contains "$TEST_MNT/deeper/leaf.txt" "deep" "Nested directories visible"
+    assert_file_not_exists "$TEST_MNT/file1.txt" "Siblings of the subdir hidden"
+    assert "! ls '$TEST_MNT' | grep -q file2.txt" "Siblings not listed"
+
+    do_create "subdir_branch" "main"
+    echo "branched" > "$TEST_MNT/new.txt"
+    rm "$TEST_MNT/nested.txt"
+    assert_file_contains "$TEST_MNT/@subdir_branch/new.txt" "branched" "@branch paths use the subdir"
+
+    # Paths given to CLI commands are relative to the mount root too
+    local run="--storage $TEST_STORAGE"
+    echo "truncate me" > "$TEST_MNT/trunc.txt"
+    "$BRANCHFS" truncate subdir_branch trunc.txt 4 "$TEST_MNT" $run > /dev/null
+    assert_file_contains "$TEST_MNT/trunc.txt" "trun" "Truncate resolves in the subdir"
+    "$BRANCHFS" mv subdir_branch trunc.txt moved.txt "$TEST_MNT" $run > /dev/null
+    assert_file_contains "$TEST_MNT/moved.txt" "trun" "Move resolves in the subdir"
+    assert_file_not_exists "$TEST_MNT/trunc.txt" "Moved from the subdir"
+    assert "'$BRANCHFS' hash subdir_branch deeper/leaf.txt '$TEST_MNT' $run > /dev/null" \
+        "Hash resolves in the subdir"
+    assert "'$BRANCHFS' where subdir_branch deeper/leaf.txt '$TEST_MNT' $run > /dev/null" \
+        "Where resolves in the subdir"
+    assert "'$BRANCHFS' diff-file subdir_branch new.txt '$TEST_MNT' $run > /dev/null" \
+        "Diff-file resolves in the subdir"
+    rm "$TEST_MNT/moved.txt"
+    do_commit
+    assert_file_contains "$TEST_BASE/subdir/new.txt" "branched" "Commit writes into the subdir"
+    assert_file_not_exists "$TEST_BASE/subdir/nested.txt" "Deletion committed in the subdir"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Siblings untouched"
+
+    do_unmount
+}
+
+test_check_storage() {
+    setup
+    do_mount
+    do_create "check_a" "main"
+    echo "work" > "$TEST_MNT/work.txt"
+
+    local output status=0
+    output=$("$BRANCHFS" check "$TEST_MNT" --storage "$TEST_STORAGE") || status=$?
+    assert_eq "$status" "0" "Check passes on consistent storage"
+    assert_eq "$output" "ok" "Check reports ok"
+
+    # Drift the storage behind the running daemon's back
+    mkdir -p "$TEST_STORAGE/branches/ghost/files"
+    echo "/file1.txt" >> "$TEST_STORAGE/branches/check_a/tombstones"
+    rm -rf "$TEST_STORAGE/branches/check_a/files"
+
+    status=0
+    output=$("$BRANCHFS" check "$TEST_MNT" --storage "$TEST_STORAGE") || status=$?
+    assert_eq "$status" "2" "Check exits 2 when it finds problems"
+    assert "[[ \"\$output\" == *'stray_dir'$'\\t''ghost'* ]]" "Directory without a branch reported"
+    assert "[[ \"\$output\" == *'stale_tombstone'$'\\t''check_a'$'\\t''/file1.txt'* ]]" "Tombstone missing from memory reported"
+    assert "[[ \"\$output\" == *'missing_files_dir'$'\\t''check_a'* ]]" "Missing files directory reported"
+    assert "mountpoint -q '$TEST_MNT'" "Mount still up after the check"
+
+    do_unmount
+}
+
+test_foreign_storage_refused() {
+    setup
+    local foreign="$TEST_STORAGE-foreign" out="$TEST_STORAGE-foreign.out" status=0
+    rm -rf "$foreign"
+    mkdir -p "$foreign/branches"
+    echo '{"version": 99, "branches": [' > "$foreign/state.json"
+    echo "precious" > "$foreign/branches/keep.txt"
+
+    "$BRANCHFS" mount --base "$TEST_BASE" --storage "$foreign" "$TEST_MNT" > "$out" 2>&1 || status=$?
+    assert_eq "$status" "1" "Mount on a foreign storage directory fails"
+    assert "grep -q 'not a branchfs storage' '$out'" "Error says it is not a branchfs storage"
+    assert "grep -q 'state.json' '$out'" "Error names the unrelated file"
+    assert "! mountpoint -q '$TEST_MNT'" "Nothing mounted"
+    assert_eq "$(cat "$foreign/state.json")" '{"version": 99, "branches": [' "state.json untouched"
+    assert_file_contains "$foreign/branches/keep.txt" "precious" "Existing branches directory untouched"
+
+    # A storage marked by another format version
+    rm -rf "$foreign"
+    mkdir -p "$foreign"
+    echo 99 > "$foreign/storage_format"
+    status=0
+    "$BRANCHFS" mount --base "$TEST_BASE" --storage "$foreign" "$TEST_MNT" > "$out" 2>&1 || status=$?
+    assert_eq "$status" "1" "Mount on another storage format fails"
+    assert "grep -q 'storage format mismatch' '$out'" "Error reports a format mismatch"
+    assert "grep -q 'expected version 1, found \"99\"' '$out'" "Error gives both versions"
+
+    rm -rf "$foreign" "$out"
+}
+
+test_read_only_mount() {
+    setup
+    do_mount --read-only
+    do_create "ro_branch" "main"
+
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "Reads work"
+    assert_file_contains "$TEST_MNT/@main/subdir/nested.txt" "nested file" "@branch reads work"
+    assert "! sh -c \"echo changed > '$TEST_MNT/file1.txt'\" 2>/dev/null" "Write refused"
+    assert "! touch '$TEST_MNT/new.txt' 2>/dev/null" "Create refused"
+    assert "! mkdir '$TEST_MNT/newdir' 2>/dev/null" "Mkdir refused"
+    assert "! rm '$TEST_MNT/file2.txt' 2>/dev/null" "Unlink refused"
+    assert "! mv '$TEST_MNT/file1.txt' '$TEST_MNT/moved.txt' 2>/dev/null" "Rename refused"
+    assert "! chmod 600 '$TEST_MNT/file1.txt' 2>/dev/null" "Setattr refused"
+    assert "! sh -c \"echo commit > '$TEST_MNT/.branchfs_ctl'\" 2>/dev/null" "Commit through ctl refused"
+    assert "! sh -c \"echo abort > '$TEST_MNT/@ro_branch/.branchfs_ctl'\" 2>/dev/null" "Branch ctl refused"
+    assert "[[ \"\$(sh -c \"echo changed > '$TEST_MNT/file1.txt'\" 2>&1)\" == *'Read-only file system'* ]]" \
+        "Refusals report EROFS"
+
+    # Requests naming the mount are refused by the daemon too
+    local out status=0
+    out=$("$BRANCHFS" truncate ro_branch file1.txt 0 "$TEST_MNT" --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Truncate request refused"
+    assert "[[ \"$out\" == *'Read-only file system'* ]]" "Request refusal reports EROFS"
+    assert "! '$BRANCHFS' mv ro_branch file1.txt moved.txt '$TEST_MNT' --storage '$TEST_STORAGE' 2>/dev/null" \
+        "Move request refused"
+    assert "! '$BRANCHFS' commit '$TEST_MNT' --path file1.txt --storage '$TEST_STORAGE' 2>/dev/null" \
+        "Commit of selected paths refused"
+    assert "! '$BRANCHFS' delete ro_branch '$TEST_MNT' --storage '$TEST_STORAGE' 2>/dev/null" \
+        "Delete request refused"
+    assert "! '$BRANCHFS' purge '$TEST_MNT' --yes --storage '$TEST_STORAGE' 2>/dev/null" "Purge request refused"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base unchanged"
+
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "File unchanged"
+    assert_file_exists "$TEST_MNT/file2.txt" "File not deleted"
+    assert "[[ ! -e '$TEST_STORAGE/branches/ro_branch/files/new.txt' ]]" "Nothing written to the delta"
+
+    assert "echo -n 'switch:main' > '$TEST_MNT/.branchfs_ctl'" "Switch still works"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "Reads work after switch"
+    assert_branch_exists "ro_branch" "Branch left alone"
+
+    do_unmount
+}
+
 # Run tests
 run_test "Mount and Unmount" test_mount_unmount
 run_test "Mount Creates Directories" test_mount_creates_directories
 run_test "Daemon Auto Start/Stop" test_daemon_auto_start_stop
+run_test "Remount After Crash" test_remount_after_crash
+run_test "Orphans After Crash" test_orphans_after_crash
+run_test "Statfs Reports Storage" test_statfs_reports_storage
+run_test "Mount Subdir" test_mount_subdir
+run_test "Check Storage" test_check_storage
+run_test "Foreign Storage Refused" test_foreign_storage_refused
+run_test "Read-Only Mount" test_read_only_mount
+run_test "Gc Dedup After Crash" test_gc_dedup_after_crash
 
 print_summary
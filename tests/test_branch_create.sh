@@ -81,10 +81,270 @@ test_create_sibling_branches() {
     do_unmount
 }
 
+test_child_branches() {
+    setup
+    do_mount
+
+    do_create "kid_b" "main"
+    do_create "kid_a" "main"
+    do_create "grandkid" "kid_a"
+
+    local output
+    output=$("$BRANCHFS" children main "$TEST_MNT" --storage "$TEST_STORAGE" | tr '\n' ' ')
+    assert_eq "$output" "kid_a kid_b " "Direct children of main, sorted"
+
+    output=$("$BRANCHFS" children main "$TEST_MNT" --recursive --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'grandkid'*'kid_a'* ]]" "Recursive listing includes grandchild with its parent"
+
+    output=$("$BRANCHFS" children grandkid "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "" "Leaf branch has no children"
+
+    assert "! '$BRANCHFS' children missing '$TEST_MNT' --storage '$TEST_STORAGE' 2>/dev/null" "Unknown branch fails"
+
+    do_unmount
+}
+
+test_list_sort() {
+    setup
+    do_mount
+
+    # Creation times have one-second resolution
+    do_create "zeta" "main"
+    echo "z" > "$TEST_MNT/@zeta/z.txt"
+    sleep 1
+    do_create "alpha" "main"
+    head -c 10000 /dev/zero > "$TEST_MNT/@alpha/big.bin"
+    sleep 1
+    do_create "mid" "main"
+    head -c 100 /dev/zero > "$TEST_MNT/@mid/small.bin"
+
+    local order
+    order=$("$BRANCHFS" list --sort name --storage "$TEST_STORAGE" | tail -n +3 | awk '{print $1}' | tr '\n' ' ')
+    assert_eq "$order" "alpha main mid zeta " "Sort by name"
+
+    # main may share zeta's creation second, so leave it out
+    order=$("$BRANCHFS" list --sort age --storage "$TEST_STORAGE" | tail -n +3 | awk '$1 != "main" {print $1}' | tr '\n' ' ')
+    assert_eq "$order" "zeta alpha mid " "Sort by age puts oldest first"
+
+    order=$("$BRANCHFS" list --sort size --storage "$TEST_STORAGE" | tail -n +3 | awk '{print $1}' | head -2 | tr '\n' ' ')
+    assert_eq "$order" "alpha mid " "Sort by size puts largest first"
+
+    do_unmount
+}
+
+test_rename_branch() {
+    setup
+    do_mount
+
+    do_create "old_name" "main"
+    echo "renamed content" > "$TEST_MNT/renamed.txt"
+    do_create "kid" "old_name"
+    "$BRANCHFS" switch old_name "$TEST_MNT" --storage "$TEST_STORAGE" >/dev/null
+
+    "$BRANCHFS" rename old_name new_name --storage "$TEST_STORAGE" >/dev/null
+    assert "[[ \$? -eq 0 ]]" "Rename succeeds"
+
+    local list
+    list=$("$BRANCHFS" list --storage "$TEST_STORAGE")
+    assert "! echo '$list' | grep -q old_name" "Old name is gone from list"
+    assert "echo '$list' | grep -q '^kid *new_name'" "Child is re-parented"
+    assert_file_exists "$TEST_MNT/@new_name/renamed.txt" "File visible under new name"
+    assert_eq "$(cat "$TEST_MNT/renamed.txt")" "renamed content" "Mount still serves the branch"
+
+    assert "! '$BRANCHFS' rename main other --storage '$TEST_STORAGE' 2>/dev/null" "Cannot rename main"
+    assert "! '$BRANCHFS' rename new_name kid --storage '$TEST_STORAGE' 2>/dev/null" "Cannot rename onto an existing branch"
+
+    # Committing the child lands in the renamed parent, then that commits to base
+    "$BRANCHFS" switch kid "$TEST_MNT" --storage "$TEST_STORAGE" >/dev/null
+    echo "from kid" > "$TEST_MNT/kid.txt"
+    do_commit >/dev/null
+    assert_file_exists "$TEST_MNT/@new_name/kid.txt" "Child commits into renamed parent"
+    do_commit >/dev/null
+    assert_file_exists "$TEST_BASE/renamed.txt" "Renamed branch commits to base"
+
+    do_unmount
+}
+
+test_switch_back() {
+    setup
+    do_mount
+
+    do_create "back_a" "main"
+    echo "a" > "$TEST_MNT/back.txt"
+    do_create "back_b" "main"
+    echo "b" > "$TEST_MNT/back.txt"
+
+    local output
+    output=$("$BRANCHFS" back "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "Switched to branch 'back_a'" "Back returns to the previous branch"
+    assert_eq "$(cat "$TEST_MNT/back.txt")" "a" "Mount serves the previous branch"
+
+    "$BRANCHFS" back "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert_file_not_exists "$TEST_MNT/back.txt" "Second back reaches main"
+
+    output=$("$BRANCHFS" back "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "Switched to branch 'main'" "Empty history falls back to main"
+
+    # Branches removed since the switch are skipped
+    "$BRANCHFS" switch back_b "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    "$BRANCHFS" switch back_a "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    echo "abort" > "$TEST_MNT/@back_b/.branchfs_ctl"
+    output=$("$BRANCHFS" back "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "Switched to branch 'main'" "Back skips a removed branch"
+
+    do_unmount
+}
+
+test_create_missing_parent() {
+    setup
+    do_mount
+    "$BRANCHFS" create feature "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+
+    local output status=0
+    output=$("$BRANCHFS" create child "$TEST_MNT" --parent nosuch --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Create with a missing parent fails"
+    assert_eq "$(echo "$output" | head -1)" "Error: parent branch 'nosuch' does not exist" "Error names the missing parent"
+    assert_eq "$(echo "$output" | sed -n 2p)" "Available branches: feature, main" "Error lists the available branches"
+    assert_branch_not_exists "child" "No branch created"
+
+    # A parent that only exists in another storage's daemon
+    local other="${TEST_STORAGE}_other"
+    mkdir -p "$other"
+    "$BRANCHFS" mount --base "$TEST_BASE" --storage "$other" "$TEST_MNT2" > /dev/null
+    sleep 0.5
+    status=0
+    output=$("$BRANCHFS" create child "$TEST_MNT" --parent feature --storage "$other" 2>&1) || status=$?
+    assert_eq "$status" "1" "Create against the wrong storage fails"
+    assert_eq "$(echo "$output" | grep -c 'is not served by the daemon for')" "1" "Error points at the storage mismatch"
+    "$BRANCHFS" unmount "$TEST_MNT2" --storage "$other" > /dev/null
+    sleep 0.3
+    rm -rf "$other"
+
+    do_unmount
+}
+
+test_create_returns_info() {
+    setup
+    do_mount
+    do_create "info_parent" "main"
+
+    local created info
+    created=$("$BRANCHFS" create info_child "$TEST_MNT" --parent info_parent --json --storage "$TEST_STORAGE")
+    info=$("$BRANCHFS" info info_child --storage "$TEST_STORAGE")
+    assert_eq "$created" "$info" "Create returns the same info as a later Info query"
+    assert "[[ '$created' == *'\"parent\": \"info_parent\"'* ]]" "Info names the parent"
+    assert "[[ '$created' == *'\"storage\": \"$TEST_STORAGE/branches/info_child\"'* ]]" "Info gives the storage directory"
+    assert "[[ -d '$TEST_STORAGE/branches/info_child/files' ]]" "Storage directory holds the branch's files"
+
+    local status=0
+    "$BRANCHFS" info nosuch --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Info on an unknown branch fails"
+
+    do_unmount
+}
+
+test_switch_across_mounts() {
+    setup
+    do_mount
+    do_mount2
+
+    # A branch created through one mount can be switched to on another
+    do_create "shared_branch" "main"
+    echo "shared" > "$TEST_MNT/shared.txt"
+    "$BRANCHFS" switch shared_branch "$TEST_MNT2" --storage "$TEST_STORAGE" >/dev/null
+    assert_file_contains "$TEST_MNT2/shared.txt" "shared" "Second mount sees the branch"
+
+    local err
+    err=$("$BRANCHFS" switch no_such_branch "$TEST_MNT2" --storage "$TEST_STORAGE" 2>&1 || true)
+    assert "[[ \"$err\" == *\"branch 'no_such_branch' does not exist\"* ]]" "Missing branch is named"
+
+    do_unmount2
+    do_unmount
+}
+
+test_info_resolution_order() {
+    setup
+    do_mount
+    do_create "level1" "main"
+    do_create "level2" "level1"
+    do_create "level3" "level2"
+
+    local order expected
+    order=$("$BRANCHFS" info level3 --storage "$TEST_STORAGE" |
+        sed -n '/"resolution_order"/,/]/p' | grep '^ *"/' | tr -d ' ",')
+    expected=$(printf '%s\n' \
+        "$TEST_STORAGE/branches/level3/files" \
+        "$TEST_STORAGE/branches/level2/files" \
+        "$TEST_STORAGE/branches/level1/files" \
+        "$TEST_STORAGE/branches/main/files" \
+        "$TEST_BASE")
+    assert_eq "$order" "$expected" "Layers listed from the leaf to the base"
+
+    do_unmount
+}
+
+test_created_by() {
+    setup
+    do_mount
+
+    "$BRANCHFS" create by_flag "$TEST_MNT" --created-by alice --storage "$TEST_STORAGE" > /dev/null
+    BRANCHFS_CREATED_BY=bob "$BRANCHFS" create by_env "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    env -u BRANCHFS_CREATED_BY "$BRANCHFS" create by_nobody "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+
+    local info listing
+    info=$("$BRANCHFS" info by_flag --storage "$TEST_STORAGE")
+    assert "[[ \"\$info\" == *'\"created_by\": \"alice\"'* ]]" "Info reports the --created-by name"
+    info=$("$BRANCHFS" info by_nobody --storage "$TEST_STORAGE")
+    assert "[[ \"\$info\" == *'\"created_by\": null'* ]]" "Creator is null when not given"
+
+    listing=$("$BRANCHFS" list --storage "$TEST_STORAGE")
+    assert_eq "$(echo "$listing" | awk '$1 == "by_flag" {print $NF}')" "alice" "List shows the --created-by name"
+    assert_eq "$(echo "$listing" | awk '$1 == "by_env" {print $NF}')" "bob" "List shows the name from BRANCHFS_CREATED_BY"
+    assert_eq "$(echo "$listing" | awk '$1 == "by_nobody" {print $NF}')" "-" "List shows - for an unknown creator"
+
+    do_unmount
+}
+
+test_list_filters() {
+    setup
+    do_mount
+    do_create "flt_a" "main"
+    do_create "flt_a1" "flt_a"
+    do_create "flt_a1x" "flt_a1"
+    do_create "flt_a2" "flt_a"
+    do_create "flt_b" "main"
+
+    list_names() {
+        "$BRANCHFS" list --sort name "$@" --storage "$TEST_STORAGE" | tail -n +3 | awk '{print $1}' | tr '\n' ' '
+    }
+    assert_eq "$(list_names --parent flt_a)" "flt_a1 flt_a2 " "--parent lists direct children only"
+    assert_eq "$(list_names --parent main | grep -o 'flt_[a-z0-9]*' | tr '\n' ' ')" "flt_a flt_b " "--parent main lists top-level branches"
+    assert_eq "$(list_names --under flt_a)" "flt_a1 flt_a1x flt_a2 " "--under lists the whole subtree"
+    assert_eq "$(list_names --under flt_a1)" "flt_a1x " "--under a nested branch"
+    assert_eq "$(list_names --under flt_b)" "" "--under a leaf lists nothing"
+    assert_eq "$(list_names --parent flt_a1 --under flt_a)" "flt_a1x " "Filters combine"
+
+    local status=0
+    "$BRANCHFS" list --under nosuch --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Filtering on an unknown branch fails"
+
+    do_unmount
+}
+
 # Run tests
 run_test "Create Branch Without Mount" test_create_branch_without_mount
 run_test "Create Branch and Auto-Switch" test_create_branch_with_switch
 run_test "Create Nested Branches" test_create_nested_branches
 run_test "Create Sibling Branches" test_create_sibling_branches
+run_test "Child Branches" test_child_branches
+run_test "List Sort" test_list_sort
+run_test "Rename Branch" test_rename_branch
+run_test "Switch Back" test_switch_back
+run_test "Create Missing Parent" test_create_missing_parent
+run_test "Create Returns Info" test_create_returns_info
+run_test "Switch Across Mounts" test_switch_across_mounts
+run_test "Info Resolution Order" test_info_resolution_order
+run_test "Created By" test_created_by
+run_test "List Filters" test_list_filters
 
 print_summary
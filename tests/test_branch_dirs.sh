@@ -243,6 +243,104 @@ test_branch_dir_mkdir() {
     do_unmount
 }
 
+test_hide_branches_and_ctl() {
+    setup
+    do_mount --hide-branches --hide-ctl
+    do_create "hidden_branch" "main"
+
+    local listing
+    listing=$(ls -A "$TEST_MNT")
+    assert "[[ '$listing' != *'@'* ]]" "Root listing has no @branch entries"
+    assert "[[ '$listing' != *'.branchfs_ctl'* ]]" "Root listing has no ctl file"
+    assert "[[ '$listing' == *'file1.txt'* ]]" "Root listing still has real files"
+    assert "[[ ! -d '$TEST_MNT/@hidden_branch' ]]" "@branch lookup fails when hidden"
+
+    # The ctl file is unlisted but still usable by name
+    echo "hidden change" > "$TEST_MNT/hidden.txt"
+    do_commit
+    assert_file_contains "$TEST_BASE/hidden.txt" "hidden change" "Commit still works with hidden ctl"
+
+    do_unmount
+}
+
+test_inode_ranges_under_churn() {
+    setup
+    do_mount
+
+    # Each switch clears the inode table; each new branch takes a ctl inode
+    local i regular ctl
+    local ctl_inos=""
+    for i in $(seq 1 40); do
+        do_create "churn$i" "main"
+        "$BRANCHFS" switch main "$TEST_MNT" --storage "$TEST_STORAGE" >/dev/null
+        ls "$TEST_MNT" > /dev/null
+        regular=$(stat -c %i "$TEST_MNT/file1.txt")
+        ctl=$(stat -c %i "$TEST_MNT/@churn$i/.branchfs_ctl")
+        ctl_inos="$ctl_inos $ctl"
+        # Regular inodes stay small; ctl inodes sit just below 2^64
+        [[ ${#regular} -lt 19 && ${#ctl} -eq 20 && "$ctl" == 1844674407370* ]] || break
+    done
+    assert_eq "$i" "40" "Regular and ctl inodes stay in their ranges"
+
+    local unique
+    unique=$(echo $ctl_inos | tr ' ' '\n' | sort -u | wc -l)
+    assert_eq "$unique" "40" "Every branch gets its own ctl inode"
+    assert "[[ '$(stat -c %i "$TEST_MNT/.branchfs_ctl")' == '18446744073709551614' ]]" "Root ctl inode unchanged"
+
+    do_unmount
+}
+
+test_root_listing_matches_lookup() {
+    setup
+    # A real entry named @... is reachable, so it is listed like any other
+    mkdir "$TEST_BASE/@not_a_branch"
+    do_mount
+    do_create "listed_branch" "main"
+
+    local listing
+    listing=$(ls -a "$TEST_MNT")
+    assert "[[ '$listing' == *'@listed_branch'* ]]" "Branch listed at root"
+    assert "[[ '$listing' == *'@not_a_branch'* ]]" "Real @ entry listed"
+    assert "[[ -d '$TEST_MNT/@not_a_branch' ]]" "Real @ entry resolves"
+    assert "[[ -d '$TEST_MNT/@listed_branch' ]]" "Listed branch resolves"
+
+    echo "abort" > "$TEST_MNT/.branchfs_ctl"
+    listing=$(ls -a "$TEST_MNT")
+    assert "[[ '$listing' != *'@listed_branch'* ]]" "Aborted branch no longer listed"
+    assert "! stat '$TEST_MNT/@listed_branch' 2>/dev/null" "Aborted branch no longer resolves"
+
+    do_unmount
+}
+
+test_real_at_entry_beside_branch() {
+    setup
+    echo "real at file" > "$TEST_BASE/@x"
+    do_mount
+    do_create "x" "main"
+    do_create "other" "main"
+
+    assert_file_contains "$TEST_MNT/@x" "real at file" "Base file @x readable while branch x exists"
+    assert "[[ -f '$TEST_MNT/@x' ]]" "@x is the real file, not the branch directory"
+    assert_eq "$(ls -a "$TEST_MNT" | grep -c '^@x$')" "1" "@x listed once"
+    assert_file_contains "$TEST_MNT/@other/@x" "real at file" "@x readable through another branch's directory"
+
+    echo "edited" > "$TEST_MNT/@x"
+    assert_file_contains "$TEST_MNT/@x" "edited" "@x writable"
+    assert_file_contains "$TEST_BASE/@x" "real at file" "Write stays in the branch"
+
+    # Branch x itself is still reachable by switching to it
+    "$BRANCHFS" switch x "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    echo "on x" > "$TEST_MNT/x_file.txt"
+    assert_file_contains "$TEST_MNT/@x" "real at file" "Branch x sees the base @x"
+    assert "[[ -f '$TEST_STORAGE/branches/x/files/x_file.txt' ]]" "Writes land in branch x"
+
+    rm "$TEST_MNT/@x"
+    assert_file_not_exists "$TEST_MNT/@x" "Real @x can be deleted"
+    assert "[[ -f '$TEST_MNT/@x/x_file.txt' ]]" "Once it is gone, @x is branch x's directory again"
+
+    do_unmount
+}
+
 # Run tests
 run_test "@branch Dir Appears After Create" test_branch_dir_appears_after_create
 run_test "@branch Dir Shows Base Files" test_branch_dir_shows_base_files
@@ -256,5 +354,9 @@ run_test "@branch Dir Nested Child" test_branch_dir_nested_child
 run_test "@branch Dir Bogus ENOENT" test_branch_dir_bogus_enoent
 run_test "@branch Dir Delete File" test_branch_dir_delete_file
 run_test "@branch Dir Mkdir" test_branch_dir_mkdir
+run_test "Hide Branches And Ctl" test_hide_branches_and_ctl
+run_test "Inode Ranges Under Churn" test_inode_ranges_under_churn
+run_test "Root Listing Matches Lookup" test_root_listing_matches_lookup
+run_test "Real @ Entry Beside Branch" test_real_at_entry_beside_branch
 
 print_summary
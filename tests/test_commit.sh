@@ -175,6 +175,620 @@ test_commit_non_leaf_fails() {
     do_unmount
 }
 
+test_commit_during_switch() {
+    setup
+    do_mount
+
+    for i in $(seq 1 5); do
+        "$BRANCHFS" create "side_$i" "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+        echo "side $i" > "$TEST_MNT/@side_$i/side.txt"
+    done
+
+    # Commit the side branches through @branch ctl files while the mount
+    # keeps switching (create sends NotifySwitch to the daemon)
+    (
+        for i in $(seq 1 5); do
+            echo "commit" > "$TEST_MNT/@side_$i/.branchfs_ctl"
+        done
+    ) &
+    local committer=$!
+    for i in $(seq 1 5); do
+        "$BRANCHFS" create "switch_$i" "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    done
+    wait $committer
+
+    local output
+    output=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'switch_5'* ]]" "Daemon tracks the last switch"
+    assert "[[ '$output' == *'registered'* ]]" "Notifier still registered after interleaving"
+    assert_file_exists "$TEST_BASE/side.txt" "Side branch commits reached base"
+
+    do_unmount
+}
+
+test_commit_force_when_mounted() {
+    setup
+    do_mount
+    do_mount2
+    do_create "shared" "main"
+    "$BRANCHFS" switch shared "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    echo "shared work" > "$TEST_MNT/shared.txt"
+
+    # Default policy: the commit goes ahead and the other view goes stale
+    do_commit > /dev/null
+    assert_file_exists "$TEST_BASE/shared.txt" "Commit reached base"
+    assert "! cat '$TEST_MNT2/file1.txt' > /dev/null 2>&1" "Other mount on the branch went stale"
+
+    do_unmount2
+    do_unmount
+}
+
+test_commit_fail_if_mounted() {
+    setup
+    do_mount
+    do_mount2
+    do_create "guarded" "main"
+    "$BRANCHFS" switch guarded "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    echo "guarded work" > "$TEST_MNT/guarded.txt"
+
+    local output status=0
+    output=$("$BRANCHFS" commit "$TEST_MNT" --fail-if-mounted --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Commit refused while another mount is on the branch"
+    assert "[[ '$output' == *'$TEST_MNT2'* ]]" "Error lists the conflicting mount"
+    assert_file_not_exists "$TEST_BASE/guarded.txt" "Nothing reached base"
+    assert_eq "$(cat "$TEST_MNT2/guarded.txt")" "guarded work" "Other mount still sees the branch"
+
+    status=0
+    output=$("$BRANCHFS" commit "$TEST_MNT" --path guarded.txt --fail-if-mounted --storage "$TEST_STORAGE" 2>&1) ||
+        status=$?
+    assert_eq "$status" "1" "Commit of selected paths refused too"
+    assert "[[ '$output' == *'$TEST_MNT2'* ]]" "Path commit error lists the conflicting mount"
+    assert_file_not_exists "$TEST_BASE/guarded.txt" "Nothing reached base through a path commit"
+
+    # Once the other mount moves away the commit goes through
+    "$BRANCHFS" switch main "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    "$BRANCHFS" commit "$TEST_MNT" --fail-if-mounted --storage "$TEST_STORAGE" > /dev/null
+    assert_file_exists "$TEST_BASE/guarded.txt" "Commit succeeds after the other mount switched"
+    assert_file_contains "$TEST_MNT/guarded.txt" "guarded work" "Committing mount moves to the parent"
+    assert "'$BRANCHFS' inspect '$TEST_MNT' --storage '$TEST_STORAGE' | grep -q '^branch: *main'" \
+        "Daemon tracks the committing mount on the parent"
+    assert_branch_not_exists "guarded" "Committed branch gone"
+
+    do_unmount2
+    do_unmount
+}
+
+test_epoch_file() {
+    setup
+    do_mount --epoch-file
+
+    assert "ls -A '$TEST_MNT' | grep -qx '.branchfs_epoch'" "Epoch file is listed at the root"
+    assert_eq "$(cat "$TEST_MNT/.branchfs_epoch")" "0" "Epoch starts at zero"
+    assert "! echo 5 > '$TEST_MNT/.branchfs_epoch' 2>/dev/null" "Epoch file is read-only"
+
+    do_create "epoch_branch" "main"
+    echo "epoch" > "$TEST_MNT/epoch.txt"
+    do_commit > /dev/null
+    assert_eq "$(cat "$TEST_MNT/.branchfs_epoch")" "1" "Commit advances the epoch file"
+    assert_eq "$(stat -c %s "$TEST_MNT/.branchfs_epoch")" "2" "Size matches the content"
+
+    do_unmount
+}
+
+test_epoch_file_off_by_default() {
+    setup
+    do_mount
+    assert_file_not_exists "$TEST_MNT/.branchfs_epoch" "No epoch file without --epoch-file"
+    do_unmount
+}
+
+test_commit_dry_run() {
+    setup
+    do_mount
+    do_create "preview" "main"
+
+    echo "previewed change" > "$TEST_MNT/file1.txt"
+    echo "brand new" > "$TEST_MNT/new.txt"
+    rm -r "$TEST_MNT/subdir"
+
+    local preview patch after_mod after_new
+    preview=$("$BRANCHFS" commit "$TEST_MNT" --dry-run --storage "$TEST_STORAGE")
+    patch=$("$BRANCHFS" commit "$TEST_MNT" --dry-run --format patch --storage "$TEST_STORAGE")
+    after_mod=$("$BRANCHFS" hash preview /file1.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')
+    after_new=$("$BRANCHFS" hash preview /new.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')
+
+    assert "echo '$preview' | grep -A3 '\"overwrite\"' | grep -q '/file1.txt'" "Modified file listed as overwrite"
+    assert "echo '$preview' | grep -A3 '\"create\"' | grep -q '/new.txt'" "New file listed as create"
+    assert "echo '$preview' | grep -q '$after_mod'" "Overwrite carries the new content hash"
+    assert "echo '$preview' | grep -A3 '\"delete\"' | grep -q '\"/subdir/nested.txt\"'" "Files under a deleted directory are listed"
+    assert "echo '$patch' | grep -q '^+previewed change'" "Patch shows the added line"
+    assert "echo '$patch' | grep -q '^-nested file'" "Patch shows the deleted file's content"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Dry run leaves base untouched"
+    assert_branch_exists "preview" "Dry run keeps the branch"
+
+    do_commit > /dev/null
+    assert_eq "$("$BRANCHFS" hash main /file1.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')" \
+        "$after_mod" "Committed file matches the previewed hash"
+    assert_eq "$("$BRANCHFS" hash main /new.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')" \
+        "$after_new" "Created file matches the previewed hash"
+    assert_file_not_exists "$TEST_BASE/subdir" "Previewed deletion happened"
+
+    local status=0
+    "$BRANCHFS" commit "$TEST_MNT" --dry-run --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Nothing to preview on main"
+
+    do_unmount
+}
+
+test_commit_lock() {
+    setup
+    do_mount
+
+    local output status=0
+    "$BRANCHFS" lock --holder agent1 --storage "$TEST_STORAGE" > /dev/null
+    output=$("$BRANCHFS" status --storage "$TEST_STORAGE")
+    assert_eq "$(echo "$output" | grep -c "commit lock: held by 'agent1'")" "1" "Status reports the lock holder"
+
+    output=$("$BRANCHFS" lock --holder agent2 --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Second holder cannot take the lock"
+    assert_eq "$output" "Error: commit lock is held by 'agent1'" "Error names the current holder"
+
+    status=0
+    "$BRANCHFS" unlock --holder agent2 --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Only the holder can release the lock"
+    "$BRANCHFS" unlock --holder agent1 --storage "$TEST_STORAGE" > /dev/null
+    output=$("$BRANCHFS" status --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'commit lock: free'* ]]" "Lock free after release"
+
+    # A lock with a timeout lapses on its own
+    "$BRANCHFS" lock --holder agent1 --timeout 1 --storage "$TEST_STORAGE" > /dev/null
+    sleep 2
+    assert "'$BRANCHFS' lock --holder agent2 --storage '$TEST_STORAGE' > /dev/null" "Lapsed lock can be taken over"
+    "$BRANCHFS" unlock --holder agent2 --storage "$TEST_STORAGE" > /dev/null
+
+    do_unmount
+}
+
+test_concurrent_commits_serialize() {
+    setup
+    do_mount
+    do_mount2
+    do_create "left" "main"
+    "$BRANCHFS" create "right" "$TEST_MNT2" --parent main --storage "$TEST_STORAGE" > /dev/null
+
+    # Large files so the two commits' copies would overlap if not serialized
+    head -c 33554432 /dev/urandom > "$TEST_MNT/left.bin"
+    head -c 33554432 /dev/urandom > "$TEST_MNT2/right.bin"
+    yes left | head -n 200000 > "$TEST_MNT/shared.txt"
+    yes right | head -n 200000 > "$TEST_MNT2/shared.txt"
+    local left_sum right_sum
+    left_sum=$(md5sum < "$TEST_MNT/left.bin")
+    right_sum=$(md5sum < "$TEST_MNT2/right.bin")
+
+    local left_status=0 right_status=0
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null &
+    local left_pid=$!
+    "$BRANCHFS" commit "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null &
+    local right_pid=$!
+    wait $left_pid || left_status=$?
+    wait $right_pid || right_status=$?
+
+    assert_eq "$left_status" "0" "First commit succeeded"
+    assert_eq "$right_status" "0" "Second commit succeeded"
+    assert_eq "$(md5sum < "$TEST_BASE/left.bin")" "$left_sum" "First branch's file intact in base"
+    assert_eq "$(md5sum < "$TEST_BASE/right.bin")" "$right_sum" "Second branch's file intact in base"
+    assert_eq "$(sort -u "$TEST_BASE/shared.txt" | wc -l)" "1" "Shared file comes wholly from one commit"
+
+    do_unmount2
+    do_unmount
+}
+
+test_commit_hardlinked_base() {
+    setup
+    echo "shared v1" > "$TEST_BASE/linked_a.txt"
+    ln "$TEST_BASE/linked_a.txt" "$TEST_BASE/linked_b.txt"
+
+    # Default: the shared file is rewritten, so the other link changes too
+    do_mount
+    do_create "links_preserve" "main"
+    echo "shared v2" > "$TEST_MNT/linked_a.txt"
+    local output
+    output=$("$BRANCHFS" commit "$TEST_MNT" --dry-run --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'\"hardlinked\": ['* ]]" "Dry run flags the hard-linked target"
+    do_commit > /dev/null
+    assert_file_contains "$TEST_BASE/linked_b.txt" "shared v2" "Other link sees the committed content"
+    assert_eq "$(stat -c %h "$TEST_BASE/linked_a.txt")" "2" "Link kept"
+    do_unmount
+
+    # --hardlinks break: only the committed path changes
+    do_mount --hardlinks break
+    do_create "links_break" "main"
+    echo "shared v3" > "$TEST_MNT/linked_a.txt"
+    do_commit > /dev/null
+    assert_file_contains "$TEST_BASE/linked_a.txt" "shared v3" "Committed path has the new content"
+    assert_file_contains "$TEST_BASE/linked_b.txt" "shared v2" "Other link keeps the old content"
+    assert_eq "$(stat -c %h "$TEST_BASE/linked_b.txt")" "1" "Link broken"
+    assert_eq "$(ls -A "$TEST_BASE" | grep -c branchfs-commit)" "0" "No temporary file left behind"
+    do_unmount
+}
+
+test_commit_with_open_writer() {
+    setup
+    do_mount
+    do_create "writer_branch" "main"
+
+    exec 3>> "$TEST_MNT/file1.txt"
+    echo "half written" >&3
+
+    local output status=0
+    output=$("$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Commit refused while a file is open for writing"
+    assert "[[ '$output' == *'open for writing: /file1.txt'* ]]" "Error lists the open path"
+    assert "! grep -q 'half written' '$TEST_BASE/file1.txt'" "Base untouched"
+    assert_branch_exists "writer_branch" "Branch kept"
+    assert "! echo commit > '$TEST_MNT/.branchfs_ctl' 2>/dev/null" "Ctl commit refused too"
+
+    # Closing the writer lets the commit through
+    exec 3>&-
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert "grep -q 'half written' '$TEST_BASE/file1.txt'" "Commit applied after close"
+
+    # --force commits with the writer still open
+    do_create "forced_branch" "main"
+    exec 3>> "$TEST_MNT/file2.txt"
+    echo "forced" >&3
+    "$BRANCHFS" commit "$TEST_MNT" --force --storage "$TEST_STORAGE" > /dev/null
+    exec 3>&-
+    assert "grep -q 'forced' '$TEST_BASE/file2.txt'" "Forced commit applied"
+    assert_branch_not_exists "forced_branch" "Forced commit removed the branch"
+
+    # Read-only handles do not block
+    do_create "reader_branch" "main"
+    echo "read only" > "$TEST_MNT/file3.txt"
+    exec 3< "$TEST_MNT/file3.txt"
+    status=0
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    exec 3<&-
+    assert_eq "$status" "0" "Open reader does not block the commit"
+
+    do_unmount
+}
+
+test_commit_mtime() {
+    setup
+    touch -m -d "2003-01-01 00:00:00 UTC" "$TEST_BASE/file1.txt" "$TEST_BASE/file2.txt"
+    do_mount
+    do_mount2 --commit-mtime preserve
+
+    # now (default): committed files get the time of the commit
+    local start
+    start=$(date +%s)
+    do_create "mtime_now" "main"
+    echo "now" > "$TEST_MNT/file1.txt"
+    do_commit
+    assert "[[ $(stat -c '%Y' "$TEST_BASE/file1.txt") -ge $start ]]" "now: mtime of the commit"
+
+    # preserve: the replaced file keeps its mtime
+    "$BRANCHFS" create mtime_preserve "$TEST_MNT2" --storage "$TEST_STORAGE" >/dev/null
+    echo "preserved" > "$TEST_MNT2/file2.txt"
+    "$BRANCHFS" commit "$TEST_MNT2" --storage "$TEST_STORAGE" >/dev/null
+    assert_file_contains "$TEST_BASE/file2.txt" "preserved" "preserve: content committed"
+    assert_eq "$(stat -c '%Y' "$TEST_BASE/file2.txt")" "1041379200" "preserve: base mtime kept"
+
+    # branch: the branch's copy's mtime
+    do_unmount2
+    do_mount2 --commit-mtime branch
+    "$BRANCHFS" create mtime_branch "$TEST_MNT2" --storage "$TEST_STORAGE" >/dev/null
+    echo "branch" > "$TEST_MNT2/new.txt"
+    touch -m -d "2007-01-01 00:00:00 UTC" "$TEST_MNT2/new.txt"
+    "$BRANCHFS" commit "$TEST_MNT2" --storage "$TEST_STORAGE" >/dev/null
+    assert_eq "$(stat -c '%Y' "$TEST_BASE/new.txt")" "1167609600" "branch: mtime of the branch's copy"
+
+    do_unmount2
+    do_unmount
+}
+
+test_merge_siblings() {
+    setup
+    do_mount
+    do_create "merge_a" "main"
+    echo "from a" > "$TEST_MNT/file1.txt"
+    echo "a only" > "$TEST_MNT/a_only.txt"
+
+    do_create "merge_b" "main"
+    echo "from b" > "$TEST_MNT/file1.txt"
+    echo "b only" > "$TEST_MNT/b_only.txt"
+    echo "b nested" > "$TEST_MNT/subdir/nested.txt"
+    rm "$TEST_MNT/file2.txt"
+
+    local output expected rc=0
+    output=$("$BRANCHFS" merge merge_b "$TEST_MNT" --into merge_a --storage "$TEST_STORAGE") || rc=$?
+    assert_eq "$rc" "2" "Conflicts give exit status 2"
+    expected=$(printf 'merged\t/b_only.txt\nmerged\t/file2.txt\nmerged\t/subdir/nested.txt\nconflict\t/file1.txt')
+    assert_eq "$output" "$expected" "Merged paths and conflicts reported"
+
+    "$BRANCHFS" switch merge_a "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert_file_contains "$TEST_MNT/file1.txt" "from a" "Conflict keeps the target's version"
+    assert_file_contains "$TEST_MNT/a_only.txt" "a only" "Target's own change kept"
+    assert_file_contains "$TEST_MNT/b_only.txt" "b only" "New file merged"
+    assert_file_contains "$TEST_MNT/subdir/nested.txt" "b nested" "Modified file merged"
+    assert_file_not_exists "$TEST_MNT/file2.txt" "Deletion merged"
+
+    # The source branch and the base are untouched
+    assert_file_contains "$TEST_MNT/@merge_b/file1.txt" "from b" "Source keeps its version"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base untouched"
+    assert_file_exists "$TEST_BASE/file2.txt" "Base file not deleted"
+
+    # Merging again into the mount's branch: everything is now changed on
+    # both sides, so nothing is overwritten
+    output=$("$BRANCHFS" merge merge_b "$TEST_MNT" --storage "$TEST_STORAGE") || true
+    expected=$(printf 'conflict\t/b_only.txt\nconflict\t/file1.txt\nconflict\t/file2.txt\nconflict\t/subdir/nested.txt')
+    assert_eq "$output" "$expected" "Repeated merge only reports conflicts"
+
+    rc=0
+    "$BRANCHFS" merge merge_a "$TEST_MNT" --storage "$TEST_STORAGE" 2> /dev/null || rc=$?
+    assert_eq "$rc" "1" "Merging a branch into itself fails"
+
+    do_unmount
+}
+
+test_rename_with_open_writer() {
+    setup
+    do_mount
+    do_create "busy_branch" "main"
+
+    exec 3>> "$TEST_MNT/file1.txt"
+    echo "half written" >&3
+    "$BRANCHFS" rename busy_branch renamed_busy --storage "$TEST_STORAGE" > /dev/null
+
+    local output status=0
+    output=$("$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Writer follows the rename"
+    assert "[[ '$output' == *'open for writing: /file1.txt'* ]]" "Error lists the open path"
+
+    # Closing releases it under the new name
+    exec 3>&-
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert "grep -q 'half written' '$TEST_BASE/file1.txt'" "Commit applied after close"
+
+    do_unmount
+}
+
+test_commit_log() {
+    setup
+    do_mount
+
+    # The storage, and with it the log, is shared by every test here
+    rm -f "$TEST_STORAGE/commits.log"
+    local output
+    output=$("$BRANCHFS" log --storage "$TEST_STORAGE")
+    assert_eq "$output" "" "Log is empty before any commit"
+
+    do_create "log_parent" "main"
+    do_create "log_child" "log_parent"
+    echo "child" > "$TEST_MNT/child.txt"
+    rm "$TEST_MNT/file2.txt"
+    "$BRANCHFS" commit "$TEST_MNT" -m "Child work" --storage "$TEST_STORAGE" > /dev/null
+    echo "parent" > "$TEST_MNT/parent.txt"
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+
+    assert_file_contains "$TEST_BASE/child.txt" "child" "Commit still merges files"
+    assert_file_not_exists "$TEST_BASE/file2.txt" "Commit still applies deletions"
+
+    output=$("$BRANCHFS" log --storage "$TEST_STORAGE")
+    local headers paths
+    headers=$(echo "$output" | grep -v '^ ' | cut -f2-)
+    paths=$(echo "$output" | grep '^ ' | tr -d ' ' | tr '\n' ' ')
+    assert_eq "$headers" "$(printf 'log_parent\tmain\t-\nlog_child\tlog_parent\tChild work')" "Log lists commits newest first with their messages"
+    assert_eq "$paths" "/child.txt /file2.txt /parent.txt /child.txt /file2.txt " "Log lists each commit's paths, deletions included"
+
+    do_unmount
+}
+
+test_commit_selected_paths() {
+    setup
+    do_mount --epoch-file
+    do_create "partial" "main"
+
+    echo "keep" > "$TEST_MNT/keep.txt"
+    echo "skip" > "$TEST_MNT/skip.txt"
+    mkdir "$TEST_MNT/pick"
+    echo "picked" > "$TEST_MNT/pick/a.txt"
+    rm "$TEST_MNT/file2.txt"
+    local epoch
+    epoch=$(cat "$TEST_MNT/.branchfs_epoch")
+
+    local output status=0
+    output=$("$BRANCHFS" commit "$TEST_MNT" --path nothing_here --storage "$TEST_STORAGE") || status=$?
+    assert_eq "$status" "2" "Nothing under the paths exits 2"
+    assert_eq "$(cat "$TEST_MNT/.branchfs_epoch")" "$epoch" "Epoch unchanged when nothing merged"
+
+    output=$("$BRANCHFS" commit "$TEST_MNT" --path keep.txt --path "$TEST_MNT/pick" --path /file2.txt \
+        --storage "$TEST_STORAGE")
+    assert_eq "$output" "$(printf '/file2.txt\n/keep.txt\n/pick/a.txt')" "Committed paths listed"
+    assert_file_contains "$TEST_BASE/keep.txt" "keep" "Selected file in base"
+    assert_file_contains "$TEST_BASE/pick/a.txt" "picked" "Selected directory in base"
+    assert_file_not_exists "$TEST_BASE/file2.txt" "Selected deletion applied to base"
+    assert_file_not_exists "$TEST_BASE/skip.txt" "Unselected file not in base"
+    assert_eq "$(cat "$TEST_MNT/.branchfs_epoch")" "$((epoch + 1))" "Epoch advanced once"
+
+    # The mount stays on the branch, which keeps only the rest
+    assert_file_contains "$TEST_MNT/skip.txt" "skip" "Unselected file still in the branch"
+    assert_file_contains "$TEST_MNT/keep.txt" "keep" "Committed file still visible"
+    assert "[[ ! -e '$TEST_STORAGE/branches/partial/files/keep.txt' ]]" "Committed file left the branch delta"
+    assert "[[ ! -e '$TEST_STORAGE/branches/partial/files/pick' ]]" "Emptied directory left the branch delta"
+    assert "[[ -f '$TEST_STORAGE/branches/partial/files/skip.txt' ]]" "Unselected delta intact"
+
+    do_commit
+    assert_file_contains "$TEST_BASE/skip.txt" "skip" "Rest committed later"
+
+    do_unmount
+}
+
+test_commit_failure_leaves_base() {
+    if [[ $(id -u) -ne 0 ]]; then
+        echo -e "  ${YELLOW}skipped: mounting a small tmpfs needs root${NC}"
+        return
+    fi
+    # A base too small to take the branch's new file
+    mkdir -p "$TEST_BASE"
+    mount -t tmpfs -o size=256k tmpfs "$TEST_BASE"
+    setup
+    do_mount
+    do_create "staged_commit" "main"
+
+    echo "modified" > "$TEST_MNT/file1.txt"
+    rm "$TEST_MNT/file2.txt"
+    head -c 400000 /dev/zero > "$TEST_MNT/big.bin"
+
+    assert "! '$BRANCHFS' commit '$TEST_MNT' --storage '$TEST_STORAGE' 2>/dev/null" "Commit fails when the base fills up"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base file not overwritten"
+    assert_file_exists "$TEST_BASE/file2.txt" "Deletion not applied"
+    assert_file_not_exists "$TEST_BASE/big.bin" "No partial file in base"
+    assert_eq "$(ls -A "$TEST_BASE" | grep -c '^\.branchfs-commit-')" "0" "Staging directory removed"
+    assert_file_contains "$TEST_MNT/file1.txt" "modified" "Branch keeps its changes"
+
+    do_unmount
+    umount "$TEST_BASE"
+}
+
+test_commit_crash_recovery() {
+    setup
+    # The daemon dies once every file is staged, before anything moves
+    BRANCHFS_CRASH_COMMIT=staged do_mount
+    do_create "crash_commit" "main"
+    echo "new one" > "$TEST_MNT/file1.txt"
+    echo "new two" > "$TEST_MNT/file2.txt"
+    echo "created" > "$TEST_MNT/created.txt"
+    rm "$TEST_MNT/subdir/nested.txt"
+
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || true
+    sleep 0.3
+    assert_eq "$(ls -A "$TEST_BASE" | grep -c '^\.branchfs-commit-')" "1" "Staged copies left by the dead daemon"
+
+    # Restarted with the crash moved to after the first rename
+    BRANCHFS_CRASH_COMMIT=applying "$BRANCHFS" remount "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert_eq "$(ls -A "$TEST_BASE" | grep -c '^\.branchfs-commit-')" "0" "Staging discarded on restart"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base fully old after a crash while staging"
+    assert_file_exists "$TEST_BASE/subdir/nested.txt" "Deletion not applied"
+    assert_file_not_exists "$TEST_BASE/created.txt" "New file not applied"
+    assert_file_contains "$TEST_MNT/file1.txt" "new one" "Branch kept for another commit"
+
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || true
+    sleep 0.3
+    local moved=0
+    [ "$(cat "$TEST_BASE/file1.txt")" = "new one" ] && moved=$((moved + 1))
+    [ "$(cat "$TEST_BASE/file2.txt")" = "new two" ] && moved=$((moved + 1))
+    [ -e "$TEST_BASE/created.txt" ] && moved=$((moved + 1))
+    assert_eq "$moved" "1" "Daemon died with one file moved in"
+    assert "[ -e '$TEST_STORAGE/commit-journal.json' ]" "Journal left by the dead daemon"
+
+    "$BRANCHFS" remount "$TEST_MNT" --branch main --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert_file_contains "$TEST_BASE/file1.txt" "new one" "Commit rolled forward on restart"
+    assert_file_contains "$TEST_BASE/file2.txt" "new two" "Every staged file moved in"
+    assert_file_contains "$TEST_BASE/created.txt" "created" "New file committed"
+    assert_file_not_exists "$TEST_BASE/subdir/nested.txt" "Deletion applied"
+    assert "[ ! -e '$TEST_STORAGE/commit-journal.json' ]" "Journal removed"
+    assert_eq "$(ls -A "$TEST_BASE" | grep -c '^\.branchfs-commit-')" "0" "Staging removed"
+    assert_branch_not_exists "crash_commit" "Committed branch gone"
+
+    do_unmount
+}
+
+test_commit_keeps_user_staging_name() {
+    setup
+    mkdir -p "$TEST_BASE/.branchfs-commit" "$TEST_BASE/.branchfs-commit-1-abc"
+    echo "mine" > "$TEST_BASE/.branchfs-commit/keep.txt"
+    do_mount
+    assert_eq "$(ls -A "$TEST_MNT" | grep -c '^\.branchfs-commit-')" "0" "Staging directory hidden from listings"
+    assert "[ ! -e '$TEST_MNT/.branchfs-commit-1-abc' ]" "Staging directory hidden from lookup"
+    do_create "user_staging" "main"
+    echo "committed" > "$TEST_MNT/user_staging.txt"
+
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.3
+    assert_file_contains "$TEST_BASE/user_staging.txt" "committed" "Commit applied"
+    assert_file_contains "$TEST_BASE/.branchfs-commit/keep.txt" "mine" "Directory of the same name kept"
+    assert_file_contains "$TEST_MNT/.branchfs-commit/keep.txt" "mine" "Directory of the same name still listed"
+
+    # A restart with no commit to recover leaves the base alone
+    pkill -9 -f -- "--storage $TEST_STORAGE"
+    sleep 0.3
+    "$BRANCHFS" remount "$TEST_MNT" --branch main --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert_file_contains "$TEST_BASE/.branchfs-commit/keep.txt" "mine" "Directory of the same name kept on restart"
+    assert "[ -d '$TEST_BASE/.branchfs-commit-1-abc' ]" "Staging-like directory nothing named kept on restart"
+
+    do_unmount
+}
+
+test_txn_publishes_atomically() {
+    setup
+    do_mount
+    do_create "txn_branch" "main"
+    echo "old" > "$TEST_MNT/keep.txt"
+    local files="$TEST_STORAGE/branches/txn_branch/files"
+
+    echo "txn:begin" > "$TEST_MNT/.branchfs_ctl"
+    echo "staged" > "$TEST_MNT/txn_new.txt"
+    echo "new" > "$TEST_MNT/keep.txt"
+    rm "$TEST_MNT/file1.txt"
+    mkdir "$TEST_MNT/newdir"
+    echo "deep" > "$TEST_MNT/newdir/inner.txt"
+
+    assert_file_contains "$TEST_MNT/txn_new.txt" "staged" "Staged file readable in the branch"
+    assert_file_contains "$TEST_MNT/keep.txt" "new" "Staged edit readable in the branch"
+    assert_file_not_exists "$TEST_MNT/file1.txt" "Staged deletion applies in the branch"
+    assert_file_contains "$TEST_MNT/newdir/inner.txt" "deep" "Staged directory readable"
+    assert_eq "$(ls "$TEST_MNT" | grep -c '^keep.txt$')" "1" "Listing shows a staged file once"
+    assert "ls '$TEST_MNT' | grep -q '^txn_new.txt$'" "Listing shows staged files"
+    assert_file_not_exists "$files/txn_new.txt" "Branch delta untouched while open"
+    assert_file_contains "$files/keep.txt" "old" "Branch delta keeps the old content"
+
+    assert "! do_commit >/dev/null 2>&1" "Commit refused while a transaction is open"
+    assert_file_not_exists "$TEST_BASE/txn_new.txt" "Base untouched by the refused commit"
+
+    # Nested: only the outermost commit publishes
+    echo "txn:begin" > "$TEST_MNT/.branchfs_ctl"
+    echo "txn:commit" > "$TEST_MNT/.branchfs_ctl"
+    assert_file_not_exists "$files/txn_new.txt" "Inner commit publishes nothing"
+    echo "txn:commit" > "$TEST_MNT/.branchfs_ctl"
+    assert_file_contains "$files/txn_new.txt" "staged" "Outer commit publishes new files"
+    assert_file_contains "$files/keep.txt" "new" "Outer commit publishes edits"
+    assert_file_not_exists "$TEST_MNT/file1.txt" "Deletion kept after publishing"
+    assert_file_contains "$TEST_MNT/newdir/inner.txt" "deep" "Directory kept after publishing"
+
+    do_commit >/dev/null
+    assert_file_contains "$TEST_BASE/txn_new.txt" "staged" "Published file committed"
+    assert_file_contains "$TEST_BASE/keep.txt" "new" "Published edit committed"
+    assert_file_not_exists "$TEST_BASE/file1.txt" "Published deletion committed"
+
+    do_unmount
+}
+
+test_txn_abort() {
+    setup
+    do_mount
+    do_create "txn_abort_branch" "main"
+
+    assert "! echo txn:commit > '$TEST_MNT/.branchfs_ctl' 2>/dev/null" \
+        "Commit without a transaction fails"
+
+    echo "txn:begin" > "$TEST_MNT/@txn_abort_branch/.branchfs_ctl"
+    echo "discarded" > "$TEST_MNT/scratch.txt"
+    echo "changed" > "$TEST_MNT/file1.txt"
+    rm "$TEST_MNT/file2.txt"
+    echo "txn:abort" > "$TEST_MNT/@txn_abort_branch/.branchfs_ctl"
+
+    assert_file_not_exists "$TEST_MNT/scratch.txt" "Aborted file gone"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "Aborted edit gone"
+    assert_file_exists "$TEST_MNT/file2.txt" "Aborted deletion undone"
+
+    do_unmount
+}
+
 # Run tests
 run_test "Commit New File" test_commit_new_file
 run_test "Commit Modified File" test_commit_modified_file
@@ -183,5 +797,25 @@ run_test "Commit Switches to Main" test_commit_switches_to_main
 run_test "Commit Nested Branches" test_commit_nested_branches
 run_test "Commit Preserves Siblings" test_commit_preserves_siblings
 run_test "Commit Non-Leaf Fails" test_commit_non_leaf_fails
+run_test "Commit During Switch" test_commit_during_switch
+run_test "Commit Force When Mounted" test_commit_force_when_mounted
+run_test "Commit Fail If Mounted" test_commit_fail_if_mounted
+run_test "Commit Dry Run" test_commit_dry_run
+run_test "Commit Lock" test_commit_lock
+run_test "Concurrent Commits Serialize" test_concurrent_commits_serialize
+run_test "Commit Hardlinked Base" test_commit_hardlinked_base
+run_test "Epoch File" test_epoch_file
+run_test "Epoch File Off By Default" test_epoch_file_off_by_default
+run_test "Commit With Open Writer" test_commit_with_open_writer
+run_test "Commit Mtime" test_commit_mtime
+run_test "Merge Siblings" test_merge_siblings
+run_test "Rename With Open Writer" test_rename_with_open_writer
+run_test "Commit Log" test_commit_log
+run_test "Commit Selected Paths" test_commit_selected_paths
+run_test "Failed Commit Leaves Base" test_commit_failure_leaves_base
+run_test "Commit Crash Recovery" test_commit_crash_recovery
+run_test "Commit Keeps User Staging Name" test_commit_keeps_user_staging_name
+run_test "Transaction Publishes Atomically" test_txn_publishes_atomically
+run_test "Transaction Abort" test_txn_abort
 
 print_summary
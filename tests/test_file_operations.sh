@@ -233,6 +233,945 @@ test_synthetic_entry_ownership() {
     do_unmount
 }
 
+test_open_create_preserves_content() {
+    setup
+    do_mount
+    do_create "create_test" "main"
+
+    # `<>` opens with O_RDWR|O_CREAT but without O_TRUNC
+    : 1<> "$TEST_MNT/file1.txt"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "open(O_CREAT) keeps base content"
+
+    # `>` opens with O_TRUNC
+    : > "$TEST_MNT/file2.txt"
+    assert_eq "$(stat -c '%s' "$TEST_MNT/file2.txt")" "0" "open(O_CREAT|O_TRUNC) truncates"
+    assert_file_contains "$TEST_BASE/file2.txt" "another file" "Base file unchanged after truncate"
+
+    do_unmount
+}
+
+test_recreate_deleted_file() {
+    setup
+    do_mount
+    do_create "recreate_test" "main"
+
+    rm "$TEST_MNT/file1.txt"
+    assert_file_not_exists "$TEST_MNT/file1.txt" "File deleted"
+
+    echo "recreated" > "$TEST_MNT/file1.txt"
+    assert_file_contains "$TEST_MNT/file1.txt" "recreated" "Deleted file can be recreated"
+    assert "! grep -qx /file1.txt '$TEST_STORAGE/branches/recreate_test/tombstones'" \
+        "Tombstone removed on disk"
+    assert_eq "$("$BRANCHFS" check "$TEST_MNT" --storage "$TEST_STORAGE")" "ok" "Check finds no stale tombstone"
+
+    # Still there for a daemon that loads the tombstones again
+    pkill -9 -f -- "--storage $TEST_STORAGE"
+    sleep 0.3
+    "$BRANCHFS" remount "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert_file_contains "$TEST_MNT/file1.txt" "recreated" "Recreated file survives a restart"
+
+    do_unmount
+}
+
+test_dotdot_inode() {
+    setup
+    mkdir -p "$TEST_BASE/subdir/deeper"
+    do_mount
+    do_create "dotdot_test" "main"
+
+    local dotdot parent
+    dotdot=$(ls -ai "$TEST_MNT/subdir/deeper" | awk '$2 == ".." {print $1}')
+    parent=$(stat -c '%i' "$TEST_MNT/subdir")
+    assert_eq "$dotdot" "$parent" ".. of a subdir has the parent's inode"
+
+    dotdot=$(ls -ai "$TEST_MNT/@dotdot_test/subdir" | awk '$2 == ".." {print $1}')
+    parent=$(stat -c '%i' "$TEST_MNT/@dotdot_test")
+    assert_eq "$dotdot" "$parent" ".. inside @branch has the parent's inode"
+
+    do_unmount
+}
+
+test_readahead_sees_writes() {
+    setup
+    head -c 4194304 /dev/urandom > "$TEST_BASE/stream.bin"
+    do_mount
+    do_create "readahead_test" "main"
+
+    assert "cmp -s '$TEST_MNT/stream.bin' '$TEST_BASE/stream.bin'" "Sequential read returns base content"
+
+    # Patch the middle of the file through the @branch path, which shares
+    # its backing delta with the root path
+    local expected="$TEST_STORAGE/expected.bin"
+    cp "$TEST_BASE/stream.bin" "$expected"
+    printf 'PATCHED' | dd of="$expected" bs=1 seek=1048576 conv=notrunc 2>/dev/null
+    printf 'PATCHED' | dd of="$TEST_MNT/@readahead_test/stream.bin" bs=1 seek=1048576 conv=notrunc 2>/dev/null
+
+    assert "cmp -s '$TEST_MNT/stream.bin' '$expected'" "Re-read after write sees the new bytes"
+
+    do_unmount
+}
+
+test_durable_delete_survives_crash() {
+    setup
+    local mode file
+    # The restarted daemon recovers main, so each mode deletes its own file
+    for mode in sync deferred; do
+        file=$([[ "$mode" == "sync" ]] && echo file1.txt || echo file2.txt)
+        do_mount --durable "$mode"
+        rm "$TEST_MNT/$file"
+        [[ "$mode" == "deferred" ]] && sleep 0.5
+
+        # Simulate a crash: kill the daemon without letting it clean up
+        pkill -9 -f -- "--storage $TEST_STORAGE" || true
+        sleep 0.3
+        fusermount3 -u "$TEST_MNT" 2>/dev/null || fusermount -u "$TEST_MNT" 2>/dev/null || umount -l "$TEST_MNT"
+
+        assert "grep -qx '/$file' '$TEST_STORAGE/branches/main/tombstones'" "Tombstone on disk after crash ($mode)"
+        rm -f "$TEST_STORAGE/daemon.sock"
+    done
+
+    do_mount
+    assert "'$BRANCHFS' list --storage '$TEST_STORAGE' > /dev/null" "Daemon restarts after crash"
+    do_unmount
+}
+
+test_sync_writes_survive_crash() {
+    setup
+    local mode file
+    # --sync-writes flushes every write; without it only O_DSYNC handles are
+    for mode in option dsync; do
+        file="synced_$mode.txt"
+        if [[ "$mode" == "option" ]]; then
+            do_mount --sync-writes
+            printf 'synced %s' "$mode" | dd of="$TEST_MNT/$file" status=none
+        else
+            do_mount
+            printf 'synced %s' "$mode" | dd of="$TEST_MNT/$file" oflag=dsync status=none
+        fi
+
+        # Crash straight after the write returned
+        pkill -9 -f -- "--storage $TEST_STORAGE" || true
+        sleep 0.3
+        fusermount3 -u "$TEST_MNT" 2>/dev/null || fusermount -u "$TEST_MNT" 2>/dev/null || umount -l "$TEST_MNT"
+
+        assert_file_contains "$TEST_STORAGE/branches/main/files/$file" "synced $mode" "Write on disk after crash ($mode)"
+        rm -f "$TEST_STORAGE/daemon.sock"
+    done
+
+    do_mount
+    assert_file_contains "$TEST_MNT/synced_option.txt" "synced option" "Synced write visible after restart"
+    do_unmount
+}
+
+test_atime_relatime() {
+    setup
+    do_mount --atime relatime
+    do_create "atime_branch"
+    echo "atime" > "$TEST_MNT/atime.txt"
+    local delta="$TEST_STORAGE/branches/atime_branch/files/atime.txt"
+
+    # atime older than mtime/ctime: a read bumps it
+    touch -a -d @978307200 "$delta"
+    cat "$TEST_MNT/atime.txt" > /dev/null
+    assert "[[ $(stat -c %X "$delta") -gt 978307200 ]]" "Read updates a stale atime"
+
+    # atime newer than mtime/ctime: a read leaves it alone
+    touch -a -d @4070908800 "$delta"
+    local future
+    future=$(stat -c %X "$delta")
+    cat "$TEST_MNT/atime.txt" > /dev/null
+    assert_eq "$(stat -c %X "$delta")" "$future" "Read keeps a fresh atime"
+
+    # Files not copied up are never touched
+    cat "$TEST_MNT/file1.txt" > /dev/null
+    assert "[[ ! -e '$TEST_STORAGE/branches/atime_branch/files/file1.txt' ]]" "Base file is not copied up"
+
+    do_unmount
+}
+
+test_atime_none() {
+    setup
+    do_mount
+    do_create "atime_branch"
+    echo "atime" > "$TEST_MNT/atime.txt"
+    local delta="$TEST_STORAGE/branches/atime_branch/files/atime.txt"
+
+    touch -a -d @978307200 "$delta"
+    cat "$TEST_MNT/atime.txt" > /dev/null
+    assert_eq "$(stat -c %X "$delta")" "978307200" "Default mode leaves atime alone"
+
+    do_unmount
+}
+
+test_open_flag_mismatch() {
+    setup
+    do_mount --epoch-file
+    do_create "flags_branch"
+
+    local output
+    output=$(cat "$TEST_MNT/file1.txt/" 2>&1)
+    assert "[[ '$output' == *'Not a directory'* ]]" "Directory open of a file fails with ENOTDIR"
+
+    output=$( (echo "x" > "$TEST_MNT/subdir") 2>&1)
+    assert "[[ '$output' == *'Is a directory'* ]]" "Opening a directory for writing fails"
+
+    output=$( (echo 5 > "$TEST_MNT/.branchfs_epoch") 2>&1)
+    assert "[[ '$output' == *'Read-only file system'* ]]" "Writing the epoch file fails with EROFS"
+
+    # Handles opened read-only cannot be written through
+    assert "! (exec 3< '$TEST_MNT/.branchfs_ctl'; echo commit >&3) 2>/dev/null" "Read-only ctl handle rejects writes"
+    assert_branch_exists "flags_branch" "Branch not committed through a read-only handle"
+    assert "! (exec 3< '$TEST_MNT/file1.txt'; echo changed >&3) 2>/dev/null" "Read-only file handle rejects writes"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "File unchanged"
+
+    do_unmount
+}
+
+test_large_directory_listing() {
+    setup
+    mkdir -p "$TEST_BASE/big"
+    (cd "$TEST_BASE/big" && seq -f "base_%g" 1 5000 | xargs touch)
+    do_mount
+    do_create "big_branch"
+
+    # Shadow a base entry and add new ones in the delta
+    echo "changed" > "$TEST_MNT/big/base_17"
+    (cd "$TEST_MNT/big" && seq -f "delta_%g" 1 300 | xargs touch)
+
+    local listing
+    listing=$(ls -A "$TEST_MNT/big")
+    assert_eq "$(echo "$listing" | wc -l)" "5300" "Every entry listed across readdir pages"
+    assert_eq "$(echo "$listing" | sort | uniq -d | wc -l)" "0" "No entry listed twice"
+    assert "echo '$listing' | grep -qx 'delta_300'" "Delta-only entries listed"
+    assert_eq "$(find "$TEST_MNT/big" -mindepth 1 | wc -l)" "5300" "A second traversal agrees"
+
+    do_unmount
+}
+
+# Overwrite 2 bytes at offset 3 without truncating
+partial_write() {
+    printf 'XY' | dd of="$1" bs=1 seek=3 conv=notrunc status=none
+}
+
+test_write_mode_copyup() {
+    setup
+    printf 'abcdefghij' > "$TEST_BASE/partial.txt"
+    do_mount --write-mode copyup
+    do_create "copyup_branch"
+
+    partial_write "$TEST_MNT/partial.txt"
+    assert_file_contains "$TEST_MNT/partial.txt" "abcXYfghij" "Partial overwrite keeps the rest"
+    assert_file_contains "$TEST_STORAGE/branches/copyup_branch/files/partial.txt" "abcXYfghij" "Delta holds the whole file"
+    assert_file_contains "$TEST_BASE/partial.txt" "abcdefghij" "Base unchanged"
+
+    do_unmount
+}
+
+test_write_mode_redirect() {
+    setup
+    # 4 MiB of non-zero data, so unfilled holes would show up as zeros
+    yes 0123456789 | head -c 4194304 > "$TEST_BASE/big.log"
+    printf 'abcdefghij' > "$TEST_BASE/partial.txt"
+    do_mount --write-mode redirect
+    do_create "redirect_branch"
+    local delta="$TEST_STORAGE/branches/redirect_branch/files"
+
+    partial_write "$TEST_MNT/partial.txt"
+    assert_file_contains "$TEST_MNT/partial.txt" "abcXYfghij" "Partial overwrite keeps the rest"
+    assert_file_contains "$TEST_MNT/@redirect_branch/partial.txt" "abcXYfghij" "@branch path sees the same content"
+
+    # Appending to a large file does not copy it
+    echo "appended" >> "$TEST_MNT/big.log"
+    partial_write "$TEST_MNT/big.log"
+    local expected="/tmp/branchfs_test_expected_$TEST_ID"
+    cp "$TEST_BASE/big.log" "$expected"
+    echo "appended" >> "$expected"
+    partial_write "$expected"
+    assert "cmp -s '$expected' '$TEST_MNT/big.log'" "Large file reads back base data around the writes"
+    assert "[[ $(du -k "$delta/big.log" | cut -f1) -lt 1024 ]]" "Delta stays sparse"
+    assert_eq "$(stat -c %s "$TEST_MNT/big.log")" "$(stat -c %s "$expected")" "Size includes the appended data"
+
+    # Truncating drops the tail for good, even when the file grows again
+    truncate -s 5 "$TEST_MNT/partial.txt"
+    truncate -s 8 "$TEST_MNT/partial.txt"
+    assert_eq "$(od -An -c "$TEST_MNT/partial.txt" | tr -d ' ')" 'abcXY\0\0\0' "Regrown file is zero-filled past the truncation"
+
+    # Commit materializes the inherited ranges
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert "cmp -s '$expected' '$TEST_BASE/big.log'" "Commit writes the merged file to base"
+    rm -f "$expected"
+
+    do_unmount
+}
+
+test_write_mode_redirect_restart() {
+    setup
+    printf 'AAAAAAAAAABBBBBBBBBB' > "$TEST_BASE/log.txt"
+    printf 'abcdefghij' > "$TEST_BASE/partial.txt"
+    do_mount --write-mode redirect
+    do_create "rb"
+
+    printf 'xx' | dd of="$TEST_MNT/log.txt" bs=1 conv=notrunc status=none
+    assert_file_contains "$TEST_MNT/log.txt" "xxAAAAAAAABBBBBBBBBB" "Redirected write reads through"
+    partial_write "$TEST_MNT/partial.txt"
+    truncate -s 5 "$TEST_MNT/partial.txt"
+    truncate -s 8 "$TEST_MNT/partial.txt"
+    assert "[[ -s '$TEST_STORAGE/branches/rb/redirects' ]]" "Redirects logged beside the branch's files"
+
+    # A daemon that died leaves the redirects for the next one
+    pkill -9 -f -- "--storage $TEST_STORAGE"
+    sleep 0.3
+    "$BRANCHFS" remount "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert_eq "$(cat "$TEST_MNT/@rb/log.txt")" "xxAAAAAAAABBBBBBBBBB" "Unwritten ranges still inherited after a restart"
+    assert_eq "$(od -An -c "$TEST_MNT/partial.txt" | tr -d ' ')" 'abcXY\0\0\0' "Truncation kept after a restart"
+
+    # And the recovered redirect is written to and materialized as before
+    printf 'yy' | dd of="$TEST_MNT/log.txt" bs=1 seek=18 conv=notrunc status=none
+    "$BRANCHFS" commit "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert_file_contains "$TEST_BASE/log.txt" "xxAAAAAAAABBBBBBBByy" "Commit writes the merged file to base"
+    assert_file_not_exists "$TEST_STORAGE/branches/rb/redirects" "Log gone with the branch"
+
+    do_unmount
+}
+
+test_write_mode_overwrite() {
+    setup
+    printf 'abcdefghij' > "$TEST_BASE/partial.txt"
+    printf 'abcdefghij' > "$TEST_BASE/whole.txt"
+    do_mount --write-mode overwrite
+    do_create "overwrite_branch"
+
+    # The first write discards the inherited content
+    printf 'XY' | dd of="$TEST_MNT/whole.txt" bs=1 conv=notrunc status=none
+    assert_file_contains "$TEST_MNT/whole.txt" "XY" "Write from offset 0 replaces the file"
+    partial_write "$TEST_MNT/partial.txt"
+    assert_eq "$(od -An -c "$TEST_MNT/partial.txt" | tr -d ' ')" '\0\0\0XY' "Partial overwrite leaves a hole before the data"
+
+    # Later writes go to the branch's own file
+    partial_write "$TEST_MNT/whole.txt"
+    assert_eq "$(od -An -c "$TEST_MNT/whole.txt" | tr -d ' ')" 'XY\0XY' "Second write lands in the new file"
+    assert_file_contains "$TEST_BASE/partial.txt" "abcdefghij" "Base unchanged"
+
+    do_unmount
+}
+
+test_strict_errno_permission_denied() {
+    setup
+    do_mount
+    do_create "eacces_branch"
+
+    if [[ $(id -u) -eq 0 ]]; then
+        echo -e "  ${YELLOW}skipped: root bypasses the delta directory's permissions${NC}"
+        do_unmount
+        return
+    fi
+
+    # The mount still shows a writable directory; the backing create fails
+    chmod 555 "$TEST_STORAGE/branches/eacces_branch/files"
+    local err
+    err=$(touch "$TEST_MNT/denied.txt" 2>&1 || true)
+    chmod 755 "$TEST_STORAGE/branches/eacces_branch/files"
+    assert "[[ '$err' == *'Permission denied'* ]]" "Create reports EACCES"
+
+    do_unmount
+}
+
+test_strict_errno_no_space() {
+    setup
+    do_mount
+    do_create "enospc_branch"
+
+    if [[ $(id -u) -ne 0 ]]; then
+        echo -e "  ${YELLOW}skipped: mounting a small tmpfs needs root${NC}"
+        do_unmount
+        return
+    fi
+
+    # Back the branch's deltas with a filesystem that fills up quickly
+    local delta="$TEST_STORAGE/branches/enospc_branch/files"
+    mount -t tmpfs -o size=64k tmpfs "$delta"
+
+    local err
+    err=$(head -c 262144 /dev/zero 2>/dev/null | dd of="$TEST_MNT/full.bin" bs=4k 2>&1 || true)
+    assert "[[ '$err' == *'No space left on device'* ]]" "Write reports ENOSPC"
+
+    do_mount2 --strict-errno=false
+    err=$(head -c 262144 /dev/zero 2>/dev/null | dd of="$TEST_MNT2/@enospc_branch/more.bin" bs=4k 2>&1 || true)
+    assert "[[ '$err' == *'Input/output error'* ]]" "--strict-errno=false flattens it to EIO"
+
+    # The daemon holds files on the tmpfs open until it exits
+    do_unmount2
+    do_unmount
+    umount "$delta"
+}
+
+test_handle_reaper() {
+    setup
+    mkdir -p "$TEST_BASE/many"
+    local i
+    for i in $(seq 1 30); do echo "$i" > "$TEST_BASE/many/f$i"; done
+    do_mount --max-handles 30 --handle-idle-timeout 2
+
+    handles() {
+        "$BRANCHFS" status --storage "$TEST_STORAGE" | grep '^handles:'
+    }
+
+    # Hold every file open without using the handles again
+    local fds=() fd
+    for i in $(seq 1 30); do
+        exec {fd}<"$TEST_MNT/many/f$i"
+        fds+=("$fd")
+    done
+    assert "[[ '$(handles)' == *'30 open'* ]]" "Status counts open handles"
+    assert "! cat '$TEST_MNT/file1.txt' 2>/dev/null" "Open fails while the table is full"
+    assert "[[ '$(handles)' == *'refused=1'* ]]" "Refused open is counted"
+
+    sleep 3
+    assert "[[ '$(handles)' == *'0 open'*'reaped=30'* ]]" "Reaper reclaims idle handles"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "Opens succeed again"
+
+    for fd in "${fds[@]}"; do
+        exec {fd}<&-
+    done
+    do_unmount
+}
+
+test_rename_in_branch() {
+    setup
+    do_mount
+    do_create "rename_test" "main"
+
+    # A file created in the branch
+    echo "fresh" > "$TEST_MNT/fresh.txt"
+    mv "$TEST_MNT/fresh.txt" "$TEST_MNT/moved.txt"
+    assert_file_not_exists "$TEST_MNT/fresh.txt" "Renamed file gone from old name"
+    assert_file_contains "$TEST_MNT/moved.txt" "fresh" "Renamed file has its content"
+
+    # A base file with no delta yet, over an existing file
+    mv "$TEST_MNT/file1.txt" "$TEST_MNT/file2.txt"
+    assert_file_not_exists "$TEST_MNT/file1.txt" "Moved base file hidden at old name"
+    assert_file_contains "$TEST_MNT/file2.txt" "base content" "Target replaced by moved file"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base untouched before commit"
+
+    # A directory that only exists in the base
+    mv "$TEST_MNT/subdir" "$TEST_MNT/renamed_dir"
+    assert "[ ! -d '$TEST_MNT/subdir' ]" "Old directory gone"
+    assert_file_contains "$TEST_MNT/renamed_dir/nested.txt" "nested file" "Children moved with directory"
+
+    # Ctl files and @branch directories stay put
+    assert "! mv '$TEST_MNT/.branchfs_ctl' '$TEST_MNT/ctl' 2>/dev/null" "Ctl file cannot be renamed"
+
+    do_commit
+    assert_file_not_exists "$TEST_BASE/file1.txt" "Move survives commit"
+    assert_file_contains "$TEST_BASE/file2.txt" "base content" "Replaced file committed"
+    assert_file_contains "$TEST_BASE/moved.txt" "fresh" "New name committed"
+    assert "[ ! -d '$TEST_BASE/subdir' ]" "Old directory removed on commit"
+    assert_file_contains "$TEST_BASE/renamed_dir/nested.txt" "nested file" "Directory move committed"
+
+    do_unmount
+}
+
+test_symlinks() {
+    setup
+    ln -s "$TEST_BASE/file1.txt" "$TEST_BASE/abs_link"
+    ln -s file2.txt "$TEST_BASE/rel_link"
+    do_mount
+    do_create "symlink_test" "main"
+
+    ln -s file1.txt "$TEST_MNT/link"
+    assert "[ -L '$TEST_MNT/link' ]" "Symlink created in branch"
+    assert_eq "$(readlink "$TEST_MNT/link")" "file1.txt" "readlink reports the target"
+    assert_file_contains "$TEST_MNT/link" "base content" "Symlink resolves through the mount"
+    assert "ls -l '$TEST_MNT' | grep -q 'link -> file1.txt'" "ls -l shows the target"
+    assert "! ln -s file2.txt '$TEST_MNT/link' 2>/dev/null" "Existing name refused"
+
+    ln -s nowhere "$TEST_MNT/dangling"
+    assert "[ -L '$TEST_MNT/dangling' ]" "Dangling symlink visible"
+
+    # Base symlinks: an absolute target inside the base stays in the mount
+    assert_eq "$(readlink "$TEST_MNT/rel_link")" "file2.txt" "Relative base link unchanged"
+    assert_eq "$(readlink "$TEST_MNT/abs_link")" "file1.txt" "Absolute base link made relative"
+    echo "branch content" > "$TEST_MNT/file1.txt"
+    assert_file_contains "$TEST_MNT/abs_link" "branch content" "Base link follows the branch"
+
+    rm "$TEST_MNT/link"
+    assert "[ ! -L '$TEST_MNT/link' ]" "Symlink removed"
+
+    do_commit
+    assert "[ -L '$TEST_BASE/dangling' ]" "Symlink committed as a symlink"
+    assert_eq "$(readlink "$TEST_BASE/dangling")" "nowhere" "Committed target kept"
+    assert "[ -L '$TEST_BASE/abs_link' ]" "Base symlink untouched"
+
+    do_unmount
+}
+
+test_hardlinks() {
+    setup
+    do_mount
+    do_create "link_test" "main"
+
+    echo "linked" > "$TEST_MNT/new.txt"
+    ln "$TEST_MNT/new.txt" "$TEST_MNT/new_link"
+    assert_eq "$(stat -c %h "$TEST_MNT/new_link")" "2" "Link count reflects both names"
+    echo "through link" > "$TEST_MNT/new_link"
+    assert_file_contains "$TEST_MNT/new.txt" "through link" "Names share content"
+
+    # A base file is copied into the branch once, then linked
+    ln "$TEST_MNT/file1.txt" "$TEST_MNT/base_link"
+    assert_file_contains "$TEST_MNT/base_link" "base content" "Link to a base file"
+    assert_eq "$(stat -c %h "$TEST_MNT/file1.txt")" "2" "Base file's branch copy is linked"
+    assert_eq "$(stat -c %h "$TEST_BASE/file1.txt")" "1" "Base untouched before commit"
+    assert "! ln '$TEST_MNT/file2.txt' '$TEST_MNT/file1.txt' 2>/dev/null" "Existing name refused"
+
+    rm "$TEST_MNT/new.txt"
+    assert_file_contains "$TEST_MNT/new_link" "through link" "Other name survives a delete"
+    assert_eq "$(stat -c %h "$TEST_MNT/new_link")" "1" "Link count drops"
+
+    do_commit
+    assert_file_contains "$TEST_BASE/base_link" "base content" "Link committed"
+    assert_eq "$(stat -c %i "$TEST_BASE/base_link")" "$(stat -c %i "$TEST_BASE/file1.txt")" \
+        "Committed names stay hard-linked"
+    assert_file_not_exists "$TEST_BASE/new.txt" "Deleted name not committed"
+    assert_file_contains "$TEST_BASE/new_link" "through link" "Surviving name committed"
+
+    do_unmount
+}
+
+test_non_utf8_names() {
+    setup
+    local ff fe
+    ff="$TEST_BASE/$(printf 'raw\xff')"
+    fe="$TEST_BASE/$(printf 'raw\xfe')"
+    echo "ff" > "$ff"
+    echo "fe" > "$fe"
+    do_mount
+    do_create "bytes_test" "main"
+
+    local mnt_ff mnt_fe listed
+    mnt_ff="$TEST_MNT/$(printf 'raw\xff')"
+    mnt_fe="$TEST_MNT/$(printf 'raw\xfe')"
+    listed=$(LC_ALL=C ls "$TEST_MNT" | LC_ALL=C grep -c '^raw')
+    assert_eq "$listed" "2" "Both names listed"
+    assert "LC_ALL=C ls '$TEST_MNT' | LC_ALL=C grep -q \"^raw$(printf '\xff')\$\"" "Name listed with its bytes"
+    assert_file_contains "$mnt_ff" "ff" "First name readable"
+    assert_file_contains "$mnt_fe" "fe" "Names differing in invalid bytes stay distinct"
+
+    echo "changed" > "$mnt_ff"
+    rm "$mnt_fe"
+    local created
+    created="$TEST_MNT/$(printf 'new\x80')"
+    echo "created" > "$created"
+    assert_file_contains "$created" "created" "Non-UTF-8 name created in branch"
+
+    do_commit
+    assert_file_contains "$ff" "changed" "Write committed to the same name"
+    assert_file_not_exists "$fe" "Deletion committed to the same name"
+    assert_file_contains "$TEST_BASE/$(printf 'new\x80')" "created" "New name committed with its bytes"
+
+    do_unmount
+}
+
+test_open_trunc_append() {
+    setup
+    do_mount
+    do_create "flags_test" "main"
+
+    # O_TRUNC on an inherited file empties the branch's copy only
+    : > "$TEST_MNT/file1.txt"
+    assert_eq "$(stat -c %s "$TEST_MNT/file1.txt")" "0" "O_TRUNC empties the file"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base untouched"
+    echo "rewritten" > "$TEST_MNT/file1.txt"
+    assert_file_contains "$TEST_MNT/file1.txt" "rewritten" "Truncate then write"
+
+    # An O_APPEND handle appends at the current end, even when the file
+    # grew through another path the kernel does not connect to it
+    exec 3>>"$TEST_MNT/file2.txt"
+    echo "via branch path" >> "$TEST_MNT/@flags_test/file2.txt"
+    echo "appended" >&3
+    exec 3>&-
+    assert_eq "$(cat "$TEST_MNT/file2.txt")" "$(printf 'another file\nvia branch path\nappended')" \
+        "O_APPEND writes land at end of file"
+
+    # Ctl files keep their own handling of O_TRUNC
+    echo "abort" > "$TEST_MNT/.branchfs_ctl"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "Ctl write through O_TRUNC still works"
+
+    do_unmount
+}
+
+test_interleaved_handles() {
+    setup
+    do_mount
+    do_create "handles_test" "main"
+
+    # Each handle writes at its own position, whichever wrote last
+    exec 3<>"$TEST_MNT/file1.txt"
+    exec 4<>"$TEST_MNT/file1.txt"
+    printf 'BASE' >&3
+    printf 'b' >&4
+    printf '_CONTENT' >&3
+    exec 3>&-
+    printf 'A' >&4
+    exec 4>&-
+    assert_file_contains "$TEST_MNT/file1.txt" "bASE_CONTENT" "Interleaved handles keep their offsets"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base untouched"
+
+    # Closed handles leave nothing open for writing
+    do_commit
+    assert_file_contains "$TEST_BASE/file1.txt" "bASE_CONTENT" "Commit after release"
+
+    do_unmount
+}
+
+test_cow_failure_keeps_base() {
+    setup
+    head -c 262144 /dev/urandom > "$TEST_BASE/big.bin"
+    do_mount
+    do_create "cow_fail_branch"
+
+    if [[ $(id -u) -ne 0 ]]; then
+        echo -e "  ${YELLOW}skipped: mounting a small tmpfs needs root${NC}"
+        do_unmount
+        return
+    fi
+
+    # Too small for a copy of big.bin
+    local delta="$TEST_STORAGE/branches/cow_fail_branch/files"
+    mount -t tmpfs -o size=64k tmpfs "$delta"
+
+    local err
+    err=$(printf 'x' 2>&1 | dd of="$TEST_MNT/big.bin" bs=1 count=1 conv=notrunc 2>&1 || true)
+    assert "[[ '$err' == *'No space left on device'* ]]" "Failed copy-up reports ENOSPC"
+    assert_eq "$(ls -A "$delta")" "" "No partial delta left behind"
+    assert "cmp -s '$TEST_BASE/big.bin' '$TEST_MNT/big.bin'" "Reads still see the base file"
+
+    do_unmount
+    umount "$delta"
+}
+
+test_fsync() {
+    setup
+    do_mount
+
+    # Nothing to flush for files and directories the branch never wrote
+    assert "sync '$TEST_MNT/file1.txt'" "fsync of a base file on main"
+    assert "sync '$TEST_MNT'" "fsyncdir on main"
+
+    do_create "fsync_branch" "main"
+    echo "synced" > "$TEST_MNT/new.txt"
+    echo "modified" > "$TEST_MNT/file1.txt"
+    rm "$TEST_MNT/file2.txt"
+    assert "sync '$TEST_MNT/new.txt'" "fsync of a new file"
+    assert "sync -d '$TEST_MNT/file1.txt'" "fdatasync of a copied-up file"
+    assert "sync '$TEST_MNT' '$TEST_MNT/@fsync_branch'" "fsyncdir of the branch root"
+    assert "sync '$TEST_MNT/@fsync_branch/new.txt'" "fsync through @branch"
+
+    do_commit
+    assert_file_contains "$TEST_BASE/new.txt" "synced" "Synced file committed"
+    assert_file_contains "$TEST_BASE/file1.txt" "modified" "Synced copy-up committed"
+    assert_file_not_exists "$TEST_BASE/file2.txt" "Synced deletion committed"
+
+    do_unmount
+}
+
+test_setattr_owner_and_times() {
+    setup
+    do_mount
+    do_create "setattr_test" "main"
+
+    touch -m -d "2001-02-03 04:05:06 UTC" "$TEST_MNT/file1.txt"
+    assert_eq "$(stat -c '%Y' "$TEST_MNT/file1.txt")" "981173106" "touch -d sets mtime"
+
+    if [[ $(id -u) -eq 0 ]]; then
+        chown 1234:5678 "$TEST_MNT/file2.txt"
+        assert_eq "$(stat -c '%u:%g' "$TEST_MNT/file2.txt")" "1234:5678" "chown applied"
+        assert_eq "$(stat -c '%u' "$TEST_BASE/file2.txt")" "0" "Base owner unchanged"
+    fi
+
+    # A directory only the base has gets a copy in the branch
+    chmod 0700 "$TEST_MNT/subdir"
+    assert_eq "$(stat -c '%a' "$TEST_MNT/subdir")" "700" "chmod on base directory applied"
+    assert_file_contains "$TEST_MNT/subdir/nested.txt" "nested file" "Directory contents still visible"
+    assert "[[ $(stat -c '%a' "$TEST_BASE/subdir") != 700 ]]" "Base directory mode unchanged"
+
+    # The mode of a copied-up file reaches the base
+    chmod 0751 "$TEST_MNT/file1.txt"
+    do_commit
+    assert_eq "$(stat -c '%a' "$TEST_BASE/file1.txt")" "751" "chmod survives commit"
+
+    do_unmount
+}
+
+test_rmdir_hides_subtree() {
+    setup
+    mkdir -p "$TEST_BASE/tree/inner"
+    echo "leaf" > "$TEST_BASE/tree/leaf.txt"
+    echo "deep" > "$TEST_BASE/tree/inner/deep.txt"
+    do_mount
+    do_create "rmdir_tree" "main"
+
+    rm -rf "$TEST_MNT/tree"
+    assert "! ls '$TEST_MNT' | grep -qx tree" "Removed directory not listed"
+    assert "[[ ! -e '$TEST_MNT/tree' ]]" "Removed directory not found"
+    assert_file_not_exists "$TEST_MNT/tree/leaf.txt" "Child hidden"
+    assert_file_not_exists "$TEST_MNT/tree/inner/deep.txt" "Grandchild hidden"
+
+    # Recreating the directory does not bring its old contents back
+    mkdir "$TEST_MNT/tree"
+    echo "fresh" > "$TEST_MNT/tree/fresh.txt"
+    assert_eq "$(ls "$TEST_MNT/tree")" "fresh.txt" "Recreated directory holds only new files"
+    assert_file_not_exists "$TEST_MNT/tree/leaf.txt" "Old child still hidden"
+
+    do_commit
+    assert_file_exists "$TEST_BASE/tree/fresh.txt" "New file committed"
+    assert_file_not_exists "$TEST_BASE/tree/leaf.txt" "Old child removed by commit"
+    assert "[[ ! -e '$TEST_BASE/tree/inner' ]]" "Old subdirectory removed by commit"
+
+    do_unmount
+}
+
+test_rmdir_errors() {
+    setup
+    mkdir -p "$TEST_BASE/full/sub"
+    echo "kept" > "$TEST_BASE/full/kept.txt"
+    do_mount
+    do_create "rmdir_errors" "main"
+
+    local rc
+    rc=$(python3 -c "import errno, os, sys
+try:
+    os.rmdir(sys.argv[1])
+except OSError as e:
+    print(errno.errorcode[e.errno])" "$TEST_MNT/full")
+    assert_eq "$rc" "ENOTEMPTY" "rmdir of a non-empty base directory fails"
+    assert_file_exists "$TEST_MNT/full/kept.txt" "Contents untouched"
+
+    rc=$(python3 -c "import errno, os, sys
+try:
+    os.rmdir(sys.argv[1])
+except OSError as e:
+    print(errno.errorcode[e.errno])" "$TEST_MNT/full/kept.txt")
+    assert_eq "$rc" "ENOTDIR" "rmdir of a file fails"
+
+    rc=$(python3 -c "import errno, os, sys
+try:
+    os.unlink(sys.argv[1])
+except OSError as e:
+    print(errno.errorcode[e.errno])" "$TEST_MNT/full/sub")
+    assert_eq "$rc" "EISDIR" "unlink of a directory fails"
+    assert "[[ -d '$TEST_MNT/full/sub' ]]" "Directory untouched"
+
+    # Emptied in the branch, the directory can go
+    rm "$TEST_MNT/full/kept.txt"
+    rmdir "$TEST_MNT/full/sub"
+    assert "rmdir '$TEST_MNT/full'" "rmdir succeeds once the directory is empty"
+    assert "[[ ! -e '$TEST_MNT/full' ]]" "Directory removed"
+    assert "[[ -d '$TEST_BASE/full' ]]" "Base directory still exists"
+
+    do_unmount
+}
+
+test_copy_file_range() {
+    setup
+    head -c 3000000 /dev/urandom > "$TEST_BASE/large.bin"
+    do_mount
+    do_create "copy_range" "main"
+
+    python3 -c "import os, sys
+src = os.open(sys.argv[1] + '/large.bin', os.O_RDONLY)
+dst = os.open(sys.argv[1] + '/large_copy.bin', os.O_WRONLY | os.O_CREAT, 0o644)
+while os.copy_file_range(src, dst, 1 << 22):
+    pass
+# Into a base file at an offset: the destination is copied up first
+dst = os.open(sys.argv[1] + '/file1.txt', os.O_WRONLY)
+os.copy_file_range(src, dst, 4, 0, 5)" "$TEST_MNT"
+
+    assert "cmp -s '$TEST_BASE/large.bin' '$TEST_MNT/large_copy.bin'" "Copy matches the source"
+    assert_eq "$(head -c 5 "$TEST_MNT/file1.txt")" "base " "Bytes before the copied range kept"
+    assert_eq "$(cmp -n 4 -i 5:0 "$TEST_MNT/file1.txt" "$TEST_BASE/large.bin" && echo same)" "same" "Copied range written into the branch's file"
+    assert_file_contains "$TEST_BASE/file1.txt" "base content" "Base file untouched"
+    assert_file_not_exists "$TEST_BASE/large_copy.bin" "Copy only in the branch"
+
+    do_unmount
+}
+
+test_inode_eviction() {
+    setup
+    mkdir -p "$TEST_BASE/many"
+    local i
+    for i in $(seq 1 100); do echo "$i" > "$TEST_BASE/many/f$i"; done
+    do_mount --max-inodes 16
+
+    local first held
+    first=$(stat -c %i "$TEST_MNT/file1.txt")
+    exec {held}<>"$TEST_MNT/file2.txt"
+    local held_ino
+    held_ino=$(stat -c %i "$TEST_MNT/file2.txt")
+
+    local ok=1
+    for i in $(seq 1 100); do
+        [ "$(cat "$TEST_MNT/many/f$i")" = "$i" ] || ok=0
+    done
+    assert_eq "$ok" "1" "Files read correctly past the inode cap"
+    assert_eq "$(ls "$TEST_MNT/many" | wc -l)" "100" "Listing complete past the inode cap"
+    assert_eq "$(stat -c %i "$TEST_MNT/file1.txt")" "$first" "Inode the kernel holds is kept"
+
+    # Once the kernel forgets them, the oldest inodes make room for new ones
+    mkdir -p "$TEST_BASE/more"
+    for i in $(seq 1 20); do
+        echo "$i" > "$TEST_BASE/more/g$i"
+        echo 2 > /proc/sys/vm/drop_caches
+        cat "$TEST_MNT/more/g$i" > /dev/null
+    done
+    assert "[ '$(stat -c %i "$TEST_MNT/file1.txt")' != '$first' ]" "Least recently used inode evicted"
+    assert_file_contains "$TEST_MNT/file1.txt" "base content" "Evicted path looked up again"
+    assert_eq "$(stat -c %i "$TEST_MNT/file2.txt")" "$held_ino" "Open file keeps its inode"
+    assert_eq "$(stat -c %i "$TEST_MNT")" "1" "Root keeps its inode"
+
+    echo "through held fd" >&$held
+    exec {held}>&-
+    assert_file_contains "$TEST_MNT/file2.txt" "through held fd" "Write through the held handle lands"
+
+    do_unmount
+}
+
+test_inode_cap_keeps_cwd() {
+    setup
+    mkdir -p "$TEST_BASE/deep/dir" "$TEST_BASE/many"
+    echo "deep" > "$TEST_BASE/deep/dir/kept.txt"
+    local i
+    for i in $(seq 1 50); do echo "$i" > "$TEST_BASE/many/f$i"; done
+    do_mount --max-inodes 8
+
+    # The kernel holds the working directory without looking it up again
+    local out
+    out=$(cd "$TEST_MNT/deep/dir" &&
+        for i in $(seq 1 50); do cat "$TEST_MNT/many/f$i" > /dev/null || exit 1; done &&
+        touch x && ls | wc -l)
+    assert_eq "$out" "2" "Working directory usable past the inode cap"
+    assert_file_exists "$TEST_MNT/deep/dir/x" "File created in the working directory"
+
+    do_unmount
+}
+
+test_cow_keeps_dir_metadata() {
+    setup
+    mkdir -p "$TEST_BASE/tree/deep"
+    echo "deep" > "$TEST_BASE/tree/deep/f.txt"
+    chmod 750 "$TEST_BASE/tree"
+    chmod 700 "$TEST_BASE/tree/deep"
+    chown -R 1234:1234 "$TEST_BASE/tree"
+    do_mount
+    do_create "dir_meta" "main"
+
+    echo "edited" >> "$TEST_MNT/tree/deep/f.txt"
+    echo "new" > "$TEST_MNT/tree/new.txt"
+
+    local files="$TEST_STORAGE/branches/dir_meta/files"
+    assert_eq "$(stat -c '%a %u:%g' "$files/tree")" "750 1234:1234" "Copied-up parent keeps mode and owner"
+    assert_eq "$(stat -c '%a %u:%g' "$files/tree/deep")" "700 1234:1234" "Nested parent keeps mode and owner"
+    assert_eq "$(stat -c '%a %u:%g' "$TEST_MNT/tree/deep")" "700 1234:1234" "Mount shows the inherited metadata"
+
+    do_commit
+    assert_eq "$(stat -c '%a %u:%g' "$TEST_BASE/tree/deep")" "700 1234:1234" "Base directory unchanged by commit"
+    assert_file_contains "$TEST_BASE/tree/deep/f.txt" $'deep\nedited' "Edit committed"
+
+    do_unmount
+}
+
+test_sparse_blocks() {
+    setup
+    do_mount
+    do_create "blocks_branch" "main"
+
+    truncate -s 64M "$TEST_MNT/sparse.bin"
+    dd if=/dev/zero of="$TEST_MNT/dense.bin" bs=1M count=4 status=none
+
+    local delta="$TEST_STORAGE/branches/blocks_branch/files"
+    assert_eq "$(stat -c %b "$TEST_MNT/sparse.bin")" "$(stat -c %b "$delta/sparse.bin")" \
+        "Sparse file reports the delta's allocation"
+    assert "[[ \$(stat -c %b '$TEST_MNT/sparse.bin') -lt 1024 ]]" "Holes not counted"
+    assert_eq "$(stat -c %b "$TEST_MNT/dense.bin")" "$(stat -c %b "$delta/dense.bin")" \
+        "Dense file reports the delta's allocation"
+    assert_eq "$(stat -c %o "$TEST_MNT/dense.bin")" "$(stat -c %o "$delta/dense.bin")" \
+        "Block size of the delta file"
+
+    do_unmount
+}
+
+test_readdir_snapshot() {
+    setup
+    mkdir -p "$TEST_BASE/snap"
+    (cd "$TEST_BASE/snap" && seq -f "entry_%g" 1 2000 | xargs touch)
+    do_mount
+    do_create "snap_branch"
+
+    # Change the directory between readdir pages of one open handle: the
+    # handle keeps listing the directory as it was at opendir
+    local result
+    result=$(python3 -c "import os, sys
+d = sys.argv[1]
+it = os.scandir(d)
+names = [next(it).name]
+for i in range(1, 1001):
+    os.unlink(os.path.join(d, 'entry_%d' % i))
+for i in range(1, 501):
+    open(os.path.join(d, 'added_%d' % i), 'w').close()
+names += [e.name for e in it]
+print(len(names), len(set(names)), sum(n.startswith('added_') for n in names))" "$TEST_MNT/snap")
+    assert_eq "$result" "2000 2000 0" "Open handle pages through the opendir snapshot"
+    assert_eq "$(ls -A "$TEST_MNT/snap" | wc -l)" "1500" "A new handle sees the changes"
+
+    do_unmount
+}
+
+test_readdir_reaped_handle() {
+    setup
+    mkdir -p "$TEST_BASE/snap"
+    (cd "$TEST_BASE/snap" && seq -f "entry_%g" 1 2000 | xargs touch)
+    do_mount --handle-idle-timeout 1
+    do_create "reaped_branch"
+
+    # Let the handle be reaped after its first page (the next opendir drops
+    # its snapshot), then change the directory between later pages: the
+    # listing taken on the first page after the reap is kept for the rest
+    local result
+    result=$(python3 -c "import ctypes, os, platform, struct, sys, time
+libc = ctypes.CDLL(None, use_errno=True)
+nr = {'x86_64': 217, 'aarch64': 61}[platform.machine()]
+buf = ctypes.create_string_buffer(4096)
+def page(fd):
+    n = libc.syscall(nr, fd, buf, len(buf))
+    names, pos = [], 0
+    while pos < n:
+        reclen, = struct.unpack_from('H', buf.raw, pos + 16)
+        names.append(buf.raw[pos + 19:pos + reclen].split(b'\\0')[0].decode())
+        pos += reclen
+    return names
+d = sys.argv[1]
+fd = os.open(d, os.O_RDONLY | os.O_DIRECTORY)
+names = page(fd)
+time.sleep(3)
+os.listdir(os.path.join(d, '..'))
+names += page(fd)
+for i in range(1, 1001):
+    os.unlink(os.path.join(d, 'entry_%d' % i))
+for i in range(1, 501):
+    open(os.path.join(d, 'added_%d' % i), 'w').close()
+while True:
+    more = page(fd)
+    if not more:
+        break
+    names += more
+names = [n for n in names if n not in ('.', '..')]
+print(len(names), len(set(names)), sum(n.startswith('added_') for n in names))" "$TEST_MNT/snap")
+    assert_eq "$result" "2000 2000 0" "Reaped handle keeps the listing it took"
+
+    do_unmount
+}
+
 # Run tests
 run_test "Read Base Files" test_read_base_files
 run_test "Write New File in Branch" test_write_new_file_in_branch
@@ -247,5 +1186,40 @@ run_test "Chmod File" test_chmod_file
 run_test "Chmod Directory" test_chmod_directory
 run_test "Chmod Existing File (COW)" test_chmod_existing_file_cow
 run_test "Synthetic Entry Ownership" test_synthetic_entry_ownership
+run_test "Open Create Preserves Content" test_open_create_preserves_content
+run_test "Recreate Deleted File" test_recreate_deleted_file
+run_test "Dotdot Inode" test_dotdot_inode
+run_test "Readahead Sees Writes" test_readahead_sees_writes
+run_test "Durable Delete Survives Crash" test_durable_delete_survives_crash
+run_test "Sync Writes Survive Crash" test_sync_writes_survive_crash
+run_test "Atime Relatime" test_atime_relatime
+run_test "Atime None" test_atime_none
+run_test "Open Flag Mismatch" test_open_flag_mismatch
+run_test "Large Directory Listing" test_large_directory_listing
+run_test "Write Mode Copyup" test_write_mode_copyup
+run_test "Write Mode Redirect" test_write_mode_redirect
+run_test "Write Mode Redirect Restart" test_write_mode_redirect_restart
+run_test "Write Mode Overwrite" test_write_mode_overwrite
+run_test "Strict Errno Permission Denied" test_strict_errno_permission_denied
+run_test "Strict Errno No Space" test_strict_errno_no_space
+run_test "Handle Reaper" test_handle_reaper
+run_test "Rename in Branch" test_rename_in_branch
+run_test "Symlinks" test_symlinks
+run_test "Hardlinks" test_hardlinks
+run_test "Non-UTF-8 Names" test_non_utf8_names
+run_test "Open Trunc Append" test_open_trunc_append
+run_test "Interleaved Handles" test_interleaved_handles
+run_test "COW Failure Keeps Base" test_cow_failure_keeps_base
+run_test "Fsync" test_fsync
+run_test "Setattr Owner And Times" test_setattr_owner_and_times
+run_test "Rmdir Hides Subtree" test_rmdir_hides_subtree
+run_test "Rmdir Errors" test_rmdir_errors
+run_test "Copy File Range" test_copy_file_range
+run_test "Inode Eviction" test_inode_eviction
+run_test "Inode Cap Keeps Working Directory" test_inode_cap_keeps_cwd
+run_test "COW Keeps Directory Metadata" test_cow_keeps_dir_metadata
+run_test "Sparse Blocks" test_sparse_blocks
+run_test "Readdir Snapshot" test_readdir_snapshot
+run_test "Readdir Reaped Handle" test_readdir_reaped_handle
 
 print_summary
@@ -24,6 +24,7 @@ TEST_ID="$$_$(date +%s)"
 TEST_BASE="/tmp/branchfs_test_base_$TEST_ID"
 TEST_STORAGE="/tmp/branchfs_test_storage_$TEST_ID"
 TEST_MNT="/tmp/branchfs_test_mnt_$TEST_ID"
+TEST_MNT2="/tmp/branchfs_test_mnt2_$TEST_ID"
 
 # Track if we've set up
 SETUP_DONE=0
@@ -32,7 +33,7 @@ SETUP_DONE=0
 build_if_needed() {
     if [[ ! -x "$BRANCHFS" ]]; then
         echo -e "${YELLOW}Building branchfs...${NC}"
-        (cd "$PROJECT_ROOT" && cargo build --release)
+        (cd "$PROJECT_ROOT" && cargo build --release --features testing)
     fi
 }
 
@@ -63,10 +64,12 @@ cleanup() {
     echo -e "${YELLOW}Cleaning up...${NC}"
 
     # Try to unmount if mounted
-    if mountpoint -q "$TEST_MNT" 2>/dev/null; then
-        fusermount3 -u "$TEST_MNT" 2>/dev/null || fusermount -u "$TEST_MNT" 2>/dev/null || true
-        sleep 0.5
-    fi
+    for mnt in "$TEST_MNT2" "$TEST_MNT"; do
+        if mountpoint -q "$mnt" 2>/dev/null; then
+            fusermount3 -u "$mnt" 2>/dev/null || fusermount -u "$mnt" 2>/dev/null || true
+            sleep 0.5
+        fi
+    done
 
     # Kill any daemon that might be running with our storage
     local socket="$TEST_STORAGE/daemon.sock"
@@ -80,6 +83,7 @@ cleanup() {
     rm -rf "$TEST_BASE" 2>/dev/null || true
     rm -rf "$TEST_STORAGE" 2>/dev/null || true
     rm -rf "$TEST_MNT" 2>/dev/null || true
+    rm -rf "$TEST_MNT2" 2>/dev/null || true
 
     echo -e "${GREEN}Cleanup complete${NC}"
 }
@@ -88,8 +92,9 @@ cleanup() {
 trap cleanup EXIT
 
 # Mount the filesystem
+# Usage: do_mount [mount options...]
 do_mount() {
-    "$BRANCHFS" mount --base "$TEST_BASE" --storage "$TEST_STORAGE" "$TEST_MNT"
+    "$BRANCHFS" mount --base "$TEST_BASE" --storage "$TEST_STORAGE" "$@" "$TEST_MNT"
     sleep 0.5  # Give FUSE time to initialize
 }
 
@@ -99,6 +104,18 @@ do_unmount() {
     sleep 0.3
 }
 
+# Mount a second view of the same storage (daemon already running)
+do_mount2() {
+    "$BRANCHFS" mount --storage "$TEST_STORAGE" "$@" "$TEST_MNT2"
+    sleep 0.5
+}
+
+# Unmount the second view
+do_unmount2() {
+    "$BRANCHFS" unmount "$TEST_MNT2" --storage "$TEST_STORAGE"
+    sleep 0.3
+}
+
 # Create a branch (always switches to it)
 # Usage: do_create <name> [parent]
 # - name: branch name
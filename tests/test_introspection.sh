@@ -0,0 +1,656 @@
+#!/bin/bash
+# Test daemon introspection commands
+
+source "$(dirname "$0")/test_helper.sh"
+
+test_inspect_mount() {
+    setup
+    do_mount
+
+    local output
+    output=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'branch:'*'main'* ]]" "Inspect reports main branch"
+    assert "[[ '$output' == *'registered'* ]]" "Inspect reports registered notifier"
+
+    do_create "inspect_branch" "main"
+
+    output=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'inspect_branch'* ]]" "Inspect follows branch switch"
+
+    do_unmount
+}
+
+test_branch_counters() {
+    setup
+    do_mount
+    do_create "busy_branch" "main"
+
+    counters() {
+        "$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" | grep '^counters:'
+    }
+
+    echo "output" > "$TEST_MNT/out.txt"
+    assert "[[ '$(counters)' == *'writes=1 '*'creates=1 '* ]]" "Write and create are counted"
+
+    cat "$TEST_MNT/out.txt" > /dev/null
+    cat "$TEST_MNT/out.txt" > /dev/null
+    assert "[[ '$(counters)' == *'writes=1 '* ]]" "Reads do not inflate the write counter"
+    assert "[[ '$(counters)' != *'reads=0 '* ]]" "Reads are counted"
+
+    echo "more" >> "$TEST_MNT/file1.txt"
+    assert "[[ '$(counters)' == *'cow=1 '* ]]" "Copy-up is counted"
+
+    rm "$TEST_MNT/out.txt"
+    assert "[[ '$(counters)' == *'unlinks=1' ]]" "Unlink is counted"
+
+    # The mount returns to main, whose counters restart on commit
+    do_commit > /dev/null
+    assert "[[ '$(counters)' == *'writes=0 '* ]]" "Counters reset on commit"
+
+    do_unmount
+}
+
+test_inspect_unknown_mount() {
+    setup
+    do_mount
+
+    local other="/tmp/branchfs_test_other_$TEST_ID"
+    mkdir -p "$other"
+    if "$BRANCHFS" inspect "$other" --storage "$TEST_STORAGE" 2>/dev/null; then
+        assert "false" "Inspect of untracked mountpoint fails"
+    else
+        assert "true" "Inspect of untracked mountpoint fails"
+    fi
+    rmdir "$other"
+
+    do_unmount
+}
+
+test_diff_file() {
+    setup
+    printf 'line one\nline two\n' > "$TEST_BASE/text.txt"
+    printf 'bin\0ary' > "$TEST_BASE/blob.bin"
+    do_mount
+    do_create "diff_branch" "main"
+
+    local output
+    output=$("$BRANCHFS" diff-file diff_branch /text.txt "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "" "Unchanged file has an empty diff"
+
+    printf 'line one\nline 2\n' > "$TEST_MNT/text.txt"
+    output=$("$BRANCHFS" diff-file diff_branch /text.txt "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'--- a/text.txt'* ]]" "Diff has base header"
+    assert "[[ '$output' == *'-line two'* ]]" "Diff shows removed line"
+    assert "[[ '$output' == *'+line 2'* ]]" "Diff shows added line"
+
+    printf 'bin\0ari' > "$TEST_MNT/blob.bin"
+    output=$("$BRANCHFS" diff-file diff_branch /blob.bin "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == 'Binary files'*'differ' ]]" "Binary change reported as differing"
+
+    echo "fresh" > "$TEST_MNT/new.txt"
+    output=$("$BRANCHFS" diff-file diff_branch new.txt "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'--- /dev/null'* ]]" "New file diffs against /dev/null"
+
+    do_unmount
+}
+
+test_read_if_epoch() {
+    setup
+    do_mount
+    do_create "epoch_reader" "main"
+    echo "reader data" > "$TEST_MNT/@epoch_reader/data.txt"
+
+    local epoch output status
+    epoch=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" | awk '/^epoch:/ {print $2}')
+
+    output=$("$BRANCHFS" read-if-epoch epoch_reader /data.txt "$TEST_MNT" --epoch "$epoch" --storage "$TEST_STORAGE")
+    assert_eq "$output" "reader data" "Read succeeds with current epoch"
+
+    status=0
+    output=$("$BRANCHFS" read-if-epoch epoch_reader ../../../../../../../../etc/passwd "$TEST_MNT" --epoch "$epoch" --storage "$TEST_STORAGE" 2>/dev/null) || status=$?
+    assert_eq "$status" "1" "Read of a .. path refused"
+    assert_eq "$output" "" "Nothing outside the branch read"
+
+    # Committing another branch bumps the epoch
+    do_create "epoch_writer" "main"
+    echo "commit" > "$TEST_MNT/@epoch_writer/.branchfs_ctl"
+
+    status=0
+    "$BRANCHFS" read-if-epoch epoch_reader /data.txt "$TEST_MNT" --epoch "$epoch" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "2" "Read with an old epoch reports stale"
+
+    do_unmount
+}
+
+test_truncate() {
+    setup
+    echo "0123456789" > "$TEST_BASE/sized.txt"
+    do_mount
+    do_create "trunc_branch" "main"
+
+    "$BRANCHFS" truncate trunc_branch /sized.txt 4 "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert_eq "$(cat "$TEST_MNT/@trunc_branch/sized.txt")" "0123" "Truncate shrinks file in branch"
+    assert_eq "$(cat "$TEST_BASE/sized.txt")" "0123456789" "Base file untouched by truncate"
+
+    "$BRANCHFS" truncate trunc_branch /prealloc.db 4096 "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert_eq "$(stat -c %s "$TEST_MNT/@trunc_branch/prealloc.db")" "4096" "Truncate creates preallocated file"
+    assert_file_not_exists "$TEST_BASE/prealloc.db" "Created file stays in the branch"
+
+    local status=0
+    "$BRANCHFS" truncate no_such_branch /sized.txt 0 "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Truncate on unknown branch fails"
+
+    local escaped="/tmp/branchfs_escaped_$$.txt"
+    status=0
+    "$BRANCHFS" truncate trunc_branch "../../../../../../../..$escaped" 10 "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Truncate of a .. path refused"
+    assert_file_not_exists "$escaped" "Nothing created outside the branch"
+
+    do_unmount
+}
+
+test_rename_path_directory() {
+    setup
+    mkdir -p "$TEST_BASE/dir/sub"
+    echo "base a" > "$TEST_BASE/dir/a.txt"
+    echo "base b" > "$TEST_BASE/dir/b.txt"
+    echo "base c" > "$TEST_BASE/dir/sub/c.txt"
+    do_mount
+    do_create "mv_branch" "main"
+
+    # Mix of origins: modified, added, deleted and untouched base files
+    echo "branch a" > "$TEST_MNT/dir/a.txt"
+    echo "new" > "$TEST_MNT/dir/new.txt"
+    rm "$TEST_MNT/dir/b.txt"
+
+    "$BRANCHFS" mv mv_branch /dir /moved "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    assert "[[ \$? -eq 0 ]]" "Directory move succeeds"
+
+    assert_eq "$(cat "$TEST_MNT/moved/a.txt")" "branch a" "Modified file moves with its delta"
+    assert_eq "$(cat "$TEST_MNT/moved/new.txt")" "new" "Branch-only file moves"
+    assert_eq "$(cat "$TEST_MNT/moved/sub/c.txt")" "base c" "Base-only nested file is materialized"
+    assert_file_not_exists "$TEST_MNT/moved/b.txt" "Deleted child stays deleted"
+    assert "[[ ! -e '$TEST_MNT/dir' ]]" "Source directory is gone"
+    assert_file_exists "$TEST_BASE/dir/sub/c.txt" "Base untouched before commit"
+
+    local status=0
+    "$BRANCHFS" mv mv_branch /moved /file1.txt "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Move onto an existing path fails"
+
+    local escaped="/tmp/branchfs_escaped_$$"
+    mkdir -p "$escaped"
+    status=0
+    "$BRANCHFS" mv mv_branch /moved/a.txt "../../../../../../../..$escaped/moved.txt" "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Move to a .. path refused"
+    assert_file_not_exists "$escaped/moved.txt" "Nothing moved outside the branch"
+    assert_eq "$(cat "$TEST_MNT/moved/a.txt")" "branch a" "Refused move leaves the source"
+    rmdir "$escaped"
+
+    do_commit > /dev/null
+    assert_file_contains "$TEST_BASE/moved/sub/c.txt" "base c" "Commit writes the moved tree"
+    assert "[[ ! -e '$TEST_BASE/dir' ]]" "Commit removes the old directory"
+
+    do_unmount
+}
+
+test_hash() {
+    setup
+    do_mount
+    do_create "hash_a" "main"
+    do_create "hash_b" "main"
+
+    local hash_cmd=("$BRANCHFS" hash --storage "$TEST_STORAGE")
+    echo "same output" > "$TEST_MNT/@hash_a/out.txt"
+    echo "same output" > "$TEST_MNT/@hash_b/out.txt"
+
+    local a b base
+    a=$("${hash_cmd[@]}" hash_a /out.txt "$TEST_MNT" | awk '{print $1}')
+    b=$("${hash_cmd[@]}" hash_b /out.txt "$TEST_MNT" | awk '{print $1}')
+    assert_eq "${#a}" "64" "Hash is 256-bit hex"
+    assert_eq "$a" "$b" "Identical content hashes equal across branches"
+
+    base=$("${hash_cmd[@]}" main /file1.txt "$TEST_MNT" | awk '{print $1}')
+    a=$("${hash_cmd[@]}" hash_a /file1.txt "$TEST_MNT" | awk '{print $1}')
+    assert_eq "$a" "$base" "Unmodified base file hashes equal to main"
+
+    a=$("${hash_cmd[@]}" hash_a /out.txt "$TEST_MNT" | awk '{print $1}')
+    echo "different output" > "$TEST_MNT/@hash_b/out.txt"
+    b=$("${hash_cmd[@]}" hash_b /out.txt "$TEST_MNT" | awk '{print $1}')
+    assert "[[ '$a' != '$b' ]]" "Changed content hashes differ"
+
+    local status=0
+    "${hash_cmd[@]}" hash_a /missing.txt "$TEST_MNT" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Hash of missing file fails"
+
+    status=0
+    "${hash_cmd[@]}" hash_a ../../../../../../../../etc/passwd "$TEST_MNT" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Hash of a .. path refused"
+
+    do_unmount
+}
+
+test_hash_after_preserved_commit() {
+    setup
+    echo "aaaa" > "$TEST_BASE/hashed.txt"
+    do_mount --commit-mtime preserve
+    local before after
+    before=$("$BRANCHFS" hash main /hashed.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')
+
+    # Same size, and the commit keeps the base file's mtime
+    do_create "hash_commit" "main"
+    echo "bbbb" > "$TEST_MNT/hashed.txt"
+    do_commit > /dev/null
+
+    after=$("$BRANCHFS" hash main /hashed.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')
+    assert "[[ '$before' != '$after' ]]" "Cached hash not reused"
+    echo "bbbb" > "$TEST_MNT/expected.txt"
+    assert_eq "$after" "$("$BRANCHFS" hash main /expected.txt "$TEST_MNT" --storage "$TEST_STORAGE" | awk '{print $1}')" \
+        "Hash follows the committed content"
+
+    do_unmount
+}
+
+test_bump_epoch() {
+    setup
+    do_mount
+
+    if ! "$BRANCHFS" help bump-epoch > /dev/null 2>&1; then
+        echo -e "  ${YELLOW}skipped: binary built without the 'testing' feature${NC}"
+        do_unmount
+        return
+    fi
+
+    assert "cat '$TEST_MNT/file1.txt' > /dev/null" "File readable before bump"
+
+    local before after
+    before=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" | awk '/^epoch:/ {print $2}')
+    "$BRANCHFS" bump-epoch "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    after=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" | awk '/^epoch:/ {print $2}')
+    assert_eq "$after" "$((before + 1))" "Bump advances the epoch by one"
+
+    assert "! cat '$TEST_MNT/file1.txt' > /dev/null 2>&1" "Root path is stale after bump"
+
+    # Switching re-syncs the mount with the manager epoch
+    echo "switch:main" > "$TEST_MNT/.branchfs_ctl"
+    assert_eq "$(cat "$TEST_MNT/file1.txt")" "base content" "File readable after switch"
+
+    do_unmount
+}
+
+test_notifier_health() {
+    setup
+    do_mount
+
+    if ! "$BRANCHFS" help fail-notifier > /dev/null 2>&1; then
+        echo -e "  ${YELLOW}skipped: binary built without the 'testing' feature${NC}"
+        do_unmount
+        return
+    fi
+
+    health() {
+        "$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" | grep '^notifier health:'
+    }
+
+    assert "[[ '$(health)' == *'healthy (failures=0 repairs=0)' ]]" "Fresh mount has a healthy notifier"
+
+    "$BRANCHFS" fail-notifier "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+    sleep 0.5
+    assert "[[ '$(health)' == *'failures=1 '* ]]" "Failure is counted"
+    assert "[[ '$(health)' == *'last error: injected failure'* ]]" "Failure reason is reported"
+    assert "[[ '$(health)' == *'healthy (failures=1 repairs=1)'* ]]" "Daemon re-acquires the notifier"
+
+    # Invalidation keeps working through the repaired notifier
+    do_create "health_branch" "main"
+    echo "after repair" > "$TEST_MNT/repair.txt"
+    do_commit > /dev/null
+    assert_eq "$(cat "$TEST_MNT/repair.txt")" "after repair" "Commit visible after repair"
+    assert "[[ '$(health)' == *'failures=1 '* ]]" "Benign invalidations are not failures"
+
+    do_unmount
+}
+
+test_deletions() {
+    setup
+    do_mount
+    do_create "del_parent" "main"
+    rm "$TEST_MNT/file1.txt" "$TEST_MNT/file2.txt"
+    echo "temp" > "$TEST_MNT/scratch.txt"
+    rm "$TEST_MNT/scratch.txt"
+
+    do_create "del_child" "del_parent"
+    rm "$TEST_MNT/subdir/nested.txt"
+    echo "restored" > "$TEST_MNT/file2.txt"
+
+    local output
+    output=$("$BRANCHFS" deletions del_child "$TEST_MNT" --storage "$TEST_STORAGE")
+    local expected
+    expected=$(printf '/file1.txt\tdel_parent\tshadows\n/scratch.txt\tdel_parent\tgone\n/subdir/nested.txt\tdel_child\tshadows')
+    assert_eq "$output" "$expected" "Deletions cover the chain, minus paths written back"
+
+    output=$("$BRANCHFS" deletions del_parent "$TEST_MNT" --storage "$TEST_STORAGE" | cut -f1 | tr '\n' ' ')
+    assert_eq "$output" "/file1.txt /file2.txt /scratch.txt " "Parent only sees its own deletions"
+
+    local status=0
+    "$BRANCHFS" deletions nosuch "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Unknown branch fails"
+
+    do_unmount
+}
+
+test_who() {
+    setup
+    do_mount
+    do_mount2
+    do_create "who_branch" "main"
+
+    who() {
+        "$BRANCHFS" who "$1" --storage "$TEST_STORAGE" | tr '\n' ' '
+    }
+
+    assert_eq "$(who main)" "$TEST_MNT2 " "Only the second mount is on main"
+    assert_eq "$(who who_branch)" "$TEST_MNT " "Create moves the first mount"
+
+    "$BRANCHFS" switch who_branch "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    assert_eq "$(who main)" "" "No mount left on main"
+    assert_eq "$(who who_branch)" "$(printf '%s\n' "$TEST_MNT" "$TEST_MNT2" | sort | tr '\n' ' ')" "Switch adds the second mount"
+
+    local output
+    output=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'branch mounts:'*'$TEST_MNT2'* ]]" "Inspect lists mounts sharing the branch"
+
+    local status=0
+    "$BRANCHFS" who nosuch --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Unknown branch fails"
+
+    do_unmount2
+    do_unmount
+}
+
+test_where() {
+    setup
+    do_mount
+    do_create "where_parent" "main"
+    echo "parent" > "$TEST_MNT/file1.txt"
+    do_create "where_child" "where_parent"
+    rm "$TEST_MNT/file2.txt"
+
+    local branches="$TEST_STORAGE/branches"
+    local output expected
+    output=$("$BRANCHFS" where where_child file1.txt "$TEST_MNT" --storage "$TEST_STORAGE")
+    expected=$(printf 'delta:     %s (missing)\nresolved:  %s' \
+        "$branches/where_child/files/file1.txt" "$branches/where_parent/files/file1.txt")
+    assert_eq "$output" "$expected" "Unmodified file resolves to the parent's delta"
+
+    echo "child" >> "$TEST_MNT/file1.txt"
+    output=$("$BRANCHFS" where where_child /file1.txt "$TEST_MNT" --storage "$TEST_STORAGE")
+    expected=$(printf 'delta:     %s (exists)\nresolved:  %s' \
+        "$branches/where_child/files/file1.txt" "$branches/where_child/files/file1.txt")
+    assert_eq "$output" "$expected" "Written file resolves to the branch's own delta"
+
+    output=$("$BRANCHFS" where where_child file2.txt "$TEST_MNT" --storage "$TEST_STORAGE")
+    expected=$(printf 'delta:     %s (tombstoned)\nresolved:  (none)' "$branches/where_child/files/file2.txt")
+    assert_eq "$output" "$expected" "Deleted file reports its tombstone"
+
+    output=$("$BRANCHFS" where where_parent file2.txt "$TEST_MNT" --storage "$TEST_STORAGE" | tail -1)
+    assert "[[ '$output' == 'resolved:'*'/file2.txt' && '$output' != *'/branches/'* ]]" "Parent still resolves to the base"
+
+    local status=0
+    "$BRANCHFS" where nosuch file1.txt "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Unknown branch fails"
+
+    do_unmount
+}
+
+test_deltas() {
+    setup
+    do_mount
+    do_create "deltas_parent" "main"
+    echo "parent" > "$TEST_MNT/parent_only.txt"
+    do_create "deltas_child" "deltas_parent"
+    printf 'twelve bytes' > "$TEST_MNT/file1.txt"
+    mkdir -p "$TEST_MNT/newdir"
+    printf 'abc' > "$TEST_MNT/newdir/inner.txt"
+    rm "$TEST_MNT/file2.txt"
+
+    local output expected
+    output=$("$BRANCHFS" deltas deltas_child "$TEST_MNT" --storage "$TEST_STORAGE")
+    expected=$(printf '/file1.txt\t12\n/file2.txt\ttombstone\n/newdir/inner.txt\t3')
+    assert_eq "$output" "$expected" "Own delta files with sizes, and tombstones"
+
+    output=$("$BRANCHFS" deltas deltas_parent "$TEST_MNT" --storage "$TEST_STORAGE")
+    assert_eq "$output" "$(printf '/parent_only.txt\t7')" "Parent lists only its own delta"
+
+    local status=0
+    "$BRANCHFS" deltas nosuch "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Unknown branch fails"
+
+    do_unmount
+}
+
+test_is_empty() {
+    setup
+    do_mount
+    do_create "empty_parent" "main"
+    echo "parent" > "$TEST_MNT/parent.txt"
+    do_create "empty_child" "empty_parent"
+
+    is_empty() {
+        local status=0
+        "$BRANCHFS" is-empty "$1" "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+        echo "$status"
+    }
+
+    assert_eq "$(is_empty empty_child)" "0" "Fresh child is empty despite its parent's changes"
+    assert_eq "$(is_empty empty_parent)" "2" "Branch with a delta file is not empty"
+
+    mkdir "$TEST_MNT/only_dir"
+    assert_eq "$(is_empty empty_child)" "0" "Empty directories do not count"
+
+    rm "$TEST_MNT/file1.txt"
+    assert_eq "$(is_empty empty_child)" "2" "Tombstone makes the branch non-empty"
+    assert_eq "$("$BRANCHFS" is-empty empty_child "$TEST_MNT" --storage "$TEST_STORAGE")" "not empty" "Prints the result"
+
+    assert_eq "$(is_empty nosuch)" "1" "Unknown branch fails"
+
+    do_unmount
+}
+
+test_fuse_features() {
+    setup
+    do_mount
+
+    # Every kernel this runs on offers atomic O_TRUNC, and the mount uses it
+    local line
+    line=$("$BRANCHFS" status --storage "$TEST_STORAGE" | grep '^fuse:')
+    assert "[[ '$line' == 'fuse: supported='*atomic_o_trunc* ]]" "Status lists supported features"
+    assert "[[ '$line' == *' enabled=atomic_o_trunc' ]]" "Status lists enabled features"
+
+    line=$("$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" | grep '^fuse:')
+    assert "[[ '$line' == *'enabled=atomic_o_trunc' ]]" "Inspect lists the mount's features"
+
+    do_unmount
+}
+
+test_branch_diff() {
+    setup
+    do_mount
+    do_create "diff_parent" "main"
+    echo "parent" > "$TEST_MNT/parent_only.txt"
+    do_create "diff_branch" "diff_parent"
+    echo "changed" > "$TEST_MNT/file1.txt"
+    echo "new" > "$TEST_MNT/added.txt"
+    rm "$TEST_MNT/file2.txt"
+
+    local output expected
+    output=$("$BRANCHFS" diff "$TEST_MNT" --storage "$TEST_STORAGE")
+    expected=$(printf 'added\t/added.txt\nmodified\t/file1.txt\ndeleted\t/file2.txt')
+    assert_eq "$output" "$expected" "Current branch's changes against the base"
+
+    output=$("$BRANCHFS" diff "$TEST_MNT" --branch diff_parent --storage "$TEST_STORAGE")
+    assert_eq "$output" "$(printf 'added\t/parent_only.txt')" "Named branch"
+
+    # Read-only: nothing about the branch changes
+    assert_file_contains "$TEST_MNT/file1.txt" "changed" "Branch content untouched"
+    assert "[[ ! -e '$TEST_BASE/added.txt' ]]" "Base untouched"
+
+    do_unmount
+}
+
+test_check_access() {
+    setup
+    do_mount
+    do_create "access_branch" "main"
+
+    echo "secret" > "$TEST_MNT/owned.txt"
+    chown 1000:1000 "$TEST_MNT/owned.txt"
+    chmod 640 "$TEST_MNT/owned.txt"
+
+    access() {
+        "$BRANCHFS" access access_branch "$1" "$TEST_MNT" --storage "$TEST_STORAGE" "${@:2}"
+    }
+
+    # Owner class
+    assert_eq "$(access owned.txt --uid 1000 --gid 1000 --mask rw)" "allowed" "Owner may read and write"
+    assert_eq "$(access owned.txt --uid 1000 --gid 1000 --mask x)" "denied" "Owner may not execute"
+
+    # Group class
+    assert_eq "$(access owned.txt --uid 2000 --gid 1000 --mask r)" "allowed" "Group may read"
+    assert_eq "$(access owned.txt --uid 2000 --gid 1000 --mask w)" "denied" "Group may not write"
+
+    # Other class, with the status for a denial
+    local rc=0
+    access owned.txt --uid 2000 --gid 2000 --mask r > /dev/null || rc=$?
+    assert_eq "$rc" "2" "Others may not read (exit 2)"
+    assert_eq "$(access owned.txt --uid 2000 --gid 2000 --mask f)" "allowed" "Existence check passes"
+
+    # Root: read/write always, execute only with an execute bit; --gid defaults
+    # to the user's primary group
+    assert_eq "$(access owned.txt --uid 0 --mask rw)" "allowed" "Root may read and write"
+    assert_eq "$(access owned.txt --uid 0 --mask x)" "denied" "Root may not execute without x bits"
+
+    # Mode changes are seen per branch
+    chmod 600 "$TEST_MNT/file1.txt"
+    assert_eq "$(access file1.txt --uid 2000 --gid 2000)" "denied" "Branch's own mode"
+    local main_result
+    main_result=$("$BRANCHFS" access main file1.txt "$TEST_MNT" --uid 2000 --gid 2000 --storage "$TEST_STORAGE")
+    assert_eq "$main_result" "allowed" "Base mode on main"
+
+    rc=0
+    access missing.txt --uid 1000 --gid 1000 2> /dev/null || rc=$?
+    assert_eq "$rc" "1" "Missing path is an error"
+
+    do_unmount
+}
+
+test_status_ping() {
+    setup
+    do_mount
+
+    local output
+    output=$("$BRANCHFS" status --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'version: '[0-9]* ]]" "Status reports the daemon version"
+    assert "[[ '$output' == *'uptime: '[0-9]* ]]" "Status reports the daemon uptime"
+
+    output=$(python3 -c "import json, socket, sys
+s = socket.socket(socket.AF_UNIX)
+s.connect(sys.argv[1])
+s.sendall(b'{\"cmd\":\"ping\"}\\n')
+data = json.loads(s.makefile().readline())['data']
+print(data['mounts'], data['started_at'] > 0)" "$TEST_STORAGE/daemon.sock")
+    assert_eq "$output" "1 True" "Ping reports the mount count and start time"
+
+    do_unmount
+
+    # A daemon that accepts connections but never answers is reported as
+    # not responding
+    local wedged="$TEST_STORAGE-wedged" status=0
+    mkdir -p "$wedged"
+    python3 -c "import socket, sys, time
+s = socket.socket(socket.AF_UNIX)
+s.bind(sys.argv[1])
+s.listen(1)
+time.sleep(30)" "$wedged/daemon.sock" &
+    local listener=$!
+    sleep 0.5
+    output=$(timeout 20 "$BRANCHFS" status --storage "$wedged" 2>&1) || status=$?
+    kill "$listener" 2>/dev/null || true
+    wait "$listener" 2>/dev/null || true
+    rm -rf "$wedged"
+    assert_eq "$status" "1" "Status fails against a wedged daemon"
+    assert "[[ '$output' == 'Error: daemon not responding'* ]]" "Error says the daemon is not responding"
+}
+
+test_list_mounts() {
+    setup
+    do_mount
+    do_mount2
+    do_create "mounts_branch" "main"
+
+    local expected
+    expected=$(printf '%s\tmounts_branch\n%s\tmain\n' "$TEST_MNT" "$TEST_MNT2" | sort)
+    assert_eq "$("$BRANCHFS" mounts --storage "$TEST_STORAGE")" "$expected" "Mounts lists each mountpoint with its branch"
+
+    do_unmount2
+    assert_eq "$("$BRANCHFS" mounts --storage "$TEST_STORAGE")" "$(printf '%s\tmounts_branch' "$TEST_MNT")" "Unmounted view no longer listed"
+
+    do_unmount
+}
+
+test_metrics() {
+    setup
+    do_mount
+
+    metric() {
+        "$BRANCHFS" metrics --storage "$TEST_STORAGE" | awk -v name="$1" '$1 == name { print $2 }'
+    }
+
+    local output
+    output=$("$BRANCHFS" metrics --storage "$TEST_STORAGE")
+    assert "[[ '$output' == *'# TYPE branchfs_commits_total counter'* ]]" "Metrics are in the Prometheus text format"
+    assert_eq "$(metric branchfs_mounts)" "1" "Mount gauge counts the mount"
+
+    local commits aborts created written
+    commits=$(metric branchfs_commits_total)
+    aborts=$(metric branchfs_aborts_total)
+    created=$(metric branchfs_branches_created_total)
+    written=$(metric branchfs_bytes_written_total)
+
+    do_create "metrics_commit" "main"
+    printf '0123456789' > "$TEST_MNT/metrics.txt"
+    do_commit > /dev/null
+    do_create "metrics_abort" "main"
+    do_abort > /dev/null
+
+    assert_eq "$(metric branchfs_commits_total)" "$((commits + 1))" "Commit counted"
+    assert_eq "$(metric branchfs_aborts_total)" "$((aborts + 1))" "Abort counted"
+    assert_eq "$(metric branchfs_branches_created_total)" "$((created + 2))" "Creations counted"
+    assert_eq "$(metric branchfs_bytes_written_total)" "$((written + 10))" "Written bytes counted"
+
+    do_unmount
+}
+
+# Run tests
+run_test "Inspect Mount" test_inspect_mount
+run_test "Branch Counters" test_branch_counters
+run_test "Inspect Unknown Mount" test_inspect_unknown_mount
+run_test "Diff File" test_diff_file
+run_test "Read If Epoch" test_read_if_epoch
+run_test "Truncate" test_truncate
+run_test "Rename Path Directory" test_rename_path_directory
+run_test "Hash" test_hash
+run_test "Hash After Preserved Commit" test_hash_after_preserved_commit
+run_test "Bump Epoch" test_bump_epoch
+run_test "Notifier Health" test_notifier_health
+run_test "Deletions" test_deletions
+run_test "Who" test_who
+run_test "Where" test_where
+run_test "Deltas" test_deltas
+run_test "Is Empty" test_is_empty
+run_test "FUSE Features" test_fuse_features
+run_test "Branch Diff" test_branch_diff
+run_test "Check Access" test_check_access
+run_test "Status Ping" test_status_ping
+run_test "List Mounts" test_list_mounts
+run_test "Metrics" test_metrics
+
+print_summary
@@ -92,10 +92,147 @@ test_unmount_cleanup() {
     do_unmount
 }
 
+test_unmount_remount_race() {
+    setup
+    do_mount
+    do_mount2
+
+    local status=0
+    "$BRANCHFS" mount --storage "$TEST_STORAGE" "$TEST_MNT" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Mounting an already mounted path is refused"
+
+    # Race unmount against a remount of the same path; whichever wins, the
+    # daemon's view must match the kernel's mount table
+    local i consistent=0 tracked mounted
+    for i in $(seq 1 5); do
+        "$BRANCHFS" unmount "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 &
+        "$BRANCHFS" mount --storage "$TEST_STORAGE" "$TEST_MNT" > /dev/null 2>&1 || true
+        wait
+        sleep 0.3
+        tracked=0
+        "$BRANCHFS" inspect "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null 2>&1 && tracked=1
+        mounted=0
+        grep -q " $TEST_MNT fuse" /proc/mounts && mounted=1
+        [[ "$tracked" == "$mounted" ]] && consistent=$((consistent + 1))
+        [[ "$tracked" == 1 ]] || do_mount
+    done
+    assert_eq "$consistent" "5" "Daemon and kernel agree after each race"
+    assert_eq "$(grep -c " $TEST_MNT fuse" /proc/mounts)" "1" "Path is mounted exactly once"
+
+    # Remount right after the last unmount, while the daemon winds down
+    do_unmount2
+    local ok=0
+    for i in $(seq 1 5); do
+        "$BRANCHFS" unmount "$TEST_MNT" --storage "$TEST_STORAGE" > /dev/null
+        if "$BRANCHFS" mount --base "$TEST_BASE" --storage "$TEST_STORAGE" "$TEST_MNT" > /dev/null 2>&1; then
+            sleep 0.3
+            [[ "$(cat "$TEST_MNT/file1.txt" 2>/dev/null)" == "base content" ]] && ok=$((ok + 1))
+        fi
+    done
+    assert_eq "$ok" "5" "Every immediate remount after the last unmount is usable"
+
+    do_unmount
+}
+
+test_unmount_auto_commit() {
+    setup
+    do_mount --auto-commit
+    do_create "auto_branch" "main"
+    echo "agent result" > "$TEST_MNT/result.txt"
+
+    do_unmount
+    assert "! mountpoint -q '$TEST_MNT'" "Mount point unmounted"
+    assert_file_contains "$TEST_BASE/result.txt" "agent result" "Branch committed to base on unmount"
+
+    # A nested branch commits into its parent, not the base
+    do_mount --auto-commit
+    do_mount2
+    do_create "outer" "main"
+    do_create "inner" "outer"
+    echo "inner work" > "$TEST_MNT/inner.txt"
+    do_unmount
+    assert_file_not_exists "$TEST_BASE/inner.txt" "Nested branch not committed to base"
+    assert_file_contains "$TEST_MNT2/@outer/inner.txt" "inner work" "Nested branch committed to its parent"
+    assert_branch_not_exists "inner" "Committed branch removed"
+
+    do_unmount2
+}
+
+test_unmount_auto_abort() {
+    setup
+    do_mount --auto-abort
+    do_mount2
+    do_create "doomed" "main"
+    echo "discard me" > "$TEST_MNT/doomed.txt"
+
+    do_unmount
+    assert "! mountpoint -q '$TEST_MNT'" "Mount point unmounted"
+    assert_file_not_exists "$TEST_BASE/doomed.txt" "Nothing committed"
+    assert_branch_not_exists "doomed" "Branch aborted on unmount"
+
+    do_unmount2
+}
+
+test_unmount_auto_commit_shared_branch() {
+    setup
+    do_mount --auto-commit
+    do_mount2
+    do_create "shared" "main"
+    echo "shared work" > "$TEST_MNT/shared.txt"
+    "$BRANCHFS" switch shared "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+
+    # Another mount is on the branch: the unmount is refused
+    local output status=0
+    output=$("$BRANCHFS" unmount "$TEST_MNT" --storage "$TEST_STORAGE" 2>&1) || status=$?
+    assert_eq "$status" "1" "Unmount refused"
+    assert "[[ \"\$output\" == *'also mounted at $TEST_MNT2'* ]]" "Error names the other mount"
+    assert "mountpoint -q '$TEST_MNT'" "Still mounted"
+    assert_file_not_exists "$TEST_BASE/shared.txt" "Nothing committed"
+
+    # Once the other mount has moved on, the unmount commits
+    "$BRANCHFS" switch main "$TEST_MNT2" --storage "$TEST_STORAGE" > /dev/null
+    do_unmount
+    assert_file_contains "$TEST_BASE/shared.txt" "shared work" "Committed after the other mount left"
+
+    # Mounts on main unmount as usual
+    do_mount --auto-commit
+    do_unmount
+    assert "! mountpoint -q '$TEST_MNT'" "Main mount unmounted"
+
+    do_unmount2
+}
+
+test_shutdown_unmounts_all() {
+    setup
+    local third="$TEST_MNT-third"
+    mkdir -p "$third"
+    do_mount
+    do_mount2
+    "$BRANCHFS" mount --storage "$TEST_STORAGE" "$third" > /dev/null
+    sleep 0.5
+    assert "mountpoint -q '$third'" "Third mount active"
+
+    "$BRANCHFS" shutdown --storage "$TEST_STORAGE" > /dev/null
+    assert "! mountpoint -q '$TEST_MNT'" "First mount unmounted"
+    assert "! mountpoint -q '$TEST_MNT2'" "Second mount unmounted"
+    assert "! mountpoint -q '$third'" "Third mount unmounted"
+    assert "[[ ! -S '$TEST_STORAGE/daemon.sock' ]]" "Daemon exited"
+    rmdir "$third"
+
+    local status=0
+    "$BRANCHFS" shutdown --storage "$TEST_STORAGE" > /dev/null 2>&1 || status=$?
+    assert_eq "$status" "1" "Shutdown without a daemon fails"
+}
+
 # Run tests
 run_test "Unmount Main" test_unmount_main
 run_test "Unmount Discards Single Branch" test_unmount_discards_single_branch
 run_test "Unmount Cleans All Branches" test_unmount_cleans_all_branches
 run_test "Unmount Cleanup" test_unmount_cleanup
+run_test "Unmount Remount Race" test_unmount_remount_race
+run_test "Unmount Auto Commit" test_unmount_auto_commit
+run_test "Unmount Auto Abort" test_unmount_auto_abort
+run_test "Unmount Auto Commit Shared Branch" test_unmount_auto_commit_shared_branch
+run_test "Shutdown Unmounts All" test_shutdown_unmounts_all
 
 print_summary